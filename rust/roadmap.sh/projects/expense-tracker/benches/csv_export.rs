@@ -0,0 +1,85 @@
+//! Peak memory of `export --format csv` streaming a large JSON lines datastore
+//! against reading every expense first. Run with `cargo bench --bench csv_export`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use expense_tracker::{export_csv, jsonl_expenses, read_expenses, write_datastore, Datastore};
+
+const EXPENSES: u32 = 200_000;
+
+/// The system allocator, keeping count of the bytes in use and the most there were
+struct Counting;
+
+static IN_USE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let pointer = unsafe { System.alloc(layout) };
+        if !pointer.is_null() {
+            let in_use = IN_USE.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(in_use, Ordering::Relaxed);
+        }
+        pointer
+    }
+
+    unsafe fn dealloc(&self, pointer: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(pointer, layout) };
+        IN_USE.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// The most bytes `run` had allocated at once on top of what was already in use
+fn peak_of(run: impl FnOnce() -> usize) -> usize {
+    let before = IN_USE.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+
+    assert_eq!(run(), EXPENSES as usize);
+
+    PEAK.load(Ordering::Relaxed) - before
+}
+
+fn main() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("expenses.jsonl");
+    let path = path.to_str().unwrap();
+
+    let expenses = (1..=EXPENSES)
+        .map(|id| {
+            serde_json::from_value(serde_json::json!({
+                "id": id,
+                "description": format!("Expense number {}", id),
+                "amount_cents": id % 10_000 + 1,
+                "date": "2024-03-01",
+                "category": "food",
+            }))
+            .unwrap()
+        })
+        .collect();
+    let datastore = Datastore {
+        next_id: EXPENSES + 1,
+        expenses,
+        recurring: Vec::new(),
+    };
+    write_datastore(path, &datastore).unwrap();
+    drop(datastore);
+
+    let streamed =
+        peak_of(|| export_csv(jsonl_expenses(path).unwrap(), std::io::sink(), "USD").unwrap());
+    let batch = peak_of(|| {
+        let expenses = read_expenses(path).unwrap();
+        export_csv(expenses.into_iter().map(Ok), std::io::sink(), "USD").unwrap()
+    });
+
+    println!(
+        "CSV export of {} expenses: streamed at a peak of {} KiB, read whole at {} KiB ({:.0}x)",
+        EXPENSES,
+        streamed / 1024,
+        batch / 1024,
+        batch as f64 / streamed as f64
+    );
+}
@@ -0,0 +1,72 @@
+//! What `list --limit` saves by streaming a large JSON lines datastore instead
+//! of reading every expense. Run with `cargo bench --bench streaming`.
+
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use expense_tracker::{read_expenses, stream_expenses, write_datastore, Datastore, ExpenseFilter};
+
+const EXPENSES: u32 = 200_000;
+const LIMIT: usize = 10;
+const RUNS: u32 = 5;
+
+#[derive(Parser)]
+struct List {
+    #[command(flatten)]
+    filter: ExpenseFilter,
+}
+
+/// The fastest of `RUNS` runs of `run`
+fn best_of(mut run: impl FnMut() -> usize) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let started = Instant::now();
+            assert_eq!(run(), LIMIT);
+            started.elapsed()
+        })
+        .min()
+        .unwrap_or_default()
+}
+
+fn main() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("expenses.jsonl");
+    let path = path.to_str().unwrap();
+
+    let expenses = (1..=EXPENSES)
+        .map(|id| {
+            serde_json::from_value(serde_json::json!({
+                "id": id,
+                "description": format!("Expense number {}", id),
+                "amount_cents": id % 10_000 + 1,
+                "date": "2024-03-01",
+                "category": "food",
+            }))
+            .unwrap()
+        })
+        .collect();
+    let datastore = Datastore {
+        next_id: EXPENSES + 1,
+        expenses,
+        recurring: Vec::new(),
+    };
+    write_datastore(path, &datastore).unwrap();
+
+    let filter = List::parse_from(["list"]).filter;
+
+    let streamed = best_of(|| {
+        stream_expenses(path, &filter, "USD", 0, LIMIT)
+            .unwrap()
+            .len()
+    });
+    let read = best_of(|| read_expenses(path).unwrap().into_iter().take(LIMIT).count());
+
+    println!(
+        "first {} of {} expenses: streamed in {:?}, read whole in {:?} ({:.0}x)",
+        LIMIT,
+        EXPENSES,
+        streamed,
+        read,
+        read.as_secs_f64() / streamed.as_secs_f64()
+    );
+}
@@ -0,0 +1,41 @@
+//! Captures build metadata for `expense-tracker version --verbose`
+
+use std::process::Command;
+
+/// First line of a command's output, or "unknown" when it can't run
+fn output_of(program: &str, args: &[&str]) -> String {
+    Command::new(program)
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .and_then(|stdout| stdout.lines().next().map(str::to_string))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+fn main() {
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+
+    println!(
+        "cargo:rustc-env=BUILD_GIT_COMMIT={}",
+        output_of("git", &["rev-parse", "--short", "HEAD"])
+    );
+    println!(
+        "cargo:rustc-env=BUILD_DATE={}",
+        chrono::Utc::now().format("%Y-%m-%d")
+    );
+    println!(
+        "cargo:rustc-env=BUILD_RUSTC_VERSION={}",
+        output_of(&rustc, &["--version"])
+    );
+
+    // Rebuild when the checked out commit changes, not on every build
+    for path in ["HEAD", "refs"] {
+        let path = output_of("git", &["rev-parse", "--git-path", path]);
+        if path != "unknown" {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,256 @@
+//! Arithmetic for `add --amount`, such as `3*4.50+2.00`
+//!
+//! ```text
+//! sum      := product (("+" | "-") product)*
+//! product  := unary (("*" | "/") unary)*
+//! unary    := "-" unary | primary
+//! primary  := "(" sum ")" | number
+//! number   := digits ["." digits]
+//! ```
+//!
+//! Spaces between tokens are ignored. Errors give the column they were found at.
+
+/// Evaluates an arithmetic expression to the amount it stands for
+pub fn eval_amount(input: &str) -> Result<f64, String> {
+    let mut parser = Parser {
+        chars: input.chars().collect(),
+        next: 0,
+    };
+
+    let amount = parser.sum()?;
+
+    parser.skip_whitespace();
+    if parser.next < parser.chars.len() {
+        return Err(parser.error("an operator or the end of the amount"));
+    }
+
+    if !amount.is_finite() {
+        return Err(format!("'{}' is too large to be an amount", input));
+    }
+
+    // Leave out the float noise of e.g. `0.1*3`, which no amount has
+    Ok((amount * 1e9).round() / 1e9)
+}
+
+/// Recursive-descent parser evaluating as it goes
+struct Parser {
+    chars: Vec<char>,
+    next: usize,
+}
+
+impl Parser {
+    fn skip_whitespace(&mut self) {
+        while self.chars.get(self.next).is_some_and(|c| c.is_whitespace()) {
+            self.next += 1;
+        }
+    }
+
+    /// The next character that isn't a space, without consuming it
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.get(self.next).copied()
+    }
+
+    fn error(&self, expected: &str) -> String {
+        let found = match self.chars.get(self.next) {
+            Some(c) => format!("'{}'", c),
+            None => "the end of the amount".to_string(),
+        };
+
+        format!(
+            "expected {}, found {} at column {}",
+            expected,
+            found,
+            self.next + 1
+        )
+    }
+
+    fn sum(&mut self) -> Result<f64, String> {
+        let mut value = self.product()?;
+
+        while let Some(operator @ ('+' | '-')) = self.peek() {
+            self.next += 1;
+            let right = self.product()?;
+
+            value = match operator {
+                '+' => value + right,
+                _ => value - right,
+            };
+        }
+
+        Ok(value)
+    }
+
+    fn product(&mut self) -> Result<f64, String> {
+        let mut value = self.unary()?;
+
+        while let Some(operator @ ('*' | '/')) = self.peek() {
+            let position = self.next;
+            self.next += 1;
+            let right = self.unary()?;
+
+            value = match operator {
+                '*' => value * right,
+                _ if right == 0.0 => {
+                    return Err(format!("division by zero at column {}", position + 1))
+                }
+                _ => value / right,
+            };
+        }
+
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<f64, String> {
+        if self.peek() == Some('-') {
+            self.next += 1;
+            return self.unary().map(|value| -value);
+        }
+
+        self.primary()
+    }
+
+    fn primary(&mut self) -> Result<f64, String> {
+        if self.peek() == Some('(') {
+            self.next += 1;
+            let value = self.sum()?;
+
+            if self.peek() != Some(')') {
+                return Err(self.error("')'"));
+            }
+            self.next += 1;
+
+            return Ok(value);
+        }
+
+        self.number()
+    }
+
+    fn number(&mut self) -> Result<f64, String> {
+        let start = self.next;
+        let length = self.chars[start..]
+            .iter()
+            .position(|&c| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(self.chars.len() - start);
+
+        let number: String = self.chars[start..start + length].iter().collect();
+
+        // `f64` also takes `.5` and `5.`, but an amount has digits on both sides of the dot
+        let well_formed = match number.split_once('.') {
+            Some((integer, fraction)) => {
+                !integer.is_empty() && !fraction.is_empty() && !fraction.contains('.')
+            }
+            None => !number.is_empty(),
+        };
+        if !well_formed {
+            if number.is_empty() {
+                return Err(self.error("a number"));
+            }
+            return Err(format!(
+                "'{}' is not a number at column {}",
+                number,
+                start + 1
+            ));
+        }
+
+        self.next += length;
+        number.parse().map_err(|_| self.error("a number"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn evaluates_the_operators_by_precedence() {
+        assert_eq!(eval_amount("3*4.50+2.00"), Ok(15.5));
+        assert_eq!(eval_amount("2+3*4"), Ok(14.0));
+        assert_eq!(eval_amount("10-4-3"), Ok(3.0));
+        assert_eq!(eval_amount("12/4/3"), Ok(1.0));
+        assert_eq!(eval_amount("(2+3)*4"), Ok(20.0));
+        assert_eq!(eval_amount("((1.25))"), Ok(1.25));
+    }
+
+    #[test]
+    fn takes_plain_amounts_spaces_and_minus_signs() {
+        assert_eq!(eval_amount("4.50"), Ok(4.5));
+        assert_eq!(eval_amount(" 3 * 4.50 + 2 "), Ok(15.5));
+        assert_eq!(eval_amount("-5"), Ok(-5.0));
+        assert_eq!(eval_amount("10 - -2"), Ok(12.0));
+        assert_eq!(eval_amount("-(2+3)*2"), Ok(-10.0));
+    }
+
+    #[test]
+    fn leaves_out_float_noise() {
+        assert_eq!(eval_amount("0.1*3"), Ok(0.3));
+        assert_eq!(eval_amount("0.1+0.2"), Ok(0.3));
+        assert_eq!(eval_amount("10/3"), Ok(3.333333333));
+    }
+
+    #[test]
+    fn rejects_division_by_zero() {
+        assert_eq!(
+            eval_amount("5/0"),
+            Err("division by zero at column 2".to_string())
+        );
+        assert_eq!(
+            eval_amount("1 + 5 / (2-2)"),
+            Err("division by zero at column 7".to_string())
+        );
+    }
+
+    #[test]
+    fn reports_malformed_expressions_with_their_column() {
+        assert_eq!(
+            eval_amount(""),
+            Err("expected a number, found the end of the amount at column 1".to_string())
+        );
+        assert_eq!(
+            eval_amount("3*"),
+            Err("expected a number, found the end of the amount at column 3".to_string())
+        );
+        assert_eq!(
+            eval_amount("3 4"),
+            Err("expected an operator or the end of the amount, found '4' at column 3".to_string())
+        );
+        assert_eq!(
+            eval_amount("(2+3"),
+            Err("expected ')', found the end of the amount at column 5".to_string())
+        );
+        assert_eq!(
+            eval_amount("2+3)"),
+            Err("expected an operator or the end of the amount, found ')' at column 4".to_string())
+        );
+        assert_eq!(
+            eval_amount("4.50x"),
+            Err("expected an operator or the end of the amount, found 'x' at column 5".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_numbers_without_digits_on_both_sides() {
+        assert_eq!(
+            eval_amount("1+.5"),
+            Err("'.5' is not a number at column 3".to_string())
+        );
+        assert_eq!(
+            eval_amount("5."),
+            Err("'5.' is not a number at column 1".to_string())
+        );
+        assert_eq!(
+            eval_amount("1.2.3"),
+            Err("'1.2.3' is not a number at column 1".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_amounts_too_large_to_store() {
+        let huge = format!("{}*{}", "9".repeat(200), "9".repeat(200));
+
+        assert_eq!(
+            eval_amount(&huge),
+            Err(format!("'{}' is too large to be an amount", huge))
+        );
+    }
+}
@@ -0,0 +1,562 @@
+//! Expressions for `list --filter`, such as `amount > 50 and category == food`
+//!
+//! ```text
+//! expr       := and ("or" and)*
+//! and        := primary ("and" primary)*
+//! primary    := "(" expr ")" | field operator value
+//! field      := "amount" | "description" | "category" | "date"
+//! operator   := "==" | "!=" | "<" | "<=" | ">" | ">=" | "~"
+//! ```
+//!
+//! Values are bare words or double-quoted strings. Text is compared ignoring
+//! case, and `~` matches text containing the value.
+
+use std::cmp::Ordering;
+use std::fmt;
+
+use chrono::NaiveDate;
+
+use crate::{parse_amount, to_minor_units, Expense};
+
+/// A parsed filter expression, evaluated against each expense
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Compare(Comparison),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Comparison {
+    /// In currency units, compared in an expense's own minor units
+    Amount(Operator, f64),
+    Date(Operator, NaiveDate),
+    Description(Operator, String),
+    Category(Operator, String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operator {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Contains,
+}
+
+impl Operator {
+    fn symbol(self) -> &'static str {
+        match self {
+            Operator::Eq => "==",
+            Operator::Ne => "!=",
+            Operator::Lt => "<",
+            Operator::Le => "<=",
+            Operator::Gt => ">",
+            Operator::Ge => ">=",
+            Operator::Contains => "~",
+        }
+    }
+
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Operator::Eq => ordering == Ordering::Equal,
+            Operator::Ne => ordering != Ordering::Equal,
+            Operator::Lt => ordering == Ordering::Less,
+            Operator::Le => ordering != Ordering::Greater,
+            Operator::Gt => ordering == Ordering::Greater,
+            Operator::Ge => ordering != Ordering::Less,
+            // Only text can be searched, the parser rejects it elsewhere
+            Operator::Contains => false,
+        }
+    }
+
+    fn holds_for_text(self, text: &str, value: &str) -> bool {
+        let text = text.to_lowercase();
+        let value = value.to_lowercase();
+
+        match self {
+            Operator::Contains => text.contains(&value),
+            _ => self.holds(text.cmp(&value)),
+        }
+    }
+}
+
+impl Expr {
+    /// Whether `expense`, whose amount is in `currency`, matches
+    pub fn matches(&self, expense: &Expense, currency: &str) -> bool {
+        match self {
+            Expr::And(left, right) => {
+                left.matches(expense, currency) && right.matches(expense, currency)
+            }
+            Expr::Or(left, right) => {
+                left.matches(expense, currency) || right.matches(expense, currency)
+            }
+            Expr::Compare(comparison) => comparison.matches(expense, currency),
+        }
+    }
+}
+
+impl Comparison {
+    fn matches(&self, expense: &Expense, currency: &str) -> bool {
+        match self {
+            Comparison::Amount(operator, amount) => {
+                operator.holds(expense.amount.cmp(&to_minor_units(*amount, currency)))
+            }
+            // Expenses without a date or category only differ from every value
+            Comparison::Date(operator, date) => match expense.date {
+                Some(expense_date) => operator.holds(expense_date.cmp(date)),
+                None => *operator == Operator::Ne,
+            },
+            Comparison::Description(operator, text) => {
+                operator.holds_for_text(&expense.description, text)
+            }
+            Comparison::Category(operator, text) => match &expense.category {
+                Some(category) => operator.holds_for_text(category, text),
+                None => *operator == Operator::Ne,
+            },
+        }
+    }
+}
+
+/// Writes the expression back in filter syntax, with parentheses around `or`
+/// inside `and` so it reads the way it's grouped
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::And(left, right) => {
+                for (index, side) in [left, right].into_iter().enumerate() {
+                    if index > 0 {
+                        write!(f, " and ")?;
+                    }
+
+                    match side.as_ref() {
+                        Expr::Or(..) => write!(f, "({})", side)?,
+                        _ => write!(f, "{}", side)?,
+                    }
+                }
+                Ok(())
+            }
+            Expr::Or(left, right) => write!(f, "{} or {}", left, right),
+            Expr::Compare(comparison) => write!(f, "{}", comparison),
+        }
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Comparison::Amount(operator, amount) => {
+                write!(f, "amount {} {}", operator.symbol(), amount)
+            }
+            Comparison::Date(operator, date) => write!(f, "date {} {}", operator.symbol(), date),
+            Comparison::Description(operator, text) => {
+                write!(f, "description {} \"{}\"", operator.symbol(), text)
+            }
+            Comparison::Category(operator, text) => {
+                write!(f, "category {} \"{}\"", operator.symbol(), text)
+            }
+        }
+    }
+}
+
+/// Why an expression couldn't be parsed, and where
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseError {
+    /// Character offset of the offending token in the expression
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at column {}", self.message, self.position + 1)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Operator(Operator),
+    Open,
+    Close,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Token::Word(word) => write!(f, "'{}'", word),
+            Token::Quoted(text) => write!(f, "\"{}\"", text),
+            Token::Operator(operator) => write!(f, "'{}'", operator.symbol()),
+            Token::Open => write!(f, "'('"),
+            Token::Close => write!(f, "')'"),
+        }
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>, ParseError> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut index = 0;
+
+    while index < chars.len() {
+        let start = index;
+        let next = chars.get(index + 1).copied();
+
+        let token = match chars[index] {
+            c if c.is_whitespace() => {
+                index += 1;
+                continue;
+            }
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '~' => Token::Operator(Operator::Contains),
+            '=' if next == Some('=') => Token::Operator(Operator::Eq),
+            '!' if next == Some('=') => Token::Operator(Operator::Ne),
+            '<' if next == Some('=') => Token::Operator(Operator::Le),
+            '>' if next == Some('=') => Token::Operator(Operator::Ge),
+            '<' => Token::Operator(Operator::Lt),
+            '>' => Token::Operator(Operator::Gt),
+            '=' | '!' => {
+                return Err(ParseError {
+                    position: start,
+                    message: format!("unknown operator '{}'", chars[index]),
+                })
+            }
+            '"' => {
+                let Some(length) = chars[index + 1..].iter().position(|&c| c == '"') else {
+                    return Err(ParseError {
+                        position: start,
+                        message: "unterminated string".to_string(),
+                    });
+                };
+
+                let text = chars[index + 1..index + 1 + length].iter().collect();
+                index += length + 2;
+                tokens.push((start, Token::Quoted(text)));
+                continue;
+            }
+            _ => {
+                let length = chars[index..]
+                    .iter()
+                    .position(|&c| c.is_whitespace() || "()<>=!~\"".contains(c))
+                    .unwrap_or(chars.len() - index);
+
+                let word = chars[index..index + length].iter().collect();
+                index += length;
+                tokens.push((start, Token::Word(word)));
+                continue;
+            }
+        };
+
+        index += match token {
+            Token::Operator(Operator::Eq | Operator::Ne | Operator::Le | Operator::Ge) => 2,
+            _ => 1,
+        };
+        tokens.push((start, token));
+    }
+
+    Ok(tokens)
+}
+
+/// Recursive-descent parser over the tokens of an expression
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+    /// Position reported for errors at the end of the expression
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(_, token)| token)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens
+            .get(self.next)
+            .map_or(self.end, |(position, _)| *position)
+    }
+
+    fn error(&self, expected: &str) -> ParseError {
+        let found = match self.peek() {
+            Some(token) => token.to_string(),
+            None => "the end of the filter".to_string(),
+        };
+
+        ParseError {
+            position: self.position(),
+            message: format!("expected {}, found {}", expected, found),
+        }
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    fn expr(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.and()?;
+
+        while self.is_keyword("or") {
+            self.next += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr, ParseError> {
+        let mut expr = self.primary()?;
+
+        while self.is_keyword("and") {
+            self.next += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.primary()?));
+        }
+
+        Ok(expr)
+    }
+
+    fn primary(&mut self) -> Result<Expr, ParseError> {
+        if self.peek() == Some(&Token::Open) {
+            self.next += 1;
+            let expr = self.expr()?;
+
+            if self.peek() != Some(&Token::Close) {
+                return Err(self.error("')'"));
+            }
+            self.next += 1;
+
+            return Ok(expr);
+        }
+
+        self.comparison().map(Expr::Compare)
+    }
+
+    fn comparison(&mut self) -> Result<Comparison, ParseError> {
+        const FIELD: &str = "amount, description, category or date";
+
+        let Some(Token::Word(field)) = self.peek().cloned() else {
+            return Err(self.error(FIELD));
+        };
+        let field_position = self.position();
+        self.next += 1;
+
+        let Some(Token::Operator(operator)) = self.peek().cloned() else {
+            return Err(self.error("a comparison like '==' or '>'"));
+        };
+        let operator_position = self.position();
+        self.next += 1;
+
+        let value_position = self.position();
+        let value = match self.peek().cloned() {
+            Some(Token::Word(value) | Token::Quoted(value)) => value,
+            _ => return Err(self.error("a value")),
+        };
+        self.next += 1;
+
+        let invalid = |position: usize, message: String| ParseError { position, message };
+        let text_only = || {
+            invalid(
+                operator_position,
+                format!("'~' only works on text, not on {}", field),
+            )
+        };
+
+        match field.to_lowercase().as_str() {
+            "amount" if operator == Operator::Contains => Err(text_only()),
+            "date" if operator == Operator::Contains => Err(text_only()),
+            "amount" => parse_amount(&value)
+                .map(|amount| Comparison::Amount(operator, amount))
+                .map_err(|reason| invalid(value_position, reason)),
+            "date" => NaiveDate::parse_from_str(&value, "%Y-%m-%d")
+                .map(|date| Comparison::Date(operator, date))
+                .map_err(|_| {
+                    invalid(
+                        value_position,
+                        format!("'{}' is not a date in YYYY-MM-DD format", value),
+                    )
+                }),
+            "description" => Ok(Comparison::Description(operator, value)),
+            "category" => Ok(Comparison::Category(operator, value)),
+            _ => Err(invalid(
+                field_position,
+                format!("unknown field '{}' (use {})", field, FIELD),
+            )),
+        }
+    }
+}
+
+pub fn parse(input: &str) -> Result<Expr, ParseError> {
+    let mut parser = Parser {
+        tokens: tokenize(input)?,
+        next: 0,
+        end: input.chars().count(),
+    };
+
+    let expr = parser.expr()?;
+
+    if parser.peek().is_some() {
+        return Err(parser.error("'and', 'or' or the end of the filter"));
+    }
+
+    Ok(expr)
+}
+
+/// Parses a `--filter` argument
+pub fn parse_filter(value: &str) -> Result<Expr, String> {
+    parse(value).map_err(|error| error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn coffee() -> Expense {
+        serde_json::from_value(serde_json::json!({
+            "id": 1,
+            "description": "Coffee at the station",
+            "amount_cents": 350,
+            "date": "2024-03-01",
+            "category": "Food",
+        }))
+        .unwrap()
+    }
+
+    fn matches(filter: &str, expense: &Expense) -> bool {
+        parse(filter).unwrap().matches(expense, "USD")
+    }
+
+    fn error(filter: &str) -> ParseError {
+        parse(filter).unwrap_err()
+    }
+
+    #[test]
+    fn parses_a_comparison_of_each_field() {
+        assert_eq!(
+            parse("amount >= 3.50").unwrap(),
+            Expr::Compare(Comparison::Amount(Operator::Ge, 3.5))
+        );
+        assert_eq!(
+            parse("date < 2024-03-01").unwrap(),
+            Expr::Compare(Comparison::Date(
+                Operator::Lt,
+                NaiveDate::from_ymd_opt(2024, 3, 1).unwrap()
+            ))
+        );
+        assert_eq!(
+            parse("description ~ \"at the\"").unwrap(),
+            Expr::Compare(Comparison::Description(
+                Operator::Contains,
+                "at the".to_string()
+            ))
+        );
+        assert_eq!(
+            parse("CATEGORY!=food").unwrap(),
+            Expr::Compare(Comparison::Category(Operator::Ne, "food".to_string()))
+        );
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let expr = parse("category == food or amount > 5 and amount < 10").unwrap();
+
+        assert!(matches!(&expr, Expr::Or(_, right) if matches!(**right, Expr::And(..))));
+        assert_eq!(
+            expr.to_string(),
+            "category == \"food\" or amount > 5 and amount < 10"
+        );
+    }
+
+    #[test]
+    fn parentheses_group_first() {
+        let expr = parse("(category == food or amount > 5) and amount < 10").unwrap();
+
+        assert!(matches!(&expr, Expr::And(left, _) if matches!(**left, Expr::Or(..))));
+        assert_eq!(
+            expr.to_string(),
+            "(category == \"food\" or amount > 5) and amount < 10"
+        );
+    }
+
+    #[test]
+    fn matches_amounts_dates_and_text() {
+        let coffee = coffee();
+
+        assert!(matches("amount == 3.50", &coffee));
+        assert!(matches("amount > 3 and amount <= 3.5", &coffee));
+        assert!(!matches("amount > 3.50", &coffee));
+        assert!(matches("date >= 2024-03-01 and date < 2024-04-01", &coffee));
+        assert!(matches("category == food", &coffee));
+        assert!(matches("description ~ STATION", &coffee));
+        assert!(!matches("description == coffee", &coffee));
+        assert!(matches(
+            "category == travel or description ~ coffee",
+            &coffee
+        ));
+    }
+
+    #[test]
+    fn missing_fields_only_differ_from_every_value() {
+        let mut undated = coffee();
+        undated.date = None;
+        undated.category = None;
+
+        assert!(!matches("date < 2030-01-01", &undated));
+        assert!(matches("date != 2024-03-01", &undated));
+        assert!(!matches("category == food", &undated));
+        assert!(matches("category != food", &undated));
+    }
+
+    #[test]
+    fn errors_point_at_the_offending_token() {
+        assert_eq!(
+            error("amount > 5 and colour == red"),
+            ParseError {
+                position: 15,
+                message: "unknown field 'colour' (use amount, description, category or date)"
+                    .to_string(),
+            }
+        );
+        assert_eq!(
+            error("amount 5").to_string(),
+            "expected a comparison like '==' or '>', found '5' at column 8"
+        );
+        assert_eq!(
+            error("amount >").to_string(),
+            "expected a value, found the end of the filter at column 9"
+        );
+        assert_eq!(
+            error("(amount > 5").to_string(),
+            "expected ')', found the end of the filter at column 12"
+        );
+        assert_eq!(
+            error("amount > 5 amount < 9").to_string(),
+            "expected 'and', 'or' or the end of the filter, found 'amount' at column 12"
+        );
+        assert_eq!(
+            error("amount = 5").to_string(),
+            "unknown operator '=' at column 8"
+        );
+        assert_eq!(
+            error("description ~ \"open").to_string(),
+            "unterminated string at column 15"
+        );
+    }
+
+    #[test]
+    fn values_are_checked_for_their_field() {
+        assert_eq!(
+            error("amount ~ 5").to_string(),
+            "'~' only works on text, not on amount at column 8"
+        );
+        assert_eq!(
+            error("date == yesterday").to_string(),
+            "'yesterday' is not a date in YYYY-MM-DD format at column 9"
+        );
+        assert_eq!(error("amount > lots").position, 9);
+    }
+}
@@ -0,0 +1,10955 @@
+//! Core logic of the expense tracker: the datastore format, the commands and
+//! the helpers behind them. The `expense-tracker` binary is a thin CLI over it.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{mpsc, LazyLock, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Datelike, Local, NaiveDate, Utc};
+use clap::ValueEnum;
+use indicatif::ProgressBar;
+use prettytable::{format, format::TableFormat, row, Cell, Row, Table};
+use serde::{Deserialize, Serialize};
+
+pub mod amount_expr;
+pub mod filter;
+pub mod sqlite;
+pub mod storage;
+
+/// How much informational output commands print
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Verbosity {
+    /// Only errors
+    Quiet,
+    /// Success messages and warnings
+    Normal,
+    /// Everything, including datastore diagnostics
+    Verbose,
+}
+
+static VERBOSITY: AtomicU8 = AtomicU8::new(Verbosity::Normal as u8);
+
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+/// Set by `--precision`, `UNSET_PRECISION` leaving it to the currency
+static PRECISION: AtomicU8 = AtomicU8::new(UNSET_PRECISION);
+
+const UNSET_PRECISION: u8 = u8::MAX;
+
+/// Decimal places of the currencies configured with `decimal_places`
+static DECIMAL_PLACES: OnceLock<HashMap<String, u8>> = OnceLock::new();
+
+/// Exchange rates from the config file, see `set_rates`
+static RATES: OnceLock<HashMap<String, f64>> = OnceLock::new();
+
+/// How amounts and dates are displayed, see `--locale`
+static LOCALE: OnceLock<Locale> = OnceLock::new();
+
+/// Borders tables are drawn with, see `--theme`
+static THEME: OnceLock<Theme> = OnceLock::new();
+
+static BACKUP_COUNT: AtomicUsize = AtomicUsize::new(5);
+
+/// Shell command run after each datastore write, see `backup_command`
+static BACKUP_COMMAND: OnceLock<String> = OnceLock::new();
+
+static LARGE_DELETE_THRESHOLD: AtomicUsize = AtomicUsize::new(10);
+
+static TIMINGS: AtomicBool = AtomicBool::new(false);
+
+static PORCELAIN: AtomicBool = AtomicBool::new(false);
+
+/// Set by `--redact`, amounts are then shown as `***`
+static REDACT_AMOUNTS: AtomicBool = AtomicBool::new(false);
+
+/// Set by `--redact-descriptions`, descriptions then only show their first character
+static REDACT_DESCRIPTIONS: AtomicBool = AtomicBool::new(false);
+
+/// Set by `--no-init`, a missing datastore then reads as empty and can't be written
+static NO_INIT: AtomicBool = AtomicBool::new(false);
+
+/// Set by `--strict`, a datastore with fields this version doesn't know is
+/// then refused instead of read without them
+static STRICT: AtomicBool = AtomicBool::new(false);
+
+/// When the last datastore read finished, so a write can tell how long the
+/// change in between took
+static LAST_READ: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// Most decimal places amounts can be displayed with
+pub const MAX_PRECISION: u8 = 6;
+
+/// Decimal places of currencies without a `decimal_places` entry
+pub const DEFAULT_DECIMAL_PLACES: u8 = 2;
+
+/// Most decimal places a currency can have, dinars having the most in use.
+/// Amounts up to `MAX_AMOUNT` then fit in `i64` minor units.
+pub const MAX_DECIMAL_PLACES: u8 = 3;
+
+pub fn set_verbosity(verbosity: Verbosity) {
+    VERBOSITY.store(verbosity as u8, Ordering::Relaxed);
+}
+
+pub fn verbosity() -> Verbosity {
+    match VERBOSITY.load(Ordering::Relaxed) {
+        0 => Verbosity::Quiet,
+        1 => Verbosity::Normal,
+        _ => Verbosity::Verbose,
+    }
+}
+
+/// Sets how many decimal places displayed amounts get, clamped to `MAX_PRECISION`
+pub fn set_precision(precision: u8) {
+    PRECISION.store(precision.min(MAX_PRECISION), Ordering::Relaxed);
+}
+
+/// Sets the decimal places of each currency, by code, from the config file,
+/// clamped to `MAX_DECIMAL_PLACES`
+pub fn set_decimal_places(places: &HashMap<String, u8>) {
+    let places = places
+        .iter()
+        .map(|(code, places)| (code.to_uppercase(), (*places).min(MAX_DECIMAL_PLACES)))
+        .collect();
+
+    // Only `main` sets them, once
+    let _ = DECIMAL_PLACES.set(places);
+}
+
+/// Sets the `rates` from the config file that budgets, quotas and the digest
+/// convert expenses paid in other currencies with
+pub fn set_rates(rates: &HashMap<String, f64>) {
+    // Only `main` sets them, once
+    let _ = RATES.set(rates.clone());
+}
+
+pub fn set_locale(locale: Locale) {
+    // Only `main` sets it, once
+    let _ = LOCALE.set(locale);
+}
+
+fn locale() -> Locale {
+    LOCALE.get().copied().unwrap_or(Locale::CANONICAL)
+}
+
+pub fn set_theme(theme: Theme) {
+    // Only `main` sets it, once
+    let _ = THEME.set(theme);
+}
+
+/// Decimal places amounts in `currency` are entered with
+pub fn decimal_places(currency: &str) -> u8 {
+    DECIMAL_PLACES
+        .get()
+        .and_then(|places| places.get(&currency.to_uppercase()).copied())
+        .unwrap_or(DEFAULT_DECIMAL_PLACES)
+}
+
+/// Decimal places of the minor unit amounts in `currency` are stored in:
+/// cents, or fils and the like for currencies with three. Currencies with
+/// fewer, such as yen, still count cents so their stored amounts are the same
+/// whatever `decimal_places` says.
+pub fn minor_unit_places(currency: &str) -> u8 {
+    decimal_places(currency).max(2)
+}
+
+/// Decimal places amounts in `currency` are shown with: `--precision` if
+/// given, otherwise the currency's own
+pub fn display_precision(currency: &str) -> u8 {
+    match PRECISION.load(Ordering::Relaxed) {
+        UNSET_PRECISION => decimal_places(currency),
+        precision => precision,
+    }
+}
+
+/// Turns on reporting how long datastore reads, changes and writes take
+pub fn set_timings(enabled: bool) {
+    TIMINGS.store(enabled, Ordering::Relaxed);
+}
+
+/// Makes mutating commands report what they did as `key=value` lines for scripts
+pub fn set_porcelain(enabled: bool) {
+    PORCELAIN.store(enabled, Ordering::Relaxed);
+}
+
+fn porcelain() -> bool {
+    PORCELAIN.load(Ordering::Relaxed)
+}
+
+pub fn set_redaction(redaction: Redaction) {
+    REDACT_AMOUNTS.store(redaction.redact, Ordering::Relaxed);
+    REDACT_DESCRIPTIONS.store(redaction.redact_descriptions, Ordering::Relaxed);
+}
+
+pub fn set_no_init(enabled: bool) {
+    NO_INIT.store(enabled, Ordering::Relaxed);
+}
+
+pub fn set_strict(enabled: bool) {
+    STRICT.store(enabled, Ordering::Relaxed);
+}
+
+/// Whether `path` is missing and `--no-init` keeps it from being created
+fn missing_without_init(path: &str) -> bool {
+    NO_INIT.load(Ordering::Relaxed) && !std::path::Path::new(path).exists()
+}
+
+fn not_initialized(path: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!(
+            "'{}' doesn't exist and --no-init keeps it from being created",
+            path
+        ),
+    )
+}
+
+/// Prints how long `stage` took to stderr, keeping stdout clean for scripts
+fn report_timing(stage: &str, elapsed: Duration) {
+    if TIMINGS.load(Ordering::Relaxed) {
+        eprintln!(
+            "[timing] {}: {:.3} ms",
+            stage,
+            elapsed.as_secs_f64() * 1000.0
+        );
+    }
+}
+
+/// Sets how many timestamped backups writes keep, at least one
+pub fn set_backup_count(count: usize) {
+    BACKUP_COUNT.store(count.max(1), Ordering::Relaxed);
+}
+
+pub fn set_backup_command(command: String) {
+    // Only `main` sets it, once
+    let _ = BACKUP_COMMAND.set(command);
+}
+
+/// Sets how many expenses a command can remove before asking for their count
+/// to be typed instead of `y`
+pub fn set_large_delete_threshold(count: usize) {
+    LARGE_DELETE_THRESHOLD.store(count, Ordering::Relaxed);
+}
+
+fn large_delete_threshold() -> usize {
+    LARGE_DELETE_THRESHOLD.load(Ordering::Relaxed)
+}
+
+/// Prints a status message unless running with `--quiet`
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if verbosity() >= Verbosity::Normal {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Prints a diagnostic message only when running with `--verbose`
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if verbosity() >= Verbosity::Verbose {
+            println!($($arg)*);
+        }
+    };
+}
+
+/// Fields of an expense to add, as given on the command line
+#[derive(clap::Args, Clone)]
+pub struct NewExpense {
+    /// Expense's description
+    #[arg(short, long)]
+    pub description: String,
+
+    /// Expense's amount, e.g. 12.50, 1,200, $3 or 3*4.50+2
+    #[arg(short, long, value_parser = parse_amount_or_expr)]
+    pub amount: f64,
+
+    /// Expense's date (YYYY-MM-DD), defaults to today
+    #[arg(long)]
+    pub date: Option<NaiveDate>,
+
+    /// Expense's category
+    #[arg(short, long)]
+    pub category: Option<String>,
+
+    /// Longer free-form note, shown by `show`
+    #[arg(long)]
+    pub note: Option<String>,
+
+    /// Free-form tag, can be given multiple times
+    #[arg(long = "tag")]
+    pub tags: Vec<String>,
+
+    /// Accept negative amounts
+    #[arg(long)]
+    pub allow_negative: bool,
+
+    /// Record money given back, offsetting spending; give the amount as a positive number
+    #[arg(long, conflicts_with = "allow_negative")]
+    pub refund: bool,
+
+    /// Add the expense even if a similar one already exists
+    #[arg(long)]
+    pub force: bool,
+
+    /// Give the expense a short random ID instead of the next number
+    #[arg(long)]
+    pub random_id: bool,
+
+    /// Ask before adding an expense of at least this amount, unless --force is given
+    #[arg(long, value_parser = parse_amount)]
+    pub threshold: Option<f64>,
+
+    /// Don't offer a category learned from similar descriptions when none is given
+    #[arg(long, conflicts_with = "category")]
+    pub no_suggest: bool,
+
+    /// Accept an empty description
+    #[arg(long)]
+    pub allow_empty: bool,
+
+    /// Currency the expense was paid in, if not the datastore's
+    #[arg(long, value_name = "CURRENCY")]
+    pub paid_in: Option<String>,
+
+    /// Record the part of a `vendor: item` description before the first colon as the vendor
+    #[arg(long)]
+    pub parse_vendor: bool,
+
+    /// Refuse to add an expense that would take its category over its quota
+    #[arg(long)]
+    pub enforce: bool,
+
+    /// Path or URL of the expense's receipt, opened by `open`
+    #[arg(long)]
+    pub receipt: Option<String>,
+
+    /// Line item as `description=amount`, can be given multiple times; the
+    /// items must add up to the amount
+    #[arg(long = "item", value_name = "DESCRIPTION=AMOUNT", value_parser = parse_line_item)]
+    pub items: Vec<NewLineItem>,
+
+    /// Key stored with the expense; adding again with the same key does
+    /// nothing, so a retried script doesn't add it twice
+    #[arg(long)]
+    pub idempotency_key: Option<String>,
+}
+
+/// Criteria used to narrow down the expenses a command operates on
+#[derive(clap::Args)]
+pub struct ExpenseFilter {
+    /// Only show expenses in this category (case-insensitive)
+    #[arg(short, long)]
+    pub category: Option<String>,
+
+    /// Only show expenses of at least this amount
+    #[arg(long)]
+    pub min: Option<f64>,
+
+    /// Only show expenses of at most this amount
+    #[arg(long)]
+    pub max: Option<f64>,
+
+    /// Only show expenses with this tag (case-insensitive); when given multiple
+    /// times, expenses with any of the tags are shown
+    #[arg(long = "tag")]
+    pub tags: Vec<String>,
+
+    /// Only show expenses dated on or after this day (YYYY-MM-DD)
+    #[arg(long, value_parser = parse_date)]
+    pub since: Option<NaiveDate>,
+
+    /// Only show expenses dated on or before this day (YYYY-MM-DD)
+    #[arg(long, value_parser = parse_date)]
+    pub until: Option<NaiveDate>,
+
+    /// Only show expenses matching an expression like
+    /// `amount > 50 and (category == food or description ~ lunch)`
+    #[arg(long, value_parser = filter::parse_filter)]
+    pub filter: Option<filter::Expr>,
+
+    /// Also show soft-deleted expenses
+    #[arg(long)]
+    pub include_deleted: bool,
+}
+
+#[derive(Clone, Copy, ValueEnum, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum SortField {
+    Id,
+    Date,
+    Amount,
+    Description,
+}
+
+/// Day `summary --weekday` starts the week on
+#[derive(Clone, Copy, ValueEnum, Deserialize, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum WeekStart {
+    Monday,
+    Sunday,
+}
+
+impl WeekStart {
+    pub fn weekday(self) -> chrono::Weekday {
+        match self {
+            WeekStart::Monday => chrono::Weekday::Mon,
+            WeekStart::Sunday => chrono::Weekday::Sun,
+        }
+    }
+}
+
+/// What `--redact` hides from `list` and `summary`, e.g. for a screenshot.
+/// IDs, dates and categories stay, and the datastore isn't touched.
+#[derive(clap::Args, Clone, Copy, Debug, Default)]
+pub struct Redaction {
+    /// Show amounts as `***`
+    #[arg(long)]
+    pub redact: bool,
+
+    /// Also show descriptions as their first character only
+    #[arg(long, requires = "redact")]
+    pub redact_descriptions: bool,
+}
+
+/// Borders tables are drawn with
+#[derive(Clone, Copy, ValueEnum, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    /// `+`, `-` and `|` lines around every cell
+    #[default]
+    Ascii,
+    /// GitHub-style pipe table
+    Markdown,
+    /// Columns lined up with spaces only
+    Borderless,
+    /// Box-drawing characters
+    Unicode,
+}
+
+/// The prettytable format drawing tables in `theme`
+pub fn table_format(theme: Theme) -> TableFormat {
+    match theme {
+        Theme::Ascii => *format::consts::FORMAT_NO_TITLE,
+        Theme::Markdown => format::FormatBuilder::new()
+            .column_separator('|')
+            .borders('|')
+            .separator(
+                format::LinePosition::Title,
+                format::LineSeparator::new('-', '|', '|', '|'),
+            )
+            .padding(1, 1)
+            .build(),
+        Theme::Borderless => *format::consts::FORMAT_CLEAN,
+        Theme::Unicode => {
+            let mut format = *format::consts::FORMAT_BOX_CHARS;
+            format.separator(
+                format::LinePosition::Title,
+                format::LineSeparator::new('─', '┼', '├', '┤'),
+            );
+            format
+        }
+    }
+}
+
+/// Length of the periods the summary can group expenses into
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Period {
+    Week,
+    Month,
+    Year,
+}
+
+impl Period {
+    /// First day of the period containing `date`
+    pub fn start(self, date: NaiveDate) -> NaiveDate {
+        match self {
+            Period::Week => date.week(chrono::Weekday::Mon).first_day(),
+            Period::Month => date.with_day(1).unwrap_or(date),
+            Period::Year => date.with_ordinal(1).unwrap_or(date),
+        }
+    }
+
+    /// Name of the period starting on `start`, e.g. `2024-W05`, `2024-02` or `2024`
+    pub fn label(self, start: NaiveDate) -> String {
+        match self {
+            Period::Week => {
+                let week = start.iso_week();
+                format!("{}-W{:02}", week.year(), week.week())
+            }
+            Period::Month => start.format("%Y-%m").to_string(),
+            Period::Year => start.year().to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Bordered table
+    Table,
+    /// Tab-separated columns without borders
+    Plain,
+    /// GitHub-style pipe table
+    Markdown,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+/// How JSON output is laid out
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum JsonLayout {
+    /// Pretty for someone at a terminal, compact for other programs
+    Auto,
+    Pretty,
+    Compact,
+}
+
+impl JsonLayout {
+    /// Layout asked for by the `--pretty` and `--compact` flags
+    pub fn from_flags(pretty: bool, compact: bool) -> JsonLayout {
+        match (pretty, compact) {
+            (true, _) => JsonLayout::Pretty,
+            (_, true) => JsonLayout::Compact,
+            _ => JsonLayout::Auto,
+        }
+    }
+
+    /// Whether to pretty-print JSON written to a terminal or not
+    pub fn is_pretty(self, terminal: bool) -> bool {
+        match self {
+            JsonLayout::Auto => terminal,
+            JsonLayout::Pretty => true,
+            JsonLayout::Compact => false,
+        }
+    }
+}
+
+pub fn to_json<T: Serialize + ?Sized>(value: &T, pretty: bool) -> serde_json::Result<String> {
+    if pretty {
+        serde_json::to_string_pretty(value)
+    } else {
+        serde_json::to_string(value)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ImportFormat {
+    /// Another datastore, in any of the datastore formats
+    Datastore,
+    /// Rows of a CSV file, read through a column mapping
+    Csv,
+}
+
+/// Which CSV columns hold which expense field, numbered from 1 on the
+/// command line and stored from 0
+#[derive(Clone, Debug)]
+pub struct ColumnMap {
+    pub date: usize,
+    pub description: usize,
+    pub amount: usize,
+    pub category: Option<usize>,
+}
+
+impl Default for ColumnMap {
+    fn default() -> Self {
+        ColumnMap {
+            date: 0,
+            description: 1,
+            amount: 2,
+            category: None,
+        }
+    }
+}
+
+impl std::str::FromStr for ColumnMap {
+    type Err = String;
+
+    /// Parses `date=1,description=2,amount=3`, fields left out keeping their default column
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let mut map = ColumnMap::default();
+
+        for pair in text.split(',') {
+            let Some((field, column)) = pair.split_once('=') else {
+                return Err(format!("'{}' is not a field=column pair", pair));
+            };
+
+            let column = match column.trim().parse::<usize>() {
+                Ok(column) if column >= 1 => column - 1,
+                _ => return Err(format!("'{}' is not a column number from 1", column)),
+            };
+
+            match field.trim() {
+                "date" => map.date = column,
+                "description" => map.description = column,
+                "amount" => map.amount = column,
+                "category" => map.category = Some(column),
+                field => {
+                    return Err(format!(
+                        "unknown field '{}', expected date, description, amount or category",
+                        field
+                    ))
+                }
+            }
+        }
+
+        Ok(map)
+    }
+}
+
+/// Whether a record is money spent or money received
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Kind {
+    #[default]
+    Expense,
+    Income,
+    /// Money given back for an expense, stored as a negative amount that
+    /// offsets spending
+    Refund,
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Kind::Expense => write!(f, "Expense"),
+            Kind::Income => write!(f, "Income"),
+            Kind::Refund => write!(f, "Refund"),
+        }
+    }
+}
+
+/// An expense's ID, either a sequential number or a short random base36
+/// string. Datastores from before random IDs store plain integers, which
+/// still read fine and are written back as strings.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+#[serde(from = "RawId")]
+pub struct Id(String);
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawId {
+    Number(u64),
+    Text(String),
+}
+
+impl From<RawId> for Id {
+    fn from(raw: RawId) -> Self {
+        match raw {
+            RawId::Number(number) => Id(number.to_string()),
+            RawId::Text(text) => Id(text),
+        }
+    }
+}
+
+impl From<u32> for Id {
+    fn from(number: u32) -> Self {
+        Id(number.to_string())
+    }
+}
+
+/// Length of the IDs `--random-id` generates
+const RANDOM_ID_LEN: usize = 6;
+
+impl Id {
+    /// The ID's number, if it's a sequential one
+    pub fn number(&self) -> Option<u32> {
+        self.0.parse().ok()
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// A random ID of `RANDOM_ID_LEN` base36 characters, never all digits so
+    /// it can't be mistaken for, or collide with, a sequential one
+    fn random() -> Id {
+        use std::hash::{BuildHasher, Hasher};
+
+        const DIGITS: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+
+        loop {
+            // Every `RandomState` is seeded differently, which is all the randomness we need
+            let mut value = std::collections::hash_map::RandomState::new()
+                .build_hasher()
+                .finish();
+
+            let id: String = (0..RANDOM_ID_LEN)
+                .map(|_| {
+                    let digit = DIGITS[(value % 36) as usize];
+                    value /= 36;
+                    char::from(digit)
+                })
+                .collect();
+
+            if id.bytes().any(|byte| byte.is_ascii_lowercase()) {
+                return Id(id);
+            }
+        }
+    }
+}
+
+impl std::str::FromStr for Id {
+    type Err = String;
+
+    /// Accepts both forms, normalizing `007` to `7` and `ABC123` to `abc123`
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        if text.is_empty() || !text.bytes().all(|byte| byte.is_ascii_alphanumeric()) {
+            return Err("an ID is a number or a string of letters and digits".to_string());
+        }
+
+        match text.parse::<u32>() {
+            Ok(number) => Ok(Id::from(number)),
+            Err(_) => Ok(Id(text.to_ascii_lowercase())),
+        }
+    }
+}
+
+impl fmt::Display for Id {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// An ID prefix that's the start of more than one expense's ID
+#[derive(Debug, PartialEq, Eq)]
+pub struct AmbiguityError {
+    pub prefix: String,
+    pub candidates: Vec<Id>,
+}
+
+impl fmt::Display for AmbiguityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let candidates: Vec<&str> = self.candidates.iter().map(Id::as_str).collect();
+        write!(
+            f,
+            "ID '{}' matches more than one expense: {}",
+            self.prefix,
+            candidates.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for AmbiguityError {}
+
+/// Finds the expense `id` stands for: the one with exactly that ID, or else
+/// the only one whose random ID starts with it. Sequential IDs only match
+/// exactly, so `1` never picks expense 12. Soft-deleted expenses never match.
+pub fn resolve_id(id: &Id, expenses: &[Expense]) -> Result<Option<usize>, AmbiguityError> {
+    resolve_id_among(id, expenses, |expense| !expense.is_deleted())
+}
+
+/// Like `resolve_id`, soft-deleted expenses included, for `restore`
+pub fn resolve_any_id(id: &Id, expenses: &[Expense]) -> Result<Option<usize>, AmbiguityError> {
+    resolve_id_among(id, expenses, |_| true)
+}
+
+fn resolve_id_among(
+    id: &Id,
+    expenses: &[Expense],
+    candidate: impl Fn(&Expense) -> bool,
+) -> Result<Option<usize>, AmbiguityError> {
+    let candidates = || {
+        expenses
+            .iter()
+            .enumerate()
+            .filter(|(_, expense)| candidate(expense))
+    };
+
+    if let Some((index, _)) = candidates().find(|(_, expense)| expense.id == *id) {
+        return Ok(Some(index));
+    }
+
+    if id.number().is_some() {
+        return Ok(None);
+    }
+
+    let matches: Vec<usize> = candidates()
+        .filter(|(_, expense)| expense.id.as_str().starts_with(id.as_str()))
+        .map(|(index, _)| index)
+        .collect();
+
+    match matches[..] {
+        [] => Ok(None),
+        [index] => Ok(Some(index)),
+        _ => Err(AmbiguityError {
+            prefix: id.to_string(),
+            candidates: matches
+                .iter()
+                .map(|&index| expenses[index].id.clone())
+                .collect(),
+        }),
+    }
+}
+
+/// Sequential IDs sort by number and before random ones, which sort alphabetically
+impl Ord for Id {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        match (self.number(), other.number()) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            (Some(_), None) => std::cmp::Ordering::Less,
+            (None, Some(_)) => std::cmp::Ordering::Greater,
+            (None, None) => self.0.cmp(&other.0),
+        }
+    }
+}
+
+impl PartialOrd for Id {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Represents an expense
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Expense {
+    pub id: Id,
+    pub description: String,
+    /// Amount in minor units of its currency, cents for most, so totals add up exactly
+    #[serde(rename = "amount_cents")]
+    pub amount: i64,
+    #[serde(default)]
+    pub date: Option<NaiveDate>,
+    #[serde(default)]
+    pub category: Option<String>,
+    /// ID of the recurring template this expense was generated from
+    #[serde(default)]
+    pub recurring_id: Option<u32>,
+    #[serde(default)]
+    pub kind: Kind,
+    #[serde(default)]
+    pub note: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Currency the expense was paid in, when it isn't the datastore's
+    #[serde(default)]
+    pub currency: Option<String>,
+    /// When the expense was added, unknown for expenses added before this was recorded
+    #[serde(default)]
+    pub created_at: Option<DateTime<Utc>>,
+    /// Who was paid, split off a `vendor: item` description by `add --parse-vendor`
+    #[serde(default)]
+    pub vendor: Option<String>,
+    /// When the expense was soft-deleted, see `delete --soft`
+    #[serde(default)]
+    pub deleted_at: Option<DateTime<Utc>>,
+    /// Path or URL of the receipt, see `add --receipt`
+    #[serde(default)]
+    pub receipt: Option<String>,
+    /// What the amount was spent on, adding up to it unless the expense was split
+    #[serde(default)]
+    pub items: Vec<LineItem>,
+    /// Key it was added with, see `add --idempotency-key`
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+}
+
+/// One of the things bought in a single expense, see `add --item`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LineItem {
+    pub description: String,
+    /// Amount in minor units of the expense's currency
+    #[serde(rename = "amount_cents")]
+    pub amount: i64,
+}
+
+/// A line item as given on the command line, before the currency its amount
+/// is stored in is known
+#[derive(Debug, Clone, PartialEq)]
+pub struct NewLineItem {
+    pub description: String,
+    pub amount: f64,
+}
+
+impl NewLineItem {
+    /// The line item stored for this in an expense paid in `currency`
+    pub fn to_line_item(&self, currency: &str) -> Result<LineItem, Error> {
+        validate_amount(self.amount, false, currency)?;
+
+        Ok(LineItem {
+            description: self.description.clone(),
+            amount: to_minor_units(self.amount, currency),
+        })
+    }
+}
+
+/// The line items stored for `items` in an expense paid in `currency`
+fn to_line_items(items: &[NewLineItem], currency: &str) -> Result<Vec<LineItem>, Error> {
+    items
+        .iter()
+        .map(|item| item.to_line_item(currency))
+        .collect()
+}
+
+impl Expense {
+    pub fn is_income(&self) -> bool {
+        self.kind == Kind::Income
+    }
+
+    /// Whether the expense was soft-deleted and is only kept for `restore --id`
+    pub fn is_deleted(&self) -> bool {
+        self.deleted_at.is_some()
+    }
+}
+
+/// Sums the amounts of the records that are expenses, leaving out income
+pub fn total_spent(expenses: &[Expense]) -> i64 {
+    expenses
+        .iter()
+        .filter(|expense| !expense.is_income())
+        .map(|expense| expense.amount)
+        .sum()
+}
+
+pub fn total_income(expenses: &[Expense]) -> i64 {
+    expenses
+        .iter()
+        .filter(|expense| expense.is_income())
+        .map(|expense| expense.amount)
+        .sum()
+}
+
+/// `amount` as a percentage of `income`, `None` when there's no income to
+/// compare it with
+pub fn percent_of_income(amount: i64, income: i64) -> Option<f64> {
+    (income > 0).then(|| amount as f64 / income as f64 * 100.0)
+}
+
+/// Formats `percent_of_income`, as "n/a" when there's no income
+pub fn format_percent_of_income(amount: i64, income: i64) -> String {
+    match percent_of_income(amount, income) {
+        Some(percent) => format!("{:.1}%", percent),
+        None => "n/a".to_string(),
+    }
+}
+
+/// Template for an expense that repeats every month
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RecurringExpense {
+    pub id: u32,
+    pub description: String,
+    /// Amount in minor units of the datastore's currency
+    #[serde(rename = "amount_cents")]
+    pub amount: i64,
+    #[serde(default)]
+    pub category: Option<String>,
+    pub day: u32,
+    pub active: bool,
+    /// Months (YYYY-MM) this template has already been applied to
+    #[serde(default)]
+    pub applied: Vec<String>,
+}
+
+/// On-disk layout of the datastore
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Datastore {
+    /// ID handed to the next added expense, never lowered so IDs aren't reused
+    #[serde(default)]
+    pub next_id: u32,
+    pub expenses: Vec<Expense>,
+    #[serde(default)]
+    pub recurring: Vec<RecurringExpense>,
+}
+
+impl Default for Datastore {
+    fn default() -> Self {
+        Datastore {
+            next_id: 1,
+            expenses: Vec::new(),
+            recurring: Vec::new(),
+        }
+    }
+}
+
+impl Datastore {
+    pub fn allocate_id(&mut self) -> Id {
+        let id = self.next_id;
+        self.next_id += 1;
+        Id::from(id)
+    }
+
+    /// A random ID no expense uses yet
+    pub fn random_id(&self) -> Id {
+        loop {
+            let id = Id::random();
+
+            if !self.expenses.iter().any(|expense| expense.id == id) {
+                return id;
+            }
+        }
+    }
+}
+
+/// User-wide defaults read from the config file, overridden by command-line flags
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub datastore: Option<String>,
+    pub currency: Option<String>,
+    pub sort: Option<SortField>,
+    pub highlight_above: Option<f64>,
+    /// Give added expenses random IDs, as if `--random-id` was always passed
+    #[serde(default)]
+    pub random_ids: bool,
+    /// Refuse datastores with unknown fields, as if `--strict` was always passed
+    #[serde(default)]
+    pub strict: bool,
+    /// Keep deleted expenses, as if `delete --soft` was always passed
+    #[serde(default)]
+    pub soft_delete: bool,
+    /// Default for `add --threshold`
+    pub large_expense_threshold: Option<f64>,
+    /// Number of timestamped backups to keep [default: 5]
+    pub backups: Option<usize>,
+    /// Shell command run after each datastore write, `{path}` standing for
+    /// the datastore, e.g. to copy it offsite
+    pub backup_command: Option<String>,
+    /// Number of expenses a delete or clear can remove with a plain `y` [default: 10]
+    pub large_delete_threshold: Option<usize>,
+    /// Value of one unit of each currency in a common reference currency,
+    /// used by `summary --in` and to count expenses paid in other currencies
+    /// towards budgets, quotas and the digest
+    #[serde(default)]
+    pub rates: HashMap<String, f64>,
+    /// Decimal places of each currency's amounts, e.g. 0 for JPY [default: 2]
+    #[serde(default)]
+    pub decimal_places: HashMap<String, u8>,
+    /// Pay cycle totalled by `summary --pay-period`
+    pub pay_period: Option<PayPeriod>,
+    /// Default for `summary --week-start` [default: monday]
+    pub week_start: Option<WeekStart>,
+    /// Expenses `add --from-template` can add by name, see `template save`
+    #[serde(default)]
+    pub templates: BTreeMap<String, Template>,
+    /// Default for `--theme`
+    pub theme: Option<Theme>,
+}
+
+/// An expense entered often, saved under a name in the config file
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Template {
+    pub description: String,
+    pub amount: f64,
+    pub category: Option<String>,
+}
+
+/// A pay cycle of `days` days, one of which starts on `start`
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(deny_unknown_fields)]
+pub struct PayPeriod {
+    pub start: NaiveDate,
+    pub days: NonZeroU32,
+}
+
+/// Per-datastore settings, kept in a file next to the datastore
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Settings {
+    /// Monthly budget in minor units of the datastore's currency
+    #[serde(default, rename = "budget_cents")]
+    pub budget: Option<i64>,
+    #[serde(default)]
+    pub currency: Option<String>,
+    /// Last day the weekly digest was shown, so it shows at most once a day
+    #[serde(default)]
+    pub digest_shown: Option<NaiveDate>,
+    /// Last time `summary --since-last` ran
+    #[serde(default)]
+    pub summarized_at: Option<DateTime<Utc>>,
+    /// Monthly caps in minor units of the datastore's currency, keyed by lowercase category
+    #[serde(default, rename = "quotas_cents")]
+    pub quotas: BTreeMap<String, i64>,
+}
+
+/// Errors surfaced by the expense commands
+pub enum Error {
+    Init(io::Error),
+    Read(io::Error),
+    Write(io::Error),
+    Settings(io::Error),
+    Config(io::Error),
+    Lock(io::Error),
+    Prompt(io::Error),
+    Serialize(serde_json::Error),
+    InvalidAmount { amount: f64, reason: &'static str },
+    InvalidFilter(String),
+    InvalidPattern { pattern: String, reason: String },
+    EmptyDescription,
+    ExpenseNotFound(Vec<Id>),
+    IdCollision(Vec<Id>),
+    AmbiguousId(AmbiguityError),
+    NoBackup(String),
+    BackupNotFound(String),
+    CategoryNotFound(String),
+    NoEditor,
+    NoDataDir,
+    SameProfile(String),
+    NoPayPeriod,
+    NoConfigDir,
+    TemplateNotFound(String),
+    OverQuota { category: String, excess: String },
+    AddCancelled,
+    Unconfirmed(String),
+    ItemsMismatch { sum: String, amount: String },
+    MixedCurrencies(Vec<String>),
+    NoReceipt(Id),
+    AlreadySplit(Id),
+    Editor(io::Error),
+    Open(io::Error),
+    Watch(io::Error),
+    Pager(io::Error),
+    EditRejected { saved: String, source: io::Error },
+    Export { path: String, source: io::Error },
+    Import { path: String, source: io::Error },
+    Compare { path: String, source: io::Error },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Init(error) => write!(f, "Failed to initialize datastore: {}", error),
+            Error::Read(error) => write!(f, "Failed to read from datastore: {}", error),
+            Error::Write(error) => write!(f, "Failed to write to datastore: {}", error),
+            Error::Settings(error) => write!(f, "Failed to access settings: {}", error),
+            Error::Config(error) => write!(f, "Failed to read config: {}", error),
+            Error::Lock(error) => write!(f, "Failed to lock datastore: {}", error),
+            Error::Prompt(error) => write!(f, "Failed to read your answer: {}", error),
+            Error::Serialize(error) => write!(f, "Failed to serialize expenses: {}", error),
+            Error::InvalidAmount { amount, reason } => {
+                write!(f, "Invalid amount '{}': {}", amount, reason)
+            }
+            Error::InvalidFilter(reason) => write!(f, "Invalid filter: {}", reason),
+            Error::InvalidPattern { pattern, reason } => {
+                write!(f, "Invalid pattern '{}': {}", pattern, reason)
+            }
+            Error::EmptyDescription => write!(
+                f,
+                "Description must not be empty (use --allow-empty to add it anyway)"
+            ),
+            Error::ExpenseNotFound(ids) => {
+                let ids: Vec<String> = ids.iter().map(Id::to_string).collect();
+                write!(f, "No expense found with ID: {}", ids.join(", "))
+            }
+            Error::IdCollision(ids) => {
+                let ids: Vec<String> = ids.iter().map(Id::to_string).collect();
+                write!(
+                    f,
+                    "Nothing imported, these IDs are already used by other expenses: {} (import without --preserve-ids to give them new IDs)",
+                    ids.join(", ")
+                )
+            }
+            Error::AmbiguousId(error) => write!(f, "{}", error),
+            Error::CategoryNotFound(category) => {
+                write!(f, "No expense uses the category '{}'", category)
+            }
+            Error::NoEditor => write!(f, "No editor set, set $VISUAL or $EDITOR"),
+            Error::NoDataDir => write!(
+                f,
+                "No directory to keep profiles in, set $XDG_DATA_HOME or $HOME"
+            ),
+            Error::SameProfile(profile) => {
+                write!(f, "The expense is already in profile '{}'", profile)
+            }
+            Error::NoConfigDir => write!(
+                f,
+                "No directory to keep the config file in, set $XDG_CONFIG_HOME or $HOME"
+            ),
+            Error::TemplateNotFound(name) => {
+                write!(f, "No template named '{}', see `template list`", name)
+            }
+            Error::MixedCurrencies(codes) => write!(
+                f,
+                "The expenses are in several currencies ({}), pass --in to total them in one",
+                codes.join(", ")
+            ),
+            Error::NoPayPeriod => write!(
+                f,
+                "No pay period set, add a [pay_period] table with its start and days to the config file"
+            ),
+            Error::OverQuota { category, excess } => write!(
+                f,
+                "Not added, it would take '{}' {} over its monthly quota",
+                category, excess
+            ),
+            Error::AddCancelled => write!(f, "Add cancelled"),
+            Error::Unconfirmed(reason) => write!(
+                f,
+                "Not added, {} and there's no terminal to ask on (pass --force to add it anyway)",
+                reason
+            ),
+            Error::ItemsMismatch { sum, amount } => write!(
+                f,
+                "The line items add up to {}, not the amount of {}",
+                sum, amount
+            ),
+            Error::NoReceipt(id) => write!(
+                f,
+                "Expense with ID: {} has no receipt, add one with `update --receipt`",
+                id
+            ),
+            Error::AlreadySplit(id) => write!(
+                f,
+                "Expense with ID: {} is already split, its amount is a share already",
+                id
+            ),
+            Error::Editor(error) => write!(f, "Failed to run the editor: {}", error),
+            Error::Open(error) => write!(f, "Failed to open the receipt: {}", error),
+            Error::Watch(error) => write!(f, "Failed to watch datastore: {}", error),
+            Error::Pager(error) => write!(f, "Failed to run the pager: {}", error),
+            Error::EditRejected { saved, source } => write!(
+                f,
+                "Edit rejected, the datastore was left unchanged: {} (your edit was saved to '{}')",
+                source, saved
+            ),
+            Error::NoBackup(path) => {
+                write!(f, "Nothing to undo, no backup found at '{}'", path)
+            }
+            Error::BackupNotFound(name) => {
+                write!(f, "No backup '{}', run `restore` to list them", name)
+            }
+            Error::Export { path, source } => {
+                write!(f, "Failed to export expenses to '{}': {}", path, source)
+            }
+            Error::Import { path, source } => {
+                write!(f, "Failed to import expenses from '{}': {}", path, source)
+            }
+            Error::Compare { path, source } => {
+                write!(f, "Failed to read '{}' to compare with: {}", path, source)
+            }
+        }
+    }
+}
+
+// `main` reports errors through `Debug`, so mirror `Display` to keep them on one line
+impl fmt::Debug for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Init(error)
+            | Error::Read(error)
+            | Error::Write(error)
+            | Error::Settings(error)
+            | Error::Config(error)
+            | Error::Lock(error)
+            | Error::Prompt(error)
+            | Error::Editor(error)
+            | Error::Open(error)
+            | Error::Pager(error)
+            | Error::Watch(error) => Some(error),
+            Error::Export { source, .. }
+            | Error::Import { source, .. }
+            | Error::Compare { source, .. }
+            | Error::EditRejected { source, .. } => Some(source),
+            Error::Serialize(error) => Some(error),
+            Error::AmbiguousId(error) => Some(error),
+            Error::InvalidAmount { .. }
+            | Error::InvalidFilter(_)
+            | Error::InvalidPattern { .. }
+            | Error::EmptyDescription
+            | Error::ExpenseNotFound(_)
+            | Error::IdCollision(_)
+            | Error::NoBackup(_)
+            | Error::BackupNotFound(_)
+            | Error::CategoryNotFound(_)
+            | Error::NoEditor
+            | Error::NoDataDir
+            | Error::SameProfile(_)
+            | Error::NoPayPeriod
+            | Error::NoConfigDir
+            | Error::TemplateNotFound(_)
+            | Error::OverQuota { .. }
+            | Error::AddCancelled
+            | Error::AlreadySplit(_)
+            | Error::Unconfirmed(_)
+            | Error::ItemsMismatch { .. }
+            | Error::MixedCurrencies(_)
+            | Error::NoReceipt(_) => None,
+        }
+    }
+}
+
+pub const DEFAULT_CURRENCY: &str = "USD";
+
+/// Most symbolic links `follow_symlinks` goes through, as a guard against loops
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// Resolves a datastore path that's a symbolic link to the file it points to,
+/// even one that doesn't exist yet. Writes replace the file by renaming over
+/// it, which would otherwise replace the link itself.
+pub fn follow_symlinks(path: &str) -> io::Result<String> {
+    let mut path = std::path::PathBuf::from(path);
+
+    for _ in 0..MAX_SYMLINK_HOPS {
+        match std::fs::symlink_metadata(&path) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                let target = std::fs::read_link(&path)?;
+
+                // Relative targets are relative to the link's directory
+                path = match path.parent() {
+                    Some(parent) => parent.join(target),
+                    None => target,
+                };
+            }
+            _ => return Ok(path.to_string_lossy().into_owned()),
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("'{}' goes through too many symbolic links", path.display()),
+    ))
+}
+
+pub fn init_datastore(path: &str) -> io::Result<()> {
+    if missing_without_init(path) {
+        debug!("Datastore '{}' doesn't exist, not creating it", path);
+        return Ok(());
+    }
+
+    if !std::path::Path::new(path).exists() {
+        // A bare file name has an empty parent, which is the current directory
+        if let Some(dir) = std::path::Path::new(path)
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+        {
+            std::fs::create_dir_all(dir).map_err(|error| {
+                io::Error::new(
+                    error.kind(),
+                    format!("could not create directory '{}': {}", dir.display(), error),
+                )
+            })?;
+        }
+
+        write_datastore(path, &Datastore::default())?;
+        debug!("Datastore initialized at '{}'", path);
+        return Ok(());
+    }
+
+    debug!("Reading from datastore at '{}'", path);
+
+    Ok(())
+}
+
+/// Serialization format of a datastore, picked from its file extension
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Format {
+    Json,
+    /// A header line with everything but the expenses, then one expense per
+    /// line, which `list --limit` can stop reading early
+    Jsonl,
+    Toml,
+    Yaml,
+    /// A SQLite database, changed a row at a time instead of rewritten whole
+    Sqlite,
+}
+
+impl Format {
+    /// Unrecognized or missing extensions fall back to JSON
+    pub fn from_path(path: &str) -> Format {
+        let extension = std::path::Path::new(path)
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map(str::to_ascii_lowercase);
+
+        match extension.as_deref() {
+            Some("jsonl") => Format::Jsonl,
+            Some("toml") => Format::Toml,
+            Some("yaml" | "yml") => Format::Yaml,
+            Some("db" | "sqlite") => Format::Sqlite,
+            _ => Format::Json,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Json => "JSON",
+            Format::Jsonl => "JSON lines",
+            Format::Toml => "TOML",
+            Format::Yaml => "YAML",
+            Format::Sqlite => "SQLite",
+        }
+    }
+
+    fn parse(self, data: &str) -> Result<serde_json::Value, String> {
+        match self {
+            Format::Json => serde_json::from_str(data).map_err(|error| error.to_string()),
+            Format::Jsonl => parse_jsonl(data),
+            Format::Toml => toml::from_str(data).map_err(|error| error.to_string()),
+            Format::Yaml => serde_yaml::from_str(data).map_err(|error| error.to_string()),
+            Format::Sqlite => Err("a SQLite database isn't text".to_string()),
+        }
+    }
+
+    fn serialize(self, value: &impl Serialize) -> io::Result<String> {
+        match self {
+            Format::Json => Ok(serde_json::to_string_pretty(value)?),
+            Format::Jsonl => serialize_jsonl(value),
+            Format::Toml => toml::to_string_pretty(value).map_err(io::Error::other),
+            Format::Yaml => serde_yaml::to_string(value).map_err(io::Error::other),
+            Format::Sqlite => Err(io::Error::other("a SQLite database isn't text")),
+        }
+    }
+}
+
+fn parse_jsonl(data: &str) -> Result<serde_json::Value, String> {
+    let mut lines = data
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty());
+
+    let parse_line = |(index, line): (usize, &str)| {
+        serde_json::from_str::<serde_json::Value>(line)
+            .map_err(|error| format!("line {}: {}", index + 1, error))
+    };
+
+    let mut value = match lines.next() {
+        Some(header) => parse_line(header)?,
+        None => serde_json::json!({}),
+    };
+
+    let expenses = lines.map(parse_line).collect::<Result<Vec<_>, _>>()?;
+
+    let Some(header) = value.as_object_mut() else {
+        return Err("line 1: the header must be an object".to_string());
+    };
+    header.insert("expenses".to_string(), expenses.into());
+
+    Ok(value)
+}
+
+fn serialize_jsonl(value: &impl Serialize) -> io::Result<String> {
+    let mut header = serde_json::to_value(value)?;
+
+    let expenses = match header
+        .as_object_mut()
+        .and_then(|header| header.remove("expenses"))
+    {
+        Some(serde_json::Value::Array(expenses)) => expenses,
+        _ => Vec::new(),
+    };
+
+    let mut data = serde_json::to_string(&header)?;
+    data.push('\n');
+
+    for expense in expenses {
+        data.push_str(&serde_json::to_string(&expense)?);
+        data.push('\n');
+    }
+
+    Ok(data)
+}
+
+/// Reads the expenses of a JSON lines datastore one line at a time, in file
+/// order, without holding more than one of them in memory
+pub fn jsonl_expenses(path: &str) -> io::Result<impl Iterator<Item = io::Result<Expense>> + '_> {
+    let reader = io::BufReader::new(std::fs::File::open(path)?);
+
+    // The first line is the header, the expenses come after it
+    let expenses = reader
+        .lines()
+        .enumerate()
+        .skip(1)
+        .filter(|(_, line)| !line.as_ref().is_ok_and(|line| line.trim().is_empty()))
+        .map(move |(index, line)| {
+            let invalid = |error: serde_json::Error| {
+                corrupt_datastore(path, format!("line {}: {}", index + 1, error))
+            };
+
+            let mut value: serde_json::Value = serde_json::from_str(&line?).map_err(invalid)?;
+            migrate_float_amount(&mut value, "amount", "amount_cents");
+
+            let original = STRICT.load(Ordering::Relaxed).then(|| value.clone());
+            let expense: Expense = serde_json::from_value(value).map_err(invalid)?;
+
+            if let Some(original) = original {
+                let known = serde_json::to_value(&expense).map_err(invalid)?;
+                let unknown = unknown_fields(&original, &known);
+
+                if !unknown.is_empty() {
+                    let unknown = unknown
+                        .iter()
+                        .map(|field| format!("line {} {}", index + 1, field))
+                        .collect();
+                    return Err(unknown_fields_error(path, unknown));
+                }
+            }
+
+            Ok(expense)
+        });
+
+    Ok(expenses)
+}
+
+/// Reads the expenses of a JSON lines datastore in file order, keeping the
+/// ones `filter` matches and stopping as soon as `limit` of them, after
+/// skipping `offset`, have been read
+pub fn stream_expenses(
+    path: &str,
+    filter: &ExpenseFilter,
+    currency: &str,
+    offset: usize,
+    limit: usize,
+) -> io::Result<Vec<Expense>> {
+    if missing_without_init(path) {
+        return Ok(Vec::new());
+    }
+
+    let mut expenses = Vec::new();
+    let mut skipped = 0;
+
+    for expense in jsonl_expenses(path)? {
+        if expenses.len() >= limit {
+            break;
+        }
+
+        let expense = expense?;
+        if !filter.matches(&expense, currency) {
+            continue;
+        }
+
+        if skipped < offset {
+            skipped += 1;
+        } else {
+            expenses.push(expense);
+        }
+    }
+
+    Ok(expenses)
+}
+
+/// Fields of `value` missing from `known`, the same data as this version
+/// writes it back, e.g. `expenses[2].receipt_hash`. Those are the fields a
+/// rewrite would drop.
+pub fn unknown_fields(value: &serde_json::Value, known: &serde_json::Value) -> Vec<String> {
+    fn walk(
+        value: &serde_json::Value,
+        known: &serde_json::Value,
+        at: &str,
+        unknown: &mut Vec<String>,
+    ) {
+        match (value, known) {
+            (serde_json::Value::Object(fields), serde_json::Value::Object(known)) => {
+                for (name, field) in fields {
+                    let at = if at.is_empty() {
+                        name.clone()
+                    } else {
+                        format!("{}.{}", at, name)
+                    };
+
+                    match known.get(name) {
+                        Some(known) => walk(field, known, &at, unknown),
+                        None => unknown.push(at),
+                    }
+                }
+            }
+            (serde_json::Value::Array(items), serde_json::Value::Array(known)) => {
+                for (index, (item, known)) in items.iter().zip(known).enumerate() {
+                    walk(item, known, &format!("{}[{}]", at, index), unknown);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut unknown = Vec::new();
+    walk(value, known, "", &mut unknown);
+    unknown
+}
+
+fn unknown_fields_error(path: &str, unknown: Vec<String>) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "'{}' has fields this version doesn't know, which a change would drop: {} (use the version that added them, or leave out --strict)",
+            path,
+            unknown.join(", ")
+        ),
+    )
+}
+
+fn corrupt_datastore(path: &str, problem: String) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!(
+            "'{}' is corrupt ({}); run `doctor --fix`, fix it by hand, or run `clear` to start over",
+            path, problem
+        ),
+    )
+}
+
+/// Amounts used to be stored as floating-point units, rewrites such a field
+/// of `object` into its integer cents counterpart
+fn migrate_float_amount(object: &mut serde_json::Value, from: &str, to: &str) {
+    let Some(object) = object.as_object_mut() else {
+        return;
+    };
+
+    if object.contains_key(to) {
+        return;
+    }
+
+    if let Some(amount) = object.get(from).and_then(serde_json::Value::as_f64) {
+        object.remove(from);
+        object.insert(to.to_string(), to_cents(amount).into());
+    }
+}
+
+fn migrate_float_amounts(value: &mut serde_json::Value) {
+    let lists = match value {
+        serde_json::Value::Array(expenses) => vec![expenses],
+        serde_json::Value::Object(datastore) => datastore
+            .iter_mut()
+            .filter(|(key, _)| *key == "expenses" || *key == "recurring")
+            .filter_map(|(_, list)| list.as_array_mut())
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    for list in lists {
+        for item in list {
+            migrate_float_amount(item, "amount", "amount_cents");
+        }
+    }
+}
+
+/// Shape of a datastore once upgraded to the current layout, checked before
+/// deserializing so hand-edited mistakes are reported precisely
+const DATASTORE_SCHEMA: &str = r##"{
+  "type": "object",
+  "required": ["expenses"],
+  "properties": {
+    "next_id": { "type": "integer", "minimum": 0 },
+    "expenses": { "type": "array", "items": { "$ref": "#/$defs/expense" } },
+    "recurring": { "type": "array", "items": { "$ref": "#/$defs/recurring" } }
+  },
+  "$defs": {
+    "expense": {
+      "type": "object",
+      "required": ["id", "description", "amount_cents"],
+      "properties": {
+        "id": { "type": ["integer", "string"], "minimum": 0, "pattern": "^[0-9a-z]+$" },
+        "description": { "type": "string" },
+        "amount_cents": { "type": "integer" },
+        "date": { "type": ["string", "null"], "pattern": "^\\d{4}-\\d{2}-\\d{2}$" },
+        "category": { "type": ["string", "null"] },
+        "recurring_id": { "type": ["integer", "null"], "minimum": 0 },
+        "kind": { "enum": ["Expense", "Income", "Refund"] },
+        "note": { "type": ["string", "null"] },
+        "tags": { "type": "array", "items": { "type": "string" } },
+        "currency": { "type": ["string", "null"] },
+        "created_at": { "type": ["string", "null"] },
+        "vendor": { "type": ["string", "null"] },
+        "deleted_at": { "type": ["string", "null"] },
+        "receipt": { "type": ["string", "null"] },
+        "idempotency_key": { "type": ["string", "null"] },
+        "items": {
+          "type": "array",
+          "items": {
+            "type": "object",
+            "required": ["description", "amount_cents"],
+            "properties": {
+              "description": { "type": "string" },
+              "amount_cents": { "type": "integer" }
+            }
+          }
+        }
+      }
+    },
+    "recurring": {
+      "type": "object",
+      "required": ["id", "description", "amount_cents", "day", "active"],
+      "properties": {
+        "id": { "type": "integer", "minimum": 0 },
+        "description": { "type": "string" },
+        "amount_cents": { "type": "integer" },
+        "category": { "type": ["string", "null"] },
+        "day": { "type": "integer", "minimum": 1, "maximum": 31 },
+        "active": { "type": "boolean" },
+        "applied": { "type": "array", "items": { "type": "string" } }
+      }
+    }
+  }
+}"##;
+
+static DATASTORE_VALIDATOR: LazyLock<jsonschema::Validator> = LazyLock::new(|| {
+    let schema = serde_json::from_str(DATASTORE_SCHEMA).expect("the schema is valid JSON");
+    jsonschema::validator_for(&schema).expect("the schema is a valid JSON Schema")
+});
+
+/// Names the record and field a JSON pointer such as `/expenses/3/amount_cents`
+/// points at, e.g. `expense 4 (ID: 7), field 'amount_cents'`
+fn describe_location(value: &serde_json::Value, pointer: &str) -> String {
+    let segments: Vec<&str> = pointer.split('/').skip(1).collect();
+
+    let record = match segments.first() {
+        Some(&"expenses") => "expense",
+        Some(&"recurring") => "recurring expense",
+        _ => return format!("'{}'", pointer),
+    };
+
+    let Some(index) = segments
+        .get(1)
+        .and_then(|index| index.parse::<usize>().ok())
+    else {
+        return format!("'{}'", segments[0]);
+    };
+
+    let mut description = format!("{} {}", record, index + 1);
+
+    let id = value
+        .pointer(&format!("/{}/{}/id", segments[0], index))
+        .and_then(|id| {
+            id.as_u64()
+                .map(|id| id.to_string())
+                .or(id.as_str().map(str::to_string))
+        });
+    if let Some(id) = id {
+        description.push_str(&format!(" (ID: {})", id));
+    }
+
+    if segments.len() > 2 {
+        description.push_str(&format!(", field '{}'", segments[2..].join(".")));
+    }
+
+    description
+}
+
+/// Reads the datastore as a JSON value upgraded to the current layout, but
+/// not checked any further
+fn read_datastore_value(path: &str) -> io::Result<serde_json::Value> {
+    if missing_without_init(path) {
+        return Ok(serde_json::json!({ "expenses": [] }));
+    }
+
+    let mut value = storage::for_path(path).read_value()?;
+
+    migrate_float_amounts(&mut value);
+
+    // Datastores used to be a bare list of expenses, upgrade them on the fly
+    if value.is_array() {
+        value = serde_json::json!({ "expenses": value });
+    }
+
+    Ok(value)
+}
+
+pub fn read_datastore(path: &str) -> io::Result<Datastore> {
+    let started = Instant::now();
+    let datastore = parse_datastore(path)?;
+
+    report_timing("read", started.elapsed());
+    if let Ok(mut last_read) = LAST_READ.lock() {
+        *last_read = Some(Instant::now());
+    }
+
+    Ok(datastore)
+}
+
+fn parse_datastore(path: &str) -> io::Result<Datastore> {
+    let value = read_datastore_value(path)?;
+
+    if let Some(error) = DATASTORE_VALIDATOR.iter_errors(&value).next() {
+        let location = describe_location(&value, &error.instance_path().to_string());
+        return Err(corrupt_datastore(path, format!("{}: {}", location, error)));
+    }
+
+    let original = STRICT.load(Ordering::Relaxed).then(|| value.clone());
+    let mut datastore: Datastore = serde_json::from_value(value)
+        .map_err(|error| corrupt_datastore(path, format!("invalid datastore: {}", error)))?;
+
+    if let Some(original) = original {
+        let known = serde_json::to_value(&datastore).map_err(io::Error::other)?;
+        let unknown = unknown_fields(&original, &known);
+
+        if !unknown.is_empty() {
+            return Err(unknown_fields_error(path, unknown));
+        }
+    }
+
+    let mut seen = HashSet::new();
+    if let Some(duplicate) = datastore.expenses.iter().find(|e| !seen.insert(&e.id)) {
+        return Err(corrupt_datastore(
+            path,
+            format!("ID {} is used by more than one expense", duplicate.id),
+        ));
+    }
+
+    let max_id = datastore
+        .expenses
+        .iter()
+        .filter_map(|e| e.id.number())
+        .max()
+        .unwrap_or(0);
+    datastore.next_id = datastore.next_id.max(max_id + 1);
+
+    Ok(datastore)
+}
+
+/// Something `doctor` found wrong with a datastore, expenses numbered by
+/// their position from 0
+#[derive(Debug, PartialEq)]
+pub enum Problem {
+    /// The expense reuses the ID of an earlier one
+    DuplicateId { index: usize, id: String },
+    /// The expense has no usable ID
+    MissingId { index: usize },
+    /// The expense can't be repaired, e.g. it has no amount
+    InvalidExpense { index: usize, reason: String },
+    /// Anything wrong outside the expenses, left for a fix by hand
+    Other { location: String, reason: String },
+}
+
+impl Problem {
+    /// Describes the problem, naming the expense as in other datastore errors
+    pub fn describe(&self, value: &serde_json::Value) -> String {
+        let expense = |index: &usize| describe_location(value, &format!("/expenses/{}", index));
+
+        match self {
+            Problem::DuplicateId { index, id } => {
+                format!(
+                    "{}: ID {} is used by an earlier expense",
+                    expense(index),
+                    id
+                )
+            }
+            Problem::MissingId { index } => format!("{}: no valid ID", expense(index)),
+            Problem::InvalidExpense { index, reason } => format!("{}: {}", expense(index), reason),
+            Problem::Other { location, reason } => format!("{}: {}", location, reason),
+        }
+    }
+}
+
+/// An expense's ID as stored, if it's one an `Id` can hold
+fn stored_id(expense: &serde_json::Value) -> Option<String> {
+    match expense.get("id")? {
+        serde_json::Value::Number(number) => number.as_u64().map(|id| id.to_string()),
+        serde_json::Value::String(id) if !id.is_empty() => id
+            .bytes()
+            .all(|byte| byte.is_ascii_digit() || byte.is_ascii_lowercase())
+            .then(|| id.clone()),
+        _ => None,
+    }
+}
+
+/// Finds what stops a datastore, already upgraded to the current layout,
+/// from being read: duplicate or missing IDs, and expenses or other parts
+/// that don't match the schema
+pub fn check_datastore(value: &serde_json::Value) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut checked = value.clone();
+    // Expenses found beyond repair, reported once each
+    let mut invalid = HashSet::new();
+
+    if let Some(expenses) = checked
+        .get_mut("expenses")
+        .and_then(serde_json::Value::as_array_mut)
+    {
+        let mut seen = HashSet::new();
+
+        for (index, expense) in expenses.iter_mut().enumerate() {
+            match stored_id(expense) {
+                Some(id) if !seen.insert(id.clone()) => {
+                    problems.push(Problem::DuplicateId { index, id });
+                }
+                Some(_) => {}
+                None => problems.push(Problem::MissingId { index }),
+            }
+
+            // Zero is valid for the schema, but an expense of nothing is a typo
+            if expense
+                .get("amount_cents")
+                .and_then(serde_json::Value::as_i64)
+                == Some(0)
+            {
+                invalid.insert(index);
+                problems.push(Problem::InvalidExpense {
+                    index,
+                    reason: "amount is zero".to_string(),
+                });
+            }
+
+            // ID problems are reported above, keep them out of the schema errors
+            if let Some(expense) = expense.as_object_mut() {
+                expense.insert("id".to_string(), serde_json::json!(index));
+            }
+        }
+    }
+
+    for error in DATASTORE_VALIDATOR.iter_errors(&checked) {
+        let pointer = error.instance_path().to_string();
+        let segments: Vec<&str> = pointer.split('/').skip(1).collect();
+
+        match segments.as_slice() {
+            ["expenses", index, ..] if index.parse::<usize>().is_ok() => {
+                let index = index.parse().unwrap_or_default();
+
+                if invalid.insert(index) {
+                    let field = segments.get(2).map(|field| format!("field '{}' ", field));
+                    problems.push(Problem::InvalidExpense {
+                        index,
+                        reason: format!("{}{}", field.unwrap_or_default(), error),
+                    });
+                }
+            }
+            _ => problems.push(Problem::Other {
+                location: describe_location(value, &pointer),
+                reason: error.to_string(),
+            }),
+        }
+    }
+
+    problems
+}
+
+/// Gives the expenses with ID problems new sequential IDs and takes out the
+/// ones that can't be repaired, returning those
+pub fn repair_datastore(
+    value: &mut serde_json::Value,
+    problems: &[Problem],
+) -> Vec<serde_json::Value> {
+    let Some(expenses) = value
+        .get_mut("expenses")
+        .and_then(serde_json::Value::as_array_mut)
+    else {
+        return Vec::new();
+    };
+
+    let mut next_id = expenses
+        .iter()
+        .filter_map(|expense| stored_id(expense)?.parse::<u64>().ok())
+        .max()
+        .unwrap_or(0)
+        + 1;
+
+    let mut kept = Vec::new();
+    let mut quarantined = Vec::new();
+
+    for (index, mut expense) in std::mem::take(expenses).into_iter().enumerate() {
+        let unrepairable = problems.iter().any(
+            |problem| matches!(problem, Problem::InvalidExpense { index: i, .. } if *i == index),
+        );
+        let needs_id = problems.iter().any(|problem| match problem {
+            Problem::DuplicateId { index: i, .. } | Problem::MissingId { index: i } => *i == index,
+            _ => false,
+        });
+
+        if unrepairable {
+            quarantined.push(expense);
+            continue;
+        }
+
+        if needs_id {
+            if let Some(expense) = expense.as_object_mut() {
+                expense.insert("id".to_string(), serde_json::json!(next_id.to_string()));
+                next_id += 1;
+            }
+        }
+
+        kept.push(expense);
+    }
+
+    *expenses = kept;
+
+    quarantined
+}
+
+/// Reports what's wrong with the datastore and, with `fix`, repairs it,
+/// moving the expenses beyond repair to `<datastore>.quarantine.json`
+pub fn doctor(fix: bool, path: &str) -> Result<(), Error> {
+    let _lock = lock_datastore(path).map_err(Error::Lock)?;
+    let mut value = read_datastore_value(path).map_err(Error::Read)?;
+
+    let problems = check_datastore(&value);
+
+    if problems.is_empty() {
+        info!("No problems found");
+        return Ok(());
+    }
+
+    for problem in &problems {
+        println!("{}", problem.describe(&value));
+    }
+
+    if !fix {
+        info!(
+            "Found {} problem(s), run `doctor --fix` to repair them",
+            problems.len()
+        );
+        return Ok(());
+    }
+
+    if problems
+        .iter()
+        .any(|problem| matches!(problem, Problem::Other { .. }))
+    {
+        return Err(Error::Read(corrupt_datastore(
+            path,
+            "some problems are outside the expenses and can't be fixed automatically".to_string(),
+        )));
+    }
+
+    let quarantined = repair_datastore(&mut value, &problems);
+
+    if !quarantined.is_empty() {
+        let quarantine_path = format!("{}.quarantine.json", path);
+
+        // Add to an earlier quarantine rather than replacing it
+        let mut records: Vec<serde_json::Value> = match std::fs::read_to_string(&quarantine_path) {
+            Ok(data) => serde_json::from_str(&data).unwrap_or_default(),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(error) => return Err(Error::Write(error)),
+        };
+        records.extend(quarantined.iter().cloned());
+
+        let data = serde_json::to_string_pretty(&records).map_err(Error::Serialize)?;
+        write_atomically(&quarantine_path, data.as_bytes()).map_err(Error::Write)?;
+
+        info!(
+            "Moved {} expense(s) that couldn't be repaired to '{}'",
+            quarantined.len(),
+            quarantine_path
+        );
+    }
+
+    let mut datastore: Datastore = serde_json::from_value(value)
+        .map_err(|error| Error::Read(corrupt_datastore(path, error.to_string())))?;
+
+    let max_id = datastore
+        .expenses
+        .iter()
+        .filter_map(|e| e.id.number())
+        .max()
+        .unwrap_or(0);
+    datastore.next_id = datastore.next_id.max(max_id + 1);
+
+    write_datastore(path, &datastore).map_err(Error::Write)?;
+
+    info!(
+        "Repaired the datastore, {} expense(s) left",
+        datastore.expenses.len()
+    );
+
+    Ok(())
+}
+
+/// Reads the expenses of the datastore, a missing datastore having none,
+/// leaving out the soft-deleted ones
+pub fn read_expenses(path: &str) -> io::Result<Vec<Expense>> {
+    let mut expenses = read_all_expenses(path)?;
+    expenses.retain(|expense| !expense.is_deleted());
+
+    Ok(expenses)
+}
+
+/// Like `read_expenses`, keeping the soft-deleted expenses
+pub fn read_all_expenses(path: &str) -> io::Result<Vec<Expense>> {
+    match read_datastore(path) {
+        Ok(datastore) => Ok(datastore.expenses),
+        // The file can vanish after `init_datastore`, e.g. on a flaky network mount
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            debug!(
+                "Warning: datastore '{}' is missing, reading no expenses",
+                path
+            );
+            Ok(Vec::new())
+        }
+        Err(error) => Err(error),
+    }
+}
+
+/// Writes to a sibling temporary file and renames it over `path`, so readers
+/// only ever see the old or the new contents, never a partial write
+fn write_atomically(path: &str, data: &[u8]) -> io::Result<()> {
+    write_then_rename(path, data, |from, to| std::fs::rename(from, to))
+}
+
+/// `write_atomically` with the final step given, so a crash before it can be tested
+fn write_then_rename(
+    path: &str,
+    data: &[u8],
+    rename: impl FnOnce(&str, &str) -> io::Result<()>,
+) -> io::Result<()> {
+    let tmp_path = format!("{}.tmp", path);
+
+    let mut file = std::fs::File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+
+    rename(&tmp_path, path)
+}
+
+/// Exclusive advisory lock serializing read-modify-write cycles on a datastore,
+/// released when dropped
+struct DatastoreLock(std::fs::File);
+
+impl Drop for DatastoreLock {
+    fn drop(&mut self) {
+        let _ = self.0.unlock();
+    }
+}
+
+// The datastore itself is replaced on every write, so lock a sibling file instead
+fn lock_datastore(path: &str) -> io::Result<DatastoreLock> {
+    // Every change takes the lock first, so this is where they're refused
+    if missing_without_init(path) {
+        return Err(not_initialized(path));
+    }
+
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(format!("{}.lock", path))?;
+
+    file.lock()?;
+
+    Ok(DatastoreLock(file))
+}
+
+fn backup_path(path: &str) -> String {
+    format!("{}.bak", path)
+}
+
+/// Layout of the timestamp in the name of rolling backups, down to the
+/// microsecond so that writes in the same second each keep their own
+const BACKUP_TIMESTAMP: &str = "%Y%m%dT%H%M%S%.6f";
+
+/// Layout of the timestamp of backups from before they had microseconds
+const BACKUP_TIMESTAMP_SECONDS: &str = "%Y%m%dT%H%M%S";
+
+/// Rolling backups of `path` sit next to it as `<datastore>.<timestamp>.bak`
+fn timestamped_backup_path(path: &str, timestamp: &str) -> String {
+    format!("{}.{}.bak", path, timestamp)
+}
+
+/// Timestamps of the rolling backups of `path`, oldest first
+pub fn list_backups(path: &str) -> io::Result<Vec<String>> {
+    let datastore = std::path::Path::new(path);
+    let dir = match datastore.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => std::path::Path::new("."),
+    };
+    let prefix = format!(
+        "{}.",
+        datastore.file_name().unwrap_or_default().to_string_lossy()
+    );
+
+    let mut backups = Vec::new();
+
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+
+        let timestamp = name
+            .strip_prefix(&prefix)
+            .and_then(|rest| rest.strip_suffix(".bak"))
+            .filter(|timestamp| {
+                [BACKUP_TIMESTAMP, BACKUP_TIMESTAMP_SECONDS]
+                    .iter()
+                    .any(|layout| chrono::NaiveDateTime::parse_from_str(timestamp, layout).is_ok())
+            });
+
+        if let Some(timestamp) = timestamp {
+            backups.push(timestamp.to_string());
+        }
+    }
+
+    // The timestamp layouts sort chronologically as text, a backup from a
+    // whole second coming before the ones later in that second
+    backups.sort();
+
+    Ok(backups)
+}
+
+/// Copies the current datastore to a new rolling backup, then deletes the
+/// oldest ones beyond `BACKUP_COUNT`. The new backup is complete before
+/// anything is pruned, so there's always at least one copy.
+fn rotate_backups(path: &str, current: &[u8]) -> io::Result<()> {
+    let timestamp = Local::now().format(BACKUP_TIMESTAMP).to_string();
+    write_atomically(&timestamped_backup_path(path, &timestamp), current)?;
+
+    let backups = list_backups(path)?;
+    let excess = backups
+        .len()
+        .saturating_sub(BACKUP_COUNT.load(Ordering::Relaxed));
+
+    for timestamp in &backups[..excess] {
+        std::fs::remove_file(timestamped_backup_path(path, timestamp))?;
+    }
+
+    Ok(())
+}
+
+/// Replaces the datastore, keeping its previous contents as a backup for
+/// `undo` and in the rolling backups `restore` offers
+pub fn write_datastore(path: &str, datastore: &Datastore) -> io::Result<()> {
+    let started = Instant::now();
+
+    let last_read = LAST_READ
+        .lock()
+        .ok()
+        .and_then(|mut last_read| last_read.take());
+    if let Some(last_read) = last_read {
+        report_timing("change", started.duration_since(last_read));
+    }
+
+    back_up_datastore(path)?;
+    storage::for_path(path).write(datastore)?;
+
+    report_timing("write", started.elapsed());
+    back_up_offsite(path);
+
+    Ok(())
+}
+
+/// `template` with each `{path}` replaced by `path`, quoted for the shell
+pub fn backup_command_line(template: &str, path: &str) -> String {
+    let quoted = shlex::try_quote(path).unwrap_or(std::borrow::Cow::Borrowed(path));
+    template.replace("{path}", &quoted)
+}
+
+/// Runs the `backup_command` `template` for the datastore at `path` with
+/// `run`, which gets the shell command line. Returns what went wrong, if
+/// anything, for a warning: the write it follows already succeeded.
+pub fn run_backup_command(
+    template: &str,
+    path: &str,
+    run: impl FnOnce(&str) -> io::Result<std::process::ExitStatus>,
+) -> Result<(), String> {
+    let command = backup_command_line(template, path);
+    debug!("Running the backup command: {}", command);
+
+    match run(&command) {
+        Ok(status) if status.success() => Ok(()),
+        Ok(status) => Err(format!("the backup command exited with {}", status)),
+        Err(error) => Err(format!("couldn't run the backup command: {}", error)),
+    }
+}
+
+/// Runs the configured `backup_command`, if any, after writing `path`
+fn back_up_offsite(path: &str) {
+    let Some(template) = BACKUP_COMMAND.get() else {
+        return;
+    };
+
+    let result = run_backup_command(template, path, |command| {
+        std::process::Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            // Keep stdout for the command's own output, e.g. `--json`
+            .stdout(std::process::Stdio::null())
+            .status()
+    });
+
+    if let Err(problem) = result {
+        eprintln!(
+            "Warning: {}, the datastore was saved but not copied",
+            problem
+        );
+    }
+}
+
+/// Keeps the current datastore at `path`, if any, for `undo` and `restore`
+fn back_up_datastore(path: &str) -> io::Result<()> {
+    match std::fs::read(path) {
+        Ok(current) => {
+            write_atomically(&backup_path(path), &current)?;
+            rotate_backups(path, &current)
+        }
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(error) => Err(error),
+    }
+}
+
+/// Backs up the datastore at `path`, then replaces its contents with `data`
+fn replace_datastore(path: &str, data: &[u8]) -> io::Result<()> {
+    back_up_datastore(path)?;
+
+    retry_transient(WRITE_ATTEMPTS, WRITE_BACKOFF, || {
+        write_atomically(path, data)
+    })
+}
+
+/// A busy or locked database is worth another try, like a busy file
+fn sqlite_error(error: rusqlite::Error) -> io::Error {
+    let busy = matches!(
+        error.sqlite_error_code(),
+        Some(rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked)
+    );
+
+    if busy {
+        io::Error::new(io::ErrorKind::ResourceBusy, error)
+    } else {
+        io::Error::other(error)
+    }
+}
+
+/// Number of times a datastore write is tried before a transient error is reported
+const WRITE_ATTEMPTS: u32 = 3;
+
+/// Wait before the second attempt at a write, doubled before each further one
+const WRITE_BACKOFF: Duration = Duration::from_millis(50);
+
+/// Whether `error` may clear up by itself, like the EAGAIN and EBUSY network
+/// filesystems sometimes return, rather than needing the user to act
+pub fn is_transient(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::WouldBlock
+            | io::ErrorKind::ResourceBusy
+            | io::ErrorKind::Interrupted
+            | io::ErrorKind::TimedOut
+    )
+}
+
+/// Calls `write` until it succeeds or fails with a permanent error, trying at
+/// most `attempts` times with an exponentially growing wait in between
+pub fn retry_transient<T>(
+    attempts: u32,
+    backoff: Duration,
+    mut write: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    let mut delay = backoff;
+    let mut attempt = 1;
+
+    loop {
+        match write() {
+            Err(error) if is_transient(&error) && attempt < attempts => {
+                debug!(
+                    "Write failed ({}), trying again in {} ms",
+                    error,
+                    delay.as_millis()
+                );
+                std::thread::sleep(delay);
+                delay *= 2;
+                attempt += 1;
+            }
+            Err(error) if is_transient(&error) => {
+                return Err(io::Error::new(
+                    error.kind(),
+                    format!("{} (gave up after {} attempts)", error, attempts),
+                ));
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Largest amount accepted, well within the range of `i64` minor units
+const MAX_AMOUNT: f64 = 1e15;
+
+/// Rejects amounts in `currency` that would corrupt totals, such as NaN,
+/// infinities and zero
+pub fn validate_amount(amount: f64, allow_negative: bool, currency: &str) -> Result<(), Error> {
+    let reason = if amount.is_nan() {
+        "amount must be a number"
+    } else if amount.is_infinite() {
+        "amount must be finite"
+    } else if amount.abs() >= MAX_AMOUNT {
+        "amount is too large"
+    } else if amount == 0.0 {
+        "amount must not be zero"
+    } else if to_minor_units(amount, currency) == 0 {
+        "amount rounds to zero"
+    } else if amount < 0.0 && !allow_negative {
+        "amount must be positive (use --refund for refunds)"
+    } else {
+        return Ok(());
+    };
+
+    Err(Error::InvalidAmount { amount, reason })
+}
+
+/// Trims a description, rejecting one left blank unless `allow_empty` is set
+pub fn normalize_description(description: &str, allow_empty: bool) -> Result<String, Error> {
+    let description = description.trim();
+
+    if description.is_empty() && !allow_empty {
+        return Err(Error::EmptyDescription);
+    }
+
+    Ok(description.to_string())
+}
+
+/// Converts an amount given in currency units to cents, rounding to the
+/// nearest cent, for the floating-point amounts of old datastores. Other
+/// amounts are in their currency's minor units, see `to_minor_units`.
+pub fn to_cents(amount: f64) -> i64 {
+    (amount * 100.0).round() as i64
+}
+
+/// Converts an amount given in units of `currency` to the minor units it's
+/// stored in, see `minor_unit_places`
+pub fn to_minor_units(amount: f64, currency: &str) -> i64 {
+    (amount * 10f64.powi(i32::from(minor_unit_places(currency)))).round() as i64
+}
+
+/// Rounds `amount` half away from zero to `places` decimals
+pub fn round_to_places(amount: f64, places: u8) -> f64 {
+    let scale = 10f64.powi(i32::from(places));
+    (amount * scale).round() / scale
+}
+
+/// Formats an amount in minor units of `currency` as a plain decimal amount
+/// with the currency's decimal places, e.g. `-12.50`
+pub fn format_minor_units(units: i64, currency: &str) -> String {
+    format_minor_units_with_precision(units, currency, decimal_places(currency))
+}
+
+/// Formats an amount in minor units of `currency` with the given number of
+/// decimal places, rounding half away from zero when there are fewer than the
+/// minor unit has
+pub fn format_minor_units_with_precision(units: i64, currency: &str, precision: u8) -> String {
+    let places = minor_unit_places(currency);
+    let magnitude = units.unsigned_abs();
+
+    let (scaled, shown) = if precision < places {
+        let divisor = 10u64.pow(u32::from(places - precision));
+        ((magnitude + divisor / 2) / divisor, precision)
+    } else {
+        (magnitude, places)
+    };
+    let scale = 10u64.pow(u32::from(shown));
+
+    // Rounding can leave nothing to show a minus sign for
+    let sign = if units < 0 && scaled > 0 { "-" } else { "" };
+    let mut amount = format!("{}{}", sign, scaled / scale);
+
+    if precision > 0 {
+        amount.push_str(&format!(
+            ".{:0width$}{:0<padding$}",
+            scaled % scale,
+            "",
+            width = usize::from(shown),
+            padding = usize::from(precision - shown)
+        ));
+    }
+
+    amount
+}
+
+fn settings_path(path: &str) -> std::path::PathBuf {
+    std::path::Path::new(path).with_extension("settings.json")
+}
+
+pub fn read_settings(path: &str) -> io::Result<Settings> {
+    match std::fs::read_to_string(settings_path(path)) {
+        Ok(data) => {
+            let mut value = serde_json::from_str(&data)?;
+            migrate_float_amount(&mut value, "budget", "budget_cents");
+            Ok(serde_json::from_value(value)?)
+        }
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Settings::default()),
+        Err(error) => Err(error),
+    }
+}
+
+pub fn write_settings(path: &str, settings: &Settings) -> io::Result<()> {
+    if missing_without_init(path) {
+        return Err(not_initialized(path));
+    }
+
+    let data = serde_json::to_string_pretty(settings)?;
+    write_atomically(&settings_path(path).to_string_lossy(), data.as_bytes())
+}
+
+/// Turns colored tables on or off, leaving them off when `NO_COLOR` is set or
+/// stdout isn't a terminal
+pub fn init_color(no_color: bool) {
+    let no_color_env = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+    let enabled = !no_color && !no_color_env && io::stdout().is_terminal();
+
+    COLOR.store(enabled, Ordering::Relaxed);
+}
+
+fn print_table(table: &Table) {
+    let table = &themed(table);
+
+    if COLOR.load(Ordering::Relaxed) {
+        table.printstd();
+    } else {
+        // Ignore write errors, like `printstd` does
+        let _ = table.print(&mut io::stdout());
+    }
+}
+
+/// `table` drawn in the theme set with `--theme`
+fn themed(table: &Table) -> Table {
+    let mut table = table.clone();
+    table.set_format(table_format(THEME.get().copied().unwrap_or_default()));
+    table
+}
+
+/// `$XDG_CONFIG_HOME/expense-tracker/config.toml`, falling back to `~/.config`
+pub fn config_path() -> Option<std::path::PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => std::path::PathBuf::from(dir),
+        _ => std::path::PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+
+    Some(config_dir.join("expense-tracker").join("config.toml"))
+}
+
+/// `$XDG_DATA_HOME/expense-tracker`, falling back to `~/.local/share`, where
+/// each profile keeps its datastore
+pub fn profiles_dir() -> Option<std::path::PathBuf> {
+    let data_dir = match std::env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => std::path::PathBuf::from(dir),
+        _ => std::path::PathBuf::from(std::env::var_os("HOME")?)
+            .join(".local")
+            .join("share"),
+    };
+
+    Some(data_dir.join("expense-tracker"))
+}
+
+/// Expands a leading `~` and `$VAR`/`${VAR}` in a path, failing on unset
+/// variables rather than using the text as is
+pub fn expand_path(value: &str) -> std::result::Result<String, String> {
+    shellexpand::full(value)
+        .map(|path| path.into_owned())
+        .map_err(|error| format!("environment variable ${} isn't set", error.var_name))
+}
+
+/// Parses a `description=amount` line item, splitting on the last `=` so the
+/// description can contain one
+pub fn parse_line_item(value: &str) -> std::result::Result<NewLineItem, String> {
+    let Some((description, amount)) = value.rsplit_once('=') else {
+        return Err(format!("'{}' is not a line item like 'Milk=2.50'", value));
+    };
+
+    let description = description.trim();
+    if description.is_empty() {
+        return Err(format!("line item '{}' has no description", value));
+    }
+
+    Ok(NewLineItem {
+        description: description.to_string(),
+        amount: parse_amount(amount)?,
+    })
+}
+
+/// Checks that line items, if there are any, add up to `amount` exactly
+pub fn check_items(items: &[LineItem], amount: i64, currency: &str) -> Result<(), Error> {
+    if items.is_empty() {
+        return Ok(());
+    }
+
+    let sum: i64 = items.iter().map(|item| item.amount).sum();
+
+    if sum == amount {
+        Ok(())
+    } else {
+        Err(Error::ItemsMismatch {
+            sum: format_amount(sum, currency),
+            amount: format_amount(amount, currency),
+        })
+    }
+}
+
+/// Checks a profile name can be used as a file name in the profiles directory
+pub fn parse_profile_name(value: &str) -> std::result::Result<String, String> {
+    let valid = !value.is_empty()
+        && !value.starts_with('.')
+        && value
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.'));
+
+    if valid {
+        Ok(value.to_string())
+    } else {
+        Err(format!(
+            "'{}' is not a valid profile name, use letters, digits, '-', '_' and '.'",
+            value
+        ))
+    }
+}
+
+pub fn profile_path(name: &str) -> Result<String, Error> {
+    let dir = profiles_dir().ok_or(Error::NoDataDir)?;
+
+    Ok(dir
+        .join(format!("{}.json", name))
+        .to_string_lossy()
+        .into_owned())
+}
+
+/// Moves the expense with the given ID to the datastore of profile `to`, where
+/// it gets a fresh ID
+///
+/// The target is written before the expense is removed here, so a crash in
+/// between leaves it in both datastores rather than in neither.
+pub fn move_expense(id: &Id, to: &str, path: &str) -> Result<(), Error> {
+    let target = profile_path(to)?;
+
+    let same = match (std::fs::canonicalize(path), std::fs::canonicalize(&target)) {
+        (Ok(source), Ok(target)) => source == target,
+        _ => path == target,
+    };
+
+    if same {
+        return Err(Error::SameProfile(to.to_string()));
+    }
+
+    init_datastore(&target).map_err(Error::Init)?;
+
+    // Always take the two locks in the same order so concurrent moves can't deadlock
+    let (_first, _second) = if path < target.as_str() {
+        let first = lock_datastore(path).map_err(Error::Lock)?;
+        (first, lock_datastore(&target).map_err(Error::Lock)?)
+    } else {
+        let first = lock_datastore(&target).map_err(Error::Lock)?;
+        (first, lock_datastore(path).map_err(Error::Lock)?)
+    };
+
+    let mut source = read_datastore(path).map_err(Error::Read)?;
+
+    let index = source
+        .expenses
+        .iter()
+        .position(|expense| expense.id == *id && !expense.is_deleted())
+        .ok_or_else(|| Error::ExpenseNotFound(vec![id.clone()]))?;
+
+    let mut destination = read_datastore(&target).map_err(Error::Read)?;
+
+    let mut expense = source.expenses[index].clone();
+    expense.id = destination.allocate_id();
+    let new_id = expense.id.clone();
+    destination.expenses.push(expense);
+
+    write_datastore(&target, &destination).map_err(Error::Write)?;
+    record_history(&target, AuditAction::Add, std::slice::from_ref(&new_id));
+
+    source.expenses.remove(index);
+    write_datastore(path, &source).map_err(Error::Write)?;
+    record_history(path, AuditAction::Delete, std::slice::from_ref(id));
+
+    info!(
+        "Moved expense with ID: {} to profile '{}' as ID: {}",
+        id, to, new_id
+    );
+
+    if porcelain() {
+        println!("moved id={} profile={} new_id={}", id, to, new_id);
+    }
+
+    Ok(())
+}
+
+/// Names of the profiles with a datastore in the profiles directory, sorted
+pub fn find_profiles(dir: &std::path::Path) -> io::Result<Vec<String>> {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(error) => return Err(error),
+    };
+
+    let mut profiles = Vec::new();
+
+    for entry in entries {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+
+        // Settings sit next to each datastore as `<name>.settings.json`
+        if let Some(profile) = name.strip_suffix(".json") {
+            if !profile.ends_with(".settings") {
+                profiles.push(profile.to_string());
+            }
+        }
+    }
+
+    profiles.sort();
+
+    Ok(profiles)
+}
+
+pub fn list_profiles() -> Result<(), Error> {
+    let dir = profiles_dir().ok_or(Error::NoDataDir)?;
+    let profiles = find_profiles(&dir).map_err(Error::Read)?;
+
+    if profiles.is_empty() {
+        info!("No profiles in '{}' yet", dir.display());
+    }
+
+    for profile in profiles {
+        println!("{}", profile);
+    }
+
+    Ok(())
+}
+
+/// Reads the config file, a missing one meaning all defaults apply
+pub fn read_config() -> io::Result<Config> {
+    let Some(path) = config_path() else {
+        return Ok(Config::default());
+    };
+
+    let invalid = |reason: String| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("'{}' is invalid: {}", path.display(), reason),
+        )
+    };
+
+    match std::fs::read_to_string(&path) {
+        Ok(data) => {
+            let mut config: Config =
+                toml::from_str(&data).map_err(|error| invalid(error.to_string()))?;
+
+            if let Some(datastore) = &config.datastore {
+                config.datastore = Some(expand_path(datastore).map_err(invalid)?);
+            }
+
+            Ok(config)
+        }
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+        Err(error) => Err(error),
+    }
+}
+
+/// `expense`'s amount in `currency`, converted with the configured rates when
+/// it was paid in another currency. `None` when there's no rate to do so.
+pub fn amount_in(expense: &Expense, currency: &str) -> Option<i64> {
+    let from = expense.currency.as_deref().unwrap_or(currency);
+
+    match RATES.get() {
+        Some(rates) => convert_cents(expense.amount, from, currency, rates),
+        None => convert_cents(expense.amount, from, currency, &HashMap::new()),
+    }
+}
+
+/// Spending added up in one currency, see `spending_in`
+#[derive(Debug, Default, PartialEq)]
+pub struct Spending {
+    /// In minor units of the currency it was added up in
+    pub total: i64,
+    /// Number of expenses left out per currency they were paid in, for want of
+    /// a rate to convert them with
+    pub left_out: BTreeMap<String, usize>,
+}
+
+impl Spending {
+    /// Warns about the expenses left out of the total in `currency`
+    pub fn warn_left_out(&self, currency: &str) {
+        warn_missing_rates(&self.left_out, currency);
+    }
+}
+
+/// Warns on stderr, so `--total-only` output stays a plain number, that the
+/// expenses counted in `missing` couldn't be converted to `to`
+fn warn_missing_rates(missing: &BTreeMap<String, usize>, to: &str) {
+    for (from, count) in missing {
+        eprintln!(
+            "Warning: no rate to convert {} to {}, left {} expense(s) out of the total",
+            from, to, count
+        );
+    }
+}
+
+/// Adds up the spending of `expenses` in `currency`, leaving out income.
+/// Amounts paid in other currencies are converted, as dollars and yen add up
+/// to nothing meaningful, or left out when there's no rate for them.
+pub fn spending_in<'a>(
+    expenses: impl IntoIterator<Item = &'a Expense>,
+    currency: &str,
+) -> Spending {
+    let mut spending = Spending::default();
+
+    for expense in expenses.into_iter().filter(|expense| !expense.is_income()) {
+        match amount_in(expense, currency) {
+            Some(amount) => spending.total += amount,
+            None => {
+                let from = expense.currency.as_deref().unwrap_or(currency);
+                *spending.left_out.entry(from.to_uppercase()).or_default() += 1;
+            }
+        }
+    }
+
+    spending
+}
+
+/// Spending in `currency` on the expenses dated within the given month of the
+/// given year, leaving out soft-deleted expenses, see `spending_in`
+pub fn month_total(expenses: &[Expense], year: i32, month: u32, currency: &str) -> Spending {
+    let in_month = expenses.iter().filter(|expense| {
+        !expense.is_deleted()
+            && expense
+                .date
+                .is_some_and(|date| date.year() == year && date.month() == month)
+    });
+
+    spending_in(in_month, currency)
+}
+
+/// Formats an amount in minor units of `currency` with its symbol, falling back to its code
+pub fn format_amount(cents: i64, currency: &str) -> String {
+    if REDACT_AMOUNTS.load(Ordering::Relaxed) {
+        return "***".to_string();
+    }
+
+    let amount = localize_number(
+        &format_minor_units_with_precision(cents, currency, display_precision(currency)),
+        locale(),
+    );
+    let (sign, amount) = match amount.strip_prefix('-') {
+        Some(amount) => ("-", amount),
+        None => ("", amount.as_str()),
+    };
+
+    match currency.to_uppercase().as_str() {
+        "USD" => format!("{}${}", sign, amount),
+        "EUR" => format!("{}€{}", sign, amount),
+        "GBP" => format!("{}£{}", sign, amount),
+        code => format!("{}{} {}", sign, code, amount),
+    }
+}
+
+/// How a locale writes numbers and dates. Only display output follows it,
+/// datastores and exports keep dot decimals and ISO dates.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Locale {
+    pub decimal_separator: char,
+    pub thousands_separator: Option<char>,
+    /// `chrono` format, e.g. `%d.%m.%Y`
+    pub date_format: &'static str,
+}
+
+impl Locale {
+    /// The storage format, used for `C`, `POSIX` and a `LANG` this doesn't know
+    pub const CANONICAL: Locale = Locale {
+        decimal_separator: '.',
+        thousands_separator: None,
+        date_format: "%Y-%m-%d",
+    };
+
+    pub fn format_date(self, date: NaiveDate) -> String {
+        date.format(self.date_format).to_string()
+    }
+}
+
+/// Locales `--locale` knows, by language and region
+const LOCALES: &[(&str, Locale)] = &[
+    ("en-US", locale_of('.', ',', "%m/%d/%Y")),
+    ("en-GB", locale_of('.', ',', "%d/%m/%Y")),
+    ("en-CA", locale_of('.', ',', "%Y-%m-%d")),
+    ("en-AU", locale_of('.', ',', "%d/%m/%Y")),
+    ("de-DE", locale_of(',', '.', "%d.%m.%Y")),
+    ("de-AT", locale_of(',', '.', "%d.%m.%Y")),
+    ("de-CH", locale_of('.', '\'', "%d.%m.%Y")),
+    ("fr-FR", locale_of(',', '\u{202f}', "%d/%m/%Y")),
+    ("es-ES", locale_of(',', '.', "%d/%m/%Y")),
+    ("it-IT", locale_of(',', '.', "%d/%m/%Y")),
+    ("nl-NL", locale_of(',', '.', "%d-%m-%Y")),
+    ("pt-PT", locale_of(',', ' ', "%d/%m/%Y")),
+    ("pt-BR", locale_of(',', '.', "%d/%m/%Y")),
+    ("pl-PL", locale_of(',', ' ', "%d.%m.%Y")),
+    ("sv-SE", locale_of(',', ' ', "%Y-%m-%d")),
+    ("ja-JP", locale_of('.', ',', "%Y/%m/%d")),
+    ("zh-CN", locale_of('.', ',', "%Y/%m/%d")),
+];
+
+const fn locale_of(decimal: char, thousands: char, date_format: &'static str) -> Locale {
+    Locale {
+        decimal_separator: decimal,
+        thousands_separator: Some(thousands),
+        date_format,
+    }
+}
+
+/// Parses a locale like `de-DE`, or as `LANG` spells it, `de_DE.UTF-8`. A
+/// language alone, e.g. `de`, takes its first region in the table.
+pub fn parse_locale(value: &str) -> std::result::Result<Locale, String> {
+    // Leave out the encoding and modifier, e.g. `.UTF-8` and `@euro`
+    let name = value
+        .split(['.', '@'])
+        .next()
+        .unwrap_or_default()
+        .replace('_', "-");
+
+    if name.eq_ignore_ascii_case("C") || name.eq_ignore_ascii_case("POSIX") {
+        return Ok(Locale::CANONICAL);
+    }
+
+    let language = name.split('-').next().unwrap_or_default();
+
+    LOCALES
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(&name))
+        .or_else(|| {
+            LOCALES.iter().find(|(known, _)| {
+                known
+                    .split('-')
+                    .next()
+                    .is_some_and(|known| known.eq_ignore_ascii_case(language))
+            })
+        })
+        .map(|(_, locale)| *locale)
+        .ok_or_else(|| {
+            let known: Vec<&str> = LOCALES.iter().map(|(name, _)| *name).collect();
+            format!(
+                "unknown locale '{}', use one of {}",
+                value,
+                known.join(", ")
+            )
+        })
+}
+
+/// Rewrites a number formatted like `-1234.56` with the separators of `locale`,
+/// e.g. `-1.234,56` for `de-DE`
+pub fn localize_number(number: &str, locale: Locale) -> String {
+    let (sign, unsigned) = match number.strip_prefix('-') {
+        Some(unsigned) => ("-", unsigned),
+        None => ("", number),
+    };
+    let (integer, fraction) = match unsigned.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (unsigned, None),
+    };
+
+    let mut localized = sign.to_string();
+
+    for (index, digit) in integer.chars().enumerate() {
+        if index > 0 && (integer.len() - index) % 3 == 0 {
+            if let Some(separator) = locale.thousands_separator {
+                localized.push(separator);
+            }
+        }
+        localized.push(digit);
+    }
+
+    if let Some(fraction) = fraction {
+        localized.push(locale.decimal_separator);
+        localized.push_str(fraction);
+    }
+
+    localized
+}
+
+/// Formats a date for display, following `--locale`
+pub fn format_date(date: NaiveDate) -> String {
+    locale().format_date(date)
+}
+
+/// Whether two expenses look like the same purchase: same description and
+/// amount, and the same date unless one of them is undated
+pub fn is_similar(a: &Expense, b: &Expense) -> bool {
+    let same_date = match (a.date, b.date) {
+        (Some(a), Some(b)) => a == b,
+        _ => true,
+    };
+
+    a.description == b.description && a.amount == b.amount && same_date
+}
+
+pub fn add_expense(
+    new: &NewExpense,
+    kind: Kind,
+    currency: &str,
+    dry_run: bool,
+    path: &str,
+) -> Result<(), Error> {
+    let _lock = lock_datastore(path).map_err(Error::Lock)?;
+    let mut datastore = read_datastore(path).map_err(Error::Read)?;
+
+    let quotas = read_settings(path).map_err(Error::Settings)?.quotas;
+    let added = add_to_datastore(&mut datastore, new, kind, &quotas, currency, dry_run)?;
+    let Some(id) = added else {
+        return Ok(());
+    };
+
+    if dry_run {
+        info!(
+            "Would add {} with ID: {}",
+            kind.to_string().to_lowercase(),
+            id
+        );
+    } else {
+        write_datastore(path, &datastore).map_err(Error::Write)?;
+        record_history(path, AuditAction::Add, std::slice::from_ref(&id));
+
+        if porcelain() {
+            println!("added id={}", id);
+        }
+    }
+
+    warn_if_over_budget(&datastore, &id, currency, path)
+}
+
+/// Kind and signed minor units of `currency` stored for an amount given as
+/// `amount`: refunds are given as positive amounts and stored negative,
+/// offsetting spending
+pub fn stored_amount(
+    kind: Kind,
+    amount: f64,
+    refund: bool,
+    currency: &str,
+) -> Result<(Kind, i64), Error> {
+    match (refund, kind) {
+        (false, kind) => Ok((kind, to_minor_units(amount, currency))),
+        (true, Kind::Income) => Err(Error::InvalidAmount {
+            amount,
+            reason: "income can't be a refund",
+        }),
+        (true, _) => Ok((Kind::Refund, -to_minor_units(amount, currency))),
+    }
+}
+
+/// Kind and signed minor units stored for `amount` as typed for an expense of
+/// `kind`, by `add` and `update` alike. Unless negative expenses were asked
+/// for, a negative amount is usually a refund typed with a minus sign; given
+/// `answers` the user is offered to store it exactly like `refund` with the
+/// positive amount would.
+fn signed_amount(
+    kind: Kind,
+    amount: f64,
+    refund: bool,
+    allow_negative: bool,
+    answers: Option<&mut dyn BufRead>,
+    currency: &str,
+) -> Result<(Kind, i64), Error> {
+    if refund && amount < 0.0 {
+        return Err(Error::InvalidAmount {
+            amount,
+            reason: "give a refund's amount as a positive number",
+        });
+    }
+
+    let refund = refund
+        || (kind == Kind::Expense
+            && amount < 0.0
+            && !allow_negative
+            && match answers {
+                Some(mut answers) => confirm_default_yes(
+                    &format!(
+                        "{} is negative, record it as a refund?",
+                        format_amount(to_minor_units(amount, currency), currency)
+                    ),
+                    &mut answers,
+                )
+                .map_err(Error::Prompt)?,
+                None => false,
+            });
+    let given = if refund { amount.abs() } else { amount };
+
+    // Yen and the like have no cents to store
+    let places = decimal_places(currency);
+    let rounded = round_to_places(given, places);
+    if rounded != given {
+        info!("Rounded {} to {} decimal place(s)", given, places);
+    }
+
+    validate_amount(rounded, allow_negative, currency)?;
+    stored_amount(kind, rounded, refund, currency)
+}
+
+/// The expense added with `add --idempotency-key key`, leaving out deleted ones
+pub fn find_by_idempotency_key<'a>(expenses: &'a [Expense], key: &str) -> Option<&'a Expense> {
+    expenses
+        .iter()
+        .find(|expense| !expense.is_deleted() && expense.idempotency_key.as_deref() == Some(key))
+}
+
+/// Validates `new` and appends it to `datastore`, asking first if a similar
+/// expense exists and checking it against the category `quotas`. Returns the
+/// new ID, or `None` if `new`'s idempotency key was already used. Backing out
+/// of a question, or there being no terminal to ask it on, is an error.
+pub fn add_to_datastore(
+    datastore: &mut Datastore,
+    new: &NewExpense,
+    kind: Kind,
+    quotas: &BTreeMap<String, i64>,
+    currency: &str,
+    dry_run: bool,
+) -> Result<Option<Id>, Error> {
+    let existing = new
+        .idempotency_key
+        .as_deref()
+        .and_then(|key| find_by_idempotency_key(&datastore.expenses, key));
+    if let Some(existing) = existing {
+        info!("Already added with ID: {}, nothing to do", existing.id);
+
+        if porcelain() {
+            println!("existing id={}", existing.id);
+        }
+
+        return Ok(None);
+    }
+
+    let paid_in = new.paid_in.as_deref().unwrap_or(currency);
+    let (kind, amount) = signed_amount(
+        kind,
+        new.amount,
+        new.refund,
+        new.allow_negative,
+        (!dry_run && io::stdin().is_terminal()).then_some(&mut io::stdin().lock()),
+        paid_in,
+    )?;
+    let description = normalize_description(&new.description, new.allow_empty)?;
+    let vendor = split_vendor(&description)
+        .filter(|_| new.parse_vendor)
+        .map(|(vendor, _)| vendor.to_string());
+
+    let items = to_line_items(&new.items, paid_in)?;
+    check_items(&items, amount.abs(), paid_in)?;
+
+    let id = if new.random_id {
+        datastore.random_id()
+    } else {
+        datastore.allocate_id()
+    };
+
+    // Only offer a category to someone at the terminal, scripts get what they asked for
+    let suggestion = match &new.category {
+        None if !new.no_suggest && !dry_run && io::stdin().is_terminal() => {
+            suggest_category(&description, &datastore.expenses)
+        }
+        _ => None,
+    };
+
+    let category = match suggestion {
+        Some(suggestion) => {
+            let prompt = format!("Use category '{}'?", suggestion);
+
+            if confirm_default_yes(&prompt, &mut io::stdin().lock()).map_err(Error::Prompt)? {
+                Some(suggestion)
+            } else {
+                None
+            }
+        }
+        None => new.category.clone(),
+    };
+
+    let expense = Expense {
+        id: id.clone(),
+        description,
+        amount,
+        date: Some(new.date.unwrap_or_else(|| Local::now().date_naive())),
+        category,
+        recurring_id: None,
+        kind,
+        note: new.note.clone(),
+        tags: new.tags.clone(),
+        currency: new.paid_in.as_deref().map(str::to_uppercase),
+        created_at: Some(Utc::now()),
+        vendor,
+        deleted_at: None,
+        receipt: new.receipt.clone(),
+        items,
+        idempotency_key: new.idempotency_key.clone(),
+    };
+
+    if let Some(receipt) = &expense.receipt {
+        warn_missing_receipt(receipt);
+    }
+
+    if kind == Kind::Expense && !new.force && !dry_run {
+        let threshold = new
+            .threshold
+            .map(|threshold| to_minor_units(threshold, paid_in));
+        let mut stdin = io::stdin().lock();
+        let input = io::stdin().is_terminal().then_some(&mut stdin);
+
+        confirm_large_expense(amount, threshold, paid_in, input)?;
+    }
+
+    let similar = if new.force {
+        None
+    } else {
+        datastore
+            .expenses
+            .iter()
+            .find(|e| !e.is_deleted() && is_similar(e, &expense))
+    };
+
+    if let Some(similar) = similar {
+        eprintln!(
+            "Warning: expense with ID: {} has the same description, amount and date",
+            similar.id
+        );
+
+        if !dry_run {
+            confirm_add(
+                "A similar expense already exists. Add anyway?",
+                "a similar expense already exists",
+                io::stdin().is_terminal().then_some(&mut io::stdin().lock()),
+            )?;
+        }
+    }
+
+    check_quota(&datastore.expenses, &expense, quotas, new.enforce, currency)?;
+
+    datastore.expenses.push(expense);
+
+    if !dry_run {
+        info!("{} added successfully with ID: {}", kind, id);
+    }
+
+    Ok(Some(id))
+}
+
+/// How far `amount` takes a category with `spent` of its `quota` already
+/// used over it, if it does. Refunds and expenses that fit never do.
+pub fn quota_overrun(quota: i64, spent: i64, amount: i64) -> Option<i64> {
+    let after = spent + amount;
+    (amount > 0 && after > quota).then_some(after - quota)
+}
+
+/// Spending in `category` dated within the given month, see `month_total`.
+/// Categories are compared lowercased, the way quotas are keyed.
+fn category_month_total(
+    expenses: &[Expense],
+    category: &str,
+    year: i32,
+    month: u32,
+    currency: &str,
+) -> Spending {
+    let category = category.to_lowercase();
+    let in_category: Vec<Expense> = expenses
+        .iter()
+        .filter(|expense| {
+            expense
+                .category
+                .as_deref()
+                .is_some_and(|c| c.to_lowercase() == category)
+        })
+        .cloned()
+        .collect();
+
+    month_total(&in_category, year, month, currency)
+}
+
+/// Warns when `expense` would take its category over its monthly quota, or
+/// with `enforce` refuses it
+fn check_quota(
+    expenses: &[Expense],
+    expense: &Expense,
+    quotas: &BTreeMap<String, i64>,
+    enforce: bool,
+    currency: &str,
+) -> Result<(), Error> {
+    let (Some(date), Some(category), false) =
+        (expense.date, &expense.category, expense.is_income())
+    else {
+        return Ok(());
+    };
+
+    let Some(&quota) = quotas.get(&category.to_lowercase()) else {
+        return Ok(());
+    };
+
+    // The quota is in the datastore's currency, the expense maybe not
+    let Some(amount) = amount_in(expense, currency) else {
+        eprintln!(
+            "Warning: no rate to convert {} to {}, not checking the '{}' quota",
+            expense.currency.as_deref().unwrap_or(currency),
+            currency,
+            category
+        );
+        return Ok(());
+    };
+    let spent = category_month_total(expenses, category, date.year(), date.month(), currency).total;
+
+    let Some(excess) = quota_overrun(quota, spent, amount) else {
+        return Ok(());
+    };
+
+    if enforce {
+        return Err(Error::OverQuota {
+            category: category.clone(),
+            excess: format_amount(excess, currency),
+        });
+    }
+
+    eprintln!(
+        "Warning: this takes '{}' {} over its monthly quota of {}",
+        category,
+        format_amount(excess, currency),
+        format_amount(quota, currency)
+    );
+
+    Ok(())
+}
+
+/// Warns when adding the expense `id` took its month over the budget
+fn warn_if_over_budget(
+    datastore: &Datastore,
+    id: &Id,
+    currency: &str,
+    path: &str,
+) -> Result<(), Error> {
+    let Some(expense) = datastore.expenses.iter().find(|expense| expense.id == *id) else {
+        return Ok(());
+    };
+
+    let (Some(date), false) = (expense.date, expense.is_income()) else {
+        return Ok(());
+    };
+
+    if let Some(budget) = read_settings(path).map_err(Error::Settings)?.budget {
+        let spent = month_total(&datastore.expenses, date.year(), date.month(), currency).total;
+
+        if spent > budget {
+            eprintln!(
+                "Warning: you are {} over your monthly budget",
+                format_amount(spent - budget, currency)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Asks a yes/no question, treating anything but `y`/`yes` as a no
+pub fn confirm(prompt: &str, input: &mut impl BufRead) -> io::Result<bool> {
+    ask(format_args!("{} [y/N] ", prompt))?;
+
+    read_yes(input)
+}
+
+/// Prints a question waiting for its answer, on stderr with `--porcelain` so
+/// stdout only ever has the porcelain lines
+fn ask(question: fmt::Arguments) -> io::Result<()> {
+    if porcelain() {
+        eprint!("{}", question);
+        io::stderr().flush()
+    } else {
+        print!("{}", question);
+        io::stdout().flush()
+    }
+}
+
+/// Asks a yes/no question like `confirm`, but on stderr, keeping it out of
+/// output meant for scripts
+pub fn confirm_on_stderr(prompt: &str, input: &mut impl BufRead) -> io::Result<bool> {
+    eprint!("{} [y/N] ", prompt);
+    io::stderr().flush()?;
+
+    read_yes(input)
+}
+
+/// Whether the answer read from `input` is `y` or `yes`, in any case
+fn read_yes(input: &mut impl BufRead) -> io::Result<bool> {
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+
+    Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+}
+
+/// Asks `prompt` before going ahead with an add, from `input` if there's a
+/// terminal to ask on. Without one the add is refused with `reason`, rather
+/// than reading an answer nobody typed.
+fn confirm_add(prompt: &str, reason: &str, input: Option<&mut impl BufRead>) -> Result<(), Error> {
+    let Some(input) = input else {
+        return Err(Error::Unconfirmed(reason.to_string()));
+    };
+
+    if confirm_on_stderr(prompt, input).map_err(Error::Prompt)? {
+        Ok(())
+    } else {
+        Err(Error::AddCancelled)
+    }
+}
+
+/// Asks before removing `count` expenses: with `prompt` when there are at most
+/// `threshold` of them, otherwise by having the count typed, so a reflexive
+/// `y` can't wipe out a datastore
+pub fn confirm_deletion(
+    prompt: &str,
+    count: usize,
+    threshold: usize,
+    input: &mut impl BufRead,
+) -> io::Result<bool> {
+    if count <= threshold {
+        return confirm(prompt, input);
+    }
+
+    ask(format_args!(
+        "{} This removes {} expenses, type {} to go ahead: ",
+        prompt, count, count
+    ))?;
+
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+
+    Ok(answer.trim() == count.to_string())
+}
+
+/// Asks for a new expense's description, amount, category and date in turn,
+/// asking again after an answer that isn't valid
+pub fn prompt_new_expense(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    currency: &str,
+) -> io::Result<NewExpense> {
+    let description = prompt_field(input, output, "Description", |answer| {
+        normalize_description(answer, false).map_err(|_| "The description must not be empty".into())
+    })?;
+
+    let amount = prompt_field(input, output, "Amount", |answer| {
+        let amount = parse_amount_or_expr(answer)?;
+        validate_amount(amount, false, currency).map_err(|error| error.to_string())?;
+        Ok(amount)
+    })?;
+
+    let category = prompt_field(input, output, "Category (optional)", |answer| {
+        Ok(Some(answer.to_string()).filter(|category| !category.is_empty()))
+    })?;
+
+    let date = prompt_field(
+        input,
+        output,
+        "Date (YYYY-MM-DD, empty for today)",
+        |answer| {
+            if answer.is_empty() {
+                return Ok(None);
+            }
+
+            NaiveDate::parse_from_str(answer, "%Y-%m-%d")
+                .map(Some)
+                .map_err(|_| format!("'{}' is not a date like 2024-01-31", answer))
+        },
+    )?;
+
+    Ok(NewExpense {
+        description,
+        amount,
+        date,
+        category,
+        note: None,
+        tags: Vec::new(),
+        allow_negative: false,
+        refund: false,
+        force: false,
+        random_id: false,
+        threshold: None,
+        // The category was just asked for, don't ask again
+        no_suggest: true,
+        allow_empty: false,
+        paid_in: None,
+        parse_vendor: false,
+        enforce: false,
+        receipt: None,
+        items: Vec::new(),
+        idempotency_key: None,
+    })
+}
+
+/// Asks for `label` until `parse` accepts the trimmed answer, printing why it didn't
+fn prompt_field<T>(
+    input: &mut impl BufRead,
+    output: &mut impl Write,
+    label: &str,
+    parse: impl Fn(&str) -> std::result::Result<T, String>,
+) -> io::Result<T> {
+    loop {
+        write!(output, "{}: ", label)?;
+        output.flush()?;
+
+        let mut answer = String::new();
+        if input.read_line(&mut answer)? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "input ended before the expense was complete",
+            ));
+        }
+
+        match parse(answer.trim()) {
+            Ok(value) => return Ok(value),
+            Err(reason) => writeln!(output, "{}", reason)?,
+        }
+    }
+}
+
+/// Asks a yes/no question on stderr like `add`'s others, treating an empty
+/// answer as a yes
+pub fn confirm_default_yes(prompt: &str, input: &mut impl BufRead) -> io::Result<bool> {
+    eprint!("{} [Y/n] ", prompt);
+    io::stderr().flush()?;
+
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+
+    Ok(matches!(
+        answer.trim().to_lowercase().as_str(),
+        "" | "y" | "yes"
+    ))
+}
+
+/// Lowercase words of a description worth matching on, leaving out short
+/// ones like "a" or "of"
+fn description_keywords(description: &str) -> HashSet<String> {
+    description
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.chars().count() >= 3)
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Category most used by earlier expenses sharing keywords with `description`,
+/// each of them counting once per shared keyword. Ties go to the category
+/// first in alphabetical order.
+pub fn suggest_category(description: &str, expenses: &[Expense]) -> Option<String> {
+    let keywords = description_keywords(description);
+    let mut scores: BTreeMap<&str, usize> = BTreeMap::new();
+
+    for expense in expenses {
+        let Some(category) = &expense.category else {
+            continue;
+        };
+
+        let overlap = description_keywords(&expense.description)
+            .intersection(&keywords)
+            .count();
+
+        if overlap > 0 {
+            *scores.entry(category).or_insert(0) += overlap;
+        }
+    }
+
+    scores
+        .into_iter()
+        .rev()
+        .max_by_key(|(_, score)| *score)
+        .map(|(category, _)| category.to_string())
+}
+
+/// Asks before adding an expense at or above the large expense `threshold`,
+/// see `confirm_add`. Smaller amounts, and any amount when there's no
+/// threshold, go ahead without asking.
+pub fn confirm_large_expense(
+    amount: i64,
+    threshold: Option<i64>,
+    currency: &str,
+    input: Option<&mut impl BufRead>,
+) -> Result<(), Error> {
+    let Some(threshold) = threshold.filter(|threshold| amount >= *threshold) else {
+        return Ok(());
+    };
+
+    let reason = format!(
+        "{} is at or above your large expense threshold of {}",
+        format_amount(amount, currency),
+        format_amount(threshold, currency)
+    );
+
+    if input.is_some() {
+        eprintln!("!!! {} !!!", reason);
+    }
+
+    confirm_add("Is this amount right?", &reason, input)
+}
+
+pub fn delete_expense(
+    ids: &[Id],
+    yes: bool,
+    soft: bool,
+    currency: &str,
+    dry_run: bool,
+    path: &str,
+) -> Result<(), Error> {
+    let _lock = lock_datastore(path).map_err(Error::Lock)?;
+    let mut datastore = read_datastore(path).map_err(Error::Read)?;
+
+    let deleted = delete_from_datastore(&mut datastore, ids, yes, soft, currency, dry_run)?;
+
+    if !deleted.is_empty() {
+        write_datastore(path, &datastore).map_err(Error::Write)?;
+        record_history(path, AuditAction::Delete, &deleted);
+        info!("Deleted {} expense(s) successfully", deleted.len());
+
+        if porcelain() {
+            let ids: Vec<&str> = deleted.iter().map(Id::as_str).collect();
+            println!("deleted id={} count={}", ids.join(","), deleted.len());
+        }
+    }
+
+    Ok(())
+}
+
+/// Removes the expenses with the given IDs from `datastore`, or with `soft`
+/// marks them as deleted, asking first unless `yes` is set. IDs are resolved
+/// with `resolve_id`, and expenses already soft-deleted count as missing.
+/// Returns the IDs of the deleted expenses.
+pub fn delete_from_datastore(
+    datastore: &mut Datastore,
+    ids: &[Id],
+    yes: bool,
+    soft: bool,
+    currency: &str,
+    dry_run: bool,
+) -> Result<Vec<Id>, Error> {
+    let expenses = &mut datastore.expenses;
+
+    let mut found = Vec::new();
+    let mut missing = Vec::new();
+
+    for id in ids {
+        match resolve_id(id, expenses).map_err(Error::AmbiguousId)? {
+            Some(index) if !found.contains(&expenses[index].id) => {
+                found.push(expenses[index].id.clone());
+            }
+            Some(_) => {}
+            None => missing.push(id.clone()),
+        }
+    }
+
+    let is_target = |expense: &Expense| found.contains(&expense.id) && !expense.is_deleted();
+
+    let targets: Vec<&Expense> = expenses
+        .iter()
+        .filter(|expense| is_target(expense))
+        .collect();
+
+    // Nothing to delete is a failure, so scripts can tell it apart from a deletion
+    if targets.is_empty() {
+        return Err(Error::ExpenseNotFound(missing));
+    }
+
+    for id in &missing {
+        info!("No expense found with ID: {}", id);
+    }
+
+    if dry_run {
+        for expense in &targets {
+            println!(
+                "Would delete expense with ID: {} ({})",
+                expense.id, expense.description
+            );
+        }
+        return Ok(Vec::new());
+    }
+
+    if !yes {
+        // Part of the question, so with `--porcelain` on stderr like it
+        for expense in &targets {
+            ask(format_args!(
+                "{}: {} ({})\n",
+                expense.id,
+                expense.description,
+                format_amount(expense.amount, currency)
+            ))
+            .map_err(Error::Prompt)?;
+        }
+
+        let prompt = if targets.len() == 1 {
+            "Delete this expense?"
+        } else {
+            "Delete these expenses?"
+        };
+
+        let confirmed = confirm_deletion(
+            prompt,
+            targets.len(),
+            large_delete_threshold(),
+            &mut io::stdin().lock(),
+        )
+        .map_err(Error::Prompt)?;
+
+        if !confirmed {
+            info!("Deletion cancelled");
+            return Ok(Vec::new());
+        }
+    }
+
+    if soft {
+        let now = Utc::now();
+
+        for expense in expenses.iter_mut().filter(|expense| is_target(expense)) {
+            expense.deleted_at = Some(now);
+        }
+    } else {
+        expenses.retain(|expense| !is_target(expense));
+    }
+
+    Ok(found)
+}
+
+/// Brings back soft-deleted expenses, saying which of `ids` aren't deleted
+pub fn restore_expenses(ids: &[Id], path: &str) -> Result<(), Error> {
+    let _lock = lock_datastore(path).map_err(Error::Lock)?;
+    let mut datastore = read_datastore(path).map_err(Error::Read)?;
+
+    let mut indices = Vec::new();
+    let mut missing = Vec::new();
+
+    for id in ids {
+        match resolve_any_id(id, &datastore.expenses).map_err(Error::AmbiguousId)? {
+            Some(index) => indices.push(index),
+            None => missing.push(id.clone()),
+        }
+    }
+
+    if missing.len() == ids.len() {
+        return Err(Error::ExpenseNotFound(missing));
+    }
+
+    for id in &missing {
+        info!("No expense found with ID: {}", id);
+    }
+
+    // In datastore order, each once however many prefixes named it
+    indices.sort_unstable();
+    indices.dedup();
+
+    let mut restored = Vec::new();
+
+    for index in indices {
+        let expense = &mut datastore.expenses[index];
+
+        if expense.deleted_at.take().is_some() {
+            restored.push(expense.id.clone());
+        } else {
+            info!("Expense with ID: {} isn't deleted", expense.id);
+        }
+    }
+
+    if !restored.is_empty() {
+        write_datastore(path, &datastore).map_err(Error::Write)?;
+        record_history(path, AuditAction::Restore, &restored);
+        info!("Restored {} expense(s) successfully", restored.len());
+
+        if porcelain() {
+            let ids: Vec<String> = restored.iter().map(Id::to_string).collect();
+            println!("restored id={} count={}", ids.join(","), restored.len());
+        }
+    }
+
+    Ok(())
+}
+
+/// Permanently removes the soft-deleted expenses, asking first unless `yes` is set
+pub fn purge_deleted(yes: bool, dry_run: bool, path: &str) -> Result<(), Error> {
+    let _lock = lock_datastore(path).map_err(Error::Lock)?;
+    let mut datastore = read_datastore(path).map_err(Error::Read)?;
+
+    let count = datastore
+        .expenses
+        .iter()
+        .filter(|expense| expense.is_deleted())
+        .count();
+
+    if count == 0 {
+        info!("No deleted expenses to purge");
+        return Ok(());
+    }
+
+    if dry_run {
+        println!("Would purge {} deleted expense(s)", count);
+        return Ok(());
+    }
+
+    let prompt = format!("Permanently remove {} deleted expense(s)?", count);
+    if !yes
+        && !confirm_deletion(
+            &prompt,
+            count,
+            large_delete_threshold(),
+            &mut io::stdin().lock(),
+        )
+        .map_err(Error::Prompt)?
+    {
+        info!("Purge cancelled");
+        return Ok(());
+    }
+
+    let (purged, kept): (Vec<Expense>, Vec<Expense>) = datastore
+        .expenses
+        .into_iter()
+        .partition(|expense| expense.is_deleted());
+    datastore.expenses = kept;
+    write_datastore(path, &datastore).map_err(Error::Write)?;
+
+    let ids: Vec<Id> = purged.into_iter().map(|expense| expense.id).collect();
+    record_history(path, AuditAction::Purge, &ids);
+    info!("Purged {} deleted expense(s)", count);
+
+    if porcelain() {
+        println!("purged count={}", count);
+    }
+
+    Ok(())
+}
+
+/// Fields of an expense to change, as given to `update`
+#[derive(clap::Args, Clone)]
+pub struct ExpenseUpdate {
+    /// Expense's new description
+    #[arg(short, long)]
+    pub description: Option<String>,
+
+    /// Expense's new amount, which can be arithmetic like `add --amount`
+    #[arg(short, long, value_parser = parse_amount_or_expr)]
+    pub amount: Option<f64>,
+
+    /// Accept a negative new amount
+    #[arg(long, requires = "amount")]
+    pub allow_negative: bool,
+
+    /// Expense's new note
+    #[arg(long)]
+    pub note: Option<String>,
+
+    /// Path or URL of the expense's receipt
+    #[arg(long)]
+    pub receipt: Option<String>,
+
+    /// Line item as `description=amount`, can be given multiple times;
+    /// replaces the expense's items and must add up to its amount
+    #[arg(long = "item", value_name = "DESCRIPTION=AMOUNT", value_parser = parse_line_item)]
+    pub items: Vec<NewLineItem>,
+}
+
+pub fn update_expense(
+    id: &Id,
+    update: ExpenseUpdate,
+    currency: &str,
+    path: &str,
+) -> Result<(), Error> {
+    let ExpenseUpdate {
+        description,
+        amount,
+        allow_negative,
+        note,
+        receipt,
+        items,
+    } = update;
+
+    if description.is_none()
+        && amount.is_none()
+        && note.is_none()
+        && receipt.is_none()
+        && items.is_empty()
+    {
+        info!("Nothing to update for expense with ID: {}", id);
+        return Ok(());
+    }
+
+    let _lock = lock_datastore(path).map_err(Error::Lock)?;
+    let mut datastore = read_datastore(path).map_err(Error::Read)?;
+
+    let Some(index) = resolve_id(id, &datastore.expenses).map_err(Error::AmbiguousId)? else {
+        info!("No expense found with ID: {}", id);
+        return Ok(());
+    };
+
+    let expense = &mut datastore.expenses[index];
+    let id = expense.id.clone();
+
+    if let Some(description) = description {
+        expense.description = description;
+    }
+
+    // A refund's new amount is positive, as with `add --refund`
+    if let Some(amount) = amount {
+        let refund = expense.kind == Kind::Refund;
+        let kind = if refund { Kind::Expense } else { expense.kind };
+
+        (expense.kind, expense.amount) = signed_amount(
+            kind,
+            amount,
+            refund,
+            allow_negative,
+            io::stdin().is_terminal().then_some(&mut io::stdin().lock()),
+            expense.currency.as_deref().unwrap_or(currency),
+        )?;
+    }
+
+    if let Some(note) = note {
+        expense.note = Some(note);
+    }
+
+    if let Some(receipt) = receipt {
+        warn_missing_receipt(&receipt);
+        expense.receipt = Some(receipt);
+    }
+
+    if amount.is_some() || !items.is_empty() {
+        let currency = expense.currency.as_deref().unwrap_or(currency);
+        if !items.is_empty() {
+            expense.items = to_line_items(&items, currency)?;
+        }
+        check_items(&expense.items, expense.amount.abs(), currency)?;
+    }
+
+    write_datastore(path, &datastore).map_err(Error::Write)?;
+    record_history(path, AuditAction::Update, std::slice::from_ref(&id));
+
+    info!("Expense with ID: '{}' updated successfully", id);
+
+    if porcelain() {
+        println!("updated id={}", id);
+    }
+
+    Ok(())
+}
+
+/// Whether a receipt is a URL, e.g. `https://...`, rather than a local path
+pub fn is_url(receipt: &str) -> bool {
+    receipt
+        .split_once("://")
+        .is_some_and(|(scheme, _)| !scheme.is_empty() && scheme.chars().all(char::is_alphanumeric))
+}
+
+/// Warns about a receipt path that doesn't exist, without refusing it: the
+/// file may be on a drive that isn't mounted right now
+fn warn_missing_receipt(receipt: &str) {
+    if !is_url(receipt) && !std::path::Path::new(receipt).exists() {
+        eprintln!("Warning: receipt '{}' doesn't exist", receipt);
+    }
+}
+
+/// Opens the receipt of an expense with the platform's opener, e.g. `xdg-open`
+pub fn open_receipt(id: &Id, path: &str) -> Result<(), Error> {
+    let expenses = read_expenses(path).map_err(Error::Read)?;
+
+    let Some(expense) = expenses.iter().find(|expense| expense.id == *id) else {
+        return Err(Error::ExpenseNotFound(vec![id.clone()]));
+    };
+
+    let Some(receipt) = &expense.receipt else {
+        return Err(Error::NoReceipt(id.clone()));
+    };
+
+    open::that(receipt).map_err(Error::Open)
+}
+
+fn print_expenses_table(expenses: &[Expense], currency: &str) {
+    print_table(&expenses_table(
+        expenses,
+        currency,
+        None,
+        true,
+        RowOptions::default(),
+    ));
+}
+
+/// Describes `date` relative to `now`, e.g. "today", "3 days ago" or "in 2 weeks"
+pub fn humanize_date(date: NaiveDate, now: NaiveDate) -> String {
+    let days = (now - date).num_days();
+
+    let (count, unit) = match days.abs() {
+        0 => return "today".to_string(),
+        1 if days > 0 => return "yesterday".to_string(),
+        1 => return "tomorrow".to_string(),
+        days @ 2..=6 => (days, "day"),
+        days @ 7..=29 => (days / 7, "week"),
+        days @ 30..=364 => (days / 30, "month"),
+        days => (days / 365, "year"),
+    };
+
+    let plural = if count == 1 { "" } else { "s" };
+
+    if days > 0 {
+        format!("{} {}{} ago", count, unit, plural)
+    } else {
+        format!("in {} {}{}", count, unit, plural)
+    }
+}
+
+/// Index of the amount column in `expense_rows`, the optional columns coming after it
+const AMOUNT_COLUMN: usize = 6;
+
+/// What goes in the rows of the standard expense listing besides the usual columns
+#[derive(Clone, Copy, Default)]
+pub struct RowOptions {
+    pub relative_dates: bool,
+    /// Add a column adding up the amounts so far
+    pub running_total: bool,
+    /// Add a column showing each expense as a percentage of this income
+    pub percent_of_income: Option<i64>,
+    /// Cut descriptions longer than this many characters short
+    pub max_description_width: Option<NonZeroUsize>,
+}
+
+/// Cuts `text` to at most `width` characters, ending it with an ellipsis when
+/// anything was cut. Counts characters rather than bytes, so multibyte ones
+/// like emoji are never split.
+pub fn truncate_chars(text: &str, width: NonZeroUsize) -> String {
+    let width = width.get();
+
+    if text.chars().count() <= width {
+        return text.to_string();
+    }
+
+    text.chars()
+        .take(width - 1)
+        .chain(std::iter::once('…'))
+        .collect()
+}
+
+/// `description` cut down to its first character, e.g. `C***` for `Coffee`.
+/// The stars don't follow its length, which would give it away.
+pub fn mask_description(description: &str) -> String {
+    match description.chars().next() {
+        Some(first) => format!("{}***", first),
+        None => String::new(),
+    }
+}
+
+/// `description`, masked when `--redact-descriptions` is set
+fn shown_description(description: &str) -> String {
+    if REDACT_DESCRIPTIONS.load(Ordering::Relaxed) {
+        mask_description(description)
+    } else {
+        description.to_string()
+    }
+}
+
+/// Cells of the standard expense listing: a header row, one row per expense
+/// and a total row per currency
+fn expense_rows(expenses: &[Expense], currency: &str, options: RowOptions) -> Vec<Vec<String>> {
+    let RowOptions {
+        relative_dates,
+        running_total,
+        percent_of_income,
+        max_description_width,
+    } = options;
+
+    let today = Local::now().date_naive();
+    // Amounts in different currencies don't add up, so each keeps its own
+    let mut running: BTreeMap<String, i64> = BTreeMap::new();
+
+    let mut rows = vec![[
+        "ID",
+        "Date",
+        "Description",
+        "Category",
+        "Tags",
+        "Kind",
+        "Amount",
+    ]
+    .map(String::from)
+    .to_vec()];
+
+    if running_total {
+        rows[0].push("Running total".to_string());
+    }
+    if percent_of_income.is_some() {
+        rows[0].push("% of income".to_string());
+    }
+
+    for expense in expenses {
+        let expense_currency = expense.currency.as_deref().unwrap_or(currency);
+        let description = shown_description(&expense.description);
+        let description = match max_description_width {
+            Some(width) => truncate_chars(&description, width),
+            None => description,
+        };
+
+        let mut row = vec![
+            expense.id.to_string(),
+            expense
+                .date
+                .map(|date| {
+                    if relative_dates {
+                        humanize_date(date, today)
+                    } else {
+                        format_date(date)
+                    }
+                })
+                .unwrap_or_else(|| "-".to_string()),
+            if expense.is_deleted() {
+                format!("{} (deleted)", description)
+            } else {
+                description
+            },
+            expense.category.clone().unwrap_or_else(|| "-".to_string()),
+            expense.tags.join(", "),
+            expense.kind.to_string(),
+            format_amount(expense.amount, expense_currency),
+        ];
+
+        // Adds up the way the total row does, so the last one matches it
+        if running_total {
+            let running = running.entry(expense_currency.to_uppercase()).or_default();
+            if !expense.is_income() {
+                *running += expense.amount;
+            }
+            row.push(format_amount(*running, expense_currency));
+        }
+
+        // Income is what the others are compared with
+        if let Some(income) = percent_of_income {
+            row.push(if expense.is_income() {
+                "-".to_string()
+            } else {
+                format_percent_of_income(expense.amount, income)
+            });
+        }
+
+        rows.push(row);
+    }
+
+    // Amounts in different currencies don't add up, so each gets a total row
+    let default_currency = currency.to_uppercase();
+    let mut totals: BTreeMap<String, i64> = BTreeMap::new();
+    for expense in expenses {
+        let code = expense
+            .currency
+            .as_deref()
+            .unwrap_or(currency)
+            .to_uppercase();
+        let total = totals.entry(code).or_default();
+        if !expense.is_income() {
+            *total += expense.amount;
+        }
+    }
+    if totals.is_empty() {
+        totals.insert(default_currency.clone(), 0);
+    }
+    let mixed = totals.len() > 1;
+
+    for (code, spent) in totals {
+        let label = if mixed {
+            format!("TOTAL {}", code)
+        } else {
+            "TOTAL".to_string()
+        };
+        let total = format_amount(spent, &code);
+        let mut total_row = ["", "", &label, "", "", "", &total]
+            .map(String::from)
+            .to_vec();
+
+        if running_total {
+            total_row.push(String::new());
+        }
+        // Income is in the datastore's currency
+        if let Some(income) = percent_of_income {
+            total_row.push(if code == default_currency {
+                format_percent_of_income(spent, income)
+            } else {
+                "-".to_string()
+            });
+        }
+
+        rows.push(total_row);
+    }
+
+    rows
+}
+
+/// Whether `column` of `expense_rows` holds numbers. They line up on their
+/// last digit, amounts all having the same number of decimals, so IDs and
+/// amounts align right.
+fn is_numeric_column(column: usize) -> bool {
+    column == 0 || column >= AMOUNT_COLUMN
+}
+
+/// Builds the standard expense table, ending with a total row per currency
+fn expenses_table(
+    expenses: &[Expense],
+    currency: &str,
+    highlight_above: Option<f64>,
+    header: bool,
+    options: RowOptions,
+) -> Table {
+    let rows = expense_rows(expenses, currency, options);
+
+    let mut table = Table::new();
+
+    for (index, row) in rows.iter().enumerate().skip(usize::from(!header)) {
+        // Row 0 is the header, rows 1..=len line up with the expenses
+        let expense = index.checked_sub(1).and_then(|index| expenses.get(index));
+        let refund = expense.is_some_and(|expense| expense.kind == Kind::Refund);
+        let highlighted = expense.is_some_and(|expense| {
+            highlight_above.is_some_and(|threshold| {
+                let currency = expense.currency.as_deref().unwrap_or(currency);
+                expense.amount > to_minor_units(threshold, currency)
+            })
+        });
+
+        let cells = row
+            .iter()
+            .enumerate()
+            .map(|(column, cell)| {
+                // Styles are prettytable specs, so column widths ignore them
+                let style = if index == 0 {
+                    "b"
+                } else if index > expenses.len() {
+                    "Fg"
+                } else if highlighted && column == AMOUNT_COLUMN {
+                    "Fr"
+                } else if refund {
+                    "Fc"
+                } else {
+                    ""
+                };
+
+                let alignment = if is_numeric_column(column) { "r" } else { "l" };
+
+                Cell::new(cell).style_spec(&format!("{}{}", style, alignment))
+            })
+            .collect();
+
+        if index == 0 {
+            table.set_titles(Row::new(cells));
+        } else {
+            table.add_row(Row::new(cells));
+        }
+    }
+
+    table
+}
+
+/// Renders expenses as tab-separated lines, header first
+pub fn render_plain(
+    expenses: &[Expense],
+    currency: &str,
+    header: bool,
+    options: RowOptions,
+) -> String {
+    expense_rows(expenses, currency, options)
+        .iter()
+        .skip(usize::from(!header))
+        .map(|row| row.join("\t") + "\n")
+        .collect()
+}
+
+/// Renders expenses as a GitHub-style pipe table with padded columns and the
+/// amounts right-aligned, leaving out the header and separator without `header`
+pub fn render_markdown(
+    expenses: &[Expense],
+    currency: &str,
+    header: bool,
+    options: RowOptions,
+) -> String {
+    let mut rows = expense_rows(expenses, currency, options);
+
+    if !header {
+        rows.remove(0);
+    }
+
+    // A `|` in a description would end its cell early
+    for cell in rows.iter_mut().flatten() {
+        *cell = cell.replace('|', "\\|");
+    }
+
+    // Markdown needs at least three dashes per separator cell
+    let mut widths = vec![3; rows[0].len()];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let render_row = |row: &Vec<String>| {
+        let cells: Vec<String> = row
+            .iter()
+            .enumerate()
+            .map(|(column, cell)| {
+                if is_numeric_column(column) {
+                    format!("{:>width$}", cell, width = widths[column])
+                } else {
+                    format!("{:<width$}", cell, width = widths[column])
+                }
+            })
+            .collect();
+
+        format!("| {} |\n", cells.join(" | "))
+    };
+
+    let separator: Vec<String> = widths
+        .iter()
+        .enumerate()
+        .map(|(column, width)| {
+            if is_numeric_column(column) {
+                format!("{}:", "-".repeat(width - 1))
+            } else {
+                "-".repeat(*width)
+            }
+        })
+        .collect();
+
+    let mut output = String::new();
+
+    for (index, row) in rows.iter().enumerate() {
+        output.push_str(&render_row(row));
+
+        if header && index == 0 {
+            output.push_str(&format!("| {} |\n", separator.join(" | ")));
+        }
+    }
+
+    output
+}
+
+pub fn sort_expenses(expenses: &mut [Expense], field: SortField, reverse: bool) {
+    match field {
+        SortField::Id => expenses.sort_by(|a, b| a.id.cmp(&b.id)),
+        SortField::Date => expenses.sort_by_key(|expense| expense.date),
+        SortField::Amount => expenses.sort_by_key(|expense| expense.amount),
+        SortField::Description => {
+            expenses.sort_by_cached_key(|expense| expense.description.to_lowercase())
+        }
+    }
+
+    if reverse {
+        expenses.reverse();
+    }
+}
+
+/// Swaps the datastore with its backup, so running it twice redoes the change
+pub fn undo(path: &str) -> Result<(), Error> {
+    let _lock = lock_datastore(path).map_err(Error::Lock)?;
+    let backup_path = backup_path(path);
+
+    let backup = match std::fs::read(&backup_path) {
+        Ok(backup) => backup,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+            return Err(Error::NoBackup(backup_path));
+        }
+        Err(error) => return Err(Error::Read(error)),
+    };
+    let current = std::fs::read(path).map_err(Error::Read)?;
+    // A corrupt datastore can still be undone, just not recorded
+    let before = read_datastore(path).ok();
+
+    write_atomically(path, &backup).map_err(Error::Write)?;
+    write_atomically(&backup_path, &current).map_err(Error::Write)?;
+    back_up_offsite(path);
+
+    let restored = read_datastore(path).map_err(Error::Read)?;
+    if let Some(before) = before {
+        record_changes(path, &before.expenses, &restored.expenses);
+    }
+    info!(
+        "Restored the previous datastore with {} expense(s), run `undo` again to redo",
+        restored.expenses.len()
+    );
+
+    Ok(())
+}
+
+/// Lists the rolling backups of the datastore, or with `backup` replaces the
+/// datastore with that one. The replaced datastore is itself backed up, so a
+/// restore can be undone.
+pub fn restore_backup(backup: Option<&str>, path: &str) -> Result<(), Error> {
+    let backups = list_backups(path).map_err(Error::Read)?;
+
+    let Some(backup) = backup else {
+        if backups.is_empty() {
+            info!("No backups of '{}' yet", path);
+        }
+
+        for timestamp in &backups {
+            println!("{}", timestamp);
+        }
+
+        return Ok(());
+    };
+
+    // Take either the timestamp `restore` lists or the backup's file name
+    let Some(timestamp) = backups.iter().find(|timestamp| {
+        backup == timestamp.as_str() || backup.ends_with(&format!(".{}.bak", timestamp))
+    }) else {
+        return Err(Error::BackupNotFound(backup.to_string()));
+    };
+
+    let _lock = lock_datastore(path).map_err(Error::Lock)?;
+
+    // Backups are byte copies in the datastore's own format, so restore them as such
+    let data = std::fs::read(timestamped_backup_path(path, timestamp)).map_err(Error::Read)?;
+    let before = read_datastore(path).ok();
+    replace_datastore(path, &data).map_err(Error::Write)?;
+    back_up_offsite(path);
+
+    let restored = read_datastore(path).map_err(Error::Read)?;
+    if let Some(before) = before {
+        record_changes(path, &before.expenses, &restored.expenses);
+    }
+    info!(
+        "Restored the backup from {} with {} expense(s)",
+        timestamp,
+        restored.expenses.len()
+    );
+
+    Ok(())
+}
+
+/// Counts how many times each label occurs, most used first and ties sorted by
+/// name. Labels are compared exactly, so near-duplicates like "food" and
+/// "Food" show up side by side.
+pub fn count_labels<'a>(labels: impl IntoIterator<Item = &'a str>) -> Vec<(String, usize)> {
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+
+    for label in labels {
+        *counts.entry(label).or_default() += 1;
+    }
+
+    let mut counts: Vec<(String, usize)> = counts
+        .into_iter()
+        .map(|(label, count)| (label.to_string(), count))
+        .collect();
+
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    counts
+}
+
+/// Prints the categories and tags in use with the number of expenses using each
+pub fn list_labels(path: &str) -> Result<(), Error> {
+    let expenses = read_expenses(path).map_err(Error::Read)?;
+
+    let categories = count_labels(expenses.iter().filter_map(|e| e.category.as_deref()));
+    let tags = count_labels(
+        expenses
+            .iter()
+            .flat_map(|e| e.tags.iter().map(String::as_str)),
+    );
+
+    if categories.is_empty() && tags.is_empty() {
+        println!("No categories or tags in use");
+        return Ok(());
+    }
+
+    for (title, labels) in [("Categories", categories), ("Tags", tags)] {
+        if labels.is_empty() {
+            continue;
+        }
+
+        println!("{}:", title);
+
+        for (label, count) in labels {
+            println!("  {}: {}", label, count);
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves every expense of the category `from`, matched case-insensitively, to `to`
+pub fn rename_category(from: &str, to: &str, path: &str) -> Result<(), Error> {
+    let _lock = lock_datastore(path).map_err(Error::Lock)?;
+    let mut datastore = read_datastore(path).map_err(Error::Read)?;
+
+    let mut renamed = Vec::new();
+
+    for expense in &mut datastore.expenses {
+        if let Some(category) = &mut expense.category {
+            if category.eq_ignore_ascii_case(from) {
+                *category = to.to_string();
+                renamed.push(expense.id.clone());
+            }
+        }
+    }
+
+    if renamed.is_empty() {
+        return Err(Error::CategoryNotFound(from.to_string()));
+    }
+
+    write_datastore(path, &datastore).map_err(Error::Write)?;
+    record_history(path, AuditAction::Update, &renamed);
+
+    info!(
+        "Moved {} expense(s) from '{}' to '{}'",
+        renamed.len(),
+        from,
+        to
+    );
+
+    Ok(())
+}
+
+/// Text to look for in `replace`, taken literally or as a regular expression
+pub enum Pattern {
+    Literal(String),
+    Regex(regex::Regex),
+}
+
+impl Pattern {
+    pub fn new(pattern: &str, regex: bool) -> Result<Pattern, Error> {
+        let invalid = |reason: String| Error::InvalidPattern {
+            pattern: pattern.to_string(),
+            reason,
+        };
+
+        // An empty pattern matches between every character
+        if pattern.is_empty() {
+            return Err(invalid("the text to find must not be empty".to_string()));
+        }
+
+        if regex {
+            // Syntax errors draw the pattern with a caret above the reason,
+            // keep only the reason so the error stays on one line
+            regex::Regex::new(pattern)
+                .map(Pattern::Regex)
+                .map_err(|error| {
+                    let message = error.to_string();
+                    let reason = message.lines().last().unwrap_or_default();
+                    invalid(reason.trim_start_matches("error: ").to_string())
+                })
+        } else {
+            Ok(Pattern::Literal(pattern.to_string()))
+        }
+    }
+}
+
+/// Replaces every match of `pattern` in `text` with `to`, where a regex
+/// replacement can refer to groups as `$1` or `${name}`. Returns `None` when
+/// nothing matched.
+pub fn replace_text(text: &str, pattern: &Pattern, to: &str) -> Option<String> {
+    match pattern {
+        Pattern::Literal(from) if text.contains(from.as_str()) => Some(text.replace(from, to)),
+        Pattern::Regex(regex) if regex.is_match(text) => {
+            Some(regex.replace_all(text, to).into_owned())
+        }
+        _ => None,
+    }
+}
+
+/// Replaces `pattern` with `to` in the description of every expense,
+/// returning how many descriptions changed
+pub fn replace_descriptions(expenses: &mut [Expense], pattern: &Pattern, to: &str) -> usize {
+    let mut changed = 0;
+
+    for expense in expenses {
+        if let Some(description) = replace_text(&expense.description, pattern, to) {
+            if description != expense.description {
+                expense.description = description;
+                changed += 1;
+            }
+        }
+    }
+
+    changed
+}
+
+pub fn replace_in_descriptions(from: &str, to: &str, regex: bool, path: &str) -> Result<(), Error> {
+    let pattern = Pattern::new(from, regex)?;
+
+    let _lock = lock_datastore(path).map_err(Error::Lock)?;
+    let mut datastore = read_datastore(path).map_err(Error::Read)?;
+
+    let before = datastore.expenses.clone();
+    let changed = replace_descriptions(&mut datastore.expenses, &pattern, to);
+
+    if changed > 0 {
+        write_datastore(path, &datastore).map_err(Error::Write)?;
+        record_changes(path, &before, &datastore.expenses);
+    }
+
+    info!("Changed the description of {} expense(s)", changed);
+
+    Ok(())
+}
+
+/// Opens the datastore in the user's editor, putting the previous contents
+/// back if the edited file no longer parses
+pub fn edit_datastore(path: &str) -> Result<(), Error> {
+    let editor = ["VISUAL", "EDITOR"]
+        .iter()
+        .filter_map(std::env::var_os)
+        .map(|editor| editor.to_string_lossy().into_owned())
+        .find(|editor| !editor.trim().is_empty())
+        .ok_or(Error::NoEditor)?;
+
+    if Format::from_path(path) == Format::Sqlite {
+        return Err(Error::Editor(io::Error::other(format!(
+            "'{}' is a SQLite database, open it with `sqlite3` instead",
+            path
+        ))));
+    }
+
+    let _lock = lock_datastore(path).map_err(Error::Lock)?;
+    let original = std::fs::read(path).map_err(Error::Read)?;
+    let before = read_datastore(path).ok();
+
+    // Editors such as `code --wait` come with arguments
+    let mut words = editor.split_whitespace();
+    let program = words.next().unwrap_or_default();
+
+    let status = std::process::Command::new(program)
+        .args(words)
+        .arg(path)
+        .status()
+        .map_err(Error::Editor);
+
+    let result = match status {
+        Ok(status) if status.success() => read_datastore(path).map_err(|source| {
+            let saved = format!("{}.rejected", path);
+            match std::fs::copy(path, &saved) {
+                Ok(_) => Error::EditRejected { saved, source },
+                Err(error) => Error::Editor(error),
+            }
+        }),
+        Ok(status) => Err(Error::Editor(io::Error::other(format!(
+            "editor exited with {}",
+            status
+        )))),
+        Err(error) => Err(error),
+    };
+
+    let datastore = match result {
+        Ok(datastore) => datastore,
+        Err(error) => {
+            write_atomically(path, &original).map_err(Error::Write)?;
+            return Err(error);
+        }
+    };
+
+    if std::fs::read(path).map_err(Error::Read)? == original {
+        info!("No changes made");
+        return Ok(());
+    }
+
+    // Keep the pre-edit contents around for `undo`
+    write_atomically(&backup_path(path), &original).map_err(Error::Write)?;
+    if let Some(before) = before {
+        record_changes(path, &before.expenses, &datastore.expenses);
+    }
+
+    info!(
+        "Datastore updated, it now has {} expense(s)",
+        datastore.expenses.len()
+    );
+
+    Ok(())
+}
+
+/// Copies the datastore and its settings to `to`, in the format its extension
+/// picks, e.g. to move a JSON datastore to SQLite
+pub fn migrate_datastore(to: &str, path: &str) -> Result<(), Error> {
+    if std::path::Path::new(to).exists() {
+        return Err(Error::Write(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("'{}' already exists, not overwriting it", to),
+        )));
+    }
+
+    let _lock = lock_datastore(path).map_err(Error::Lock)?;
+    let datastore = read_datastore(path).map_err(Error::Read)?;
+
+    write_datastore(to, &datastore).map_err(Error::Write)?;
+
+    if settings_path(path).exists() {
+        let settings = read_settings(path).map_err(Error::Settings)?;
+        write_settings(to, &settings).map_err(Error::Settings)?;
+    }
+
+    info!(
+        "Copied {} expense(s) to the {} datastore '{}'",
+        datastore.expenses.len(),
+        Format::from_path(to).name(),
+        to
+    );
+
+    Ok(())
+}
+
+/// Divides the expense `id` among `ways` people, keeping this share rounded to
+/// the cent and marking the split in its description. Returns the amount before
+/// the split. An expense already marked isn't split again, its amount being a
+/// share already.
+pub fn split_expense(expenses: &mut [Expense], id: &Id, ways: NonZeroU32) -> Result<i64, Error> {
+    let Some(expense) = expenses
+        .iter_mut()
+        .find(|expense| expense.id == *id && !expense.is_deleted())
+    else {
+        return Err(Error::ExpenseNotFound(vec![id.clone()]));
+    };
+
+    if is_split(&expense.description) {
+        return Err(Error::AlreadySplit(id.clone()));
+    }
+
+    let original = expense.amount;
+
+    expense.amount = (original as f64 / ways.get() as f64).round() as i64;
+    expense.description = format!("{} (split {} ways)", expense.description, ways);
+
+    Ok(original)
+}
+
+/// Whether `description` ends with the "(split N ways)" `split_expense` adds
+fn is_split(description: &str) -> bool {
+    description
+        .strip_suffix(" ways)")
+        .and_then(|rest| rest.rsplit_once(" (split "))
+        .is_some_and(|(_, ways)| !ways.is_empty() && ways.bytes().all(|b| b.is_ascii_digit()))
+}
+
+pub fn split_expense_command(
+    id: &Id,
+    ways: NonZeroU32,
+    currency: &str,
+    path: &str,
+) -> Result<(), Error> {
+    let _lock = lock_datastore(path).map_err(Error::Lock)?;
+    let mut datastore = read_datastore(path).map_err(Error::Read)?;
+
+    let original = split_expense(&mut datastore.expenses, id, ways)?;
+
+    write_datastore(path, &datastore).map_err(Error::Write)?;
+    record_history(path, AuditAction::Update, std::slice::from_ref(id));
+
+    let share = datastore
+        .expenses
+        .iter()
+        .find(|expense| expense.id == *id)
+        .map_or(0, |expense| expense.amount);
+
+    info!(
+        "Expense with ID: {} split {} ways, your share is {}",
+        id,
+        ways,
+        format_amount(share, currency)
+    );
+
+    let recombined = share * i64::from(ways.get());
+    if recombined != original {
+        eprintln!(
+            "Warning: {} shares of {} add up to {}, not {}",
+            ways,
+            format_amount(share, currency),
+            format_amount(recombined, currency),
+            format_amount(original, currency)
+        );
+    }
+
+    Ok(())
+}
+
+/// An interactive session working on an in-memory copy of the datastore,
+/// written back by `save`. The datastore stays locked for the whole session
+/// so other processes can't change it underneath.
+pub struct Session {
+    path: String,
+    datastore: Datastore,
+    dirty: bool,
+    /// Changes to add to the history once they're saved
+    changes: Vec<(AuditAction, Id)>,
+    _lock: DatastoreLock,
+}
+
+impl Session {
+    pub fn open(path: &str) -> Result<Session, Error> {
+        let lock = lock_datastore(path).map_err(Error::Lock)?;
+        let datastore = read_datastore(path).map_err(Error::Read)?;
+
+        Ok(Session {
+            path: path.to_string(),
+            datastore,
+            dirty: false,
+            changes: Vec::new(),
+            _lock: lock,
+        })
+    }
+
+    /// Whether there are changes that haven't been saved yet
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn add(&mut self, new: &NewExpense, kind: Kind, currency: &str) -> Result<(), Error> {
+        let quotas = read_settings(&self.path).map_err(Error::Settings)?.quotas;
+
+        if let Some(id) =
+            add_to_datastore(&mut self.datastore, new, kind, &quotas, currency, false)?
+        {
+            self.dirty = true;
+            self.changes.push((AuditAction::Add, id.clone()));
+            warn_if_over_budget(&self.datastore, &id, currency, &self.path)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn delete(
+        &mut self,
+        ids: &[Id],
+        yes: bool,
+        soft: bool,
+        currency: &str,
+    ) -> Result<(), Error> {
+        let deleted = delete_from_datastore(&mut self.datastore, ids, yes, soft, currency, false)?;
+
+        if !deleted.is_empty() {
+            self.dirty = true;
+            info!("Deleted {} expense(s) successfully", deleted.len());
+            self.changes
+                .extend(deleted.into_iter().map(|id| (AuditAction::Delete, id)));
+        }
+
+        Ok(())
+    }
+
+    pub fn list(
+        &self,
+        filter: &ExpenseFilter,
+        options: &ListOptions,
+        currency: &str,
+    ) -> Result<(), Error> {
+        print_expense_list(self.datastore.expenses.clone(), filter, options, currency)?;
+
+        Ok(())
+    }
+
+    pub fn save(&mut self) -> Result<(), Error> {
+        write_datastore(&self.path, &self.datastore).map_err(Error::Write)?;
+        self.dirty = false;
+
+        for (action, id) in std::mem::take(&mut self.changes) {
+            record_history(&self.path, action, &[id]);
+        }
+
+        info!("Saved {} expense(s)", self.datastore.expenses.len());
+
+        Ok(())
+    }
+}
+
+/// Renumbers the expenses 1, 2, 3... in their current order, random IDs included
+pub fn resequence_ids(expenses: &mut [Expense]) {
+    for (id, expense) in (1..).zip(expenses.iter_mut()) {
+        expense.id = Id::from(id);
+    }
+}
+
+pub fn compact_ids(yes: bool, path: &str) -> Result<(), Error> {
+    let _lock = lock_datastore(path).map_err(Error::Lock)?;
+    let mut datastore = read_datastore(path).map_err(Error::Read)?;
+
+    let count = datastore.expenses.len();
+    let already_compact = (1..)
+        .zip(&datastore.expenses)
+        .all(|(id, expense)| expense.id == Id::from(id));
+
+    if already_compact {
+        info!("IDs are already sequential");
+        return Ok(());
+    }
+
+    if !yes {
+        let prompt = format!(
+            "This changes the IDs of the expenses, renumbering them 1 to {}. Continue?",
+            count
+        );
+
+        if !confirm(&prompt, &mut io::stdin().lock()).map_err(Error::Prompt)? {
+            info!("Compact cancelled");
+            return Ok(());
+        }
+    }
+
+    let before: Vec<Id> = datastore.expenses.iter().map(|e| e.id.clone()).collect();
+    resequence_ids(&mut datastore.expenses);
+    // Unlike deletions, compacting hands the freed IDs out again on purpose
+    datastore.next_id = u32::try_from(count).map_or(u32::MAX, |count| count + 1);
+
+    write_datastore(path, &datastore).map_err(Error::Write)?;
+
+    let renumbered: Vec<(Id, Id)> = before
+        .into_iter()
+        .zip(datastore.expenses.iter().map(|e| e.id.clone()))
+        .filter(|(from, to)| from != to)
+        .collect();
+    record_renumbering(path, &renumbered);
+
+    info!("Renumbered {} expense(s)", count);
+
+    Ok(())
+}
+
+pub fn clear_expenses(yes: bool, dry_run: bool, path: &str) -> Result<(), Error> {
+    // A corrupt datastore can still be cleared, that's the way out we suggest for it
+    let _lock = lock_datastore(path).map_err(Error::Lock)?;
+    let mut datastore = match read_datastore(path) {
+        Ok(datastore) => Some(datastore),
+        Err(error) if error.kind() == io::ErrorKind::InvalidData => None,
+        Err(error) => return Err(Error::Read(error)),
+    };
+
+    let count = datastore.as_ref().map(|datastore| datastore.expenses.len());
+
+    if count == Some(0) {
+        info!("No expenses to clear");
+        return Ok(());
+    }
+
+    if dry_run {
+        match count {
+            Some(count) => info!("Would clear {} expense(s)", count),
+            None => info!("Would reset the corrupt datastore"),
+        }
+        return Ok(());
+    }
+
+    if !yes {
+        let prompt = match count {
+            Some(count) => format!("Delete all {} expense(s)?", count),
+            None => "The datastore is corrupt. Reset it to an empty list?".to_string(),
+        };
+
+        let confirmed = confirm_deletion(
+            &prompt,
+            count.unwrap_or(0),
+            large_delete_threshold(),
+            &mut io::stdin().lock(),
+        )
+        .map_err(Error::Prompt)?;
+
+        if !confirmed {
+            info!("Clear cancelled");
+            return Ok(());
+        }
+    }
+
+    // Keep the ID counter so cleared IDs aren't handed out again
+    let mut cleared = Vec::new();
+    let datastore = match datastore.as_mut() {
+        Some(datastore) => {
+            cleared = std::mem::take(&mut datastore.expenses);
+            datastore
+        }
+        None => &Datastore::default(),
+    };
+
+    write_datastore(path, datastore).map_err(Error::Write)?;
+    record_changes(path, &cleared, &[]);
+
+    match count {
+        Some(count) => info!("Cleared {} expense(s) successfully", count),
+        None => info!("Datastore reset successfully"),
+    }
+
+    Ok(())
+}
+
+/// Controls how the listed expenses are ordered, paginated and rendered
+#[derive(clap::Args, Clone)]
+pub struct ListOptions {
+    /// Field to sort expenses by [default: id]
+    #[arg(short, long, value_enum)]
+    pub sort: Option<SortField>,
+
+    /// Reverse the sort order
+    #[arg(short, long)]
+    pub reverse: bool,
+
+    /// Print expenses as JSON instead of a table
+    #[arg(long, conflicts_with = "redact")]
+    pub json: bool,
+
+    /// Indent the JSON, the default when printing to a terminal
+    #[arg(long, requires = "json")]
+    pub pretty: bool,
+
+    /// Print the JSON on one line, the default when piped
+    #[arg(long, requires = "json", conflicts_with = "pretty")]
+    pub compact: bool,
+
+    /// Show amounts above this one in red
+    #[arg(long, value_parser = parse_amount)]
+    pub highlight_above: Option<f64>,
+
+    /// How to lay out the expenses
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OutputFormat::Table,
+        conflicts_with = "json"
+    )]
+    pub output_format: OutputFormat,
+
+    /// Show dates as "today", "3 days ago" and so on
+    #[arg(long)]
+    pub relative_dates: bool,
+
+    /// Leave out the header row, e.g. for `awk` or `cut`; the total row stays
+    #[arg(long, conflicts_with = "json")]
+    pub no_header: bool,
+
+    /// Add a column adding up the amounts so far, in the order shown
+    #[arg(long, conflicts_with = "json")]
+    pub running_total: bool,
+
+    /// Add a column showing each expense as a percentage of the income
+    /// recorded between --since and --until
+    #[arg(long, conflicts_with = "json")]
+    pub as_pct_of_income: bool,
+
+    /// Cut descriptions longer than this many characters short with an
+    /// ellipsis; `show` still prints them in full
+    #[arg(long, conflicts_with = "json")]
+    pub max_description_width: Option<NonZeroUsize>,
+
+    /// Show at most this many expenses
+    #[arg(long)]
+    pub limit: Option<usize>,
+
+    /// Skip this many expenses before showing any
+    #[arg(long)]
+    pub offset: Option<usize>,
+
+    /// Show only the most recently added expenses, newest first [default: 10]
+    #[arg(
+        long,
+        num_args = 0..=1,
+        default_missing_value = "10",
+        conflicts_with_all = ["sort", "reverse", "limit", "offset"]
+    )]
+    pub tail: Option<usize>,
+
+    /// Show the list through $PAGER, by default `less -R`, even when it fits
+    /// on the screen
+    #[arg(long, conflicts_with = "json")]
+    pub pager: bool,
+
+    /// Print the list directly, even when it's taller than the screen
+    #[arg(long, conflicts_with = "pager")]
+    pub no_pager: bool,
+
+    #[command(flatten)]
+    pub redaction: Redaction,
+}
+
+impl ExpenseFilter {
+    fn validate(&self) -> Result<(), Error> {
+        if let (Some(min), Some(max)) = (self.min, self.max) {
+            if min > max {
+                return Err(Error::InvalidFilter(format!(
+                    "--min {} is greater than --max {}",
+                    min, max
+                )));
+            }
+        }
+
+        if let (Some(since), Some(until)) = (self.since, self.until) {
+            if since > until {
+                return Err(Error::InvalidFilter(format!(
+                    "--since {} is after --until {}",
+                    since, until
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The criteria that were given, e.g. `category = food`, for `--explain`
+    pub fn describe(&self) -> Vec<String> {
+        let mut criteria = Vec::new();
+
+        if let Some(category) = &self.category {
+            criteria.push(format!("category = {}", category));
+        }
+        if let Some(min) = self.min {
+            criteria.push(format!("min = {}", min));
+        }
+        if let Some(max) = self.max {
+            criteria.push(format!("max = {}", max));
+        }
+        if !self.tags.is_empty() {
+            criteria.push(format!("tag = {}", self.tags.join(" or ")));
+        }
+        if let Some(since) = self.since {
+            criteria.push(format!("since = {}", since));
+        }
+        if let Some(until) = self.until {
+            criteria.push(format!("until = {}", until));
+        }
+        if let Some(expr) = &self.filter {
+            criteria.push(format!("filter = {}", expr));
+        }
+        if self.include_deleted {
+            criteria.push("including deleted".to_string());
+        }
+
+        criteria
+    }
+
+    fn has_range(&self) -> bool {
+        self.min.is_some() || self.max.is_some() || self.since.is_some() || self.until.is_some()
+    }
+
+    /// Whether `expense` is one of those asked for, those without a currency
+    /// being in `currency`
+    fn matches(&self, expense: &Expense, currency: &str) -> bool {
+        if expense.is_deleted() && !self.include_deleted {
+            return false;
+        }
+
+        if let Some(category) = &self.category {
+            let same_category = expense
+                .category
+                .as_deref()
+                .is_some_and(|c| c.eq_ignore_ascii_case(category));
+
+            if !same_category {
+                return false;
+            }
+        }
+
+        let has_any_tag = self
+            .tags
+            .iter()
+            .any(|tag| expense.tags.iter().any(|t| t.eq_ignore_ascii_case(tag)));
+
+        if !self.tags.is_empty() && !has_any_tag {
+            return false;
+        }
+
+        if self.since.is_some() || self.until.is_some() {
+            let Some(date) = expense.date else {
+                return false;
+            };
+
+            if self.since.is_some_and(|since| date < since)
+                || self.until.is_some_and(|until| date > until)
+            {
+                return false;
+            }
+        }
+
+        let currency = expense.currency.as_deref().unwrap_or(currency);
+
+        self.min
+            .is_none_or(|min| expense.amount >= to_minor_units(min, currency))
+            && self
+                .max
+                .is_none_or(|max| expense.amount <= to_minor_units(max, currency))
+            && self
+                .filter
+                .as_ref()
+                .is_none_or(|expr| expr.matches(expense, currency))
+    }
+}
+
+/// How long the datastore has to stay untouched before `--watch` re-renders,
+/// so a save that takes several writes only renders once
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(200);
+
+enum WatchEvent {
+    Changed,
+    Interrupted,
+}
+
+/// Lists expenses, then lists them again on a cleared screen every time the
+/// datastore changes, until Ctrl-C
+pub fn watch_expenses(
+    filter: &ExpenseFilter,
+    options: &ListOptions,
+    currency: &str,
+    path: &str,
+) -> Result<(), Error> {
+    let datastore = std::path::Path::new(path);
+    let file_name = datastore.file_name().map(|name| name.to_os_string());
+
+    // Writes replace the file, so watch its directory rather than the file itself
+    let dir = match datastore.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => std::path::Path::new("."),
+    };
+
+    let (sender, receiver) = mpsc::channel();
+
+    let interrupt = sender.clone();
+    ctrlc::set_handler(move || {
+        let _ = interrupt.send(WatchEvent::Interrupted);
+    })
+    .map_err(io::Error::other)
+    .map_err(Error::Watch)?;
+
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        let Ok(event) = event else {
+            return;
+        };
+
+        let touches_datastore = event
+            .paths
+            .iter()
+            .any(|changed| changed.file_name().map(|name| name.to_os_string()) == file_name);
+
+        if touches_datastore && !event.kind.is_access() {
+            let _ = sender.send(WatchEvent::Changed);
+        }
+    })
+    .map_err(io::Error::other)
+    .map_err(Error::Watch)?;
+
+    notify::Watcher::watch(&mut watcher, dir, notify::RecursiveMode::NonRecursive)
+        .map_err(io::Error::other)
+        .map_err(Error::Watch)?;
+
+    // A pager would hold up the redraws
+    let options = &ListOptions {
+        pager: false,
+        no_pager: true,
+        ..options.clone()
+    };
+
+    loop {
+        // Clear the screen and move the cursor home
+        print!("\x1B[2J\x1B[H");
+
+        // The file may be half-written or mid-edit, so keep watching through errors
+        if let Err(error) = list_expenses(filter, options, currency, path) {
+            eprintln!("Error: {}", error);
+        }
+
+        let _ = io::stdout().flush();
+
+        let Ok(WatchEvent::Changed) = receiver.recv() else {
+            return Ok(());
+        };
+
+        loop {
+            match receiver.recv_timeout(WATCH_DEBOUNCE) {
+                Ok(WatchEvent::Changed) => continue,
+                Ok(WatchEvent::Interrupted) => return Ok(()),
+                Err(mpsc::RecvTimeoutError::Timeout) => break,
+                Err(mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Lists the matching expenses, returning how many were shown
+pub fn list_expenses(
+    filter: &ExpenseFilter,
+    options: &ListOptions,
+    currency: &str,
+    path: &str,
+) -> Result<usize, Error> {
+    // Showing the first few in file order doesn't need the rest of the file
+    let streamable = Format::from_path(path) == Format::Jsonl
+        && options.sort.is_none()
+        && !options.reverse
+        && options.tail.is_none()
+        && !options.as_pct_of_income;
+
+    if let (true, Some(limit)) = (streamable, options.limit) {
+        filter.validate()?;
+
+        let offset = options.offset.unwrap_or(0);
+        // One past the limit tells whether there are more, without counting them
+        let mut expenses = stream_expenses(path, filter, currency, offset, limit.saturating_add(1))
+            .map_err(Error::Read)?;
+        let more = expenses.len() > limit;
+        expenses.truncate(limit);
+
+        let full_page = expenses.len() == limit;
+        if render_expense_list(&expenses, filter, options, None, currency)? && full_page {
+            let matched = offset + limit + usize::from(more);
+            println!("{}", showing_line(limit, matched, more));
+        }
+
+        return Ok(expenses.len());
+    }
+
+    // The filter hides soft-deleted expenses unless asked not to
+    let expenses = read_all_expenses(path).map_err(Error::Read)?;
+
+    print_expense_list(expenses, filter, options, currency)
+}
+
+/// Filters, orders and prints `expenses` the way `list` does, returning how
+/// many were shown
+pub fn print_expense_list(
+    mut expenses: Vec<Expense>,
+    filter: &ExpenseFilter,
+    options: &ListOptions,
+    currency: &str,
+) -> Result<usize, Error> {
+    filter.validate()?;
+
+    // The income of the period, whatever else the filter narrows the list to
+    let income = options.as_pct_of_income.then(|| {
+        let in_period: Vec<Expense> = expenses
+            .iter()
+            .filter(|expense| !expense.is_deleted())
+            .filter(|expense| match (filter.since, filter.until) {
+                (None, None) => true,
+                (since, until) => expense.date.is_some_and(|date| {
+                    since.is_none_or(|since| date >= since)
+                        && until.is_none_or(|until| date <= until)
+                }),
+            })
+            .cloned()
+            .collect();
+        total_income(&in_period)
+    });
+
+    expenses.retain(|expense| filter.matches(expense, currency));
+
+    let matched = expenses.len();
+    let paginated = options.limit.is_some() || options.offset.is_some() || options.tail.is_some();
+
+    // Expenses are appended as they're added, random IDs saying nothing about order
+    let expenses: Vec<Expense> = if let Some(count) = options.tail {
+        expenses.into_iter().rev().take(count).collect()
+    } else {
+        sort_expenses(
+            &mut expenses,
+            options.sort.unwrap_or(SortField::Id),
+            options.reverse,
+        );
+        expenses
+            .into_iter()
+            .skip(options.offset.unwrap_or(0))
+            .take(options.limit.unwrap_or(usize::MAX))
+            .collect()
+    };
+
+    if render_expense_list(&expenses, filter, options, income, currency)? && paginated {
+        println!("{}", showing_line(expenses.len(), matched, false));
+    }
+
+    Ok(expenses.len())
+}
+
+/// The line under a page of `list`: `shown` of the `matched` expenses, or of
+/// `at_least` that many when the rest weren't counted
+fn showing_line(shown: usize, matched: usize, at_least: bool) -> String {
+    format!(
+        "Showing {} of {}{} {}",
+        shown,
+        if at_least { "at least " } else { "" },
+        matched,
+        if matched == 1 { "expense" } else { "expenses" }
+    )
+}
+
+/// Prints already selected expenses in the layout `options` asks for, with
+/// the `income` they're a percentage of if asked, returning whether a list was
+/// printed that a "Showing" line can follow
+fn render_expense_list(
+    expenses: &[Expense],
+    filter: &ExpenseFilter,
+    options: &ListOptions,
+    income: Option<i64>,
+    currency: &str,
+) -> Result<bool, Error> {
+    if options.json {
+        let pretty = JsonLayout::from_flags(options.pretty, options.compact)
+            .is_pretty(io::stdout().is_terminal());
+        let data = to_json(expenses, pretty).map_err(Error::Serialize)?;
+        println!("{}", data);
+        return Ok(false);
+    }
+
+    if expenses.is_empty() {
+        if filter.has_range() {
+            println!("No expenses in that range");
+        } else {
+            println!("No expenses found");
+        }
+        return Ok(false);
+    }
+
+    let rows = RowOptions {
+        relative_dates: options.relative_dates,
+        running_total: options.running_total,
+        percent_of_income: income,
+        max_description_width: options.max_description_width,
+    };
+
+    let table = matches!(options.output_format, OutputFormat::Table).then(|| {
+        expenses_table(
+            expenses,
+            currency,
+            options.highlight_above,
+            !options.no_header,
+            rows,
+        )
+    });
+
+    let rendered = match (&table, options.output_format) {
+        (Some(table), _) => render_table(table),
+        (None, OutputFormat::Markdown) => {
+            render_markdown(expenses, currency, !options.no_header, rows)
+        }
+        (None, _) => render_plain(expenses, currency, !options.no_header, rows),
+    };
+
+    if wants_pager(&rendered, options) {
+        page(&rendered).map_err(Error::Pager)?;
+    } else if let Some(table) = &table {
+        print_table(table);
+    } else {
+        print!("{}", rendered);
+    }
+
+    Ok(true)
+}
+
+/// Pager used when `$PAGER` isn't set
+const DEFAULT_PAGER: &str = "less -R";
+
+/// Whether to show `rendered` through the pager: only on a terminal, and
+/// with `--pager` or when it's taller than the screen
+fn wants_pager(rendered: &str, options: &ListOptions) -> bool {
+    if options.no_pager || !io::stdout().is_terminal() {
+        return false;
+    }
+
+    options.pager
+        || terminal_size::terminal_size().is_some_and(|(_, terminal_size::Height(rows))| {
+            rendered.lines().count() >= usize::from(rows)
+        })
+}
+
+/// Renders a table the way `print_table` prints it, colors included
+fn render_table(table: &Table) -> String {
+    let table = &themed(table);
+
+    if COLOR.load(Ordering::Relaxed) {
+        if let Some(mut terminal) = term::TerminfoTerminal::new(Vec::new()) {
+            if table.print_term(&mut terminal).is_ok() {
+                return String::from_utf8_lossy(&term::Terminal::into_inner(terminal)).into_owned();
+            }
+        }
+    }
+
+    table.to_string()
+}
+
+/// Feeds `text` to `$PAGER` and waits for it to quit, printing `text` instead
+/// when the pager can't be started
+pub fn page(text: &str) -> io::Result<()> {
+    let pager = std::env::var("PAGER")
+        .ok()
+        .filter(|pager| !pager.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_PAGER.to_string());
+
+    let words = shlex::split(&pager).unwrap_or_default();
+    let Some((program, args)) = words.split_first() else {
+        print!("{}", text);
+        return Ok(());
+    };
+
+    let mut child = match std::process::Command::new(program)
+        .args(args)
+        .stdin(std::process::Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(error) => {
+            debug!("Couldn't start the pager '{}': {}", pager, error);
+            print!("{}", text);
+            return Ok(());
+        }
+    };
+
+    if let Some(mut stdin) = child.stdin.take() {
+        // Quitting the pager before the end closes its input, which is fine
+        match stdin.write_all(text.as_bytes()) {
+            Err(error) if error.kind() != io::ErrorKind::BrokenPipe => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(error);
+            }
+            _ => {}
+        }
+    }
+
+    child.wait()?;
+
+    Ok(())
+}
+
+pub fn show_expense(id: &Id, currency: &str, path: &str) -> Result<(), Error> {
+    let expenses = read_expenses(path).map_err(Error::Read)?;
+
+    let Some(expense) = expenses.iter().find(|expense| expense.id == *id) else {
+        return Err(Error::ExpenseNotFound(vec![id.clone()]));
+    };
+
+    print_expense_details(expense, currency);
+
+    Ok(())
+}
+
+/// Shows the expense added last, the last one in the datastore
+pub fn show_last_expense(currency: &str, path: &str) -> Result<(), Error> {
+    let expenses = read_expenses(path).map_err(Error::Read)?;
+
+    match expenses.last() {
+        Some(expense) => print_expense_details(expense, currency),
+        None => println!("No expenses yet"),
+    }
+
+    Ok(())
+}
+
+/// Prints every field of an expense, one per line
+fn print_expense_details(expense: &Expense, currency: &str) {
+    let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+    let currency = expense.currency.as_deref().unwrap_or(currency);
+
+    println!("ID: {}", expense.id);
+    println!("Kind: {}", expense.kind);
+    println!("Date: {}", or_dash(expense.date.map(format_date)));
+    println!("Description: {}", expense.description);
+    println!("Amount: {}", format_amount(expense.amount, currency));
+    println!("Currency: {}", currency.to_uppercase());
+    if !expense.items.is_empty() {
+        println!("Items:");
+        for item in &expense.items {
+            println!(
+                "  {}: {}",
+                item.description,
+                format_amount(item.amount, currency)
+            );
+        }
+    }
+    println!("Category: {}", or_dash(expense.category.clone()));
+    if let Some(vendor) = &expense.vendor {
+        println!("Vendor: {}", vendor);
+    }
+    if let Some(receipt) = &expense.receipt {
+        println!("Receipt: {}", receipt);
+    }
+    println!(
+        "Recurring: {}",
+        or_dash(expense.recurring_id.map(|id| id.to_string()))
+    );
+    println!(
+        "Tags: {}",
+        or_dash(Some(expense.tags.join(", ")).filter(|t| !t.is_empty()))
+    );
+    println!("Note: {}", or_dash(expense.note.clone()));
+}
+
+/// Number of single-character insertions, deletions and substitutions needed
+/// to turn `a` into `b`
+pub fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(a_char != *b_char);
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+
+        previous = current;
+    }
+
+    previous[b.len()]
+}
+
+/// The description closest to `query`, if it's near enough to be a likely typo
+pub fn closest_description<'a>(
+    query: &str,
+    descriptions: impl IntoIterator<Item = &'a str>,
+) -> Option<&'a str> {
+    let query = query.to_lowercase();
+    let threshold = (query.chars().count() / 3).max(1);
+
+    descriptions
+        .into_iter()
+        .map(|description| {
+            (
+                levenshtein(&query, &description.to_lowercase()),
+                description,
+            )
+        })
+        .filter(|(distance, _)| *distance <= threshold)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, description)| description)
+}
+
+/// Prints the expenses matching `query`, returning how many there were
+pub fn search_expenses(query: &str, currency: &str, path: &str) -> Result<usize, Error> {
+    let expenses = read_expenses(path).map_err(Error::Read)?;
+
+    let lowercase_query = query.to_lowercase();
+    let matches: Vec<Expense> = expenses
+        .iter()
+        .filter(|expense| {
+            expense
+                .description
+                .to_lowercase()
+                .contains(&lowercase_query)
+        })
+        .cloned()
+        .collect();
+
+    if matches.is_empty() {
+        let descriptions = expenses.iter().map(|expense| expense.description.as_str());
+
+        match closest_description(query, descriptions) {
+            Some(suggestion) => println!("No matches. Did you mean '{}'?", suggestion),
+            None => println!("No matching expenses found"),
+        }
+        return Ok(0);
+    }
+
+    print_expenses_table(&matches, currency);
+
+    Ok(matches.len())
+}
+
+pub const UNCATEGORIZED: &str = "(uncategorized)";
+
+/// Sums amounts per category, grouping expenses without one under `UNCATEGORIZED`
+/// and leaving out income
+pub fn totals_by_category(expenses: &[Expense]) -> HashMap<String, i64> {
+    let mut totals = HashMap::new();
+
+    for expense in expenses.iter().filter(|expense| !expense.is_income()) {
+        let category = expense.category.as_deref().unwrap_or(UNCATEGORIZED);
+        *totals.entry(category.to_string()).or_insert(0) += expense.amount;
+    }
+
+    totals
+}
+
+/// Splits a `vendor: item` description on its first colon, so later colons stay
+/// part of the item. Returns `None` unless both sides have text.
+pub fn split_vendor(description: &str) -> Option<(&str, &str)> {
+    let (vendor, item) = description.split_once(':')?;
+    let (vendor, item) = (vendor.trim(), item.trim());
+
+    if vendor.is_empty() || item.is_empty() {
+        return None;
+    }
+
+    Some((vendor, item))
+}
+
+pub const NO_VENDOR: &str = "(no vendor)";
+
+/// Sums amounts per vendor, grouping expenses without one under `NO_VENDOR`
+/// and leaving out income
+pub fn totals_by_vendor(expenses: &[Expense]) -> HashMap<String, i64> {
+    let mut totals = HashMap::new();
+
+    for expense in expenses.iter().filter(|expense| !expense.is_income()) {
+        let vendor = expense.vendor.as_deref().unwrap_or(NO_VENDOR);
+        *totals.entry(vendor.to_string()).or_insert(0) += expense.amount;
+    }
+
+    totals
+}
+
+/// Sums expenses per period, keyed by the period's first day in chronological
+/// order; undated expenses are keyed by `None`, which sorts first
+pub fn group_by_period(expenses: &[Expense], period: Period) -> BTreeMap<Option<NaiveDate>, i64> {
+    let mut totals = BTreeMap::new();
+
+    for expense in expenses.iter().filter(|expense| !expense.is_income()) {
+        let start = expense.date.map(|date| period.start(date));
+        *totals.entry(start).or_insert(0) += expense.amount;
+    }
+
+    totals
+}
+
+/// Total and number of the dated expenses falling on each day of the week,
+/// all seven days in order from `week_start`. Undated expenses are left out.
+pub fn totals_by_weekday(
+    expenses: &[Expense],
+    week_start: chrono::Weekday,
+) -> Vec<(chrono::Weekday, i64, usize)> {
+    let mut totals: Vec<(chrono::Weekday, i64, usize)> =
+        std::iter::successors(Some(week_start), |day| Some(day.succ()))
+            .take(7)
+            .map(|day| (day, 0, 0))
+            .collect();
+
+    for expense in expenses {
+        if let Some(date) = expense.date {
+            let index = date.weekday().days_since(week_start) as usize;
+            totals[index].1 += expense.amount;
+            totals[index].2 += 1;
+        }
+    }
+
+    totals
+}
+
+fn print_weekday_breakdown(expenses: &[Expense], week_start: chrono::Weekday, currency: &str) {
+    let mut table = Table::new();
+
+    table.set_titles(row!["Weekday", "Total", "Expenses", "Average"]);
+
+    for (day, total, count) in totals_by_weekday(expenses, week_start) {
+        let average = match count {
+            0 => "-".to_string(),
+            count => format_amount(total / count as i64, currency),
+        };
+
+        table.add_row(row![day, format_amount(total, currency), count, average]);
+    }
+
+    print_table(&table);
+
+    let undated = expenses
+        .iter()
+        .filter(|expense| expense.date.is_none())
+        .count();
+    if undated > 0 {
+        println!("Left out of the weekdays: {} undated expense(s)", undated);
+    }
+}
+
+fn print_period_breakdown(expenses: &[Expense], period: Period, currency: &str) {
+    let totals = group_by_period(expenses, period);
+
+    let mut table = Table::new();
+
+    table.set_titles(row!["Period", "Total"]);
+
+    // Undated expenses come last, after the chronological ones
+    for (start, total) in &totals {
+        if let Some(start) = start {
+            table.add_row(row![period.label(*start), format_amount(*total, currency)]);
+        }
+    }
+
+    if let Some(total) = totals.get(&None) {
+        table.add_row(row!["(undated)", format_amount(*total, currency)]);
+    }
+
+    print_table(&table);
+}
+
+/// Prints the subtotals largest first with their share of `total`, under a
+/// `label` column such as "Category"
+fn print_breakdown(label: &str, totals: HashMap<String, i64>, total: i64, currency: &str) {
+    let mut totals: Vec<(String, i64)> = totals.into_iter().collect();
+    totals.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    let mut table = Table::new();
+
+    table.set_titles(row![label, "Subtotal", "Share"]);
+
+    for (name, subtotal) in totals {
+        let share = if total == 0 {
+            0.0
+        } else {
+            subtotal as f64 / total as f64 * 100.0
+        };
+
+        table.add_row(row![
+            name,
+            format_amount(subtotal, currency),
+            format!("{:.1}%", share)
+        ]);
+    }
+
+    print_table(&table);
+}
+
+/// Width of the bars of `--chart` when stdout isn't a terminal
+const CHART_WIDTH: usize = 40;
+
+/// Length of the bar for `subtotal` in a chart whose full `width` stands for
+/// `total`, rounded to the nearest column. Any spending gets at least one
+/// column, and nothing or a negative subtotal none.
+pub fn bar_length(subtotal: i64, total: i64, width: usize) -> usize {
+    if subtotal <= 0 || total <= 0 {
+        return 0;
+    }
+
+    let width = width as i128;
+    let length = (subtotal as i128 * width * 2 + total as i128) / (total as i128 * 2);
+
+    length.clamp(1, width) as usize
+}
+
+/// Prints a bar per subtotal, largest first, each as long as its share of
+/// `total`, filling the terminal's width
+fn print_chart(totals: HashMap<String, i64>, total: i64, currency: &str) {
+    let mut totals: Vec<(String, i64)> = totals.into_iter().collect();
+    totals.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    let amounts: Vec<String> = totals
+        .iter()
+        .map(|(_, subtotal)| format_amount(*subtotal, currency))
+        .collect();
+    let name_width = totals
+        .iter()
+        .map(|(name, _)| name.chars().count())
+        .max()
+        .unwrap_or(0);
+    let amount_width = amounts
+        .iter()
+        .map(|amount| amount.chars().count())
+        .max()
+        .unwrap_or(0);
+
+    // What's left of the line once the names, amounts and spaces are in
+    let width = match terminal_size::terminal_size() {
+        Some((terminal_size::Width(columns), _)) => {
+            usize::from(columns).saturating_sub(name_width + amount_width + 2)
+        }
+        None => CHART_WIDTH,
+    }
+    .max(1);
+
+    for ((name, subtotal), amount) in totals.iter().zip(&amounts) {
+        let bar = "#".repeat(bar_length(*subtotal, total, width));
+
+        println!(
+            "{:<name_width$} {:<width$} {:>amount_width$}",
+            name,
+            bar,
+            amount,
+            name_width = name_width,
+            width = width,
+            amount_width = amount_width
+        );
+    }
+}
+
+/// Converts `cents`, minor units of `from`, to minor units of `to`, given the
+/// value of one unit of each in a common reference currency. Returns `None`
+/// when a rate is missing.
+pub fn convert_cents(
+    cents: i64,
+    from: &str,
+    to: &str,
+    rates: &HashMap<String, f64>,
+) -> Option<i64> {
+    if from.eq_ignore_ascii_case(to) {
+        return Some(cents);
+    }
+
+    let rate = |currency: &str| {
+        rates
+            .iter()
+            .find(|(code, _)| code.eq_ignore_ascii_case(currency))
+            .map(|(_, rate)| *rate)
+            .filter(|rate| rate.is_finite() && *rate > 0.0)
+    };
+
+    let places = i32::from(minor_unit_places(to)) - i32::from(minor_unit_places(from));
+
+    Some((cents as f64 * rate(from)? / rate(to)? * 10f64.powi(places)).round() as i64)
+}
+
+/// Converts the amounts of `expenses` to `to`, expenses without a currency
+/// being in `currency`. Expenses that can't be converted are left out and
+/// counted per currency.
+pub fn convert_expenses(
+    expenses: Vec<Expense>,
+    currency: &str,
+    to: &str,
+    rates: &HashMap<String, f64>,
+) -> (Vec<Expense>, BTreeMap<String, usize>) {
+    let mut converted = Vec::new();
+    let mut missing = BTreeMap::new();
+
+    for mut expense in expenses {
+        let from = expense
+            .currency
+            .clone()
+            .unwrap_or_else(|| currency.to_string());
+
+        match convert_cents(expense.amount, &from, to, rates) {
+            Some(amount) => {
+                expense.amount = amount;
+                expense.currency = Some(to.to_string());
+                converted.push(expense);
+            }
+            None => *missing.entry(from.to_uppercase()).or_default() += 1,
+        }
+    }
+
+    (converted, missing)
+}
+
+/// Splits `expenses` by the currency they were paid in, by code, expenses
+/// without one being in `currency`
+pub fn group_by_currency(expenses: Vec<Expense>, currency: &str) -> BTreeMap<String, Vec<Expense>> {
+    let mut groups: BTreeMap<String, Vec<Expense>> = BTreeMap::new();
+
+    for expense in expenses {
+        let code = expense
+            .currency
+            .as_deref()
+            .unwrap_or(currency)
+            .to_uppercase();
+        groups.entry(code).or_default().push(expense);
+    }
+
+    groups
+}
+
+/// What `summary` covers and how it breaks the total down
+pub struct SummaryOptions<'a> {
+    pub month: Option<u32>,
+    pub category: Option<&'a str>,
+    pub by_category: bool,
+    /// Draw the category breakdown as a bar chart instead of a table
+    pub chart: bool,
+    pub by_vendor: bool,
+    pub group_by: Option<Period>,
+    pub total_only: bool,
+    /// Currency to convert every amount to with the rates from the config
+    pub convert_to: Option<&'a str>,
+    /// Only include expenses added since the previous `--since-last` summary
+    pub since_last: bool,
+    /// Only include expenses of the current cycle of this pay period
+    pub pay_period: Option<PayPeriod>,
+    /// Also show the total as a percentage of the period's income
+    pub as_pct_of_income: bool,
+    /// Compare `month` with the month before it
+    pub compare: bool,
+    /// Break the total down by day of the week, starting the week on this day
+    pub weekday: Option<chrono::Weekday>,
+}
+
+/// The month before `month` of `year`, December of the year before for January
+pub fn previous_month(year: i32, month: u32) -> (i32, u32) {
+    match month {
+        1 => (year - 1, 12),
+        month => (year, month - 1),
+    }
+}
+
+/// How much `current` differs from `previous`, and by what percentage of
+/// `previous` unless that's zero
+pub fn month_change(current: i64, previous: i64) -> (i64, Option<f64>) {
+    let delta = current - previous;
+    let percent = (previous != 0).then(|| delta as f64 / previous.abs() as f64 * 100.0);
+
+    (delta, percent)
+}
+
+/// Spending of `category`, or of every category, in the given month in
+/// `currency`, `None` when nothing was spent in it at all
+fn month_spending(
+    expenses: &[Expense],
+    year: i32,
+    month: u32,
+    category: Option<&str>,
+    currency: &str,
+) -> Option<i64> {
+    let in_month: Vec<Expense> = expenses
+        .iter()
+        .filter(|expense| {
+            !expense.is_income()
+                && expense
+                    .date
+                    .is_some_and(|date| date.year() == year && date.month() == month)
+                && category.is_none_or(|category| {
+                    expense
+                        .category
+                        .as_deref()
+                        .is_some_and(|c| c.eq_ignore_ascii_case(category))
+                })
+        })
+        .cloned()
+        .collect();
+
+    (!in_month.is_empty()).then(|| month_total(&in_month, year, month, currency).total)
+}
+
+/// Year of the last `month` up to `today`, this year's or last year's
+fn last_year_with_month(month: u32, today: NaiveDate) -> i32 {
+    if month <= today.month() {
+        today.year()
+    } else {
+        today.year() - 1
+    }
+}
+
+/// Line comparing `month`'s spending with the month before. The month is
+/// taken to be the last one by that number, see `last_year_with_month`.
+fn describe_month_change(
+    expenses: &[Expense],
+    month: u32,
+    category: Option<&str>,
+    today: NaiveDate,
+    currency: &str,
+) -> String {
+    let year = last_year_with_month(month, today);
+    let (previous_year, previous) = previous_month(year, month);
+
+    let Some(before) = month_spending(expenses, previous_year, previous, category, currency) else {
+        return format!(
+            "Compared with {}-{:02}: no prior-month data",
+            previous_year, previous
+        );
+    };
+    let current = month_spending(expenses, year, month, category, currency).unwrap_or(0);
+
+    let (delta, percent) = month_change(current, before);
+    let arrow = match delta.cmp(&0) {
+        std::cmp::Ordering::Greater => "↑",
+        std::cmp::Ordering::Less => "↓",
+        std::cmp::Ordering::Equal => "→",
+    };
+    let percent = percent
+        .map(|percent| format!(" ({:+.1}%)", percent))
+        .unwrap_or_default();
+
+    format!(
+        "{}-{:02}: {}, compared with {} in {}-{:02}: {} {}{}",
+        year,
+        month,
+        format_amount(current, currency),
+        format_amount(before, currency),
+        previous_year,
+        previous,
+        arrow,
+        format_amount(delta.abs(), currency),
+        percent
+    )
+}
+
+/// First and last day of the cycle of `period` that `today` falls in. The
+/// start can be any cycle's, including one that's still to come.
+pub fn pay_period_bounds(period: PayPeriod, today: NaiveDate) -> (NaiveDate, NaiveDate) {
+    let days = i64::from(period.days.get());
+    let cycles = (today - period.start).num_days().div_euclid(days);
+
+    let first = period.start + chrono::TimeDelta::days(cycles * days);
+    (first, first + chrono::TimeDelta::days(days - 1))
+}
+
+/// Prints the total of the expenses, optionally narrowed to a month or a
+/// category. With `total_only` just the number is printed, for scripts.
+pub fn summarize_expenses(
+    options: &SummaryOptions,
+    rates: &HashMap<String, f64>,
+    currency: &str,
+    path: &str,
+) -> Result<(), Error> {
+    let SummaryOptions {
+        month,
+        category,
+        by_category,
+        by_vendor,
+        group_by,
+        total_only,
+        convert_to,
+        since_last,
+        pay_period,
+        as_pct_of_income,
+        compare,
+        weekday,
+        ..
+    } = *options;
+
+    let started = Utc::now();
+    let mut expenses = read_expenses(path).map_err(Error::Read)?;
+
+    if since_last {
+        let mut settings = read_settings(path).map_err(Error::Settings)?;
+
+        let note = match settings.summarized_at {
+            Some(since) => {
+                // Expenses from before `created_at` was recorded are older than any summary
+                expenses.retain(|expense| expense.created_at.is_some_and(|at| at > since));
+                format!(
+                    "Expenses added since {}",
+                    since.with_timezone(&Local).format("%Y-%m-%d %H:%M")
+                )
+            }
+            None => "First summary with --since-last, showing all time".to_string(),
+        };
+
+        if !total_only {
+            info!("{}", note);
+        }
+
+        settings.summarized_at = Some(started);
+        write_settings(path, &settings).map_err(Error::Settings)?;
+    }
+
+    let today = Local::now().date_naive();
+
+    // The month before is about to be filtered out
+    let comparison = month
+        .filter(|_| compare)
+        .map(|month| describe_month_change(&expenses, month, category, today, currency));
+
+    // Compared, the total is of the one month the comparison is about
+    let year = month
+        .filter(|_| compare)
+        .map(|month| last_year_with_month(month, today));
+
+    expenses.retain(|expense| match (month, expense.date) {
+        (Some(month), Some(date)) => {
+            date.month() == month && year.is_none_or(|year| date.year() == year)
+        }
+        (Some(_), None) => false,
+        (None, _) => true,
+    });
+
+    let cycle = pay_period.map(|period| pay_period_bounds(period, today));
+    if let Some((first, last)) = cycle {
+        expenses.retain(|expense| {
+            expense
+                .date
+                .is_some_and(|date| date >= first && date <= last)
+        });
+    }
+
+    // Income of the period, before the category leaves it out
+    let (income, mut expenses): (Vec<Expense>, Vec<Expense>) =
+        expenses.into_iter().partition(Expense::is_income);
+
+    if let Some(category) = category {
+        expenses.retain(|expense| {
+            expense
+                .category
+                .as_deref()
+                .is_some_and(|c| c.eq_ignore_ascii_case(category))
+        });
+    }
+
+    let mut left_out = 0;
+
+    let currency = match convert_to {
+        Some(to) => {
+            let (converted, missing) = convert_expenses(expenses, currency, to, rates);
+            expenses = converted;
+
+            warn_missing_rates(&missing, to);
+            left_out = missing.values().sum();
+
+            to
+        }
+        None => currency,
+    };
+
+    // Adding up amounts in different currencies would mean nothing, so
+    // without `--in` each currency gets its own total
+    let mut groups = group_by_currency(expenses, currency);
+    if groups.is_empty() {
+        groups.insert(currency.to_uppercase(), Vec::new());
+    }
+    let totals: Vec<(&str, i64)> = groups
+        .iter()
+        .map(|(code, expenses)| (code.as_str(), expenses.iter().map(|e| e.amount).sum()))
+        .collect();
+
+    if total_only {
+        let [(code, total)] = totals[..] else {
+            return Err(Error::MixedCurrencies(groups.into_keys().collect()));
+        };
+        println!(
+            "{}",
+            format_minor_units_with_precision(total, code, display_precision(code))
+        );
+        return Ok(());
+    }
+
+    let breakdowns = by_category || by_vendor || group_by.is_some() || weekday.is_some();
+
+    for ((code, expenses), &(_, total)) in groups.iter().zip(&totals) {
+        if breakdowns && groups.len() > 1 {
+            println!("{}:", code);
+        }
+        print_breakdowns(expenses, total, options, code);
+    }
+
+    let total = totals
+        .iter()
+        .map(|&(code, total)| format_amount(total, code))
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    match (month, cycle) {
+        (Some(month), _) => match year {
+            Some(year) => println!("Total expenses for {}-{:02}: {}", year, month, total),
+            None => println!("Total expenses for month {}: {}", month, total),
+        },
+        (None, Some((first, last))) => {
+            println!(
+                "Total expenses for pay period {} to {}: {}",
+                format_date(first),
+                format_date(last),
+                total
+            );
+            // Today is still to be spent, so it counts as left
+            println!("Days left: {}", (last - today).num_days() + 1);
+        }
+        (None, None) => println!("Total expenses: {}", total),
+    }
+
+    if let Some(comparison) = comparison {
+        println!("{}", comparison);
+    }
+
+    if as_pct_of_income {
+        let income = group_by_currency(income, currency);
+        let shares: Vec<String> = totals
+            .iter()
+            .map(|&(code, total)| {
+                let income = income.get(code).map_or(0, |income| total_income(income));
+                let share = format_percent_of_income(total, income);
+                match totals.len() {
+                    1 => share,
+                    _ => format!("{} {}", code, share),
+                }
+            })
+            .collect();
+        println!("Share of income: {}", shares.join(", "));
+    }
+
+    if left_out > 0 {
+        println!(
+            "Not included: {} expense(s) in currencies without a rate",
+            left_out
+        );
+    }
+
+    Ok(())
+}
+
+/// The `--by-category`, `--by-vendor`, `--group-by` and `--weekday` breakdowns
+/// of `expenses`, which are all in `currency` and add up to `total`
+fn print_breakdowns(expenses: &[Expense], total: i64, options: &SummaryOptions, currency: &str) {
+    let &SummaryOptions {
+        by_category,
+        chart,
+        by_vendor,
+        group_by,
+        weekday,
+        ..
+    } = options;
+
+    if by_category && !expenses.is_empty() {
+        if chart {
+            print_chart(totals_by_category(expenses), total, currency);
+        } else {
+            print_breakdown("Category", totals_by_category(expenses), total, currency);
+        }
+    }
+
+    if by_vendor && !expenses.is_empty() {
+        let totals = totals_by_vendor(expenses).into_iter().fold(
+            HashMap::new(),
+            |mut totals, (vendor, total)| {
+                let vendor = match vendor.as_str() {
+                    NO_VENDOR => vendor,
+                    _ => shown_description(&vendor),
+                };
+                // Masked vendors can coincide
+                *totals.entry(vendor).or_insert(0) += total;
+                totals
+            },
+        );
+        print_breakdown("Vendor", totals, total, currency);
+    }
+
+    if let Some(period) = group_by.filter(|_| !expenses.is_empty()) {
+        print_period_breakdown(expenses, period, currency);
+    }
+
+    if let Some(week_start) = weekday.filter(|_| !expenses.is_empty()) {
+        print_weekday_breakdown(expenses, week_start, currency);
+    }
+}
+
+pub fn top_expenses(count: usize, currency: &str, path: &str) -> Result<(), Error> {
+    let mut expenses = read_expenses(path).map_err(Error::Read)?;
+    expenses.retain(|expense| !expense.is_income());
+
+    if expenses.is_empty() {
+        println!("No expenses found");
+        return Ok(());
+    }
+
+    let grand_total: i64 = expenses.iter().map(|expense| expense.amount).sum();
+
+    sort_expenses(&mut expenses, SortField::Amount, true);
+    expenses.truncate(count);
+
+    let top_total: i64 = expenses.iter().map(|expense| expense.amount).sum();
+    let share = if grand_total == 0 {
+        0.0
+    } else {
+        top_total as f64 / grand_total as f64 * 100.0
+    };
+
+    let mut table = expenses_table(&expenses, currency, None, true, RowOptions::default());
+    table.add_row(row![
+        "",
+        "",
+        "SHARE OF ALL",
+        "",
+        "",
+        "",
+        r->format!("{:.1}%", share)
+    ]);
+    print_table(&table);
+
+    Ok(())
+}
+
+/// Aggregate figures over a non-empty list of expenses
+pub struct Stats<'a> {
+    pub count: usize,
+    pub total: i64,
+    /// Rounded to the nearest cent
+    pub average: i64,
+    pub cheapest: &'a Expense,
+    pub most_expensive: &'a Expense,
+}
+
+pub fn compute_stats(expenses: &[Expense]) -> Option<Stats<'_>> {
+    let cheapest = expenses.iter().min_by_key(|expense| expense.amount)?;
+    let most_expensive = expenses.iter().max_by_key(|expense| expense.amount)?;
+
+    let total: i64 = expenses.iter().map(|expense| expense.amount).sum();
+
+    Some(Stats {
+        count: expenses.len(),
+        total,
+        average: (total as f64 / expenses.len() as f64).round() as i64,
+        cheapest,
+        most_expensive,
+    })
+}
+
+/// Number of days `stats --sparkline` covers
+const SPARKLINE_DAYS: u64 = 30;
+
+/// Spending on each of the `days` days up to and including `today`, oldest first,
+/// leaving out income and undated expenses
+pub fn daily_totals(expenses: &[Expense], today: NaiveDate, days: u64) -> Vec<i64> {
+    let Some(first) = days
+        .checked_sub(1)
+        .and_then(|back| today.checked_sub_days(chrono::Days::new(back)))
+    else {
+        return Vec::new();
+    };
+
+    let mut totals = vec![0; days as usize];
+
+    for expense in expenses.iter().filter(|expense| !expense.is_income()) {
+        let Some(date) = expense.date.filter(|date| first <= *date && *date <= today) else {
+            continue;
+        };
+
+        totals[(date - first).num_days() as usize] += expense.amount;
+    }
+
+    totals
+}
+
+/// Draws one block per value, scaled so the largest gets the full block.
+/// Zero and negative values get the lowest block and any spending at least
+/// the next one, so quiet days stand out.
+pub fn sparkline(values: &[i64]) -> String {
+    const BLOCKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+    let max = values.iter().copied().max().unwrap_or(0);
+
+    values
+        .iter()
+        .map(|&value| {
+            if value <= 0 {
+                return BLOCKS[0];
+            }
+
+            let level = (value as i128 * 7 / max as i128) as usize;
+            BLOCKS[level.max(1)]
+        })
+        .collect()
+}
+
+pub fn show_stats(with_sparkline: bool, currency: &str, path: &str) -> Result<(), Error> {
+    let mut expenses = read_expenses(path).map_err(Error::Read)?;
+    expenses.retain(|expense| !expense.is_income());
+
+    let Some(stats) = compute_stats(&expenses) else {
+        println!("No expenses to analyze");
+        return Ok(());
+    };
+
+    println!("Count: {}", stats.count);
+    println!("Total: {}", format_amount(stats.total, currency));
+    println!("Average: {}", format_amount(stats.average, currency));
+    println!(
+        "Minimum: {} ({})",
+        format_amount(stats.cheapest.amount, currency),
+        stats.cheapest.description
+    );
+    println!(
+        "Maximum: {} ({})",
+        format_amount(stats.most_expensive.amount, currency),
+        stats.most_expensive.description
+    );
+
+    if with_sparkline {
+        let today = Local::now().date_naive();
+        let totals = daily_totals(&expenses, today, SPARKLINE_DAYS);
+        let peak = totals.iter().copied().max().unwrap_or(0);
+
+        println!(
+            "Last {} days: {} (peak {})",
+            SPARKLINE_DAYS,
+            sparkline(&totals),
+            format_amount(peak, currency)
+        );
+    }
+
+    Ok(())
+}
+
+pub fn show_balance(currency: &str, path: &str) -> Result<(), Error> {
+    let expenses = read_expenses(path).map_err(Error::Read)?;
+
+    let income = total_income(&expenses);
+    let spent = total_spent(&expenses);
+
+    println!("Income: {}", format_amount(income, currency));
+    println!("Expenses: {}", format_amount(spent, currency));
+    println!("Balance: {}", format_amount(income - spent, currency));
+
+    Ok(())
+}
+
+/// Spending in `currency` in the 7 days up to and including `today`, and in
+/// the 7 days before those, see `spending_in`
+pub fn weekly_comparison(expenses: &[Expense], today: NaiveDate, currency: &str) -> (i64, i64) {
+    let week_start = today - chrono::Days::new(6);
+    let previous_start = today - chrono::Days::new(13);
+
+    let spent_between = |from: NaiveDate, to: NaiveDate| -> i64 {
+        let between = expenses
+            .iter()
+            .filter(|expense| expense.date.is_some_and(|date| from <= date && date <= to));
+
+        spending_in(between, currency).total
+    };
+
+    (
+        spent_between(week_start, today),
+        spent_between(previous_start, week_start - chrono::Days::new(1)),
+    )
+}
+
+/// One-line summary of this week's spending against the week before
+pub fn format_digest(current: i64, previous: i64, currency: &str) -> String {
+    let change = match current.cmp(&previous) {
+        std::cmp::Ordering::Greater => format!(
+            "↑ {} more than",
+            format_amount(current - previous, currency)
+        ),
+        std::cmp::Ordering::Less => {
+            format!(
+                "↓ {} less than",
+                format_amount(previous - current, currency)
+            )
+        }
+        std::cmp::Ordering::Equal => "→ the same as".to_string(),
+    };
+
+    format!(
+        "Last 7 days: {} spent, {} the 7 days before",
+        format_amount(current, currency),
+        change
+    )
+}
+
+/// Prints the weekly digest unless it was already shown today
+pub fn show_daily_digest(currency: &str, path: &str) -> Result<(), Error> {
+    let today = Local::now().date_naive();
+
+    // There's nothing to digest, and nowhere to remember it was shown
+    if missing_without_init(path) {
+        return Ok(());
+    }
+
+    let mut settings = read_settings(path).map_err(Error::Settings)?;
+    if settings.digest_shown.is_some_and(|shown| shown >= today) {
+        return Ok(());
+    }
+
+    let expenses = read_expenses(path).map_err(Error::Read)?;
+    let (current, previous) = weekly_comparison(&expenses, today, currency);
+
+    info!("{}", format_digest(current, previous, currency));
+
+    settings.digest_shown = Some(today);
+    write_settings(path, &settings).map_err(Error::Settings)
+}
+
+pub fn set_budget(amount: f64, currency: &str, path: &str) -> Result<(), Error> {
+    validate_amount(amount, false, currency)?;
+
+    let mut settings = read_settings(path).map_err(Error::Settings)?;
+    settings.budget = Some(to_minor_units(amount, currency));
+    write_settings(path, &settings).map_err(Error::Settings)?;
+
+    info!(
+        "Monthly budget set to {}",
+        format_amount(to_minor_units(amount, currency), currency)
+    );
+
+    Ok(())
+}
+
+pub fn budget_status(currency: &str, path: &str) -> Result<(), Error> {
+    let Some(budget) = read_settings(path).map_err(Error::Settings)?.budget else {
+        println!("No monthly budget set");
+        return Ok(());
+    };
+
+    let expenses = read_expenses(path).map_err(Error::Read)?;
+    let today = Local::now().date_naive();
+    let spending = month_total(&expenses, today.year(), today.month(), currency);
+    spending.warn_left_out(currency);
+    let spent = spending.total;
+
+    println!("Budget: {}", format_amount(budget, currency));
+    println!("Spent this month: {}", format_amount(spent, currency));
+    println!("Remaining: {}", format_amount(budget - spent, currency));
+
+    Ok(())
+}
+
+/// Number of days in the month `date` falls in
+pub fn days_in_month(date: NaiveDate) -> u32 {
+    let first = date.with_day(1).unwrap_or(date);
+    let next = first
+        .checked_add_months(chrono::Months::new(1))
+        .unwrap_or(first);
+
+    (next - first).num_days() as u32
+}
+
+/// Month-end spending if the rest of the month goes like the part up to and
+/// including `today`, `spent` being what was spent in it. Today counts as
+/// elapsed, so the first of the month projects from one day.
+pub fn project_month_end(spent: i64, today: NaiveDate) -> i64 {
+    let elapsed = i64::from(today.day());
+    let days = i64::from(days_in_month(today));
+
+    (spent * days + elapsed / 2) / elapsed
+}
+
+/// Projects this month's spending to its end, next to the budget if one is set
+pub fn show_trend(currency: &str, path: &str) -> Result<(), Error> {
+    let expenses = read_expenses(path).map_err(Error::Read)?;
+    let today = Local::now().date_naive();
+
+    let spending = month_total(&expenses, today.year(), today.month(), currency);
+    spending.warn_left_out(currency);
+    let spent = spending.total;
+    let projected = project_month_end(spent, today);
+
+    println!(
+        "Spent so far: {} (day {} of {})",
+        format_amount(spent, currency),
+        today.day(),
+        days_in_month(today)
+    );
+    println!(
+        "Projected month-end total: {} (an estimate at the pace so far)",
+        format_amount(projected, currency)
+    );
+
+    if let Some(budget) = read_settings(path).map_err(Error::Settings)?.budget {
+        let outlook = if projected > budget {
+            format!("{} over", format_amount(projected - budget, currency))
+        } else {
+            format!("{} under", format_amount(budget - projected, currency))
+        };
+
+        println!(
+            "Budget: {}, on pace to end {} it",
+            format_amount(budget, currency),
+            outlook
+        );
+    }
+
+    Ok(())
+}
+
+pub fn set_quota(category: &str, amount: f64, currency: &str, path: &str) -> Result<(), Error> {
+    validate_amount(amount, false, currency)?;
+
+    let mut settings = read_settings(path).map_err(Error::Settings)?;
+    settings
+        .quotas
+        .insert(category.to_lowercase(), to_minor_units(amount, currency));
+    write_settings(path, &settings).map_err(Error::Settings)?;
+
+    info!(
+        "Monthly quota for '{}' set to {}",
+        category,
+        format_amount(to_minor_units(amount, currency), currency)
+    );
+
+    Ok(())
+}
+
+/// Lists each category with a quota, with this month's spending and what's left
+pub fn quota_status(currency: &str, path: &str) -> Result<(), Error> {
+    let settings = read_settings(path).map_err(Error::Settings)?;
+
+    if settings.quotas.is_empty() {
+        println!("No quotas set");
+        return Ok(());
+    }
+
+    let expenses = read_expenses(path).map_err(Error::Read)?;
+    let today = Local::now().date_naive();
+
+    let mut table = Table::new();
+    table.set_titles(row!["Category", "Quota", "Spent", "Remaining"]);
+
+    for (category, quota) in &settings.quotas {
+        let spending =
+            category_month_total(&expenses, category, today.year(), today.month(), currency);
+        spending.warn_left_out(currency);
+        let spent = spending.total;
+
+        table.add_row(row![
+            category,
+            r->format_amount(*quota, currency),
+            r->format_amount(spent, currency),
+            r->format_amount(quota - spent, currency)
+        ]);
+    }
+
+    print_table(&table);
+
+    Ok(())
+}
+
+/// Shows how this month's total, budget and the category's quota would change
+/// with an expense of `amount` in `category` added today, without adding it
+pub fn what_if(
+    amount: f64,
+    category: Option<&str>,
+    currency: &str,
+    path: &str,
+) -> Result<(), Error> {
+    validate_amount(amount, false, currency)?;
+
+    let settings = read_settings(path).map_err(Error::Settings)?;
+    let before = read_expenses(path).map_err(Error::Read)?;
+    let today = Local::now().date_naive();
+
+    let mut after = before.clone();
+    after.push(Expense {
+        id: Id::from(0),
+        description: String::new(),
+        amount: to_minor_units(amount, currency),
+        date: Some(today),
+        category: category.map(str::to_string),
+        recurring_id: None,
+        kind: Kind::Expense,
+        note: None,
+        tags: Vec::new(),
+        currency: None,
+        created_at: None,
+        vendor: None,
+        deleted_at: None,
+        receipt: None,
+        items: Vec::new(),
+        idempotency_key: None,
+    });
+
+    let (year, month) = (today.year(), today.month());
+    let spending = month_total(&before, year, month, currency);
+    spending.warn_left_out(currency);
+    let spent_before = spending.total;
+    let spent_after = month_total(&after, year, month, currency).total;
+
+    let mut table = Table::new();
+    table.set_titles(row!["", "Now", "With it"]);
+    table.add_row(row![
+        "Month total",
+        r->format_amount(spent_before, currency),
+        r->format_amount(spent_after, currency)
+    ]);
+
+    if let Some(budget) = settings.budget {
+        table.add_row(row![
+            "Budget remaining",
+            r->format_amount(budget - spent_before, currency),
+            r->format_amount(budget - spent_after, currency)
+        ]);
+    }
+
+    if let Some(category) = category {
+        let in_category_before =
+            category_month_total(&before, category, year, month, currency).total;
+        let in_category_after = category_month_total(&after, category, year, month, currency).total;
+
+        table.add_row(row![
+            format!("Spent on '{}'", category),
+            r->format_amount(in_category_before, currency),
+            r->format_amount(in_category_after, currency)
+        ]);
+
+        if let Some(&quota) = settings.quotas.get(&category.to_lowercase()) {
+            table.add_row(row![
+                format!("'{}' quota remaining", category),
+                r->format_amount(quota - in_category_before, currency),
+                r->format_amount(quota - in_category_after, currency)
+            ]);
+        }
+    }
+
+    print_table(&table);
+
+    if let Some(budget) = settings.budget.filter(|&budget| spent_after > budget) {
+        eprintln!(
+            "Warning: this would take you {} over your monthly budget",
+            format_amount(spent_after - budget, currency)
+        );
+    }
+
+    if let Some(category) = category {
+        if let Some(&quota) = settings.quotas.get(&category.to_lowercase()) {
+            let spent = category_month_total(&before, category, year, month, currency).total;
+
+            if let Some(excess) = quota_overrun(quota, spent, to_minor_units(amount, currency)) {
+                eprintln!(
+                    "Warning: this would take '{}' {} over its monthly quota",
+                    category,
+                    format_amount(excess, currency)
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Splits a CSV line into its fields, undoing RFC 4180 quoting. Quoted line
+/// breaks aren't supported, every record has to fit on one line.
+pub fn split_csv_line(line: &str) -> Result<Vec<String>, String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut chars = line.chars().peekable();
+    let mut quoted = false;
+
+    while let Some(c) = chars.next() {
+        match (quoted, c) {
+            (true, '"') if chars.peek() == Some(&'"') => {
+                chars.next();
+                field.push('"');
+            }
+            (true, '"') => quoted = false,
+            (true, c) => field.push(c),
+            (false, '"') if field.is_empty() => quoted = true,
+            (false, ',') => fields.push(std::mem::take(&mut field)),
+            (false, c) => field.push(c),
+        }
+    }
+
+    if quoted {
+        return Err("unterminated quoted field".to_string());
+    }
+
+    fields.push(field);
+
+    Ok(fields)
+}
+
+/// Turns one CSV record into an expense, its ID left for the merge to assign
+fn parse_csv_expense(
+    fields: &[String],
+    map: &ColumnMap,
+    currency: &str,
+) -> Result<Expense, String> {
+    let column = |index: usize, name: &str| {
+        fields
+            .get(index)
+            .map(|field| field.trim())
+            .ok_or_else(|| format!("no column {} for the {}", index + 1, name))
+    };
+
+    let description = column(map.description, "description")?;
+    if description.is_empty() {
+        return Err("empty description".to_string());
+    }
+
+    // Bank exports list money coming back as a negative amount, which is
+    // stored the way `add --refund` stores it
+    let amount = parse_amount(column(map.amount, "amount")?)?;
+    validate_amount(amount, true, currency).map_err(|error| error.to_string())?;
+    let (kind, amount) = stored_amount(Kind::Expense, amount.abs(), amount < 0.0, currency)
+        .map_err(|error| error.to_string())?;
+
+    let category = match map.category {
+        Some(index) => Some(column(index, "category")?)
+            .filter(|category| !category.is_empty())
+            .map(str::to_string),
+        None => None,
+    };
+
+    Ok(Expense {
+        id: Id::from(0),
+        description: description.to_string(),
+        amount,
+        date: Some(parse_date(column(map.date, "date")?)?),
+        category,
+        recurring_id: None,
+        kind,
+        note: None,
+        tags: Vec::new(),
+        currency: None,
+        created_at: None,
+        vendor: None,
+        deleted_at: None,
+        receipt: None,
+        items: Vec::new(),
+        idempotency_key: None,
+    })
+}
+
+/// Number of a line that couldn't be read, and why
+pub type LineError = (usize, String);
+
+/// Reads expenses from CSV rows, collecting each unreadable row's line number
+/// and reason instead of stopping at the first
+pub fn parse_csv_expenses(
+    reader: impl BufRead,
+    map: &ColumnMap,
+    skip_header: bool,
+    currency: &str,
+) -> io::Result<(Vec<Expense>, Vec<LineError>)> {
+    let mut expenses = Vec::new();
+    let mut failures = Vec::new();
+
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+
+        if (skip_header && index == 0) || line.trim().is_empty() {
+            continue;
+        }
+
+        match split_csv_line(&line).and_then(|fields| parse_csv_expense(&fields, map, currency)) {
+            Ok(expense) => expenses.push(expense),
+            Err(reason) => failures.push((index + 1, reason)),
+        }
+    }
+
+    Ok((expenses, failures))
+}
+
+/// An expense read from a line of a bulk add file
+#[derive(Debug, PartialEq)]
+pub struct BulkLine {
+    pub description: String,
+    /// Amount in minor units
+    pub amount: i64,
+    pub category: Option<String>,
+}
+
+/// Parses a `description,amount[,category]` line, quoting the description
+/// like a CSV field when it contains a comma
+pub fn parse_bulk_line(line: &str, currency: &str) -> Result<BulkLine, String> {
+    let fields = split_csv_line(line)?;
+
+    let (description, amount, category) = match fields.as_slice() {
+        [description, amount] => (description, amount, None),
+        [description, amount, category] => (description, amount, Some(category)),
+        _ => {
+            return Err(format!(
+                "expected description,amount[,category], found {} field(s)",
+                fields.len()
+            ))
+        }
+    };
+
+    let description = description.trim();
+    if description.is_empty() {
+        return Err("empty description".to_string());
+    }
+
+    let amount = parse_amount(amount)?;
+    validate_amount(amount, false, currency).map_err(|error| error.to_string())?;
+
+    Ok(BulkLine {
+        description: description.to_string(),
+        amount: to_minor_units(amount, currency),
+        category: category
+            .map(|category| category.trim().to_string())
+            .filter(|category| !category.is_empty()),
+    })
+}
+
+/// Adds every well-formed line of `file` as an expense dated today, in a
+/// single write, reporting the malformed ones with their line number
+pub fn bulk_add(file: &str, currency: &str, path: &str) -> Result<(), Error> {
+    let data = std::fs::read_to_string(file).map_err(|source| Error::Import {
+        path: file.to_string(),
+        source,
+    })?;
+
+    let mut lines = Vec::new();
+    let mut failures = 0;
+
+    for (index, line) in data.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match parse_bulk_line(line, currency) {
+            Ok(line) => lines.push(line),
+            Err(reason) => {
+                eprintln!("Line {}: {}", index + 1, reason);
+                failures += 1;
+            }
+        }
+    }
+
+    if !lines.is_empty() {
+        let _lock = lock_datastore(path).map_err(Error::Lock)?;
+        let mut datastore = read_datastore(path).map_err(Error::Read)?;
+        let today = Local::now().date_naive();
+        let mut added = Vec::new();
+
+        for line in &lines {
+            let id = datastore.allocate_id();
+            added.push(id.clone());
+
+            datastore.expenses.push(Expense {
+                id,
+                description: line.description.clone(),
+                amount: line.amount,
+                date: Some(today),
+                category: line.category.clone(),
+                recurring_id: None,
+                kind: Kind::Expense,
+                note: None,
+                tags: Vec::new(),
+                currency: None,
+                created_at: Some(Utc::now()),
+                vendor: None,
+                deleted_at: None,
+                receipt: None,
+                items: Vec::new(),
+                idempotency_key: None,
+            });
+        }
+
+        write_datastore(path, &datastore).map_err(Error::Write)?;
+        record_history(path, AuditAction::Add, &added);
+    }
+
+    info!("Added {} expense(s)", lines.len());
+
+    if failures > 0 {
+        eprintln!("{} malformed line(s) were skipped", failures);
+    }
+
+    Ok(())
+}
+
+/// Quotes a CSV field per RFC 4180 when it contains a delimiter, quote or line break
+fn escape_csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Writes `expenses` as CSV as they come, so they can be streamed from the
+/// datastore. Returns how many were written.
+pub fn export_csv(
+    expenses: impl IntoIterator<Item = io::Result<Expense>>,
+    mut writer: impl Write,
+    currency: &str,
+) -> io::Result<usize> {
+    writer.write_all(b"id,description,amount,date,category,kind\r\n")?;
+
+    let mut count = 0;
+    for expense in expenses {
+        let expense = expense?;
+        count += 1;
+
+        write!(
+            writer,
+            "{},{},{},{},{},{}\r\n",
+            expense.id,
+            escape_csv_field(&expense.description),
+            format_minor_units(
+                expense.amount,
+                expense.currency.as_deref().unwrap_or(currency)
+            ),
+            expense
+                .date
+                .map(|date| date.to_string())
+                .unwrap_or_default(),
+            escape_csv_field(expense.category.as_deref().unwrap_or_default()),
+            expense.kind,
+        )?;
+    }
+
+    writer.flush()?;
+    Ok(count)
+}
+
+pub fn export_json(
+    expenses: &[Expense],
+    mut writer: impl Write,
+    pretty: bool,
+) -> io::Result<usize> {
+    writeln!(writer, "{}", to_json(expenses, pretty)?)?;
+    writer.flush()?;
+    Ok(expenses.len())
+}
+
+/// The expenses `export` writes, streamed from a JSON lines datastore when
+/// exporting CSV and read all at once otherwise
+fn exported_expenses<'a>(
+    format: ExportFormat,
+    path: &'a str,
+) -> io::Result<Box<dyn Iterator<Item = io::Result<Expense>> + 'a>> {
+    if matches!(format, ExportFormat::Csv)
+        && Format::from_path(path) == Format::Jsonl
+        && !missing_without_init(path)
+    {
+        let live = jsonl_expenses(path)?
+            .filter(|expense| !expense.as_ref().is_ok_and(|expense| expense.is_deleted()));
+        return Ok(Box::new(live));
+    }
+
+    Ok(Box::new(read_expenses(path)?.into_iter().map(Ok)))
+}
+
+/// Exports every expense to `output`, or to stdout when it's `-`. Files get
+/// pretty JSON unless `layout` says otherwise.
+pub fn export_expenses(
+    format: ExportFormat,
+    layout: JsonLayout,
+    output: &str,
+    currency: &str,
+    path: &str,
+) -> Result<(), Error> {
+    let expenses = exported_expenses(format, path).map_err(Error::Read)?;
+
+    let export_error = |source| Error::Export {
+        path: output.to_string(),
+        source,
+    };
+
+    let as_list = |expenses: Box<dyn Iterator<Item = io::Result<Expense>>>| {
+        expenses.collect::<io::Result<Vec<Expense>>>()
+    };
+
+    if output == "-" {
+        let exported = match format {
+            ExportFormat::Csv => export_csv(expenses, io::stdout().lock(), currency),
+            ExportFormat::Json => export_json(
+                &as_list(expenses).map_err(Error::Read)?,
+                io::stdout().lock(),
+                layout.is_pretty(io::stdout().is_terminal()),
+            ),
+        }
+        // a reader like `head` closing the pipe early isn't a failure, though
+        // there's then no telling how many expenses it got
+        .map(Some)
+        .or_else(|error| match error.kind() {
+            io::ErrorKind::BrokenPipe => Ok(None),
+            _ => Err(error),
+        })
+        .map_err(export_error)?;
+
+        // stdout carries the export, keep the status message out of it
+        if let Some(exported) = exported.filter(|_| verbosity() >= Verbosity::Normal) {
+            eprintln!("Exported {} expense(s)", exported);
+        }
+
+        return Ok(());
+    }
+
+    let file = std::fs::File::create(output).map_err(export_error)?;
+
+    let exported = match format {
+        ExportFormat::Csv => export_csv(expenses, io::BufWriter::new(file), currency),
+        ExportFormat::Json => export_json(
+            &as_list(expenses).map_err(Error::Read)?,
+            io::BufWriter::new(file),
+            layout.is_pretty(true),
+        ),
+    }
+    .map_err(export_error)?;
+
+    info!("Exported {} expense(s) to '{}'", exported, output);
+
+    Ok(())
+}
+
+/// Writes one row per category with its total in `currency` for the month,
+/// sorted by category, followed by a grand-total row. Expenses paid in
+/// currencies without a rate to convert them with are left out.
+pub fn report_csv(
+    expenses: &[Expense],
+    year: i32,
+    month: u32,
+    mut writer: impl Write,
+    currency: &str,
+) -> io::Result<()> {
+    let in_month: Vec<Expense> = expenses
+        .iter()
+        .filter(|expense| {
+            expense
+                .date
+                .is_some_and(|date| date.year() == year && date.month() == month)
+        })
+        .filter_map(|expense| {
+            let amount = amount_in(expense, currency)?;
+            Some(Expense {
+                amount,
+                currency: None,
+                ..expense.clone()
+            })
+        })
+        .collect();
+
+    let mut totals: Vec<(String, i64)> = totals_by_category(&in_month).into_iter().collect();
+    totals.sort_by(|a, b| a.0.cmp(&b.0));
+
+    writer.write_all(b"category,total\r\n")?;
+
+    for (category, total) in &totals {
+        write!(
+            writer,
+            "{},{}\r\n",
+            escape_csv_field(category),
+            format_minor_units(*total, currency)
+        )?;
+    }
+
+    write!(
+        writer,
+        "TOTAL,{}\r\n",
+        format_minor_units(spending_in(&in_month, currency).total, currency)
+    )?;
+
+    writer.flush()
+}
+
+pub fn write_report(
+    year: i32,
+    month: u32,
+    output: &str,
+    currency: &str,
+    path: &str,
+) -> Result<(), Error> {
+    let expenses = read_expenses(path).map_err(Error::Read)?;
+
+    let export_error = |source| Error::Export {
+        path: output.to_string(),
+        source,
+    };
+
+    month_total(&expenses, year, month, currency).warn_left_out(currency);
+
+    let file = std::fs::File::create(output).map_err(export_error)?;
+    report_csv(&expenses, year, month, io::BufWriter::new(file), currency).map_err(export_error)?;
+
+    info!("Wrote the {}-{:02} report to '{}'", year, month, output);
+
+    Ok(())
+}
+
+/// Parses an amount as typed by a person, allowing a leading currency symbol and
+/// commas between groups of thousands, e.g. `$1,200.50`
+pub fn parse_amount(value: &str) -> std::result::Result<f64, String> {
+    let invalid = || format!("'{}' is not a valid amount", value);
+
+    let (sign, unsigned) = match value.trim().strip_prefix('-') {
+        Some(rest) => ("-", rest),
+        None => ("", value.trim()),
+    };
+    let unsigned = unsigned.trim_start_matches(['$', '€', '£']);
+
+    let (integer, fraction) = match unsigned.split_once('.') {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (unsigned, None),
+    };
+
+    if integer.contains(',') {
+        let mut groups = integer.split(',');
+        let first = groups.next().unwrap_or_default();
+
+        if first.is_empty() || first.len() > 3 || groups.any(|group| group.len() != 3) {
+            return Err(invalid());
+        }
+    }
+
+    let digits = match fraction {
+        Some(fraction) => format!("{}{}.{}", sign, integer.replace(',', ""), fraction),
+        None => format!("{}{}", sign, integer.replace(',', "")),
+    };
+
+    // Leave out the spellings `f64` accepts but nobody types as an amount
+    if !digits.chars().any(|c| c.is_ascii_digit())
+        || digits.contains(|c: char| c.is_alphabetic() || c == '+')
+    {
+        return Err(invalid());
+    }
+
+    digits.parse().map_err(|_| invalid())
+}
+
+/// Parses an amount that can also be arithmetic, e.g. `3*4.50+2`, see
+/// `amount_expr`. Plain amounts are parsed by `parse_amount`, as they always were.
+pub fn parse_amount_or_expr(value: &str) -> std::result::Result<f64, String> {
+    let arithmetic = value.contains(['+', '*', '/', '(', ')'])
+        || value.trim().trim_start_matches('-').contains('-');
+
+    match parse_amount(value) {
+        Err(_) if arithmetic => amount_expr::eval_amount(value)
+            .map_err(|reason| format!("'{}' is not a valid amount: {}", value, reason)),
+        result => result,
+    }
+}
+
+pub fn parse_date(value: &str) -> std::result::Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map_err(|_| format!("'{}' is not a date in YYYY-MM-DD format", value))
+}
+
+/// Parses a `YYYY-MM` month into the first day of that month
+pub fn parse_year_month(value: &str) -> std::result::Result<NaiveDate, String> {
+    NaiveDate::parse_from_str(&format!("{}-01", value), "%Y-%m-%d")
+        .map_err(|_| format!("'{}' is not a month in YYYY-MM format", value))
+}
+
+/// Returns the given day of the month of `month`, clamped to the month's last day
+fn day_in_month(month: NaiveDate, day: u32) -> NaiveDate {
+    (1..=day)
+        .rev()
+        .find_map(|day| month.with_day(day))
+        .unwrap_or(month)
+}
+
+pub fn add_recurring(
+    description: String,
+    amount: f64,
+    category: Option<String>,
+    day: u32,
+    currency: &str,
+    path: &str,
+) -> Result<(), Error> {
+    validate_amount(amount, false, currency)?;
+
+    let _lock = lock_datastore(path).map_err(Error::Lock)?;
+    let mut datastore = read_datastore(path).map_err(Error::Read)?;
+
+    let id = datastore.recurring.iter().map(|r| r.id).max().unwrap_or(0) + 1;
+
+    datastore.recurring.push(RecurringExpense {
+        id,
+        description,
+        amount: to_minor_units(amount, currency),
+        category,
+        day,
+        active: true,
+        applied: Vec::new(),
+    });
+
+    write_datastore(path, &datastore).map_err(Error::Write)?;
+
+    info!("Recurring expense added successfully with ID: {}", id);
+
+    Ok(())
+}
+
+pub fn list_recurring(currency: &str, path: &str) -> Result<(), Error> {
+    let datastore = read_datastore(path).map_err(Error::Read)?;
+
+    if datastore.recurring.is_empty() {
+        println!("No recurring expenses found");
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+
+    table.set_titles(row![
+        "ID",
+        "Day",
+        "Description",
+        "Category",
+        "Amount",
+        "Active"
+    ]);
+
+    for recurring in &datastore.recurring {
+        table.add_row(row![
+            recurring.id,
+            recurring.day,
+            recurring.description,
+            recurring.category.as_deref().unwrap_or("-"),
+            format_amount(recurring.amount, currency),
+            if recurring.active { "yes" } else { "no" }
+        ]);
+    }
+
+    print_table(&table);
+
+    Ok(())
+}
+
+pub fn set_recurring_active(id: u32, active: bool, path: &str) -> Result<(), Error> {
+    let _lock = lock_datastore(path).map_err(Error::Lock)?;
+    let mut datastore = read_datastore(path).map_err(Error::Read)?;
+
+    let Some(recurring) = datastore.recurring.iter_mut().find(|r| r.id == id) else {
+        info!("No recurring expense found with ID: {}", id);
+        return Ok(());
+    };
+
+    recurring.active = active;
+
+    write_datastore(path, &datastore).map_err(Error::Write)?;
+
+    if active {
+        info!("Recurring expense with ID: '{}' resumed", id);
+    } else {
+        info!("Recurring expense with ID: '{}' paused", id);
+    }
+
+    Ok(())
+}
+
+/// The expense `add --from-template` adds: the template's, or with
+/// `overrides` the one given on the command line with the template's
+/// description, and its amount and category unless others were given. An
+/// amount of zero, which no expense can have, stands for none given.
+pub fn apply_template(template: &Template, overrides: Option<NewExpense>) -> NewExpense {
+    match overrides {
+        Some(new) => NewExpense {
+            description: template.description.clone(),
+            amount: if new.amount == 0.0 {
+                template.amount
+            } else {
+                new.amount
+            },
+            category: new.category.or_else(|| template.category.clone()),
+            ..new
+        },
+        None => NewExpense {
+            description: template.description.clone(),
+            amount: template.amount,
+            date: None,
+            category: template.category.clone(),
+            note: None,
+            tags: Vec::new(),
+            allow_negative: false,
+            refund: false,
+            force: false,
+            random_id: false,
+            threshold: None,
+            // The template says which category it wants
+            no_suggest: true,
+            allow_empty: false,
+            paid_in: None,
+            parse_vendor: false,
+            enforce: false,
+            receipt: None,
+            items: Vec::new(),
+            idempotency_key: None,
+        },
+    }
+}
+
+/// Saves `template` under `name` in the config file, replacing any template
+/// of that name. The rest of the file, comments included, is left as it was.
+pub fn save_template(name: &str, template: &Template, currency: &str) -> Result<(), Error> {
+    validate_amount(template.amount, false, currency)?;
+    normalize_description(&template.description, false)?;
+
+    let path = config_path().ok_or(Error::NoConfigDir)?;
+    let invalid = |reason: String| {
+        Error::Config(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("'{}' is invalid: {}", path.display(), reason),
+        ))
+    };
+
+    let data = match std::fs::read_to_string(&path) {
+        Ok(data) => data,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(error) => return Err(Error::Config(error)),
+    };
+
+    let mut document: toml_edit::DocumentMut = data
+        .parse()
+        .map_err(|error: toml_edit::TomlError| invalid(error.to_string()))?;
+
+    let templates = document
+        .entry("templates")
+        .or_insert_with(|| {
+            let mut table = toml_edit::Table::new();
+            table.set_implicit(true);
+            toml_edit::Item::Table(table)
+        })
+        .as_table_mut()
+        .ok_or_else(|| invalid("`templates` is not a table".to_string()))?;
+
+    let replaced = templates.contains_key(name);
+
+    let mut table = toml_edit::Table::new();
+    table["description"] = toml_edit::value(template.description.as_str());
+    table["amount"] = toml_edit::value(template.amount);
+    if let Some(category) = &template.category {
+        table["category"] = toml_edit::value(category.as_str());
+    }
+    templates.insert(name, toml_edit::Item::Table(table));
+
+    // Don't leave behind a config file the next run can't read
+    let data = document.to_string();
+    toml::from_str::<Config>(&data).map_err(|error| invalid(error.to_string()))?;
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(Error::Config)?;
+    }
+    write_atomically(&path.to_string_lossy(), data.as_bytes()).map_err(Error::Config)?;
+
+    if replaced {
+        info!("Template '{}' replaced", name);
+    } else {
+        info!("Template '{}' saved", name);
+    }
+
+    Ok(())
+}
+
+pub fn list_templates(templates: &BTreeMap<String, Template>, currency: &str) {
+    if templates.is_empty() {
+        println!("No templates found, save one with `template save`");
+        return;
+    }
+
+    let mut table = Table::new();
+
+    table.set_titles(row!["Name", "Description", "Category", "Amount"]);
+
+    for (name, template) in templates {
+        table.add_row(row![
+            name,
+            template.description,
+            template.category.as_deref().unwrap_or("-"),
+            format_amount(to_minor_units(template.amount, currency), currency)
+        ]);
+    }
+
+    print_table(&table);
+}
+
+/// Adds an expense for every active template not yet applied to `month`,
+/// returning the IDs of the new expenses
+pub fn apply_recurring(datastore: &mut Datastore, month: NaiveDate) -> Vec<Id> {
+    let key = month.format("%Y-%m").to_string();
+    let mut added = Vec::new();
+
+    for index in 0..datastore.recurring.len() {
+        let due = &datastore.recurring[index];
+
+        if !due.active || due.applied.contains(&key) {
+            continue;
+        }
+
+        let id = datastore.allocate_id();
+        let recurring = &mut datastore.recurring[index];
+        recurring.applied.push(key.clone());
+
+        datastore.expenses.push(Expense {
+            id: id.clone(),
+            description: recurring.description.clone(),
+            amount: recurring.amount,
+            date: Some(day_in_month(month, recurring.day)),
+            category: recurring.category.clone(),
+            recurring_id: Some(recurring.id),
+            kind: Kind::Expense,
+            note: None,
+            tags: Vec::new(),
+            currency: None,
+            created_at: Some(Utc::now()),
+            vendor: None,
+            deleted_at: None,
+            receipt: None,
+            items: Vec::new(),
+            idempotency_key: None,
+        });
+        added.push(id);
+    }
+
+    added
+}
+
+pub fn apply_recurring_expenses(month: Option<NaiveDate>, path: &str) -> Result<(), Error> {
+    let month = month.unwrap_or_else(|| {
+        let today = Local::now().date_naive();
+        today.with_day(1).unwrap_or(today)
+    });
+
+    let _lock = lock_datastore(path).map_err(Error::Lock)?;
+    let mut datastore = read_datastore(path).map_err(Error::Read)?;
+
+    let added = apply_recurring(&mut datastore, month);
+
+    if added.is_empty() {
+        info!(
+            "No recurring expenses left to apply for {}",
+            month.format("%Y-%m")
+        );
+        return Ok(());
+    }
+
+    write_datastore(path, &datastore).map_err(Error::Write)?;
+    record_history(path, AuditAction::Add, &added);
+
+    info!(
+        "Added {} recurring expense(s) for {}",
+        added.len(),
+        month.format("%Y-%m")
+    );
+
+    Ok(())
+}
+
+/// Outcome of merging incoming expenses into an existing list
+pub struct MergeReport {
+    pub imported: usize,
+    pub duplicates: usize,
+    pub invalid: usize,
+}
+
+/// Appends `incoming` to `existing` with fresh IDs, skipping invalid records and
+/// records with the same description, amount and date as one already present
+pub fn merge_expenses(existing: &mut Datastore, incoming: Vec<Expense>) -> MergeReport {
+    merge_expenses_with_progress(existing, incoming, || {})
+}
+
+/// Same as `merge_expenses`, calling `on_record` after each incoming record
+pub fn merge_expenses_with_progress(
+    existing: &mut Datastore,
+    incoming: Vec<Expense>,
+    mut on_record: impl FnMut(),
+) -> MergeReport {
+    let mut report = MergeReport {
+        imported: 0,
+        duplicates: 0,
+        invalid: 0,
+    };
+
+    for expense in incoming {
+        merge_record(existing, expense, &mut report);
+        on_record();
+    }
+
+    report
+}
+
+/// IDs of `incoming` expenses that another expense in `existing` already uses.
+/// An expense imported again under its own ID is a duplicate, not a collision.
+pub fn id_collisions(existing: &Datastore, incoming: &[Expense]) -> Vec<Id> {
+    incoming
+        .iter()
+        .filter(|expense| {
+            existing
+                .expenses
+                .iter()
+                .any(|e| e.id == expense.id && !same_content(e, expense))
+        })
+        .map(|expense| expense.id.clone())
+        .collect()
+}
+
+/// Like `merge_expenses`, but keeps the incoming IDs. Nothing is merged when
+/// any of them is taken, the error listing which.
+pub fn merge_expenses_preserving_ids(
+    existing: &mut Datastore,
+    incoming: Vec<Expense>,
+) -> Result<MergeReport, Error> {
+    let collisions = id_collisions(existing, &incoming);
+    if !collisions.is_empty() {
+        return Err(Error::IdCollision(collisions));
+    }
+
+    let mut report = MergeReport {
+        imported: 0,
+        duplicates: 0,
+        invalid: 0,
+    };
+
+    for expense in incoming {
+        if expense.amount == 0 {
+            report.invalid += 1;
+        } else if existing.expenses.iter().any(|e| same_content(e, &expense)) {
+            report.duplicates += 1;
+        } else {
+            // Later expenses mustn't be handed a number that's now taken
+            if let Some(number) = expense.id.number() {
+                existing.next_id = existing.next_id.max(number + 1);
+            }
+
+            existing.expenses.push(expense);
+            report.imported += 1;
+        }
+    }
+
+    Ok(report)
+}
+
+fn merge_record(existing: &mut Datastore, mut expense: Expense, report: &mut MergeReport) {
+    if expense.amount == 0 {
+        report.invalid += 1;
+        return;
+    }
+
+    let duplicate = existing.expenses.iter().any(|e| same_content(e, &expense));
+
+    if duplicate {
+        report.duplicates += 1;
+        return;
+    }
+
+    expense.id = existing.allocate_id();
+    existing.expenses.push(expense);
+    report.imported += 1;
+}
+
+/// Archived expenses sit next to the datastore as `<datastore>.archive.json`
+fn archive_path(path: &str) -> String {
+    format!("{}.archive.json", path)
+}
+
+fn history_path(path: &str) -> String {
+    format!("{}.history.jsonl", path)
+}
+
+/// What a change recorded in the history did to an expense
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditAction {
+    Add,
+    Update,
+    Delete,
+    Restore,
+    Purge,
+    Archive,
+    Unarchive,
+    Renumber,
+}
+
+/// One line of the history `history` shows
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    pub at: DateTime<Utc>,
+    pub action: AuditAction,
+    pub id: Id,
+    /// The ID a renumbered expense had before
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<Id>,
+}
+
+/// The history line recording `action` on the expense `id` at `at`, newline included
+pub fn audit_line(action: AuditAction, id: &Id, at: DateTime<Utc>) -> serde_json::Result<String> {
+    let entry = AuditEntry {
+        at,
+        action,
+        id: id.clone(),
+        from: None,
+    };
+
+    Ok(format!("{}\n", serde_json::to_string(&entry)?))
+}
+
+/// The history line recording that the expense `from` was renumbered to `to`
+/// at `at`, newline included
+pub fn renumber_line(from: &Id, to: &Id, at: DateTime<Utc>) -> serde_json::Result<String> {
+    let entry = AuditEntry {
+        at,
+        action: AuditAction::Renumber,
+        id: to.clone(),
+        from: Some(from.clone()),
+    };
+
+    Ok(format!("{}\n", serde_json::to_string(&entry)?))
+}
+
+/// The changes that took the expenses from `before` to `after`, matched by ID,
+/// for commands like `undo` that replace them wholesale
+pub fn audit_changes(before: &[Expense], after: &[Expense]) -> Vec<(AuditAction, Id)> {
+    let find = |expenses: &[Expense], id: &Id| -> Option<Expense> {
+        expenses.iter().find(|expense| expense.id == *id).cloned()
+    };
+    let mut changes = Vec::new();
+
+    for old in before {
+        match find(after, &old.id) {
+            None if old.is_deleted() => changes.push((AuditAction::Purge, old.id.clone())),
+            None => changes.push((AuditAction::Delete, old.id.clone())),
+            Some(new) if new == *old => {}
+            Some(new) => {
+                let action = match (old.is_deleted(), new.is_deleted()) {
+                    (false, true) => AuditAction::Delete,
+                    (true, false) => AuditAction::Restore,
+                    _ => AuditAction::Update,
+                };
+                changes.push((action, new.id));
+            }
+        }
+    }
+
+    for new in after {
+        if find(before, &new.id).is_none() {
+            changes.push((AuditAction::Add, new.id.clone()));
+        }
+    }
+
+    changes
+}
+
+/// Appends `action` on each of `ids` to the datastore's history. The change
+/// itself is already written, so failing to record it only warns.
+fn record_history(path: &str, action: AuditAction, ids: &[Id]) {
+    let at = Utc::now();
+    let lines = ids.iter().map(|id| audit_line(action, id, at)).collect();
+
+    append_history(path, lines);
+}
+
+/// Records the changes `audit_changes` finds between `before` and `after`
+fn record_changes(path: &str, before: &[Expense], after: &[Expense]) {
+    let at = Utc::now();
+    let lines = audit_changes(before, after)
+        .iter()
+        .map(|(action, id)| audit_line(*action, id, at))
+        .collect();
+
+    append_history(path, lines);
+}
+
+/// Records each renumbering in `renumbered`, old ID first
+fn record_renumbering(path: &str, renumbered: &[(Id, Id)]) {
+    let at = Utc::now();
+    let lines = renumbered
+        .iter()
+        .map(|(from, to)| renumber_line(from, to, at))
+        .collect();
+
+    append_history(path, lines);
+}
+
+fn append_history(path: &str, lines: serde_json::Result<String>) {
+    let history = history_path(path);
+
+    let result = lines.map_err(io::Error::from).and_then(|lines| {
+        if lines.is_empty() {
+            return Ok(());
+        }
+
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&history)?
+            .write_all(lines.as_bytes())
+    });
+
+    if let Err(error) = result {
+        eprintln!("Warning: couldn't add the change to {}: {}", history, error);
+    }
+}
+
+/// Lists the changes recorded in the datastore's history, oldest first, only
+/// those to the expense `id` if given
+pub fn show_history(id: Option<&Id>, path: &str) -> Result<(), Error> {
+    let history = history_path(path);
+
+    let data = match std::fs::read_to_string(&history) {
+        Ok(data) => data,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(error) => return Err(Error::Read(error)),
+    };
+
+    let mut table = Table::new();
+    table.set_titles(row!["When", "Action", "ID"]);
+
+    for (number, line) in data.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let Ok(entry) = serde_json::from_str::<AuditEntry>(line) else {
+            eprintln!(
+                "Warning: skipped line {} of {}, it isn't a change",
+                number + 1,
+                history
+            );
+            continue;
+        };
+
+        // A renumbered expense's history goes on under its new ID
+        if id.is_some_and(|id| *id != entry.id && entry.from.as_ref() != Some(id)) {
+            continue;
+        }
+
+        let action = match entry.action {
+            AuditAction::Add => "added".to_string(),
+            AuditAction::Update => "updated".to_string(),
+            AuditAction::Delete => "deleted".to_string(),
+            AuditAction::Restore => "restored".to_string(),
+            AuditAction::Purge => "purged".to_string(),
+            AuditAction::Archive => "archived".to_string(),
+            AuditAction::Unarchive => "unarchived".to_string(),
+            AuditAction::Renumber => match &entry.from {
+                Some(from) => format!("renumbered from {}", from),
+                None => "renumbered".to_string(),
+            },
+        };
+
+        table.add_row(row![
+            entry.at.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S"),
+            action,
+            r->entry.id
+        ]);
+    }
+
+    if table.is_empty() {
+        match id {
+            Some(id) => println!("No changes recorded for expense with ID: {}", id),
+            None => println!("No changes recorded yet"),
+        }
+        return Ok(());
+    }
+
+    print_table(&table);
+
+    Ok(())
+}
+
+fn read_archive(archive: &str) -> io::Result<Vec<Expense>> {
+    match std::fs::read_to_string(archive) {
+        Ok(data) => serde_json::from_str(&data).map_err(|error| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("archive '{}' is corrupt: {}", archive, error),
+            )
+        }),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(error) => Err(error),
+    }
+}
+
+/// Moves the expenses dated before `before` to the archive, adding to what's
+/// already there
+///
+/// The archive is written before the datastore, so a crash in between leaves
+/// the expenses in both, and `unarchive` skips them as duplicates.
+pub fn archive_expenses(before: NaiveDate, path: &str) -> Result<(), Error> {
+    let _lock = lock_datastore(path).map_err(Error::Lock)?;
+    let mut datastore = read_datastore(path).map_err(Error::Read)?;
+
+    let (old, kept): (Vec<Expense>, Vec<Expense>) = datastore
+        .expenses
+        .into_iter()
+        .partition(|expense| expense.date.is_some_and(|date| date < before));
+    datastore.expenses = kept;
+
+    if old.is_empty() {
+        info!("No expenses dated before {}", before);
+        return Ok(());
+    }
+
+    let archive = archive_path(path);
+    let mut archived = read_archive(&archive).map_err(Error::Read)?;
+    archived.extend(old.iter().cloned());
+
+    let data = serde_json::to_string_pretty(&archived).map_err(Error::Serialize)?;
+    write_atomically(&archive, data.as_bytes()).map_err(Error::Write)?;
+
+    write_datastore(path, &datastore).map_err(Error::Write)?;
+
+    let ids: Vec<Id> = old.iter().map(|expense| expense.id.clone()).collect();
+    record_history(path, AuditAction::Archive, &ids);
+
+    info!(
+        "Archived {} expense(s) dated before {} to '{}'",
+        old.len(),
+        before,
+        archive
+    );
+
+    Ok(())
+}
+
+/// Merges the archive back into the datastore with fresh IDs and removes it
+pub fn unarchive_expenses(path: &str) -> Result<(), Error> {
+    let _lock = lock_datastore(path).map_err(Error::Lock)?;
+
+    let archive = archive_path(path);
+    let archived = read_archive(&archive).map_err(Error::Read)?;
+
+    if archived.is_empty() {
+        info!("Nothing archived at '{}'", archive);
+        return Ok(());
+    }
+
+    let mut datastore = read_datastore(path).map_err(Error::Read)?;
+    let existing = datastore.expenses.len();
+    let report = merge_expenses(&mut datastore, archived);
+
+    write_datastore(path, &datastore).map_err(Error::Write)?;
+
+    // Merged expenses are added at the end, under new IDs
+    let ids: Vec<Id> = datastore.expenses[existing..]
+        .iter()
+        .map(|expense| expense.id.clone())
+        .collect();
+    record_history(path, AuditAction::Unarchive, &ids);
+
+    // Only drop the archive once its expenses are safely in the datastore
+    std::fs::remove_file(&archive).map_err(Error::Write)?;
+
+    info!(
+        "Restored {} archived expense(s), skipped {} duplicate(s) and {} invalid record(s)",
+        report.imported, report.duplicates, report.invalid
+    );
+
+    Ok(())
+}
+
+/// Whether two expenses have the same description, amount and date, which is
+/// how expenses are told apart across datastores whose IDs don't line up
+fn same_content(a: &Expense, b: &Expense) -> bool {
+    a.description == b.description && a.amount == b.amount && a.date == b.date
+}
+
+/// How two lists of expenses differ, see `diff_expenses`
+pub struct ExpenseDiff {
+    pub only_ours: Vec<Expense>,
+    pub only_theirs: Vec<Expense>,
+    /// Expenses with the same ID but different contents, ours first
+    pub changed: Vec<(Expense, Expense)>,
+}
+
+/// Compares two lists of expenses. Expenses with the same description, amount
+/// and date are the same expense whatever their IDs; of the remaining ones,
+/// those sharing an ID are the same expense edited on one side.
+pub fn diff_expenses(ours: &[Expense], theirs: &[Expense]) -> ExpenseDiff {
+    let mut unmatched: Vec<&Expense> = theirs.iter().collect();
+    let mut unpaired = Vec::new();
+
+    for expense in ours {
+        match unmatched
+            .iter()
+            .position(|other| same_content(expense, other))
+        {
+            Some(index) => {
+                unmatched.remove(index);
+            }
+            None => unpaired.push(expense),
+        }
+    }
+
+    let mut only_ours = Vec::new();
+    let mut changed = Vec::new();
+
+    for expense in unpaired {
+        match unmatched.iter().position(|other| other.id == expense.id) {
+            Some(index) => changed.push((expense.clone(), unmatched.remove(index).clone())),
+            None => only_ours.push(expense.clone()),
+        }
+    }
+
+    ExpenseDiff {
+        only_ours,
+        only_theirs: unmatched.into_iter().cloned().collect(),
+        changed,
+    }
+}
+
+/// Describes the fields `theirs` changed from `ours`, e.g. `amount: $3.50 -> $4.00`
+fn changed_fields(ours: &Expense, theirs: &Expense, currency: &str) -> Vec<String> {
+    let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+    let mut changes = Vec::new();
+
+    let mut compare = |field: &str, ours: String, theirs: String| {
+        if ours != theirs {
+            changes.push(format!("{}: {} -> {}", field, ours, theirs));
+        }
+    };
+
+    compare(
+        "description",
+        ours.description.clone(),
+        theirs.description.clone(),
+    );
+    compare(
+        "amount",
+        format_amount(ours.amount, currency),
+        format_amount(theirs.amount, currency),
+    );
+    compare(
+        "date",
+        or_dash(ours.date.map(format_date)),
+        or_dash(theirs.date.map(format_date)),
+    );
+    compare(
+        "category",
+        or_dash(ours.category.clone()),
+        or_dash(theirs.category.clone()),
+    );
+    compare("kind", ours.kind.to_string(), theirs.kind.to_string());
+    let tags =
+        |expense: &Expense| or_dash(Some(expense.tags.join(", ")).filter(|tags| !tags.is_empty()));
+    compare("tags", tags(ours), tags(theirs));
+    compare(
+        "note",
+        or_dash(ours.note.clone()),
+        or_dash(theirs.note.clone()),
+    );
+
+    changes
+}
+
+/// Prints the expenses only in the datastore, only in `against` and changed
+/// between them, e.g. before importing one into the other
+pub fn diff_datastores(against: &str, currency: &str, path: &str) -> Result<(), Error> {
+    let ours = read_expenses(path).map_err(Error::Read)?;
+
+    // Unlike the datastore itself, a missing file to compare against is an error
+    let mut theirs = read_datastore(against)
+        .map_err(|source| Error::Compare {
+            path: against.to_string(),
+            source,
+        })?
+        .expenses;
+    theirs.retain(|expense| !expense.is_deleted());
+
+    let diff = diff_expenses(&ours, &theirs);
+
+    if diff.only_ours.is_empty() && diff.only_theirs.is_empty() && diff.changed.is_empty() {
+        println!("Both datastores have the same expenses");
+        return Ok(());
+    }
+
+    let print_section = |title: String, expenses: &[Expense]| {
+        println!("{} ({}):", title, expenses.len());
+
+        for expense in expenses {
+            println!(
+                "  {}: {} ({}, {})",
+                expense.id,
+                expense.description,
+                format_amount(expense.amount, currency),
+                expense
+                    .date
+                    .map_or_else(|| "no date".to_string(), format_date)
+            );
+        }
+    };
+
+    print_section(format!("Only in '{}'", path), &diff.only_ours);
+    print_section(format!("Only in '{}'", against), &diff.only_theirs);
+
+    println!("Changed ({}):", diff.changed.len());
+    for (ours, theirs) in &diff.changed {
+        println!(
+            "  {}: {}",
+            ours.id,
+            changed_fields(ours, theirs, currency).join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// How tracked expenses line up with the rows of a bank statement, see
+/// `reconcile_expenses`
+pub struct Reconciliation {
+    /// Tracked expenses with the bank row they were matched with
+    pub matched: Vec<(Expense, Expense)>,
+    pub only_tracked: Vec<Expense>,
+    pub only_bank: Vec<Expense>,
+}
+
+/// Pairs tracked expenses with bank rows of the same amount dated at most
+/// `tolerance_days` apart, ignoring the sign since banks often show spending
+/// as negative. Each expense and row is used once, the closest dates first;
+/// undated expenses never match.
+pub fn reconcile_expenses(
+    tracked: &[Expense],
+    bank: &[Expense],
+    tolerance_days: u32,
+) -> Reconciliation {
+    let mut candidates = Vec::new();
+
+    for (t, expense) in tracked.iter().enumerate() {
+        for (b, row) in bank.iter().enumerate() {
+            let (Some(date), Some(bank_date)) = (expense.date, row.date) else {
+                continue;
+            };
+            let gap = (date - bank_date).num_days().unsigned_abs();
+
+            if expense.amount.abs() == row.amount.abs() && gap <= u64::from(tolerance_days) {
+                candidates.push((gap, t, b));
+            }
+        }
+    }
+
+    // Closest dates first, then file order, so the result doesn't depend on chance
+    candidates.sort_unstable();
+
+    let mut tracked_used = vec![false; tracked.len()];
+    let mut bank_used = vec![false; bank.len()];
+    let mut pairs = Vec::new();
+
+    for (_, t, b) in candidates {
+        if !tracked_used[t] && !bank_used[b] {
+            tracked_used[t] = true;
+            bank_used[b] = true;
+            pairs.push((t, b));
+        }
+    }
+
+    // In the order the expenses were tracked
+    pairs.sort_unstable();
+    let matched = pairs
+        .into_iter()
+        .map(|(t, b)| (tracked[t].clone(), bank[b].clone()))
+        .collect();
+
+    let unused = |expenses: &[Expense], used: &[bool]| {
+        expenses
+            .iter()
+            .zip(used)
+            .filter(|(_, used)| !**used)
+            .map(|(expense, _)| expense.clone())
+            .collect()
+    };
+
+    Reconciliation {
+        only_tracked: unused(tracked, &tracked_used),
+        only_bank: unused(bank, &bank_used),
+        matched,
+    }
+}
+
+/// Reconciles the datastore with a bank CSV, only looking at the tracked
+/// expenses dated within the statement's dates, widened by the tolerance
+pub fn reconcile(
+    file: &str,
+    map: &ColumnMap,
+    skip_header: bool,
+    tolerance_days: u32,
+    currency: &str,
+    path: &str,
+) -> Result<(), Error> {
+    let import_error = |source| Error::Import {
+        path: file.to_string(),
+        source,
+    };
+
+    let (bank, failures) = if file == "-" {
+        parse_csv_expenses(io::stdin().lock(), map, skip_header, currency).map_err(import_error)?
+    } else {
+        let reader = io::BufReader::new(std::fs::File::open(file).map_err(import_error)?);
+        parse_csv_expenses(reader, map, skip_header, currency).map_err(import_error)?
+    };
+
+    for (line, reason) in &failures {
+        eprintln!("Line {}: {}", line, reason);
+    }
+
+    let dates = || bank.iter().filter_map(|row| row.date);
+    let (Some(first), Some(last)) = (dates().min(), dates().max()) else {
+        println!("No bank rows to reconcile");
+        return Ok(());
+    };
+    let slack = chrono::Days::new(u64::from(tolerance_days));
+    let (first, last) = (first - slack, last + slack);
+
+    let mut tracked = read_expenses(path).map_err(Error::Read)?;
+    tracked.retain(|expense| {
+        expense
+            .date
+            .is_some_and(|date| date >= first && date <= last)
+    });
+
+    let reconciliation = reconcile_expenses(&tracked, &bank, tolerance_days);
+
+    let describe = |expense: &Expense| {
+        format!(
+            "{} ({}, {})",
+            expense.description,
+            format_amount(expense.amount, currency),
+            expense
+                .date
+                .map_or_else(|| "no date".to_string(), format_date)
+        )
+    };
+
+    println!("Matched ({}):", reconciliation.matched.len());
+    for (expense, row) in &reconciliation.matched {
+        println!(
+            "  {}: {} with {}",
+            expense.id,
+            describe(expense),
+            describe(row)
+        );
+    }
+
+    println!("Only tracked ({}):", reconciliation.only_tracked.len());
+    for expense in &reconciliation.only_tracked {
+        println!("  {}: {}", expense.id, describe(expense));
+    }
+
+    println!("Only in '{}' ({}):", file, reconciliation.only_bank.len());
+    for row in &reconciliation.only_bank {
+        println!("  {}", describe(row));
+    }
+
+    if !failures.is_empty() {
+        eprintln!(
+            "{} line(s) couldn't be read and were skipped",
+            failures.len()
+        );
+    }
+
+    Ok(())
+}
+
+/// Imports with more records than this show a progress bar
+const PROGRESS_THRESHOLD: usize = 100;
+
+pub fn import_expenses(
+    file: &str,
+    format: ImportFormat,
+    map: &ColumnMap,
+    skip_header: bool,
+    preserve_ids: bool,
+    currency: &str,
+    path: &str,
+) -> Result<(), Error> {
+    let import_error = |source| Error::Import {
+        path: file.to_string(),
+        source,
+    };
+
+    if preserve_ids && !matches!(format, ImportFormat::Datastore) {
+        return Err(import_error(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "--preserve-ids needs a datastore to import, CSV rows have no IDs",
+        )));
+    }
+
+    let (incoming, failures) = match format {
+        ImportFormat::Datastore => (read_expenses(file).map_err(import_error)?, Vec::new()),
+        ImportFormat::Csv if file == "-" => {
+            parse_csv_expenses(io::stdin().lock(), map, skip_header, currency)
+                .map_err(import_error)?
+        }
+        ImportFormat::Csv => {
+            let reader = io::BufReader::new(std::fs::File::open(file).map_err(import_error)?);
+            parse_csv_expenses(reader, map, skip_header, currency).map_err(import_error)?
+        }
+    };
+
+    for (line, reason) in &failures {
+        eprintln!("Line {}: {}", line, reason);
+    }
+
+    let _lock = lock_datastore(path).map_err(Error::Lock)?;
+    let mut datastore = read_datastore(path).map_err(Error::Read)?;
+
+    // A bar only helps for big interactive imports, and would garble piped output
+    let show_progress = incoming.len() > PROGRESS_THRESHOLD
+        && io::stdout().is_terminal()
+        && verbosity() >= Verbosity::Normal;
+
+    let existing = datastore.expenses.len();
+    let report = if preserve_ids {
+        merge_expenses_preserving_ids(&mut datastore, incoming)?
+    } else if show_progress {
+        let bar = ProgressBar::new(incoming.len() as u64);
+        let report = merge_expenses_with_progress(&mut datastore, incoming, || bar.inc(1));
+        bar.finish_and_clear();
+        report
+    } else {
+        merge_expenses(&mut datastore, incoming)
+    };
+
+    if report.imported > 0 {
+        write_datastore(path, &datastore).map_err(Error::Write)?;
+
+        // Imported expenses are added at the end
+        let ids: Vec<Id> = datastore.expenses[existing..]
+            .iter()
+            .map(|expense| expense.id.clone())
+            .collect();
+        record_history(path, AuditAction::Add, &ids);
+    }
+
+    info!(
+        "Imported {} expense(s), skipped {} duplicate(s) and {} invalid record(s)",
+        report.imported, report.duplicates, report.invalid
+    );
+
+    if !failures.is_empty() {
+        eprintln!(
+            "{} line(s) couldn't be read and were skipped",
+            failures.len()
+        );
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn expense(id: u32, description: &str, amount: i64) -> Expense {
+        Expense {
+            id: Id::from(id),
+            description: description.to_string(),
+            amount,
+            date: None,
+            category: None,
+            recurring_id: None,
+            kind: Kind::Expense,
+            note: None,
+            tags: Vec::new(),
+            currency: None,
+            created_at: None,
+            vendor: None,
+            deleted_at: None,
+            receipt: None,
+            items: Vec::new(),
+            idempotency_key: None,
+        }
+    }
+
+    fn date(text: &str) -> NaiveDate {
+        text.parse().unwrap()
+    }
+
+    fn export(expenses: &[Expense]) -> String {
+        let mut buffer = Vec::new();
+        export_csv(expenses.iter().cloned().map(Ok), &mut buffer, "USD").unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn export_csv_writes_a_header_and_a_row_per_expense() {
+        let mut coffee = expense(1, "Coffee", 350);
+        coffee.date = Some(date("2024-03-01"));
+        coffee.category = Some("food".to_string());
+
+        assert_eq!(
+            export(&[coffee, expense(2, "Lunch", 1200)]),
+            "id,description,amount,date,category,kind\r\n\
+             1,Coffee,3.50,2024-03-01,food,Expense\r\n\
+             2,Lunch,12.00,,,Expense\r\n"
+        );
+    }
+
+    #[test]
+    fn export_csv_quotes_commas_quotes_and_newlines() {
+        let csv = export(&[
+            expense(1, "Bread, milk", 100),
+            expense(2, "The \"good\" coffee", 200),
+            expense(3, "Two\nlines", 300),
+        ]);
+
+        let rows: Vec<&str> = csv.split("\r\n").collect();
+        assert_eq!(rows[1], "1,\"Bread, milk\",1.00,,,Expense");
+        assert_eq!(rows[2], "2,\"The \"\"good\"\" coffee\",2.00,,,Expense");
+        assert_eq!(rows[3], "3,\"Two\nlines\",3.00,,,Expense");
+    }
+
+    #[test]
+    fn export_csv_counts_what_it_wrote() {
+        let mut buffer = Vec::new();
+        let expenses = [expense(1, "Coffee", 350), expense(2, "Lunch", 1200)];
+
+        let count = export_csv(expenses.into_iter().map(Ok), &mut buffer, "USD").unwrap();
+
+        assert_eq!(count, 2);
+    }
+
+    fn answer(text: &str) -> bool {
+        confirm("Delete this expense?", &mut io::Cursor::new(text)).unwrap()
+    }
+
+    #[test]
+    fn confirm_takes_y_and_yes_in_any_case() {
+        assert!(answer("y\n"));
+        assert!(answer("yes\n"));
+        assert!(answer("  YES \n"));
+        assert!(answer("Y"));
+    }
+
+    #[test]
+    fn confirm_takes_anything_else_as_a_no() {
+        assert!(!answer("n\n"));
+        assert!(!answer("no\n"));
+        assert!(!answer("\n"));
+        assert!(!answer("yep\n"));
+        assert!(!answer(""));
+    }
+
+    /// A datastore path in a directory removed with the guard
+    fn datastore_path(name: &str) -> (tempfile::TempDir, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(name).to_str().unwrap().to_string();
+        (dir, path)
+    }
+
+    #[test]
+    fn a_failure_before_the_rename_keeps_the_old_contents() {
+        let (_dir, path) = datastore_path("expenses.json");
+        write_atomically(&path, b"old").unwrap();
+
+        let result = write_then_rename(&path, b"new", |_, _| {
+            Err(io::Error::other("killed before the rename"))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(std::fs::read(&path).unwrap(), b"old");
+        // The next write replaces what the failed one left behind
+        write_atomically(&path, b"newer").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"newer");
+        assert!(!std::path::Path::new(&format!("{}.tmp", path)).exists());
+    }
+
+    #[test]
+    fn a_crash_mid_write_leaves_the_datastore_readable() {
+        let (_dir, path) = datastore_path("expenses.json");
+        let datastore = Datastore {
+            next_id: 2,
+            expenses: vec![expense(1, "Coffee", 350)],
+            recurring: Vec::new(),
+        };
+        write_datastore(&path, &datastore).unwrap();
+
+        // What a process killed halfway through writing the temporary file leaves
+        std::fs::write(format!("{}.tmp", path), "{\"next_id\": 3, \"expen").unwrap();
+
+        let read = read_datastore(&path).unwrap();
+        assert_eq!(read.expenses, datastore.expenses);
+    }
+
+    fn datastore(expenses: Vec<Expense>) -> Datastore {
+        let next_id = expenses
+            .iter()
+            .filter_map(|expense| expense.id.number())
+            .max()
+            .unwrap_or(0)
+            + 1;
+
+        Datastore {
+            next_id,
+            expenses,
+            recurring: Vec::new(),
+        }
+    }
+
+    fn ids(expenses: &[Expense]) -> Vec<Id> {
+        expenses.iter().map(|expense| expense.id.clone()).collect()
+    }
+
+    #[test]
+    fn merge_expenses_hands_out_fresh_ids() {
+        let mut existing = datastore(vec![expense(1, "Coffee", 350), expense(2, "Lunch", 1200)]);
+
+        let report = merge_expenses(
+            &mut existing,
+            vec![expense(1, "Train", 900), expense(2, "Book", 1500)],
+        );
+
+        assert_eq!(report.imported, 2);
+        assert_eq!(ids(&existing.expenses), [1, 2, 3, 4].map(Id::from));
+        assert_eq!(existing.expenses[2].description, "Train");
+        assert_eq!(existing.next_id, 5);
+    }
+
+    #[test]
+    fn merge_expenses_skips_duplicates_and_invalid_records() {
+        let mut coffee = expense(1, "Coffee", 350);
+        coffee.date = Some(date("2024-03-01"));
+        let mut existing = datastore(vec![coffee.clone()]);
+
+        let mut same_coffee = coffee.clone();
+        same_coffee.id = Id::from(7);
+        let mut other_day = coffee.clone();
+        other_day.date = Some(date("2024-03-02"));
+
+        let report = merge_expenses(
+            &mut existing,
+            vec![same_coffee, other_day, expense(9, "Nothing", 0)],
+        );
+
+        assert_eq!(report.imported, 1);
+        assert_eq!(report.duplicates, 1);
+        assert_eq!(report.invalid, 1);
+        assert_eq!(existing.expenses.len(), 2);
+    }
+
+    #[test]
+    fn merge_expenses_skips_duplicates_within_the_import() {
+        let mut existing = datastore(Vec::new());
+
+        let report = merge_expenses(
+            &mut existing,
+            vec![expense(1, "Coffee", 350), expense(2, "Coffee", 350)],
+        );
+
+        assert_eq!(report.imported, 1);
+        assert_eq!(report.duplicates, 1);
+    }
+
+    fn read_error(contents: &str) -> io::Error {
+        let (_dir, path) = datastore_path("expenses.json");
+        std::fs::write(&path, contents).unwrap();
+
+        read_expenses(&path).unwrap_err()
+    }
+
+    #[test]
+    fn malformed_json_is_reported_as_corrupt() {
+        let error = read_error("{not json}");
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let message = error.to_string();
+        assert!(message.contains("is corrupt (invalid JSON"), "{}", message);
+        assert!(message.contains("run `clear` to start over"), "{}", message);
+    }
+
+    #[test]
+    fn an_object_without_expenses_is_reported_as_corrupt() {
+        let error = read_error("{}");
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        let message = error.to_string();
+        assert!(
+            message.contains("\"expenses\" is a required property"),
+            "{}",
+            message
+        );
+        assert!(message.contains("run `clear` to start over"), "{}", message);
+    }
+
+    #[test]
+    fn compute_stats_finds_the_extremes_and_the_average() {
+        let expenses = [
+            expense(1, "Coffee", 350),
+            expense(2, "Rent", 90000),
+            expense(3, "Gum", 99),
+        ];
+
+        let stats = compute_stats(&expenses).unwrap();
+
+        assert_eq!(stats.count, 3);
+        assert_eq!(stats.total, 90449);
+        assert_eq!(stats.average, 30150);
+        assert_eq!(stats.cheapest.description, "Gum");
+        assert_eq!(stats.most_expensive.description, "Rent");
+    }
+
+    #[test]
+    fn compute_stats_rounds_the_average_to_the_cent() {
+        let expenses = [
+            expense(1, "A", 100),
+            expense(2, "B", 100),
+            expense(3, "C", 101),
+        ];
+
+        assert_eq!(compute_stats(&expenses).unwrap().average, 100);
+    }
+
+    #[test]
+    fn compute_stats_has_nothing_for_no_expenses() {
+        assert!(compute_stats(&[]).is_none());
+    }
+
+    fn in_category(mut expense: Expense, category: &str) -> Expense {
+        expense.category = Some(category.to_string());
+        expense
+    }
+
+    #[test]
+    fn totals_by_category_adds_up_each_category() {
+        let mut salary = in_category(expense(5, "Salary", 300000), "food");
+        salary.kind = Kind::Income;
+
+        let totals = totals_by_category(&[
+            in_category(expense(1, "Coffee", 350), "food"),
+            in_category(expense(2, "Lunch", 1200), "food"),
+            in_category(expense(3, "Train", 900), "travel"),
+            expense(4, "Gift", 2000),
+            salary,
+        ]);
+
+        assert_eq!(totals.len(), 3);
+        assert_eq!(totals["food"], 1550);
+        assert_eq!(totals["travel"], 900);
+        assert_eq!(totals[UNCATEGORIZED], 2000);
+    }
+
+    #[test]
+    fn totals_by_category_is_empty_without_expenses() {
+        assert!(totals_by_category(&[]).is_empty());
+    }
+
+    #[derive(clap::Parser)]
+    struct Add {
+        #[command(flatten)]
+        new: NewExpense,
+    }
+
+    /// A `NewExpense` as `add` would parse `args`, without its prompts
+    fn new_expense(args: &[&str]) -> NewExpense {
+        use clap::Parser;
+
+        let args = ["add", "--force", "--no-suggest"].iter().chain(args);
+        Add::parse_from(args).new
+    }
+
+    #[test]
+    fn concurrent_adds_all_survive() {
+        let (_dir, path) = datastore_path("expenses.json");
+        init_datastore(&path).unwrap();
+
+        let threads: Vec<_> = ["Coffee", "Lunch"]
+            .into_iter()
+            .map(|description| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    let new = new_expense(&["--description", description, "--amount", "2"]);
+                    for _ in 0..10 {
+                        add_expense(&new, Kind::Expense, "USD", false, &path).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let expenses = read_expenses(&path).unwrap();
+        assert_eq!(expenses.len(), 20);
+        let mut ids = ids(&expenses);
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), 20);
+    }
+
+    fn dated(mut expense: Expense, on: &str) -> Expense {
+        expense.date = Some(date(on));
+        expense
+    }
+
+    #[test]
+    fn report_csv_writes_the_month_by_category() {
+        let expenses = [
+            dated(in_category(expense(1, "Coffee", 350), "food"), "2024-03-01"),
+            dated(in_category(expense(2, "Lunch", 1200), "food"), "2024-03-15"),
+            dated(
+                in_category(expense(3, "Train, return", 900), "travel, work"),
+                "2024-03-31",
+            ),
+            dated(expense(4, "Gift", 2000), "2024-03-10"),
+            dated(
+                in_category(expense(5, "Dinner", 4000), "food"),
+                "2024-04-01",
+            ),
+            in_category(expense(6, "Undated", 100), "food"),
+        ];
+        let mut buffer = Vec::new();
+
+        report_csv(&expenses, 2024, 3, &mut buffer, "USD").unwrap();
+
+        assert_eq!(
+            buffer,
+            b"category,total\r\n\
+              (uncategorized),20.00\r\n\
+              food,15.50\r\n\
+              \"travel, work\",9.00\r\n\
+              TOTAL,44.50\r\n"
+        );
+    }
+
+    #[test]
+    fn report_csv_of_an_empty_month_only_has_the_total() {
+        let expenses = [dated(expense(1, "Coffee", 350), "2024-03-01")];
+        let mut buffer = Vec::new();
+
+        report_csv(&expenses, 2024, 2, &mut buffer, "USD").unwrap();
+
+        assert_eq!(buffer, b"category,total\r\nTOTAL,0.00\r\n");
+    }
+
+    #[test]
+    fn is_similar_needs_the_same_description_and_amount() {
+        let coffee = expense(1, "Coffee", 350);
+
+        assert!(is_similar(&coffee, &expense(2, "Coffee", 350)));
+        assert!(!is_similar(&coffee, &expense(2, "Coffee", 351)));
+        assert!(!is_similar(&coffee, &expense(2, "coffee", 350)));
+    }
+
+    #[test]
+    fn is_similar_compares_dates_only_when_both_have_one() {
+        let coffee = dated(expense(1, "Coffee", 350), "2024-03-01");
+
+        assert!(is_similar(
+            &coffee,
+            &dated(expense(2, "Coffee", 350), "2024-03-01")
+        ));
+        assert!(!is_similar(
+            &coffee,
+            &dated(expense(2, "Coffee", 350), "2024-03-02")
+        ));
+        assert!(is_similar(&coffee, &expense(2, "Coffee", 350)));
+        assert!(is_similar(&expense(2, "Coffee", 350), &coffee));
+    }
+
+    fn markdown_fixture() -> Vec<Expense> {
+        let mut coffee = dated(in_category(expense(1, "Coffee", 350), "food"), "2024-03-01");
+        coffee.tags = vec!["work".to_string()];
+
+        vec![
+            coffee,
+            dated(expense(12, "Rent | March", 90000), "2024-03-02"),
+        ]
+    }
+
+    #[test]
+    fn render_markdown_pads_and_aligns_the_columns() {
+        let markdown = render_markdown(&markdown_fixture(), "USD", true, RowOptions::default());
+
+        assert_eq!(
+            markdown,
+            "|  ID | Date       | Description   | Category | Tags | Kind    |  Amount |\n\
+             | --: | ---------- | ------------- | -------- | ---- | ------- | ------: |\n\
+             |   1 | 2024-03-01 | Coffee        | food     | work | Expense |   $3.50 |\n\
+             |  12 | 2024-03-02 | Rent \\| March | -        |      | Expense | $900.00 |\n\
+             |     |            | TOTAL         |          |      |         | $903.50 |\n"
+        );
+    }
+
+    #[test]
+    fn render_markdown_without_header_leaves_out_the_separator() {
+        let markdown = render_markdown(&markdown_fixture(), "USD", false, RowOptions::default());
+
+        let lines: Vec<&str> = markdown.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(
+            lines[0],
+            "|   1 | 2024-03-01 | Coffee        | food | work | Expense |   $3.50 |"
+        );
+    }
+
+    #[test]
+    fn levenshtein_counts_single_character_edits() {
+        assert_eq!(levenshtein("coffee", "coffee"), 0);
+        assert_eq!(levenshtein("cofee", "coffee"), 1);
+        assert_eq!(levenshtein("coffee", "cofee"), 1);
+        assert_eq!(levenshtein("coffee", "toffee"), 1);
+        assert_eq!(levenshtein("kitten", "sitting"), 3);
+        assert_eq!(levenshtein("flaw", "lawn"), 2);
+    }
+
+    #[test]
+    fn levenshtein_against_an_empty_string_is_the_length() {
+        assert_eq!(levenshtein("", ""), 0);
+        assert_eq!(levenshtein("", "tea"), 3);
+        assert_eq!(levenshtein("tea", ""), 3);
+    }
+
+    #[test]
+    fn levenshtein_counts_characters_not_bytes() {
+        assert_eq!(levenshtein("café", "cafe"), 1);
+        assert_eq!(levenshtein("☕", "🍵"), 1);
+    }
+
+    #[test]
+    fn closest_description_only_suggests_near_matches() {
+        let descriptions = ["Coffee", "Lunch", "Train ticket"];
+
+        assert_eq!(closest_description("cofee", descriptions), Some("Coffee"));
+        assert_eq!(closest_description("lnch", descriptions), Some("Lunch"));
+        assert_eq!(closest_description("groceries", descriptions), None);
+    }
+
+    fn ways(count: u32) -> NonZeroU32 {
+        NonZeroU32::new(count).unwrap()
+    }
+
+    #[test]
+    fn split_expense_divides_the_amount_and_notes_it() {
+        let mut expenses = vec![expense(1, "Dinner", 9000), expense(2, "Taxi", 1500)];
+
+        assert!(matches!(
+            split_expense(&mut expenses, &Id::from(1), ways(3)),
+            Ok(9000)
+        ));
+
+        assert_eq!(expenses[0].amount, 3000);
+        assert_eq!(expenses[0].description, "Dinner (split 3 ways)");
+        assert_eq!(expenses[1].amount, 1500);
+    }
+
+    #[test]
+    fn split_expense_rounds_to_the_nearest_cent() {
+        let mut expenses = vec![expense(1, "Dinner", 1000), expense(2, "Taxi", 1001)];
+
+        split_expense(&mut expenses, &Id::from(1), ways(3)).unwrap();
+        split_expense(&mut expenses, &Id::from(2), ways(2)).unwrap();
+
+        assert_eq!(expenses[0].amount, 333);
+        assert_eq!(expenses[1].amount, 501);
+    }
+
+    #[test]
+    fn split_expense_leaves_missing_and_deleted_expenses_alone() {
+        let mut deleted = expense(2, "Taxi", 1500);
+        deleted.deleted_at = Some(Utc::now());
+        let mut expenses = vec![expense(1, "Dinner", 9000), deleted];
+
+        assert!(matches!(
+            split_expense(&mut expenses, &Id::from(2), ways(2)),
+            Err(Error::ExpenseNotFound(_))
+        ));
+        assert!(matches!(
+            split_expense(&mut expenses, &Id::from(3), ways(2)),
+            Err(Error::ExpenseNotFound(_))
+        ));
+        assert_eq!(expenses[1].amount, 1500);
+    }
+
+    #[test]
+    fn split_expense_refuses_to_split_a_share_again() {
+        let mut expenses = vec![
+            expense(1, "Dinner", 9000),
+            expense(2, "Taxi (split the fare)", 1500),
+        ];
+        split_expense(&mut expenses, &Id::from(1), ways(3)).unwrap();
+
+        assert!(matches!(
+            split_expense(&mut expenses, &Id::from(1), ways(2)),
+            Err(Error::AlreadySplit(_))
+        ));
+        assert_eq!(expenses[0].amount, 3000);
+        assert_eq!(expenses[0].description, "Dinner (split 3 ways)");
+
+        // Only the marker itself counts
+        assert!(split_expense(&mut expenses, &Id::from(2), ways(2)).is_ok());
+    }
+
+    #[test]
+    fn the_schema_check_names_an_expense_without_an_amount() {
+        let error = read_error(
+            r#"{"next_id": 3, "expenses": [
+                {"id": 1, "description": "Coffee", "amount_cents": 350},
+                {"id": 2, "description": "Lunch"}
+            ]}"#,
+        );
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(
+            error
+                .to_string()
+                .contains("(expense 2 (ID: 2): \"amount_cents\" is a required property)"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn the_schema_check_names_a_duplicate_id() {
+        let error = read_error(
+            r#"{"next_id": 3, "expenses": [
+                {"id": 1, "description": "Coffee", "amount_cents": 350},
+                {"id": 1, "description": "Lunch", "amount_cents": 1200}
+            ]}"#,
+        );
+
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(
+            error
+                .to_string()
+                .contains("(ID 1 is used by more than one expense)"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn the_schema_check_names_a_field_of_the_wrong_type() {
+        let error = read_error(
+            r#"{"next_id": 2, "expenses": [
+                {"id": 1, "description": ["Coffee"], "amount_cents": 350}
+            ]}"#,
+        );
+
+        assert!(error.to_string().contains("expense 1 (ID: 1)"), "{}", error);
+    }
+
+    #[test]
+    fn weekly_comparison_splits_the_last_two_weeks() {
+        let mut salary = dated(expense(6, "Salary", 300000), "2024-03-14");
+        salary.kind = Kind::Income;
+        let expenses = [
+            dated(expense(1, "Today", 100), "2024-03-14"),
+            dated(expense(2, "Week start", 200), "2024-03-08"),
+            dated(expense(3, "Day before", 400), "2024-03-07"),
+            dated(expense(4, "Fortnight start", 800), "2024-03-01"),
+            dated(expense(5, "Too old", 1600), "2024-02-29"),
+            dated(expense(7, "Tomorrow", 3200), "2024-03-15"),
+            expense(8, "Undated", 6400),
+            salary,
+        ];
+
+        assert_eq!(
+            weekly_comparison(&expenses, date("2024-03-14"), "USD"),
+            (300, 1200)
+        );
+    }
+
+    #[test]
+    fn format_digest_points_the_way_spending_went() {
+        assert_eq!(
+            format_digest(5000, 2000, "USD"),
+            "Last 7 days: $50.00 spent, ↑ $30.00 more than the 7 days before"
+        );
+        assert_eq!(
+            format_digest(2000, 5000, "USD"),
+            "Last 7 days: $20.00 spent, ↓ $30.00 less than the 7 days before"
+        );
+        assert_eq!(
+            format_digest(0, 0, "USD"),
+            "Last 7 days: $0.00 spent, → the same as the 7 days before"
+        );
+    }
+
+    fn confirm_large(amount: i64, threshold: Option<i64>, answer: &str) -> bool {
+        let mut input = io::Cursor::new(answer);
+        let confirmed = match confirm_large_expense(amount, threshold, "USD", Some(&mut input)) {
+            Ok(()) => true,
+            Err(Error::AddCancelled) => false,
+            Err(error) => panic!("unexpected error: {}", error),
+        };
+
+        // Whether the answer was read, i.e. whether it asked
+        assert_eq!(
+            input.position() > 0,
+            !answer.is_empty() && threshold.is_some_and(|t| amount >= t)
+        );
+        confirmed
+    }
+
+    #[test]
+    fn confirm_large_expense_asks_at_and_above_the_threshold() {
+        assert!(confirm_large(10000, Some(10000), "y\n"));
+        assert!(!confirm_large(10000, Some(10000), "n\n"));
+        assert!(!confirm_large(1200000, Some(10000), "\n"));
+    }
+
+    #[test]
+    fn confirm_large_expense_lets_smaller_amounts_through() {
+        assert!(confirm_large(9999, Some(10000), ""));
+        assert!(confirm_large(1200000, None, ""));
+    }
+
+    #[test]
+    fn confirm_large_expense_refuses_without_a_terminal() {
+        let no_terminal = || None::<&mut io::Cursor<&str>>;
+        let result = confirm_large_expense(500000, Some(10000), "USD", no_terminal());
+
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Not added, $5000.00 is at or above your large expense threshold of $100.00 and there's no terminal to ask on (pass --force to add it anyway)"
+        );
+        assert!(confirm_large_expense(9999, Some(10000), "USD", no_terminal()).is_ok());
+    }
+
+    #[test]
+    fn resequence_ids_numbers_from_one_in_order() {
+        let mut expenses = vec![
+            expense(4, "Coffee", 350),
+            expense(9, "Lunch", 1200),
+            expense(1, "Train", 900),
+        ];
+        expenses[1].id = "k3x9ab".parse().unwrap();
+
+        resequence_ids(&mut expenses);
+
+        assert_eq!(ids(&expenses), [1, 2, 3].map(Id::from));
+        assert_eq!(
+            expenses
+                .iter()
+                .map(|e| e.description.as_str())
+                .collect::<Vec<_>>(),
+            ["Coffee", "Lunch", "Train"]
+        );
+    }
+
+    #[test]
+    fn resequence_ids_of_nothing_is_nothing() {
+        let mut expenses = Vec::new();
+        resequence_ids(&mut expenses);
+        assert!(expenses.is_empty());
+    }
+
+    fn humanized(on: &str) -> String {
+        humanize_date(date(on), date("2024-03-15"))
+    }
+
+    #[test]
+    fn humanize_date_names_the_nearest_days() {
+        assert_eq!(humanized("2024-03-15"), "today");
+        assert_eq!(humanized("2024-03-14"), "yesterday");
+        assert_eq!(humanized("2024-03-16"), "tomorrow");
+    }
+
+    #[test]
+    fn humanize_date_switches_units_at_the_boundaries() {
+        assert_eq!(humanized("2024-03-13"), "2 days ago");
+        assert_eq!(humanized("2024-03-09"), "6 days ago");
+        assert_eq!(humanized("2024-03-08"), "1 week ago");
+        assert_eq!(humanized("2024-02-15"), "4 weeks ago");
+        assert_eq!(humanized("2024-02-14"), "1 month ago");
+        assert_eq!(humanized("2023-03-17"), "12 months ago");
+        assert_eq!(humanized("2023-03-16"), "1 year ago");
+        assert_eq!(humanized("2021-03-15"), "3 years ago");
+    }
+
+    #[test]
+    fn humanize_date_puts_future_dates_ahead() {
+        assert_eq!(humanized("2024-03-17"), "in 2 days");
+        assert_eq!(humanized("2024-03-29"), "in 2 weeks");
+        assert_eq!(humanized("2025-03-15"), "in 1 year");
+    }
+
+    #[test]
+    fn parse_bulk_line_reads_the_fields() {
+        assert_eq!(
+            parse_bulk_line("Coffee,3.50", "USD"),
+            Ok(BulkLine {
+                description: "Coffee".to_string(),
+                amount: 350,
+                category: None,
+            })
+        );
+        assert_eq!(
+            parse_bulk_line(" Lunch , 12 , food ", "USD"),
+            Ok(BulkLine {
+                description: "Lunch".to_string(),
+                amount: 1200,
+                category: Some("food".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn parse_bulk_line_takes_a_quoted_description_with_commas() {
+        let line = parse_bulk_line("\"Bread, milk\",4.20,groceries", "USD").unwrap();
+
+        assert_eq!(line.description, "Bread, milk");
+        assert_eq!(line.amount, 420);
+    }
+
+    #[test]
+    fn parse_bulk_line_drops_an_empty_category() {
+        assert_eq!(
+            parse_bulk_line("Coffee,3.50,", "USD").unwrap().category,
+            None
+        );
+    }
+
+    #[test]
+    fn parse_bulk_line_refuses_malformed_lines() {
+        assert_eq!(
+            parse_bulk_line("Coffee", "USD"),
+            Err("expected description,amount[,category], found 1 field(s)".to_string())
+        );
+        assert!(parse_bulk_line("Coffee,3.50,food,extra", "USD").is_err());
+        assert_eq!(
+            parse_bulk_line(" ,3.50", "USD"),
+            Err("empty description".to_string())
+        );
+        assert!(parse_bulk_line("Coffee,lots", "USD").is_err());
+        assert!(parse_bulk_line("Coffee,-3", "USD").is_err());
+        assert!(parse_bulk_line("Coffee,0", "USD").is_err());
+    }
+
+    fn categorized() -> Vec<Expense> {
+        vec![
+            in_category(expense(1, "Coffee at the station", 350), "food"),
+            in_category(expense(2, "Morning coffee", 300), "food"),
+            in_category(expense(3, "Station parking", 500), "car"),
+            in_category(expense(4, "Train ticket", 900), "travel"),
+            expense(5, "Coffee beans", 1200),
+        ]
+    }
+
+    #[test]
+    fn suggest_category_picks_the_most_shared_keywords() {
+        assert_eq!(
+            suggest_category("Coffee", &categorized()),
+            Some("food".to_string())
+        );
+        assert_eq!(
+            suggest_category("Train ticket home", &categorized()),
+            Some("travel".to_string())
+        );
+    }
+
+    #[test]
+    fn suggest_category_ignores_case_and_short_words() {
+        assert_eq!(
+            suggest_category("PARKING", &categorized()),
+            Some("car".to_string())
+        );
+        assert_eq!(suggest_category("at a", &categorized()), None);
+    }
+
+    #[test]
+    fn suggest_category_breaks_ties_alphabetically() {
+        // "station" is in one food and one car expense
+        assert_eq!(
+            suggest_category("station", &categorized()),
+            Some("car".to_string())
+        );
+    }
+
+    #[test]
+    fn suggest_category_needs_a_shared_keyword() {
+        assert_eq!(suggest_category("Groceries", &categorized()), None);
+        assert_eq!(suggest_category("Coffee", &[]), None);
+    }
+
+    fn damaged_datastore() -> serde_json::Value {
+        serde_json::json!({
+            "next_id": 5,
+            "expenses": [
+                {"id": 1, "description": "Coffee", "amount_cents": 350},
+                {"id": 1, "description": "Lunch", "amount_cents": 1200},
+                {"description": "Train", "amount_cents": 900},
+                {"id": 3, "description": "Nothing", "amount_cents": 0},
+                {"id": 4, "description": "Book"},
+            ],
+        })
+    }
+
+    #[test]
+    fn check_datastore_reports_each_problem() {
+        assert_eq!(
+            check_datastore(&damaged_datastore()),
+            [
+                Problem::DuplicateId {
+                    index: 1,
+                    id: "1".to_string(),
+                },
+                Problem::MissingId { index: 2 },
+                Problem::InvalidExpense {
+                    index: 3,
+                    reason: "amount is zero".to_string(),
+                },
+                Problem::InvalidExpense {
+                    index: 4,
+                    reason: "\"amount_cents\" is a required property".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn check_datastore_finds_nothing_wrong_with_a_valid_datastore() {
+        let value = serde_json::json!({
+            "next_id": 3,
+            "expenses": [
+                {"id": 1, "description": "Coffee", "amount_cents": 350},
+                {"id": "k3x9ab", "description": "Lunch", "amount_cents": 1200},
+            ],
+        });
+
+        assert_eq!(check_datastore(&value), []);
+    }
+
+    #[test]
+    fn check_datastore_reports_problems_outside_the_expenses() {
+        let value = serde_json::json!({"next_id": "one", "expenses": []});
+
+        assert!(matches!(
+            check_datastore(&value)[..],
+            [Problem::Other { .. }]
+        ));
+    }
+
+    #[test]
+    fn repair_datastore_renumbers_and_quarantines() {
+        let mut value = damaged_datastore();
+        let problems = check_datastore(&value);
+
+        let quarantined = repair_datastore(&mut value, &problems);
+
+        let descriptions: Vec<&serde_json::Value> = quarantined
+            .iter()
+            .map(|expense| &expense["description"])
+            .collect();
+        assert_eq!(descriptions, ["Nothing", "Book"]);
+        assert_eq!(
+            value["expenses"],
+            serde_json::json!([
+                {"id": 1, "description": "Coffee", "amount_cents": 350},
+                {"id": "5", "description": "Lunch", "amount_cents": 1200},
+                {"id": "6", "description": "Train", "amount_cents": 900},
+            ])
+        );
+        assert_eq!(check_datastore(&value), []);
+    }
+
+    #[derive(clap::Parser)]
+    struct List {
+        #[command(flatten)]
+        filter: ExpenseFilter,
+    }
+
+    /// An `ExpenseFilter` as `list` would parse `args`
+    fn expense_filter(args: &[&str]) -> ExpenseFilter {
+        use clap::Parser;
+
+        List::parse_from(["list"].iter().chain(args)).filter
+    }
+
+    #[test]
+    fn stream_expenses_stops_reading_at_the_limit() {
+        let (_dir, path) = datastore_path("expenses.jsonl");
+        let expenses = (1..=5).map(|id| expense(id, "Coffee", 350)).collect();
+        write_datastore(&path, &datastore(expenses)).unwrap();
+
+        // Were the whole file read, this line would make it corrupt
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(b"{ not an expense\n").unwrap();
+
+        let filter = expense_filter(&[]);
+        let first = stream_expenses(&path, &filter, "USD", 0, 2).unwrap();
+        assert_eq!(ids(&first), [1, 2].map(Id::from));
+        let page = stream_expenses(&path, &filter, "USD", 3, 2).unwrap();
+        assert_eq!(ids(&page), [4, 5].map(Id::from));
+
+        assert!(read_expenses(&path).is_err());
+        assert!(stream_expenses(&path, &filter, "USD", 0, 10).is_err());
+    }
+
+    #[test]
+    fn normalize_description_trims_the_ends() {
+        assert_eq!(
+            normalize_description("  Coffee \t", false).unwrap(),
+            "Coffee"
+        );
+        assert_eq!(
+            normalize_description("Coffee  beans", false).unwrap(),
+            "Coffee  beans"
+        );
+    }
+
+    #[test]
+    fn normalize_description_refuses_blank_descriptions() {
+        assert!(matches!(
+            normalize_description("", false),
+            Err(Error::EmptyDescription)
+        ));
+        assert!(matches!(
+            normalize_description(" \t\n", false),
+            Err(Error::EmptyDescription)
+        ));
+    }
+
+    #[test]
+    fn normalize_description_allows_blank_ones_when_asked() {
+        assert_eq!(normalize_description("  ", true).unwrap(), "");
+    }
+
+    #[test]
+    fn add_expense_stores_the_trimmed_description() {
+        let (_dir, path) = datastore_path("expenses.json");
+        init_datastore(&path).unwrap();
+
+        let padded = new_expense(&["--description", "  Coffee  ", "--amount", "3.50"]);
+        add_expense(&padded, Kind::Expense, "USD", false, &path).unwrap();
+        let blank = new_expense(&["--description", "   ", "--amount", "3.50"]);
+        let result = add_expense(&blank, Kind::Expense, "USD", false, &path);
+
+        assert!(matches!(result, Err(Error::EmptyDescription)));
+        let expenses = read_expenses(&path).unwrap();
+        assert_eq!(expenses.len(), 1);
+        assert_eq!(expenses[0].description, "Coffee");
+    }
+
+    /// Runs the wizard on `answers`, returning the expense and what it printed
+    fn wizard(answers: &str) -> (io::Result<NewExpense>, String) {
+        let mut output = Vec::new();
+        let new = prompt_new_expense(&mut io::Cursor::new(answers), &mut output, "USD");
+        (new, String::from_utf8(output).unwrap())
+    }
+
+    #[test]
+    fn prompt_new_expense_asks_for_each_field() {
+        let (new, output) = wizard("Coffee\n3*1.50\nfood\n2024-03-01\n");
+        let new = new.unwrap();
+
+        assert_eq!(new.description, "Coffee");
+        assert_eq!(new.amount, 4.5);
+        assert_eq!(new.category.as_deref(), Some("food"));
+        assert_eq!(new.date, Some(date("2024-03-01")));
+        assert_eq!(
+            output,
+            "Description: Amount: Category (optional): Date (YYYY-MM-DD, empty for today): "
+        );
+    }
+
+    #[test]
+    fn prompt_new_expense_leaves_optional_fields_empty() {
+        let (new, _) = wizard("  Lunch \n12\n\n\n");
+        let new = new.unwrap();
+
+        assert_eq!(new.description, "Lunch");
+        assert_eq!(new.category, None);
+        assert_eq!(new.date, None);
+    }
+
+    #[test]
+    fn prompt_new_expense_asks_again_after_a_bad_answer() {
+        let (new, output) = wizard("\nCoffee\nfree\n0\n3.50\n\nyesterday\n2024-03-01\n");
+
+        assert_eq!(new.unwrap().amount, 3.5);
+        assert!(
+            output.contains("The description must not be empty\n"),
+            "{}",
+            output
+        );
+        assert!(
+            output.contains("'yesterday' is not a date like 2024-01-31\n"),
+            "{}",
+            output
+        );
+        assert_eq!(output.matches("Amount: ").count(), 3);
+    }
+
+    #[test]
+    fn prompt_new_expense_fails_when_the_input_ends() {
+        let (new, _) = wizard("Coffee\n");
+
+        assert!(matches!(new, Err(error) if error.kind() == io::ErrorKind::UnexpectedEof));
+    }
+
+    /// Gives KWD three decimal places and JPY none. Decimal places can only be
+    /// set once, so every test relying on them goes through here.
+    fn set_test_decimal_places() {
+        set_decimal_places(&HashMap::from([
+            ("KWD".to_string(), 3),
+            ("JPY".to_string(), 0),
+        ]));
+    }
+
+    fn rates() -> HashMap<String, f64> {
+        HashMap::from([
+            ("USD".to_string(), 1.0),
+            ("eur".to_string(), 1.25),
+            ("KWD".to_string(), 3.25),
+            ("FREE".to_string(), 0.0),
+        ])
+    }
+
+    #[test]
+    fn convert_cents_goes_through_the_common_rate() {
+        assert_eq!(convert_cents(1000, "EUR", "USD", &rates()), Some(1250));
+        assert_eq!(convert_cents(1250, "usd", "eur", &rates()), Some(1000));
+        assert_eq!(convert_cents(333, "USD", "EUR", &rates()), Some(266));
+    }
+
+    #[test]
+    fn convert_cents_rescales_between_minor_units() {
+        set_test_decimal_places();
+
+        // 1.500 KWD is $4.875, rounded to the cent
+        assert_eq!(convert_cents(1500, "KWD", "USD", &rates()), Some(488));
+        assert_eq!(convert_cents(325, "USD", "KWD", &rates()), Some(1000));
+    }
+
+    #[test]
+    fn convert_cents_leaves_the_same_currency_alone() {
+        assert_eq!(convert_cents(1234, "GBP", "gbp", &rates()), Some(1234));
+    }
+
+    #[test]
+    fn convert_cents_needs_a_usable_rate_for_both() {
+        assert_eq!(convert_cents(1000, "GBP", "USD", &rates()), None);
+        assert_eq!(convert_cents(1000, "USD", "GBP", &rates()), None);
+        assert_eq!(convert_cents(1000, "FREE", "USD", &rates()), None);
+    }
+
+    #[test]
+    fn convert_expenses_counts_what_it_leaves_out() {
+        let mut euros = expense(1, "Croissant", 200);
+        euros.currency = Some("EUR".to_string());
+        let mut pounds = expense(2, "Tea", 300);
+        pounds.currency = Some("gbp".to_string());
+
+        let (converted, missing) = convert_expenses(
+            vec![euros, pounds, expense(3, "Coffee", 350)],
+            "USD",
+            "USD",
+            &rates(),
+        );
+
+        assert_eq!(ids(&converted), [1, 3].map(Id::from));
+        assert_eq!(converted[0].amount, 250);
+        assert_eq!(converted[0].currency.as_deref(), Some("USD"));
+        assert_eq!(missing, BTreeMap::from([("GBP".to_string(), 1)]));
+    }
+
+    #[test]
+    fn the_running_total_is_kept_per_currency() {
+        let mut croissant = expense(2, "Croissant", 200);
+        croissant.currency = Some("EUR".to_string());
+        let expenses = [
+            expense(1, "Coffee", 350),
+            croissant,
+            expense(3, "Lunch", 1200),
+        ];
+        let options = RowOptions {
+            running_total: true,
+            ..RowOptions::default()
+        };
+
+        let rows = expense_rows(&expenses, "USD", options);
+
+        let running: Vec<&str> = rows[1..=3].iter().map(|row| row[7].as_str()).collect();
+        assert_eq!(running, ["$3.50", "€2.00", "$15.50"]);
+    }
+
+    #[test]
+    fn a_datastore_deleted_after_init_reads_as_empty() {
+        let (_dir, path) = datastore_path("expenses.json");
+        init_datastore(&path).unwrap();
+        let coffee = new_expense(&["--description", "Coffee", "--amount", "3.50"]);
+        add_expense(&coffee, Kind::Expense, "USD", false, &path).unwrap();
+
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(read_expenses(&path).unwrap().is_empty());
+        assert!(read_all_expenses(&path).unwrap().is_empty());
+        // Reading doesn't bring it back
+        assert!(!std::path::Path::new(&path).exists());
+    }
+
+    fn replaced(text: &str, pattern: &str, regex: bool, to: &str) -> Option<String> {
+        replace_text(text, &Pattern::new(pattern, regex).unwrap(), to)
+    }
+
+    #[test]
+    fn replace_text_replaces_every_literal_match() {
+        assert_eq!(
+            replaced("Amazn order, Amazn return", "Amazn", false, "Amazon"),
+            Some("Amazon order, Amazon return".to_string())
+        );
+        // Regex syntax has no meaning in a literal pattern
+        assert_eq!(
+            replaced("Lunch (work)", "(work)", false, "[work]"),
+            Some("Lunch [work]".to_string())
+        );
+        assert_eq!(replaced("Coffee", "coffee", false, "Tea"), None);
+    }
+
+    #[test]
+    fn replace_text_fills_in_regex_groups() {
+        assert_eq!(
+            replaced("Uber 2024-03-01", r"(\d{4})-(\d{2})-\d{2}", true, "$2/$1"),
+            Some("Uber 03/2024".to_string())
+        );
+        assert_eq!(
+            replaced("AMZN Mktp", "(?i)^amzn( mktp)?", true, "Amazon"),
+            Some("Amazon".to_string())
+        );
+        assert_eq!(replaced("Coffee", "^tea", true, "Tea"), None);
+    }
+
+    #[test]
+    fn replace_text_can_delete_the_match() {
+        assert_eq!(
+            replaced("Coffee (copy)", " (copy)", false, ""),
+            Some("Coffee".to_string())
+        );
+    }
+
+    #[test]
+    fn invalid_patterns_are_refused_on_one_line() {
+        let Err(Error::InvalidPattern { pattern, reason }) = Pattern::new("(unclosed", true) else {
+            panic!("an unclosed group should be refused");
+        };
+        assert_eq!(pattern, "(unclosed");
+        assert_eq!(reason, "unclosed group");
+
+        assert!(matches!(
+            Pattern::new("", false),
+            Err(Error::InvalidPattern { .. })
+        ));
+    }
+
+    #[test]
+    fn replace_descriptions_counts_the_changed_ones() {
+        let mut expenses = vec![
+            expense(1, "Amazn order", 350),
+            expense(2, "Lunch", 1200),
+            expense(3, "Amazn", 900),
+        ];
+        let pattern = Pattern::new("Amazn", false).unwrap();
+
+        assert_eq!(replace_descriptions(&mut expenses, &pattern, "Amazon"), 2);
+        assert_eq!(expenses[0].description, "Amazon order");
+        assert_eq!(expenses[2].description, "Amazon");
+    }
+
+    /// A write failing with `kind` the first `failures` times, then
+    /// succeeding, and the number of times it was called
+    fn flaky_write(
+        kind: io::ErrorKind,
+        failures: u32,
+    ) -> (
+        impl FnMut() -> io::Result<&'static str>,
+        std::rc::Rc<std::cell::Cell<u32>>,
+    ) {
+        let calls = std::rc::Rc::new(std::cell::Cell::new(0));
+        let counted = calls.clone();
+
+        let write = move || {
+            counted.set(counted.get() + 1);
+            if counted.get() <= failures {
+                Err(io::Error::from(kind))
+            } else {
+                Ok("written")
+            }
+        };
+
+        (write, calls)
+    }
+
+    #[test]
+    fn retry_transient_tries_again_after_transient_errors() {
+        let (write, calls) = flaky_write(io::ErrorKind::ResourceBusy, 2);
+
+        let result = retry_transient(3, Duration::ZERO, write);
+
+        assert_eq!(result.unwrap(), "written");
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn retry_transient_gives_up_after_the_last_attempt() {
+        let (write, calls) = flaky_write(io::ErrorKind::WouldBlock, 5);
+
+        let error = retry_transient(3, Duration::ZERO, write).unwrap_err();
+
+        assert_eq!(calls.get(), 3);
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+        assert!(
+            error.to_string().ends_with("(gave up after 3 attempts)"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn retry_transient_reports_permanent_errors_at_once() {
+        let (write, calls) = flaky_write(io::ErrorKind::PermissionDenied, 1);
+
+        let error = retry_transient(3, Duration::ZERO, write).unwrap_err();
+
+        assert_eq!(calls.get(), 1);
+        assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn retry_transient_doubles_the_wait() {
+        let (write, _) = flaky_write(io::ErrorKind::TimedOut, 2);
+        let started = Instant::now();
+
+        retry_transient(3, Duration::from_millis(10), write).unwrap();
+
+        // 10 ms, then 20 ms
+        assert!(started.elapsed() >= Duration::from_millis(30));
+    }
+
+    #[test]
+    fn is_transient_tells_the_errors_apart() {
+        for kind in [
+            io::ErrorKind::WouldBlock,
+            io::ErrorKind::ResourceBusy,
+            io::ErrorKind::Interrupted,
+            io::ErrorKind::TimedOut,
+        ] {
+            assert!(is_transient(&kind.into()), "{:?}", kind);
+        }
+        for kind in [
+            io::ErrorKind::PermissionDenied,
+            io::ErrorKind::NotFound,
+            io::ErrorKind::StorageFull,
+        ] {
+            assert!(!is_transient(&kind.into()), "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn refunds_are_stored_alike_however_they_were_typed() {
+        let flagged = signed_amount(Kind::Expense, 5.0, true, false, None, "USD").unwrap();
+        let negative = signed_amount(
+            Kind::Expense,
+            -5.0,
+            false,
+            false,
+            Some(&mut io::Cursor::new("y\n")),
+            "USD",
+        )
+        .unwrap();
+        let fields = ["2024-03-01", "Returned shoes", "-5"].map(String::from);
+        let imported = parse_csv_expense(&fields, &ColumnMap::default(), "USD").unwrap();
+
+        assert_eq!(flagged, (Kind::Refund, -500));
+        assert_eq!(negative, flagged);
+        assert_eq!((imported.kind, imported.amount), flagged);
+    }
+
+    #[test]
+    fn negative_amounts_need_a_refund_or_allow_negative() {
+        let declined = signed_amount(
+            Kind::Expense,
+            -5.0,
+            false,
+            false,
+            Some(&mut io::Cursor::new("n\n")),
+            "USD",
+        );
+        assert!(matches!(declined, Err(Error::InvalidAmount { .. })));
+
+        let unasked = signed_amount(Kind::Expense, -5.0, false, false, None, "USD");
+        assert!(matches!(unasked, Err(Error::InvalidAmount { .. })));
+
+        let allowed = signed_amount(Kind::Expense, -5.0, false, true, None, "USD");
+        assert_eq!(allowed.unwrap(), (Kind::Expense, -500));
+
+        // Both signs at once would subtract twice
+        let both = signed_amount(Kind::Expense, -5.0, true, false, None, "USD");
+        assert!(matches!(both, Err(Error::InvalidAmount { .. })));
+
+        let income = signed_amount(Kind::Income, 5.0, true, false, None, "USD");
+        assert!(matches!(income, Err(Error::InvalidAmount { .. })));
+    }
+
+    #[test]
+    fn split_vendor_splits_on_the_first_colon() {
+        assert_eq!(
+            split_vendor("Starbucks: latte"),
+            Some(("Starbucks", "latte"))
+        );
+        assert_eq!(
+            split_vendor("Station: ticket 10:30"),
+            Some(("Station", "ticket 10:30"))
+        );
+        assert_eq!(split_vendor("  Shop :shoes  "), Some(("Shop", "shoes")));
+    }
+
+    #[test]
+    fn split_vendor_needs_text_on_both_sides() {
+        assert_eq!(split_vendor("Groceries"), None);
+        assert_eq!(split_vendor(": latte"), None);
+        assert_eq!(split_vendor("Starbucks:"), None);
+        assert_eq!(split_vendor(" : "), None);
+        assert_eq!(split_vendor(""), None);
+    }
+
+    #[test]
+    fn totals_by_vendor_groups_the_rest_under_no_vendor() {
+        let mut latte = expense(1, "Starbucks: latte", 450);
+        latte.vendor = Some("Starbucks".to_string());
+        let mut scone = expense(2, "Starbucks: scone", 300);
+        scone.vendor = Some("Starbucks".to_string());
+        let mut salary = expense(4, "Salary", 100000);
+        salary.kind = Kind::Income;
+
+        let totals = totals_by_vendor(&[latte, scone, expense(3, "Bus", 250), salary]);
+
+        assert_eq!(totals.len(), 2);
+        assert_eq!(totals["Starbucks"], 750);
+        assert_eq!(totals[NO_VENDOR], 250);
+    }
+
+    /// Expenses with the random IDs given, in order
+    fn with_ids(ids: &[&str]) -> Vec<Expense> {
+        ids.iter()
+            .map(|id| Expense {
+                id: id.parse().unwrap(),
+                ..expense(0, id, 100)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn resolve_id_takes_a_unique_prefix() {
+        let expenses = with_ids(&["3a9kx2", "7bq41z"]);
+
+        assert_eq!(resolve_id(&"3a".parse().unwrap(), &expenses), Ok(Some(0)));
+        assert_eq!(
+            resolve_id(&"7bq41z".parse().unwrap(), &expenses),
+            Ok(Some(1))
+        );
+        assert_eq!(resolve_id(&"9".parse().unwrap(), &expenses), Ok(None));
+    }
+
+    #[test]
+    fn resolve_id_lists_the_candidates_of_an_ambiguous_prefix() {
+        let expenses = with_ids(&["3a9kx2", "7bq41z", "3ab0c1"]);
+
+        let error = resolve_id(&"3a".parse().unwrap(), &expenses).unwrap_err();
+
+        assert_eq!(
+            error.to_string(),
+            "ID '3a' matches more than one expense: 3a9kx2, 3ab0c1"
+        );
+        assert_eq!(resolve_id(&"3ab".parse().unwrap(), &expenses), Ok(Some(2)));
+    }
+
+    #[test]
+    fn resolve_id_prefers_an_exact_match_over_longer_ones() {
+        let expenses = with_ids(&["3a9kx2", "3a"]);
+
+        assert_eq!(resolve_id(&"3a".parse().unwrap(), &expenses), Ok(Some(1)));
+    }
+
+    #[test]
+    fn resolve_id_matches_sequential_ids_exactly() {
+        let expenses = [expense(12, "Lunch", 1200), expense(1, "Coffee", 350)];
+
+        assert_eq!(resolve_id(&Id::from(1), &expenses), Ok(Some(1)));
+        assert_eq!(resolve_id(&Id::from(2), &expenses), Ok(None));
+    }
+
+    #[test]
+    fn resolve_id_leaves_deleted_expenses_to_restore() {
+        let mut expenses = with_ids(&["3a9kx2", "3ab0c1"]);
+        expenses[1].deleted_at = Some(Utc::now());
+
+        assert_eq!(resolve_id(&"3a".parse().unwrap(), &expenses), Ok(Some(0)));
+        assert_eq!(resolve_id(&"3ab".parse().unwrap(), &expenses), Ok(None));
+        assert_eq!(
+            resolve_any_id(&"3ab".parse().unwrap(), &expenses),
+            Ok(Some(1))
+        );
+    }
+
+    #[test]
+    fn sparkline_scales_to_the_largest_value() {
+        assert_eq!(sparkline(&[0, 100, 700, 350, 1]), "▁▂█▄▂");
+        assert_eq!(sparkline(&[500, 500]), "██");
+        assert_eq!(sparkline(&[0, -200, 0]), "▁▁▁");
+        assert_eq!(sparkline(&[]), "");
+    }
+
+    #[test]
+    fn daily_totals_buckets_the_days_up_to_today() {
+        let expenses = [
+            dated(expense(1, "Coffee", 350), "2024-03-10"),
+            dated(expense(2, "Lunch", 1200), "2024-03-10"),
+            dated(expense(3, "Bus", 250), "2024-03-08"),
+            // Before the first day, after today, undated and income
+            dated(expense(4, "Old", 999), "2024-03-07"),
+            dated(expense(5, "Future", 999), "2024-03-11"),
+            expense(6, "Undated", 999),
+            Expense {
+                kind: Kind::Income,
+                ..dated(expense(7, "Salary", 99900), "2024-03-09")
+            },
+        ];
+
+        let totals = daily_totals(&expenses, date("2024-03-10"), 3);
+
+        assert_eq!(totals, [250, 0, 1550]);
+        assert!(daily_totals(&expenses, date("2024-03-10"), 0).is_empty());
+    }
+
+    fn confirm_deleting(count: usize, text: &str) -> bool {
+        confirm_deletion("Delete?", count, 10, &mut io::Cursor::new(text)).unwrap()
+    }
+
+    #[test]
+    fn confirm_deletion_takes_y_up_to_the_threshold() {
+        assert!(confirm_deleting(10, "y\n"));
+        assert!(!confirm_deleting(10, "10\n"));
+    }
+
+    #[test]
+    fn confirm_deletion_wants_the_count_typed_above_it() {
+        assert!(confirm_deleting(11, "11\n"));
+        assert!(confirm_deleting(11, "  11  \n"));
+        assert!(!confirm_deleting(11, "y\n"));
+        assert!(!confirm_deleting(11, "10\n"));
+        assert!(!confirm_deleting(11, ""));
+    }
+
+    #[test]
+    fn diff_expenses_matches_by_content_then_by_id() {
+        let ours = [
+            dated(expense(1, "Coffee", 350), "2024-03-01"),
+            dated(expense(2, "Lunch", 1200), "2024-03-01"),
+            dated(expense(3, "Train", 900), "2024-03-02"),
+        ];
+        let theirs = [
+            // The same coffee under another ID, and an edited lunch
+            dated(expense(7, "Coffee", 350), "2024-03-01"),
+            dated(expense(2, "Lunch", 1500), "2024-03-01"),
+            dated(expense(8, "Cinema", 1100), "2024-03-03"),
+        ];
+
+        let diff = diff_expenses(&ours, &theirs);
+
+        assert_eq!(ids(&diff.only_ours), [Id::from(3)]);
+        assert_eq!(ids(&diff.only_theirs), [Id::from(8)]);
+        assert_eq!(diff.changed.len(), 1);
+        let (before, after) = &diff.changed[0];
+        assert_eq!((before.amount, after.amount), (1200, 1500));
+        assert_eq!(
+            changed_fields(before, after, "USD"),
+            ["amount: $12.00 -> $15.00"]
+        );
+    }
+
+    #[test]
+    fn diff_expenses_pairs_duplicates_one_to_one() {
+        let ours = [expense(1, "Coffee", 350), expense(2, "Coffee", 350)];
+        let theirs = [expense(5, "Coffee", 350)];
+
+        let diff = diff_expenses(&ours, &theirs);
+
+        assert_eq!(ids(&diff.only_ours), [Id::from(2)]);
+        assert!(diff.only_theirs.is_empty());
+        assert!(diff.changed.is_empty());
+        assert!(diff_expenses(&theirs, &theirs).only_ours.is_empty());
+    }
+
+    #[test]
+    fn changed_fields_lists_each_difference() {
+        let ours = dated(expense(1, "Coffee", 350), "2024-03-01");
+        let theirs = Expense {
+            category: Some("food".to_string()),
+            tags: vec!["work".to_string()],
+            ..dated(expense(1, "Latte", 350), "2024-03-02")
+        };
+
+        assert_eq!(
+            changed_fields(&ours, &theirs, "USD"),
+            [
+                "description: Coffee -> Latte",
+                "date: 2024-03-01 -> 2024-03-02",
+                "category: - -> food",
+                "tags: - -> work",
+            ]
+        );
+    }
+
+    #[test]
+    fn amounts_are_rounded_to_the_currency_places() {
+        set_test_decimal_places();
+
+        let stored = |amount, currency| {
+            signed_amount(Kind::Expense, amount, false, false, None, currency)
+                .unwrap()
+                .1
+        };
+
+        assert_eq!(stored(1234.6, "JPY"), 123500);
+        assert_eq!(stored(3.456, "USD"), 346);
+        assert_eq!(stored(1.2346, "KWD"), 1235);
+        assert_eq!(stored(1.2346, "kwd"), 1235);
+    }
+
+    #[test]
+    fn minor_units_have_at_least_cents() {
+        set_test_decimal_places();
+
+        assert_eq!(minor_unit_places("JPY"), 2);
+        assert_eq!(minor_unit_places("USD"), 2);
+        assert_eq!(minor_unit_places("KWD"), 3);
+        assert_eq!(to_minor_units(1235.0, "JPY"), 123500);
+        assert_eq!(to_minor_units(3.46, "USD"), 346);
+        assert_eq!(to_minor_units(1.235, "KWD"), 1235);
+    }
+
+    #[test]
+    fn amounts_are_shown_with_the_currency_places() {
+        set_test_decimal_places();
+
+        assert_eq!(format_minor_units(123500, "JPY"), "1235");
+        assert_eq!(format_minor_units(123450, "JPY"), "1235");
+        assert_eq!(format_minor_units(-346, "USD"), "-3.46");
+        assert_eq!(format_minor_units(1235, "KWD"), "1.235");
+        assert_eq!(format_minor_units(-5, "KWD"), "-0.005");
+        assert_eq!(format_amount(123500, "JPY"), "JPY 1235");
+        assert_eq!(format_amount(1235, "KWD"), "KWD 1.235");
+    }
+
+    #[test]
+    fn round_to_places_rounds_half_away_from_zero() {
+        assert_eq!(round_to_places(2.5, 0), 3.0);
+        assert_eq!(round_to_places(-2.5, 0), -3.0);
+        assert_eq!(round_to_places(3.456, 2), 3.46);
+        assert_eq!(round_to_places(1.2346, 3), 1.235);
+        assert_eq!(round_to_places(7.0, 3), 7.0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn writes_through_a_symlink_update_its_target() {
+        let (dir, target) = datastore_path("expenses.json");
+        let link = dir.path().join("link.json");
+        write_datastore(&target, &datastore(vec![expense(1, "Coffee", 350)])).unwrap();
+        // Relative, as `ln -s expenses.json link.json` makes it
+        std::os::unix::fs::symlink("expenses.json", &link).unwrap();
+
+        let resolved = follow_symlinks(link.to_str().unwrap()).unwrap();
+        assert_eq!(
+            std::fs::canonicalize(&resolved).unwrap(),
+            std::fs::canonicalize(&target).unwrap()
+        );
+
+        let lunch = datastore(vec![expense(1, "Coffee", 350), expense(2, "Lunch", 1200)]);
+        write_datastore(&resolved, &lunch).unwrap();
+
+        assert!(std::fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!(
+            ids(&read_expenses(&target).unwrap()),
+            [Id::from(1), Id::from(2)]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn follow_symlinks_resolves_dangling_links_and_stops_at_loops() {
+        let (dir, target) = datastore_path("expenses.json");
+        let link = dir.path().join("link.json");
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        // The target can be created by the first write
+        assert_eq!(follow_symlinks(link.to_str().unwrap()).unwrap(), target);
+        assert_eq!(follow_symlinks(&target).unwrap(), target);
+
+        let looped = dir.path().join("loop.json");
+        std::os::unix::fs::symlink(&looped, &looped).unwrap();
+        let error = follow_symlinks(looped.to_str().unwrap()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn quota_overrun_is_how_far_over_the_quota() {
+        assert_eq!(quota_overrun(20000, 19000, 1500), Some(500));
+        assert_eq!(quota_overrun(20000, 25000, 100), Some(5100));
+        assert_eq!(quota_overrun(20000, 19000, 1000), None);
+        assert_eq!(quota_overrun(20000, 0, 0), None);
+        // A refund only brings an exceeded quota back
+        assert_eq!(quota_overrun(20000, 25000, -1000), None);
+    }
+
+    #[test]
+    fn check_quota_counts_the_category_month_only() {
+        let dining =
+            |id, amount, on| in_category(dated(expense(id, "Dinner", amount), on), "Dining");
+        let expenses = [
+            dining(1, 15000, "2024-03-02"),
+            dining(2, 9000, "2024-02-27"),
+            in_category(dated(expense(3, "Bus", 9000), "2024-03-03"), "travel"),
+        ];
+        let quotas = BTreeMap::from([("dining".to_string(), 20000)]);
+
+        let fits = dining(4, 5000, "2024-03-10");
+        assert!(check_quota(&expenses, &fits, &quotas, true, "USD").is_ok());
+
+        let over = dining(4, 5001, "2024-03-10");
+        let result = check_quota(&expenses, &over, &quotas, true, "USD");
+        assert!(matches!(
+            result,
+            Err(Error::OverQuota { category, excess }) if category == "Dining" && excess == "$0.01"
+        ));
+
+        // Without `--enforce` it's only a warning
+        assert!(check_quota(&expenses, &over, &quotas, false, "USD").is_ok());
+    }
+
+    fn fortnightly(start: &str) -> PayPeriod {
+        PayPeriod {
+            start: date(start),
+            days: NonZeroU32::new(14).unwrap(),
+        }
+    }
+
+    #[test]
+    fn pay_period_bounds_counts_cycles_from_a_past_start() {
+        let period = fortnightly("2024-01-05");
+
+        assert_eq!(
+            pay_period_bounds(period, date("2024-01-05")),
+            (date("2024-01-05"), date("2024-01-18"))
+        );
+        assert_eq!(
+            pay_period_bounds(period, date("2024-01-18")),
+            (date("2024-01-05"), date("2024-01-18"))
+        );
+        assert_eq!(
+            pay_period_bounds(period, date("2024-02-29")),
+            (date("2024-02-16"), date("2024-02-29"))
+        );
+        assert_eq!(
+            pay_period_bounds(period, date("2024-03-01")),
+            (date("2024-03-01"), date("2024-03-14"))
+        );
+    }
+
+    #[test]
+    fn pay_period_bounds_counts_back_from_a_future_start() {
+        let period = fortnightly("2024-03-15");
+
+        assert_eq!(
+            pay_period_bounds(period, date("2024-03-14")),
+            (date("2024-03-01"), date("2024-03-14"))
+        );
+        assert_eq!(
+            pay_period_bounds(period, date("2024-02-16")),
+            (date("2024-02-16"), date("2024-02-29"))
+        );
+    }
+
+    #[test]
+    fn pay_period_bounds_of_one_day_cycles() {
+        let period = PayPeriod {
+            start: date("2024-03-15"),
+            days: NonZeroU32::new(1).unwrap(),
+        };
+
+        assert_eq!(
+            pay_period_bounds(period, date("2024-01-01")),
+            (date("2024-01-01"), date("2024-01-01"))
+        );
+    }
+
+    #[test]
+    fn percent_of_income_guards_against_no_income() {
+        assert_eq!(percent_of_income(2500, 10000), Some(25.0));
+        assert_eq!(percent_of_income(-500, 10000), Some(-5.0));
+        assert_eq!(percent_of_income(2500, 0), None);
+        assert_eq!(percent_of_income(2500, -100), None);
+
+        assert_eq!(format_percent_of_income(1234, 10000), "12.3%");
+        assert_eq!(format_percent_of_income(30000, 10000), "300.0%");
+        assert_eq!(format_percent_of_income(1234, 0), "n/a");
+    }
+
+    #[test]
+    fn income_and_spending_are_totalled_apart() {
+        let salary = Expense {
+            kind: Kind::Income,
+            ..expense(1, "Salary", 200000)
+        };
+        let refund = Expense {
+            kind: Kind::Refund,
+            ..expense(3, "Returned shoes", -4000)
+        };
+        let expenses = [salary, expense(2, "Rent", 100000), refund];
+
+        assert_eq!(total_income(&expenses), 200000);
+        assert_eq!(total_spent(&expenses), 96000);
+        assert_eq!(
+            format_percent_of_income(total_spent(&expenses), total_income(&expenses)),
+            "48.0%"
+        );
+    }
+
+    fn truncated(text: &str, width: usize) -> String {
+        truncate_chars(text, NonZeroUsize::new(width).unwrap())
+    }
+
+    #[test]
+    fn truncate_chars_cuts_on_character_boundaries() {
+        assert_eq!(truncated("Coffee ☕ with friends 🎉", 9), "Coffee ☕…");
+        assert_eq!(truncated("🍕🍕🍕🍕", 3), "🍕🍕…");
+        assert_eq!(truncated("Crème brûlée", 4), "Crè…");
+    }
+
+    #[test]
+    fn truncate_chars_keeps_text_that_fits() {
+        assert_eq!(truncated("Coffee ☕", 8), "Coffee ☕");
+        assert_eq!(truncated("Coffee", 20), "Coffee");
+        assert_eq!(truncated("", 1), "");
+        assert_eq!(truncated("Coffee", 1), "…");
+    }
+
+    #[test]
+    fn previous_month_crosses_the_year_boundary() {
+        assert_eq!(previous_month(2024, 1), (2023, 12));
+        assert_eq!(previous_month(2024, 3), (2024, 2));
+        assert_eq!(previous_month(2024, 12), (2024, 11));
+    }
+
+    #[test]
+    fn month_change_gives_the_delta_and_percent() {
+        assert_eq!(month_change(15000, 10000), (5000, Some(50.0)));
+        assert_eq!(month_change(7500, 10000), (-2500, Some(-25.0)));
+        assert_eq!(month_change(10000, 10000), (0, Some(0.0)));
+        // Refunds can leave a month below zero
+        assert_eq!(month_change(500, -1000), (1500, Some(150.0)));
+        assert_eq!(month_change(5000, 0), (5000, None));
+    }
+
+    #[test]
+    fn describe_month_change_compares_january_with_december() {
+        let expenses = [
+            dated(expense(1, "Rent", 100000), "2024-01-03"),
+            dated(expense(2, "Rent", 80000), "2023-12-03"),
+        ];
+        let today = date("2024-01-20");
+
+        assert_eq!(
+            describe_month_change(&expenses, 1, None, today, "USD"),
+            "2024-01: $1000.00, compared with $800.00 in 2023-12: ↑ $200.00 (+25.0%)"
+        );
+        assert_eq!(
+            describe_month_change(&expenses, 12, None, today, "USD"),
+            "Compared with 2023-11: no prior-month data"
+        );
+        assert_eq!(
+            describe_month_change(&expenses, 1, Some("food"), today, "USD"),
+            "Compared with 2023-12: no prior-month data"
+        );
+    }
+
+    #[test]
+    fn preserving_ids_refuses_taken_ids() {
+        let mut existing = datastore(vec![expense(1, "Coffee", 350), expense(2, "Lunch", 1200)]);
+        let incoming = vec![
+            expense(2, "Train", 900),
+            expense(3, "Cinema", 1100),
+            expense(1, "Bus", 250),
+        ];
+
+        let result = merge_expenses_preserving_ids(&mut existing, incoming);
+
+        assert!(
+            matches!(result, Err(Error::IdCollision(ids)) if ids == [Id::from(2), Id::from(1)])
+        );
+        assert_eq!(ids(&existing.expenses), [Id::from(1), Id::from(2)]);
+        assert_eq!(existing.next_id, 3);
+    }
+
+    #[test]
+    fn preserving_ids_keeps_free_ones() {
+        let mut existing = datastore(vec![expense(1, "Coffee", 350)]);
+        let incoming = vec![
+            // Already there under its own ID, which isn't a collision
+            expense(1, "Coffee", 350),
+            expense(7, "Cinema", 1100),
+            expense(4, "Free sample", 0),
+        ];
+
+        let report = merge_expenses_preserving_ids(&mut existing, incoming).unwrap();
+
+        assert_eq!(
+            (report.imported, report.duplicates, report.invalid),
+            (1, 1, 1)
+        );
+        assert_eq!(ids(&existing.expenses), [Id::from(1), Id::from(7)]);
+        assert_eq!(existing.next_id, 8);
+    }
+
+    #[test]
+    fn id_collisions_leaves_out_duplicates() {
+        let existing = datastore(vec![expense(1, "Coffee", 350)]);
+
+        assert!(id_collisions(&existing, &[expense(1, "Coffee", 350)]).is_empty());
+        assert_eq!(
+            id_collisions(&existing, &[expense(1, "Coffee", 400)]),
+            [Id::from(1)]
+        );
+    }
+
+    #[test]
+    fn days_in_month_follows_the_calendar() {
+        assert_eq!(days_in_month(date("2024-01-31")), 31);
+        assert_eq!(days_in_month(date("2024-02-10")), 29);
+        assert_eq!(days_in_month(date("2023-02-10")), 28);
+        assert_eq!(days_in_month(date("2024-04-01")), 30);
+        assert_eq!(days_in_month(date("2024-12-25")), 31);
+    }
+
+    #[test]
+    fn project_month_end_goes_on_at_the_pace_so_far() {
+        assert_eq!(project_month_end(30000, date("2024-04-10")), 90000);
+        assert_eq!(project_month_end(10000, date("2024-04-30")), 10000);
+        // Rounded to the nearest cent
+        assert_eq!(project_month_end(100, date("2024-02-07")), 414);
+        assert_eq!(project_month_end(0, date("2024-04-10")), 0);
+    }
+
+    #[test]
+    fn project_month_end_counts_the_first_day() {
+        assert_eq!(project_month_end(1000, date("2024-01-01")), 31000);
+        assert_eq!(project_month_end(0, date("2024-02-01")), 0);
+    }
+
+    fn weekend_expenses() -> Vec<Expense> {
+        vec![
+            dated(expense(1, "Coffee", 350), "2024-03-04"),
+            dated(expense(2, "Brunch", 2500), "2024-03-09"),
+            dated(expense(3, "Cinema", 1500), "2024-03-10"),
+            dated(expense(4, "Bar", 3000), "2024-03-16"),
+            expense(5, "Undated", 9999),
+        ]
+    }
+
+    #[test]
+    fn totals_by_weekday_starts_on_monday() {
+        use chrono::Weekday::*;
+
+        assert_eq!(
+            totals_by_weekday(&weekend_expenses(), Mon),
+            [
+                (Mon, 350, 1),
+                (Tue, 0, 0),
+                (Wed, 0, 0),
+                (Thu, 0, 0),
+                (Fri, 0, 0),
+                (Sat, 5500, 2),
+                (Sun, 1500, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn totals_by_weekday_can_start_on_sunday() {
+        use chrono::Weekday::*;
+
+        let totals = totals_by_weekday(&weekend_expenses(), Sun);
+
+        let days: Vec<chrono::Weekday> = totals.iter().map(|&(day, _, _)| day).collect();
+        assert_eq!(days, [Sun, Mon, Tue, Wed, Thu, Fri, Sat]);
+        assert_eq!(totals[0], (Sun, 1500, 1));
+        assert_eq!(totals[6], (Sat, 5500, 2));
+    }
+
+    fn coffee_template() -> Template {
+        Template {
+            description: "Morning coffee".to_string(),
+            amount: 4.5,
+            category: Some("food".to_string()),
+        }
+    }
+
+    #[test]
+    fn apply_template_alone_gives_the_template() {
+        let new = apply_template(&coffee_template(), None);
+
+        assert_eq!(new.description, "Morning coffee");
+        assert_eq!(new.amount, 4.5);
+        assert_eq!(new.category.as_deref(), Some("food"));
+        assert_eq!(new.date, None);
+        assert!(new.no_suggest);
+    }
+
+    #[test]
+    fn apply_template_takes_what_was_given() {
+        use clap::Parser;
+
+        // What `add --from-template` parses to when nothing else is given
+        let unchanged = new_expense(&["--description", "", "--amount", "0"]);
+        let new = apply_template(&coffee_template(), Some(unchanged));
+        assert_eq!(new.amount, 4.5);
+        assert_eq!(new.category.as_deref(), Some("food"));
+
+        let larger = new_expense(&[
+            "--description",
+            "",
+            "--amount",
+            "5.25",
+            "--date",
+            "2024-03-01",
+        ]);
+        let new = apply_template(&coffee_template(), Some(larger));
+        assert_eq!(new.description, "Morning coffee");
+        assert_eq!(new.amount, 5.25);
+        assert_eq!(new.date, Some(date("2024-03-01")));
+        assert_eq!(new.category.as_deref(), Some("food"));
+
+        let elsewhere = Add::parse_from([
+            "add",
+            "--description",
+            "",
+            "--amount",
+            "0",
+            "--category",
+            "work",
+        ]);
+        let new = apply_template(&coffee_template(), Some(elsewhere.new));
+        assert_eq!(new.amount, 4.5);
+        assert_eq!(new.category.as_deref(), Some("work"));
+    }
+
+    fn locale(name: &str) -> Locale {
+        parse_locale(name).unwrap()
+    }
+
+    #[test]
+    fn localize_number_groups_thousands() {
+        let us = locale("en-US");
+        assert_eq!(localize_number("1234567.89", us), "1,234,567.89");
+        assert_eq!(localize_number("-1234.56", us), "-1,234.56");
+        assert_eq!(localize_number("123.45", us), "123.45");
+        assert_eq!(localize_number("1000", us), "1,000");
+
+        let de = locale("de-DE");
+        assert_eq!(localize_number("1234567.89", de), "1.234.567,89");
+        assert_eq!(localize_number("-1234.56", de), "-1.234,56");
+        assert_eq!(localize_number("0.50", de), "0,50");
+
+        assert_eq!(localize_number("1234.56", Locale::CANONICAL), "1234.56");
+    }
+
+    #[test]
+    fn locales_format_dates_their_way() {
+        let day = date("2024-01-15");
+
+        assert_eq!(locale("en-US").format_date(day), "01/15/2024");
+        assert_eq!(locale("de-DE").format_date(day), "15.01.2024");
+        assert_eq!(Locale::CANONICAL.format_date(day), "2024-01-15");
+    }
+
+    #[test]
+    fn parse_locale_reads_lang_spellings() {
+        assert_eq!(locale("de_DE.UTF-8"), locale("de-DE"));
+        assert_eq!(locale("en_us"), locale("en-US"));
+        assert_eq!(locale("de"), locale("de-DE"));
+        assert_eq!(locale("de_AT@euro"), locale("de-AT"));
+        assert_eq!(locale("C"), Locale::CANONICAL);
+        assert_eq!(locale("POSIX.UTF-8"), Locale::CANONICAL);
+
+        let error = parse_locale("xx-YY").unwrap_err();
+        assert!(
+            error.starts_with("unknown locale 'xx-YY', use one of en-US, "),
+            "{}",
+            error
+        );
+    }
+
+    /// The rows of the bank statement fixture
+    fn bank_rows() -> Vec<Expense> {
+        let statement = include_str!("../tests/fixtures/bank.csv");
+        let (rows, failures) =
+            parse_csv_expenses(statement.as_bytes(), &ColumnMap::default(), true, "USD").unwrap();
+        assert!(failures.is_empty(), "{:?}", failures);
+        rows
+    }
+
+    fn tracked_expenses() -> Vec<Expense> {
+        vec![
+            dated(expense(1, "Coffee", 350), "2024-03-01"),
+            dated(expense(2, "Rent", 100000), "2024-03-01"),
+            dated(expense(3, "Bus", 250), "2024-03-06"),
+            Expense {
+                kind: Kind::Refund,
+                ..dated(expense(4, "Returned shoes", -4999), "2024-03-19")
+            },
+            dated(expense(5, "Cinema", 1100), "2024-03-10"),
+            expense(6, "Lunch", 1200),
+        ]
+    }
+
+    fn descriptions(expenses: &[Expense]) -> Vec<&str> {
+        expenses
+            .iter()
+            .map(|expense| expense.description.as_str())
+            .collect()
+    }
+
+    fn pairs(reconciliation: &Reconciliation) -> Vec<(Id, &str)> {
+        reconciliation
+            .matched
+            .iter()
+            .map(|(expense, row)| (expense.id.clone(), row.description.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn reconcile_expenses_matches_amounts_within_the_tolerance() {
+        let reconciliation = reconcile_expenses(&tracked_expenses(), &bank_rows(), 3);
+
+        assert_eq!(
+            pairs(&reconciliation),
+            [
+                (Id::from(1), "CARD STARBUCKS 123"),
+                (Id::from(3), "CARD TRANSIT"),
+                (Id::from(4), "REFUND SHOE SHOP"),
+            ]
+        );
+        assert_eq!(
+            ids(&reconciliation.only_tracked),
+            [Id::from(2), Id::from(5), Id::from(6)]
+        );
+        assert_eq!(
+            descriptions(&reconciliation.only_bank),
+            ["RENT MARCH", "CARD TRANSIT", "ATM WITHDRAWAL"]
+        );
+    }
+
+    #[test]
+    fn reconcile_expenses_widens_with_the_tolerance() {
+        let reconciliation = reconcile_expenses(&tracked_expenses(), &bank_rows(), 4);
+
+        assert!(pairs(&reconciliation).contains(&(Id::from(2), "RENT MARCH")));
+
+        let exact = reconcile_expenses(&tracked_expenses(), &bank_rows(), 0);
+        assert_eq!(pairs(&exact), [(Id::from(3), "CARD TRANSIT")]);
+    }
+
+    #[test]
+    fn reconcile_expenses_pairs_the_closest_dates() {
+        let tracked = [
+            dated(expense(1, "Bus", 250), "2024-03-03"),
+            dated(expense(2, "Bus", 250), "2024-03-07"),
+        ];
+        let bank = [dated(expense(0, "CARD TRANSIT", -250), "2024-03-06")];
+
+        let reconciliation = reconcile_expenses(&tracked, &bank, 3);
+
+        assert_eq!(pairs(&reconciliation), [(Id::from(2), "CARD TRANSIT")]);
+        assert_eq!(ids(&reconciliation.only_tracked), [Id::from(1)]);
+    }
+
+    #[test]
+    fn bar_length_is_the_share_of_the_width() {
+        assert_eq!(bar_length(5000, 10000, 40), 20);
+        assert_eq!(bar_length(10000, 10000, 40), 40);
+        assert_eq!(bar_length(2500, 10000, 10), 3);
+        assert_eq!(bar_length(2400, 10000, 10), 2);
+    }
+
+    #[test]
+    fn bar_length_shows_any_spending_and_nothing_else() {
+        assert_eq!(bar_length(1, 1_000_000, 40), 1);
+        assert_eq!(bar_length(0, 10000, 40), 0);
+        assert_eq!(bar_length(-500, 10000, 40), 0);
+        assert_eq!(bar_length(500, 0, 40), 0);
+        // Refunds elsewhere can leave a subtotal above the total
+        assert_eq!(bar_length(15000, 10000, 40), 40);
+    }
+
+    fn themed_table(theme: Theme) -> String {
+        let mut table = Table::new();
+        table.set_format(table_format(theme));
+        table.set_titles(row!["ID", "Description"]);
+        table.add_row(row!["1", "Coffee"]);
+        table.to_string()
+    }
+
+    #[test]
+    fn table_format_gives_each_theme_its_own_look() {
+        let themes = [
+            Theme::Ascii,
+            Theme::Markdown,
+            Theme::Borderless,
+            Theme::Unicode,
+        ];
+        let tables: Vec<String> = themes.into_iter().map(themed_table).collect();
+
+        for (index, table) in tables.iter().enumerate() {
+            for (other, other_table) in tables.iter().enumerate().skip(index + 1) {
+                assert_ne!(
+                    table, other_table,
+                    "{:?} and {:?}",
+                    themes[index], themes[other]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn markdown_theme_draws_a_pipe_table() {
+        assert_eq!(
+            themed_table(Theme::Markdown),
+            "| ID | Description |\n\
+             |----|-------------|\n\
+             | 1  | Coffee      |\n"
+        );
+    }
+
+    /// Coffee and lunch in dollars, the default currency, and a croissant in euros
+    fn two_currencies() -> Vec<Expense> {
+        let croissant = Expense {
+            currency: Some("eur".to_string()),
+            ..expense(2, "Croissant", 200)
+        };
+        vec![
+            expense(1, "Coffee", 350),
+            croissant,
+            expense(3, "Lunch", 1200),
+        ]
+    }
+
+    #[test]
+    fn group_by_currency_keeps_currencies_apart() {
+        let groups = group_by_currency(two_currencies(), "usd");
+
+        assert_eq!(groups.keys().collect::<Vec<_>>(), ["EUR", "USD"]);
+        assert_eq!(ids(&groups["EUR"]), [Id::from(2)]);
+        assert_eq!(ids(&groups["USD"]), [Id::from(1), Id::from(3)]);
+    }
+
+    #[test]
+    fn listings_end_with_a_total_per_currency() {
+        let rows = expense_rows(&two_currencies(), "USD", RowOptions::default());
+
+        let totals: Vec<(&str, &str)> = rows[4..]
+            .iter()
+            .map(|row| (row[2].as_str(), row[AMOUNT_COLUMN].as_str()))
+            .collect();
+        assert_eq!(totals, [("TOTAL EUR", "€2.00"), ("TOTAL USD", "$15.50")]);
+
+        let rows = expense_rows(&two_currencies()[..1], "USD", RowOptions::default());
+        assert_eq!(rows[2][2], "TOTAL");
+    }
+
+    fn summary_options(total_only: bool, convert_to: Option<&str>) -> SummaryOptions<'_> {
+        SummaryOptions {
+            month: None,
+            category: None,
+            by_category: false,
+            chart: false,
+            by_vendor: false,
+            group_by: None,
+            total_only,
+            convert_to,
+            since_last: false,
+            pay_period: None,
+            as_pct_of_income: false,
+            compare: false,
+            weekday: None,
+        }
+    }
+
+    #[test]
+    fn summaries_only_add_up_currencies_once_converted() {
+        let (_dir, path) = datastore_path("expenses.json");
+        write_datastore(&path, &datastore(two_currencies())).unwrap();
+
+        let result = summarize_expenses(&summary_options(true, None), &rates(), "USD", &path);
+        assert!(matches!(
+            result,
+            Err(Error::MixedCurrencies(codes)) if codes == ["EUR", "USD"]
+        ));
+
+        let converted = summary_options(true, Some("USD"));
+        assert!(summarize_expenses(&converted, &rates(), "USD", &path).is_ok());
+        assert!(summarize_expenses(&summary_options(false, None), &rates(), "USD", &path).is_ok());
+    }
+
+    #[test]
+    fn backup_command_line_quotes_the_path() {
+        assert_eq!(
+            backup_command_line("rclone copy {path} remote:", "/home/me/expenses.json"),
+            "rclone copy /home/me/expenses.json remote:"
+        );
+        assert_eq!(
+            backup_command_line("scp {path} host: && cp {path} /mnt", "my expenses.json"),
+            "scp 'my expenses.json' host: && cp 'my expenses.json' /mnt"
+        );
+        assert_eq!(
+            backup_command_line("cp {path} /mnt", "$(rm -rf ~).json"),
+            "cp '$(rm -rf ~).json' /mnt"
+        );
+        assert_eq!(backup_command_line("sync-all", "expenses.json"), "sync-all");
+    }
+
+    #[cfg(unix)]
+    fn exit_status(code: i32) -> io::Result<std::process::ExitStatus> {
+        use std::os::unix::process::ExitStatusExt;
+
+        Ok(std::process::ExitStatus::from_raw(code << 8))
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_backup_command_runs_the_substituted_line() {
+        let mut ran = None;
+
+        let result = run_backup_command("rclone copy {path} remote:", "expenses.json", |command| {
+            ran = Some(command.to_string());
+            exit_status(0)
+        });
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(ran.as_deref(), Some("rclone copy expenses.json remote:"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_backup_command_reports_failures() {
+        let failed = run_backup_command("false", "expenses.json", |_| exit_status(3));
+        assert_eq!(
+            failed,
+            Err("the backup command exited with exit status: 3".to_string())
+        );
+
+        let missing = run_backup_command("rclone", "expenses.json", |_| {
+            Err(io::ErrorKind::NotFound.into())
+        });
+        assert_eq!(
+            missing,
+            Err("couldn't run the backup command: entity not found".to_string())
+        );
+    }
+
+    fn keyed(expense: Expense, key: &str) -> Expense {
+        Expense {
+            idempotency_key: Some(key.to_string()),
+            ..expense
+        }
+    }
+
+    #[test]
+    fn find_by_idempotency_key_finds_the_live_expense() {
+        let mut deleted = keyed(expense(1, "Coffee", 350), "retry-1");
+        deleted.deleted_at = Some(Utc::now());
+        let expenses = [
+            deleted,
+            expense(2, "Lunch", 1200),
+            keyed(expense(3, "Coffee", 350), "retry-1"),
+            keyed(expense(4, "Train", 900), "retry-2"),
+        ];
+
+        let found = find_by_idempotency_key(&expenses, "retry-1").map(|e| &e.id);
+        assert_eq!(found, Some(&Id::from(3)));
+        assert!(find_by_idempotency_key(&expenses, "retry-3").is_none());
+        assert!(find_by_idempotency_key(&expenses[..2], "retry-1").is_none());
+    }
+
+    #[test]
+    fn adding_again_with_the_same_key_does_nothing() {
+        let mut datastore = datastore(Vec::new());
+        let new = new_expense(&[
+            "--description",
+            "Coffee",
+            "--amount",
+            "3.50",
+            "--idempotency-key",
+            "retry-1",
+        ]);
+        let quotas = BTreeMap::new();
+
+        let first = add_to_datastore(&mut datastore, &new, Kind::Expense, &quotas, "USD", false);
+        let again = add_to_datastore(&mut datastore, &new, Kind::Expense, &quotas, "USD", false);
+
+        assert_eq!(first.unwrap(), Some(Id::from(1)));
+        assert_eq!(again.unwrap(), None);
+        assert_eq!(ids(&datastore.expenses), [Id::from(1)]);
+        assert_eq!(
+            datastore.expenses[0].idempotency_key.as_deref(),
+            Some("retry-1")
+        );
+    }
+
+    #[test]
+    fn expenses_from_before_idempotency_keys_have_none() {
+        let value = serde_json::json!({ "id": 1, "description": "Coffee", "amount_cents": 350 });
+
+        let expense: Expense = serde_json::from_value(value).unwrap();
+
+        assert_eq!(expense.idempotency_key, None);
+    }
+
+    fn at(timestamp: &str) -> DateTime<Utc> {
+        timestamp.parse().unwrap()
+    }
+
+    #[test]
+    fn audit_lines_are_json_lines() {
+        let added = audit_line(AuditAction::Add, &Id::from(3), at("2024-03-01T10:00:00Z"));
+        assert_eq!(
+            added.unwrap(),
+            "{\"at\":\"2024-03-01T10:00:00Z\",\"action\":\"add\",\"id\":\"3\"}\n"
+        );
+
+        let renumbered = renumber_line(&Id::from(7), &Id::from(2), at("2024-03-01T10:00:00Z"));
+        assert_eq!(
+            renumbered.unwrap(),
+            "{\"at\":\"2024-03-01T10:00:00Z\",\"action\":\"renumber\",\"id\":\"2\",\"from\":\"7\"}\n"
+        );
+    }
+
+    #[test]
+    fn audit_changes_tells_what_each_expense_went_through() {
+        let mut trashed = expense(5, "Old", 100);
+        trashed.deleted_at = Some(at("2024-02-01T00:00:00Z"));
+        let before = [
+            expense(1, "Coffee", 350),
+            expense(2, "Lunch", 1200),
+            expense(3, "Train", 900),
+            expense(4, "Bus", 250),
+            trashed.clone(),
+            Expense {
+                id: Id::from(6),
+                ..trashed.clone()
+            },
+        ];
+
+        let mut lunch = expense(2, "Lunch", 1500);
+        lunch.category = Some("food".to_string());
+        let mut train = expense(3, "Train", 900);
+        train.deleted_at = Some(at("2024-03-01T00:00:00Z"));
+        let restored = Expense {
+            deleted_at: None,
+            ..trashed
+        };
+        let after = [
+            expense(1, "Coffee", 350),
+            lunch,
+            train,
+            restored,
+            expense(7, "Cinema", 1100),
+        ];
+
+        assert_eq!(
+            audit_changes(&before, &after),
+            [
+                (AuditAction::Update, Id::from(2)),
+                (AuditAction::Delete, Id::from(3)),
+                (AuditAction::Delete, Id::from(4)),
+                (AuditAction::Restore, Id::from(5)),
+                (AuditAction::Purge, Id::from(6)),
+                (AuditAction::Add, Id::from(7)),
+            ]
+        );
+        assert!(audit_changes(&after, &after).is_empty());
+    }
+
+    #[test]
+    fn commands_append_to_the_history() {
+        let (_dir, path) = datastore_path("expenses.json");
+        init_datastore(&path).unwrap();
+        let coffee = new_expense(&["--description", "Coffee", "--amount", "3.50"]);
+        add_expense(&coffee, Kind::Expense, "USD", false, &path).unwrap();
+        add_expense(&coffee, Kind::Expense, "USD", false, &path).unwrap();
+        delete_expense(&[Id::from(1)], true, true, "USD", false, &path).unwrap();
+        undo(&path).unwrap();
+
+        let history = std::fs::read_to_string(history_path(&path)).unwrap();
+        let entries: Vec<(AuditAction, Id)> = history
+            .lines()
+            .map(|line| {
+                let entry: AuditEntry = serde_json::from_str(line).unwrap();
+                (entry.action, entry.id)
+            })
+            .collect();
+
+        assert_eq!(
+            entries,
+            [
+                (AuditAction::Add, Id::from(1)),
+                (AuditAction::Add, Id::from(2)),
+                (AuditAction::Delete, Id::from(1)),
+                (AuditAction::Restore, Id::from(1)),
+            ]
+        );
+    }
+
+    /// The exchange rates of `rates`. They can only be set once, so every
+    /// test relying on them goes through here.
+    fn set_test_rates() {
+        set_rates(&rates());
+    }
+
+    fn paid_in(expense: Expense, currency: &str) -> Expense {
+        Expense {
+            currency: Some(currency.to_string()),
+            ..expense
+        }
+    }
+
+    #[test]
+    fn month_total_converts_other_currencies() {
+        set_test_rates();
+        let salary = Expense {
+            kind: Kind::Income,
+            ..dated(expense(5, "Salary", 100000), "2024-03-01")
+        };
+        let mut deleted = dated(expense(6, "Deleted", 700), "2024-03-02");
+        deleted.deleted_at = Some(Utc::now());
+        let expenses = [
+            dated(expense(1, "Rent", 1000), "2024-03-01"),
+            dated(paid_in(expense(2, "Croissant", 800), "EUR"), "2024-03-02"),
+            dated(paid_in(expense(3, "Ramen", 5000), "JPY"), "2024-03-03"),
+            dated(expense(4, "April", 900), "2024-04-01"),
+            salary,
+            deleted,
+        ];
+
+        assert_eq!(
+            month_total(&expenses, 2024, 3, "USD"),
+            Spending {
+                total: 2000,
+                left_out: BTreeMap::from([("JPY".to_string(), 1)]),
+            }
+        );
+        // In a euro datastore, expenses without a currency are in euros too
+        assert_eq!(month_total(&expenses, 2024, 3, "EUR").total, 1800);
+    }
+
+    #[test]
+    fn weekly_comparison_converts_other_currencies() {
+        set_test_rates();
+        let expenses = [
+            dated(expense(1, "Coffee", 350), "2024-03-14"),
+            dated(paid_in(expense(2, "Croissant", 200), "eur"), "2024-03-13"),
+            dated(paid_in(expense(3, "Ramen", 5000), "JPY"), "2024-03-12"),
+        ];
+
+        assert_eq!(
+            weekly_comparison(&expenses, date("2024-03-14"), "USD"),
+            (600, 0)
+        );
+    }
+
+    #[test]
+    fn report_csv_converts_other_currencies() {
+        set_test_rates();
+        let expenses = [
+            dated(in_category(expense(1, "Coffee", 350), "food"), "2024-03-01"),
+            dated(
+                in_category(paid_in(expense(2, "Croissant", 200), "EUR"), "food"),
+                "2024-03-02",
+            ),
+            dated(
+                in_category(paid_in(expense(3, "Ramen", 5000), "JPY"), "food"),
+                "2024-03-03",
+            ),
+        ];
+        let mut buffer = Vec::new();
+
+        report_csv(&expenses, 2024, 3, &mut buffer, "USD").unwrap();
+
+        assert_eq!(buffer, b"category,total\r\nfood,6.00\r\nTOTAL,6.00\r\n");
+    }
+
+    #[test]
+    fn check_quota_converts_other_currencies() {
+        set_test_rates();
+        let dining =
+            |id, amount, on| in_category(dated(expense(id, "Dinner", amount), on), "Dining");
+        // €80.00 at 1.25 is $100.00 against the $150.00 quota
+        let expenses = [paid_in(dining(1, 8000, "2024-03-02"), "eur")];
+        let quotas = BTreeMap::from([("dining".to_string(), 15000)]);
+
+        let fits = paid_in(dining(2, 4000, "2024-03-10"), "eur");
+        assert!(check_quota(&expenses, &fits, &quotas, true, "USD").is_ok());
+
+        let over = paid_in(dining(2, 4001, "2024-03-10"), "eur");
+        let result = check_quota(&expenses, &over, &quotas, true, "USD");
+        assert!(matches!(
+            result,
+            Err(Error::OverQuota { excess, .. }) if excess == "$0.01"
+        ));
+
+        // Without a rate the quota can't be checked, so the expense isn't refused
+        let unknown = paid_in(dining(2, 1_000_000, "2024-03-10"), "GBP");
+        assert!(check_quota(&expenses, &unknown, &quotas, true, "USD").is_ok());
+    }
+
+    #[test]
+    fn category_month_total_folds_case_like_the_quotas() {
+        set_test_rates();
+        let expenses = [
+            in_category(dated(expense(1, "Latte", 450), "2024-03-02"), "CAFÉ"),
+            in_category(dated(expense(2, "Croissant", 300), "2024-03-03"), "Café"),
+        ];
+
+        let spending = category_month_total(&expenses, "café", 2024, 3, "USD");
+        assert_eq!(spending.total, 750);
+    }
+
+    #[test]
+    fn confirm_add_refuses_without_a_terminal_or_a_yes() {
+        let reason = "a similar expense already exists";
+        let mut yes = io::Cursor::new("y\n");
+        let mut no = io::Cursor::new("n\n");
+
+        assert!(confirm_add("Add anyway?", reason, Some(&mut yes)).is_ok());
+        assert!(matches!(
+            confirm_add("Add anyway?", reason, Some(&mut no)),
+            Err(Error::AddCancelled)
+        ));
+
+        let result = confirm_add("Add anyway?", reason, None::<&mut io::Cursor<&str>>);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Not added, a similar expense already exists and there's no terminal to ask on (pass --force to add it anyway)"
+        );
+    }
+
+    #[test]
+    fn last_year_with_month_goes_back_a_year_for_months_to_come() {
+        let today = date("2024-03-15");
+
+        assert_eq!(last_year_with_month(1, today), 2024);
+        assert_eq!(last_year_with_month(3, today), 2024);
+        assert_eq!(last_year_with_month(4, today), 2023);
+        assert_eq!(last_year_with_month(12, today), 2023);
+    }
+
+    #[test]
+    fn showing_line_counts_the_expenses_matched() {
+        assert_eq!(showing_line(10, 42, false), "Showing 10 of 42 expenses");
+        assert_eq!(showing_line(1, 1, false), "Showing 1 of 1 expense");
+        assert_eq!(
+            showing_line(10, 30, true),
+            "Showing 10 of at least 30 expenses"
+        );
+        assert_eq!(showing_line(1, 1, true), "Showing 1 of at least 1 expense");
+    }
+
+    #[test]
+    fn writes_in_the_same_second_keep_their_own_backups() {
+        let (_dir, path) = datastore_path("expenses.json");
+        let older = timestamped_backup_path(&path, "20240301T120000");
+        std::fs::write(&older, "{}").unwrap();
+
+        for count in 1..=4 {
+            let expenses = (1..=count).map(|id| expense(id, "Coffee", 350)).collect();
+            write_datastore(&path, &datastore(expenses)).unwrap();
+        }
+
+        // The first write had nothing to back up
+        let backups = list_backups(&path).unwrap();
+        assert_eq!(backups.len(), 4);
+        assert_eq!(backups[0], "20240301T120000");
+
+        let data: Vec<Datastore> = backups[1..]
+            .iter()
+            .map(|timestamp| {
+                let data = std::fs::read(timestamped_backup_path(&path, timestamp)).unwrap();
+                serde_json::from_slice(&data).unwrap()
+            })
+            .collect();
+        let counts: Vec<usize> = data.iter().map(|d| d.expenses.len()).collect();
+        assert_eq!(counts, [1, 2, 3]);
+    }
+}
@@ -1,170 +1,1358 @@
-use std::io::{Result, Write};
+use std::io;
+use std::iter;
+use std::num::NonZeroU32;
+use std::process;
 
-use clap::{Parser, Subcommand};
-use prettytable::{row, Table};
-use serde::{Deserialize, Serialize};
+use chrono::{Datelike, Local, NaiveDate};
+use clap::builder::ArgPredicate;
+use clap::{CommandFactory, Parser, Subcommand};
+use expense_tracker::{
+    add_expense, add_recurring, apply_recurring_expenses, apply_template, archive_expenses,
+    budget_status, bulk_add, clear_expenses, compact_ids, config_path, delete_expense,
+    diff_datastores, doctor, edit_datastore, expand_path, export_expenses, follow_symlinks,
+    import_expenses, init_color, init_datastore, list_expenses, list_labels, list_profiles,
+    list_recurring, list_templates, migrate_datastore, move_expense, open_receipt, parse_amount,
+    parse_amount_or_expr, parse_locale, parse_profile_name, parse_year_month, profile_path,
+    prompt_new_expense, purge_deleted, quota_status, read_config, read_settings, reconcile,
+    rename_category, replace_in_descriptions, restore_backup, restore_expenses, save_template,
+    search_expenses, set_backup_command, set_backup_count, set_budget, set_decimal_places,
+    set_large_delete_threshold, set_locale, set_no_init, set_porcelain, set_precision, set_quota,
+    set_rates, set_recurring_active, set_redaction, set_strict, set_theme, set_timings,
+    set_verbosity, show_balance, show_daily_digest, show_expense, show_history, show_last_expense,
+    show_stats, show_trend, split_expense_command, summarize_expenses, top_expenses,
+    unarchive_expenses, undo, update_expense, watch_expenses, what_if, write_report,
+    write_settings, ColumnMap, Config, Error, ExpenseFilter, ExpenseUpdate, ExportFormat, Format,
+    Id, ImportFormat, JsonLayout, Kind, ListOptions, Locale, NewExpense, OutputFormat, Period,
+    Redaction, Session, SummaryOptions, Template, Theme, Verbosity, WeekStart, DEFAULT_CURRENCY,
+};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// Path to the datastore file [default: datastore.json]
+    #[arg(long, global = true, env = "EXPENSE_TRACKER_DATASTORE", value_parser = expand_path)]
+    datastore: Option<String>,
+
+    /// Use the datastore of this profile, kept in ~/.local/share/expense-tracker,
+    /// instead of --datastore
+    #[arg(long, global = true, value_parser = parse_profile_name)]
+    profile: Option<String>,
+
+    /// Write a datastore that's a symbolic link by replacing the link with a
+    /// regular file, instead of writing to the file it points to
+    #[arg(long, global = true)]
+    no_follow_symlinks: bool,
+
+    /// Don't create the datastore when it's missing: reading commands see it as
+    /// empty and changing ones fail
+    #[arg(long, global = true)]
+    no_init: bool,
+
+    /// Refuse a datastore with fields this version doesn't know, e.g. one
+    /// written by a newer version, instead of dropping them on the next write
+    #[arg(long, global = true)]
+    strict: bool,
+
+    /// Currency used to display amounts, remembered for this datastore [default: USD]
+    #[arg(long, global = true)]
+    currency: Option<String>,
+
+    /// Show what add, delete and clear would do without writing anything
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Print how the datastore, config and currency were resolved and whether
+    /// the command would write, then exit without running it
+    #[arg(long, global = true)]
+    explain: bool,
+
+    /// Also print datastore diagnostics
+    #[arg(short, long, global = true, conflicts_with = "quiet")]
+    verbose: bool,
+
+    /// Only print command output and errors
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// Never color the output, even on a terminal
+    #[arg(long, global = true)]
+    no_color: bool,
+
+    /// Print how long reading, changing and writing the datastore took, to stderr
+    #[arg(long, global = true)]
+    timings: bool,
+
+    /// Report what add, income, delete, update and move did as stable
+    /// `key=value` lines instead of messages, for scripts
+    #[arg(long, global = true)]
+    porcelain: bool,
+
+    /// Decimal places used to display amounts, at most 6 [default: the
+    /// currency's `decimal_places`, or 2]
+    #[arg(long, global = true)]
+    precision: Option<u8>,
+
+    /// Locale used to display amounts and dates, e.g. de-DE; datastores and
+    /// exports aren't affected [default: from $LANG]
+    #[arg(long, global = true, value_parser = parse_locale)]
+    locale: Option<Locale>,
+
+    /// Borders to draw tables with [default: ascii]
+    #[arg(long, global = true)]
+    theme: Option<Theme>,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
 
+/// `add` asks for the fields one by one when none is given
+#[derive(clap::Args)]
+#[command(
+    // A template fills in the description, and the amount unless one is given,
+    // so only a description asks for an amount
+    mut_arg("description", |arg| {
+        arg.required(false)
+            .requires("amount")
+            .default_value_if("from_template", ArgPredicate::IsPresent, Some(""))
+    }),
+    mut_arg("amount", |arg| {
+        arg.required(false)
+            .default_value_if("from_template", ArgPredicate::IsPresent, Some("0"))
+    }),
+    mut_group("NewExpense", |group| group.requires("description"))
+)]
+struct AddArgs {
+    #[command(flatten)]
+    new: Option<NewExpense>,
+
+    /// Ask for the description, amount, category and date in turn, the
+    /// default when no option is given
+    #[arg(long, conflicts_with = "NewExpense")]
+    wizard: bool,
+
+    /// Add the expense saved as this template, with --amount and the other
+    /// options given taking precedence
+    #[arg(long, value_name = "NAME", conflicts_with_all = ["description", "wizard"])]
+    from_template: Option<String>,
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Add an expense with a description and amount
-    Add {
+    Add(AddArgs),
+
+    /// Add an income entry with a description and amount
+    Income(NewExpense),
+
+    /// Add an expense dated today for every `description,amount[,category]` line of a file
+    BulkAdd {
+        /// Path of the file to read
+        #[arg(short, long, value_parser = expand_path)]
+        file: String,
+    },
+
+    /// Delete existing expenses given their IDs
+    Delete {
+        /// Expense's ID, can be given multiple times
+        #[arg(short, long, num_args = 1.., required = true)]
+        id: Vec<Id>,
+
+        /// Skip the confirmation prompt
+        #[arg(short, long)]
+        yes: bool,
+
+        /// Only mark the expenses as deleted, so `restore --id` can bring them back
+        #[arg(long)]
+        soft: bool,
+    },
+
+    /// Update an existing expense given its ID
+    Update {
+        /// Expense's ID
+        #[arg(short, long)]
+        id: Id,
+
+        #[command(flatten)]
+        update: ExpenseUpdate,
+    },
+
+    /// List all expenses
+    List {
+        #[command(flatten)]
+        filter: ExpenseFilter,
+
+        #[command(flatten)]
+        options: ListOptions,
+
+        /// Keep running and list again whenever the datastore changes
+        #[arg(long, conflicts_with = "pager")]
+        watch: bool,
+
+        /// Exit with status 2 when no expenses are listed
+        #[arg(long, conflicts_with = "watch")]
+        fail_on_empty: bool,
+    },
+
+    /// Keep only your share of an expense split among several people
+    Split {
+        /// Expense's ID
+        #[arg(short, long)]
+        id: Id,
+
+        /// Number of people sharing the expense, you included
+        #[arg(short, long, value_parser = clap::value_parser!(u32).range(2..))]
+        ways: u32,
+    },
+
+    /// Move an expense to the datastore of another profile
+    Move {
+        /// Expense's ID
+        #[arg(short, long)]
+        id: Id,
+
+        /// Profile to move the expense to
+        #[arg(long, value_parser = parse_profile_name)]
+        to: String,
+    },
+
+    /// Show every field of a single expense
+    Show {
+        /// Expense's ID
+        #[arg(short, long)]
+        id: Id,
+    },
+
+    /// Show every field of the expense added last
+    Last,
+
+    /// Open the receipt linked to an expense
+    Open {
+        /// Expense's ID
+        #[arg(short, long)]
+        id: Id,
+    },
+
+    /// Search expenses whose description contains the given text
+    Search {
+        /// Text to look for (case-insensitive)
+        #[arg(long)]
+        query: String,
+
+        /// Exit with status 2 when nothing matches
+        #[arg(long)]
+        fail_on_empty: bool,
+    },
+
+    /// Show a summary of all expenses
+    Summary {
+        /// Only include expenses from this month (1-12)
+        #[arg(short, long, value_parser = clap::value_parser!(u32).range(1..=12))]
+        month: Option<u32>,
+
+        /// Only include expenses of this category (case-insensitive)
+        #[arg(short, long)]
+        category: Option<String>,
+
+        /// Break the total down by category
+        #[arg(long)]
+        by_category: bool,
+
+        /// Draw the category breakdown as a bar chart
+        #[arg(long, requires = "by_category")]
+        chart: bool,
+
+        /// Break the total down by vendor, as recorded by `add --parse-vendor`
+        #[arg(long)]
+        by_vendor: bool,
+
+        /// Break the total down by week, month or year
+        #[arg(long, value_enum)]
+        group_by: Option<Period>,
+
+        /// Print only the total as a plain number, e.g. for `TOTAL=$(expense-tracker summary --total-only)`
+        #[arg(long, conflicts_with_all = ["by_category", "by_vendor", "group_by", "weekday", "redact"])]
+        total_only: bool,
+
+        /// Convert every amount to this currency with the `rates` from the config file
+        #[arg(long = "in", value_name = "CURRENCY")]
+        convert_to: Option<String>,
+
+        /// Only include expenses added since the last `summary --since-last`
+        #[arg(long)]
+        since_last: bool,
+
+        /// Only include the current cycle of the config file's `pay_period`
+        #[arg(long, conflicts_with = "month")]
+        pay_period: bool,
+
+        /// Also show the total as a percentage of the income in the same period
+        #[arg(long, conflicts_with_all = ["total_only", "convert_to"])]
+        as_pct_of_income: bool,
+
+        /// Compare --month with the month before it
+        #[arg(long, requires = "month", conflicts_with_all = ["total_only", "convert_to"])]
+        compare: bool,
+
+        /// Break the total down by day of the week, with the average expense of each
+        #[arg(long)]
+        weekday: bool,
+
+        /// Day --weekday starts the week on [default: monday]
+        #[arg(long, value_enum, requires = "weekday")]
+        week_start: Option<WeekStart>,
+
+        #[command(flatten)]
+        redaction: Redaction,
+    },
+
+    /// Show the largest expenses
+    Top {
+        /// Number of expenses to show
+        #[arg(short, long, default_value_t = 5)]
+        count: usize,
+    },
+
+    /// Show statistics about all expenses
+    Stats {
+        /// Also draw the daily spending of the last 30 days
+        #[arg(long)]
+        sparkline: bool,
+    },
+
+    /// Project this month's spending to its end at the pace so far
+    Trend,
+
+    /// Show total income minus total expenses
+    Balance,
+
+    /// List the categories and tags in use, most used first
+    TagsList,
+
+    /// Rename a category on every expense that uses it
+    RenameCategory {
+        /// Current category name (case-insensitive)
+        #[arg(long)]
+        from: String,
+
+        /// New category name, stored exactly as given
+        #[arg(long)]
+        to: String,
+    },
+
+    /// Find and replace text in the descriptions of all expenses
+    Replace {
+        /// Replace in descriptions, the only field supported so far
+        #[arg(long, required = true)]
+        in_description: bool,
+
+        /// Text to find, matched exactly unless --regex is given
+        #[arg(long)]
+        from: String,
+
+        /// Replacement text, where --regex allows groups like `$1`
+        #[arg(long)]
+        to: String,
+
+        /// Treat --from as a regular expression
+        #[arg(long)]
+        regex: bool,
+    },
+
+    /// Move old expenses out of the datastore into an archive next to it
+    Archive {
+        /// Archive the expenses dated before this day (YYYY-MM-DD)
+        #[arg(long, required_unless_present = "unarchive")]
+        before: Option<NaiveDate>,
+
+        /// Merge the archived expenses back into the datastore instead
+        #[arg(long, conflicts_with = "before")]
+        unarchive: bool,
+    },
+
+    /// Remove all expenses from the datastore
+    Clear {
+        /// Skip the confirmation prompt
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// Renumber expenses 1, 2, 3... in their current order, closing gaps left by deletions
+    Compact {
+        /// Skip the confirmation prompt
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// Open the datastore in $VISUAL or $EDITOR, rejecting edits that break it
+    Edit,
+
+    /// Revert the last change to the datastore
+    Undo,
+
+    /// Look for duplicate IDs and broken expenses in the datastore
+    #[command(visible_alias = "check")]
+    Doctor {
+        /// Give duplicates new IDs and move broken expenses to a quarantine file
+        #[arg(long)]
+        fix: bool,
+    },
+
+    /// List the datastore's timestamped backups, restore one of them, or bring
+    /// back soft-deleted expenses
+    Restore {
+        /// Backup to restore, as listed by `restore`
+        #[arg(short, long)]
+        backup: Option<String>,
+
+        /// Soft-deleted expense to bring back, can be given multiple times
+        #[arg(short, long, num_args = 1.., conflicts_with = "backup")]
+        id: Vec<Id>,
+    },
+
+    /// Permanently remove the soft-deleted expenses
+    Purge {
+        /// Skip the confirmation prompt
+        #[arg(short, long)]
+        yes: bool,
+    },
+
+    /// Run commands at a prompt, saving the datastore on `save` or exit
+    #[command(visible_alias = "repl")]
+    Interactive,
+
+    /// Export all expenses to a file
+    Export {
+        /// Export format
+        #[arg(short, long, value_enum, default_value_t = ExportFormat::Csv)]
+        format: ExportFormat,
+
+        /// Path of the file to write, `-` or none for stdout
+        #[arg(short, long, default_value = "-", value_parser = expand_path)]
+        output: String,
+
+        /// Indent JSON, the default unless piped
+        #[arg(long)]
+        pretty: bool,
+
+        /// Write JSON on one line, the default when piped
+        #[arg(long, conflicts_with = "pretty")]
+        compact: bool,
+    },
+
+    /// Write a CSV of a month's totals per category
+    Report {
+        /// Year of the report, defaults to the current year
+        #[arg(short, long)]
+        year: Option<i32>,
+
+        /// Month of the report (1-12)
+        #[arg(short, long, value_parser = clap::value_parser!(u32).range(1..=12))]
+        month: u32,
+
+        /// Path of the file to write
+        #[arg(short, long, value_parser = expand_path)]
+        output: String,
+    },
+
+    /// List the profiles that have a datastore
+    Profiles,
+
+    /// Import expenses from another JSON datastore
+    Import {
+        /// Path of the file to import, `-` reads a CSV from stdin
+        #[arg(short, long, value_parser = expand_path)]
+        file: String,
+
+        /// Format of the imported file
+        #[arg(long, value_enum, default_value_t = ImportFormat::Datastore)]
+        format: ImportFormat,
+
+        /// CSV columns holding each field, e.g. date=1,description=2,amount=3,category=4
+        #[arg(long, default_value = "date=1,description=2,amount=3")]
+        map: ColumnMap,
+
+        /// Don't import the CSV's first line
+        #[arg(long)]
+        skip_header: bool,
+
+        /// Keep the imported expenses' IDs instead of giving them new ones,
+        /// importing nothing if any of them is taken
+        #[arg(long)]
+        preserve_ids: bool,
+    },
+
+    /// Match the expenses against the rows of a bank statement CSV
+    Reconcile {
+        /// Path of the bank CSV, `-` reads it from stdin
+        #[arg(short, long, value_parser = expand_path)]
+        file: String,
+
+        /// CSV columns holding each field, e.g. date=1,description=2,amount=3
+        #[arg(long, default_value = "date=1,description=2,amount=3")]
+        map: ColumnMap,
+
+        /// Don't read the CSV's first line
+        #[arg(long)]
+        skip_header: bool,
+
+        /// Most days a bank row's date can be from the expense's
+        #[arg(long, default_value_t = 3)]
+        tolerance_days: u32,
+    },
+
+    /// Show the expenses that differ from another datastore's
+    Diff {
+        /// Datastore to compare with
+        #[arg(long, value_parser = expand_path)]
+        against: String,
+    },
+
+    /// Print a shell completion script to stdout
+    ///
+    /// Redirect the output into your shell's completion directory, e.g.
+    /// `expense-tracker completions --shell bash > ~/.local/share/bash-completion/completions/expense-tracker`
+    Completions {
+        /// Shell to generate the completion script for
+        #[arg(short, long)]
+        shell: clap_complete::Shell,
+    },
+
+    /// Print the version, with --verbose also the commit, build date and compiler
+    Version,
+
+    /// Manage the monthly budget
+    Budget {
+        #[command(subcommand)]
+        command: BudgetCommands,
+    },
+
+    /// Copy the datastore to another file in the format its extension picks,
+    /// e.g. `.db` for SQLite
+    Migrate {
+        /// Datastore to create, which mustn't exist yet
+        #[arg(long, value_parser = expand_path)]
+        to: String,
+    },
+
+    /// Manage monthly caps on categories
+    Quota {
+        #[command(subcommand)]
+        command: QuotaCommands,
+    },
+
+    /// Manage the expenses `add --from-template` can add by name
+    Template {
+        #[command(subcommand)]
+        command: TemplateCommands,
+    },
+
+    /// Show when expenses were added, updated and deleted
+    History {
+        /// Only show the changes to the expense with this ID
+        #[arg(short, long)]
+        id: Option<Id>,
+    },
+
+    /// Show how this month's total, budget and quota would change with an
+    /// expense added today, without adding it
+    WhatIf {
+        /// Expense's amount, which can be arithmetic like `add --amount`
+        #[arg(short, long, value_parser = parse_amount_or_expr)]
+        amount: f64,
+
+        /// Expense's category, to also show its spending and quota
+        #[arg(short, long)]
+        category: Option<String>,
+    },
+
+    /// Manage recurring expense templates
+    Recurring {
+        #[command(subcommand)]
+        command: RecurringCommands,
+    },
+
+    /// Add the expenses of all active recurring templates for a month
+    ApplyRecurring {
+        /// Month to apply the templates to (YYYY-MM), defaults to the current month
+        #[arg(short, long, value_parser = parse_year_month)]
+        month: Option<NaiveDate>,
+    },
+}
+
+#[derive(Subcommand)]
+enum BudgetCommands {
+    /// Set the monthly budget
+    Set {
+        /// Budget's amount
+        #[arg(short, long, value_parser = parse_amount)]
+        amount: f64,
+    },
+
+    /// Show the budget, this month's spending and the remaining balance
+    Status,
+}
+
+#[derive(Subcommand)]
+enum QuotaCommands {
+    /// Set a category's monthly quota
+    Set {
+        /// Category to cap (case-insensitive)
+        #[arg(short, long)]
+        category: String,
+
+        /// Quota's amount
+        #[arg(short, long, value_parser = parse_amount)]
+        amount: f64,
+    },
+
+    /// Show each quota, this month's spending and what's left of it
+    Status,
+}
+
+#[derive(Subcommand)]
+enum TemplateCommands {
+    /// Save an expense under a name in the config file
+    Save {
+        /// Template's name
+        #[arg(short, long)]
+        name: String,
+
         /// Expense's description
         #[arg(short, long)]
         description: String,
 
         /// Expense's amount
+        #[arg(short, long, value_parser = parse_amount)]
+        amount: f64,
+
+        /// Expense's category
+        #[arg(short, long)]
+        category: Option<String>,
+    },
+
+    /// List the saved templates
+    List,
+}
+
+#[derive(Subcommand)]
+enum RecurringCommands {
+    /// Define a new recurring expense
+    Add {
+        /// Expense's description
         #[arg(short, long)]
+        description: String,
+
+        /// Expense's amount
+        #[arg(short, long, value_parser = parse_amount)]
         amount: f64,
+
+        /// Expense's category
+        #[arg(short, long)]
+        category: Option<String>,
+
+        /// Day of the month the expense is due (clamped to the month's length)
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..=31))]
+        day: u32,
     },
 
-    /// Delete an existing expense given its ID
-    Delete {
-        /// Expense's ID
+    /// List all recurring expenses
+    List,
+
+    /// Stop applying a recurring expense
+    Pause {
+        /// Recurring expense's ID
         #[arg(short, long)]
         id: u32,
     },
 
-    /// List all expenses
-    List,
+    /// Start applying a paused recurring expense again
+    Resume {
+        /// Recurring expense's ID
+        #[arg(short, long)]
+        id: u32,
+    },
+}
+
+const DATASTORE_PATH: &str = "datastore.json";
+
+/// Exit status of `list` and `search` with `--fail-on-empty` when nothing is
+/// shown, kept apart from the 1 of errors
+const EMPTY_EXIT_CODE: i32 = 2;
+
+/// Fills in command options the config file sets and the command line left off
+fn apply_config(args: &mut Args, config: &Config) {
+    if let Some(Commands::Add(AddArgs { new: Some(new), .. }) | Commands::Income(new)) =
+        &mut args.command
+    {
+        apply_expense_config(new, config);
+    }
 }
 
-/// Represents an expense
-#[derive(Serialize, Deserialize, Debug)]
-struct Expense {
-    id: u32,
-    description: String,
-    amount: f64,
+fn apply_expense_config(new: &mut NewExpense, config: &Config) {
+    new.random_id |= config.random_ids;
+    new.threshold = new.threshold.or(config.large_expense_threshold);
 }
 
-const DATASTORE_PATH: &str = "datastore.json";
+/// The expense `add` was given, the one made from its template, or the one
+/// the wizard asks for
+fn new_expense(add: &AddArgs, config: &Config, currency: &str) -> Result<NewExpense, Error> {
+    if let Some(name) = &add.from_template {
+        let template = config
+            .templates
+            .get(name)
+            .ok_or_else(|| Error::TemplateNotFound(name.clone()))?;
 
-fn init_datastore(path: &str) -> Result<()> {
-    if !std::path::Path::new(path).exists() {
-        std::fs::File::create(path)?.write_all(b"[]")?;
-        println!("Datastore initialized at '{}'", path);
-        return Ok(());
+        let mut new = apply_template(template, add.new.clone());
+        if add.new.is_none() {
+            apply_expense_config(&mut new, config);
+        }
+
+        return Ok(new);
     }
 
-    println!("Reading from datastore at '{}'", path);
+    if let Some(new) = &add.new {
+        return Ok(new.clone());
+    }
 
-    Ok(())
-}
+    // On stderr like the rest of `add`'s questions, leaving stdout to `--porcelain`
+    let mut new = prompt_new_expense(&mut io::stdin().lock(), &mut io::stderr(), currency)
+        .map_err(Error::Prompt)?;
+    apply_expense_config(&mut new, config);
 
-fn read_expenses(path: &str) -> Result<Vec<Expense>> {
-    let data = std::fs::read_to_string(path)?;
-    let expenses: Vec<Expense> = serde_json::from_str(&data)?;
-    Ok(expenses)
+    Ok(new)
 }
 
-fn write_expenses(path: &str, expenses: &[Expense]) -> Result<()> {
-    let data = serde_json::to_string(expenses)?;
-    std::fs::write(path, data)?;
-    Ok(())
+/// Prints what `--version` does, and with `verbose` the metadata `build.rs` captured
+fn print_version(verbose: bool) {
+    println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+
+    if verbose {
+        println!("commit: {}", env!("BUILD_GIT_COMMIT"));
+        println!("built: {}", env!("BUILD_DATE"));
+        println!("rustc: {}", env!("BUILD_RUSTC_VERSION"));
+    }
 }
 
-fn add_expense(description: String, amount: f64, path: &str) {
-    let mut expenses = match read_expenses(path) {
-        Ok(data) => data,
-        Err(error) => {
-            panic!("Failed to read from datastore: {}", error);
-        }
-    };
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = Args::parse();
 
-    let next_id = expenses.iter().map(|e| e.id).max().unwrap_or(0) + 1;
+    // Completions don't touch the datastore, and must keep stdout free of anything else
+    if let Some(Commands::Completions { shell }) = args.command {
+        let mut command = Args::command();
+        let name = command.get_name().to_string();
+        clap_complete::generate(shell, &mut command, name, &mut io::stdout());
+        return Ok(());
+    }
 
-    let expense = Expense {
-        id: next_id,
-        description,
-        amount,
-    };
-    expenses.push(expense);
+    if let Some(Commands::Version) = args.command {
+        print_version(args.verbose);
+        return Ok(());
+    }
 
-    if let Err(error) = write_expenses(path, &expenses) {
-        panic!("Failed to write to datastore: {}", error);
+    if args.verbose {
+        set_verbosity(Verbosity::Verbose);
+    } else if args.quiet || args.porcelain {
+        set_verbosity(Verbosity::Quiet);
     }
 
-    println!("Expense added successfully with ID: {}", next_id);
-}
+    init_color(args.no_color);
+    if let Some(precision) = args.precision {
+        set_precision(precision);
+    }
+    // A LANG this doesn't know keeps the storage format instead of failing
+    let locale = args.locale.or_else(|| {
+        std::env::var("LANG")
+            .ok()
+            .and_then(|lang| parse_locale(&lang).ok())
+    });
+    if let Some(locale) = locale {
+        set_locale(locale);
+    }
+    set_timings(args.timings);
+    set_porcelain(args.porcelain);
 
-fn delete_expense(id: u32, path: &str) {
-    let mut expenses = match read_expenses(path) {
-        Ok(data) => data,
-        Err(error) => {
-            panic!("Failed to read from datastore: {}", error);
-        }
+    if let Some(Commands::Profiles) = args.command {
+        list_profiles()?;
+        return Ok(());
+    }
+
+    let config = read_config().map_err(Error::Config)?;
+    apply_config(&mut args, &config);
+
+    set_decimal_places(&config.decimal_places);
+    set_rates(&config.rates);
+    set_theme(args.theme.or(config.theme).unwrap_or_default());
+
+    if let Some(backups) = config.backups {
+        set_backup_count(backups);
+    }
+    if let Some(command) = &config.backup_command {
+        set_backup_command(command.clone());
+    }
+
+    if let Some(threshold) = config.large_delete_threshold {
+        set_large_delete_threshold(threshold);
+    }
+
+    let profile_datastore = args.profile.as_deref().map(profile_path).transpose()?;
+
+    let path = profile_datastore
+        .as_deref()
+        .or(args.datastore.as_deref())
+        .or(config.datastore.as_deref())
+        .unwrap_or(DATASTORE_PATH);
+
+    let unresolved = path;
+    let resolved = if args.no_follow_symlinks {
+        path.to_string()
+    } else {
+        follow_symlinks(path).map_err(Error::Init)?
     };
+    let path = resolved.as_str();
+
+    if args.explain {
+        let source = if args.profile.is_some() {
+            "--profile"
+        } else if args.datastore.is_some() {
+            "--datastore or EXPENSE_TRACKER_DATASTORE"
+        } else if config.datastore.is_some() {
+            "the config file"
+        } else {
+            "the default"
+        };
+
+        explain(&args, &config, source, path, unresolved);
+        return Ok(());
+    }
 
-    let original_len = expenses.len();
+    set_no_init(args.no_init);
+    set_strict(args.strict || config.strict);
+    init_datastore(path).map_err(Error::Init)?;
 
-    expenses.retain(|expense| expense.id != id);
+    let mut settings = read_settings(path).map_err(Error::Settings)?;
 
-    if expenses.len() == original_len {
-        println!("No expense found with ID: {}", id);
-        return;
+    if let Some(currency) = args.currency.as_deref().map(str::to_uppercase) {
+        if settings.currency.as_ref() != Some(&currency) {
+            settings.currency = Some(currency);
+
+            // A datastore --no-init left missing has nowhere to remember it
+            if std::path::Path::new(path).exists() {
+                write_settings(path, &settings).map_err(Error::Settings)?;
+            }
+        }
     }
 
-    if let Err(error) = write_expenses(path, &expenses) {
-        panic!("Failed to write to datastore: {}", error);
+    let currency = settings
+        .currency
+        .as_deref()
+        .or(config.currency.as_deref())
+        .unwrap_or(DEFAULT_CURRENCY);
+
+    match &args.command {
+        Some(Commands::Add(add)) => {
+            let new = new_expense(add, &config, currency)?;
+            add_expense(&new, Kind::Expense, currency, args.dry_run, path)?;
+        }
+        Some(Commands::Income(new)) => {
+            add_expense(new, Kind::Income, currency, args.dry_run, path)?;
+        }
+        Some(Commands::BulkAdd { file }) => {
+            bulk_add(file, currency, path)?;
+        }
+        Some(Commands::Delete { id, yes, soft }) => {
+            let soft = *soft || config.soft_delete;
+            delete_expense(id, *yes, soft, currency, args.dry_run, path)?;
+        }
+        Some(Commands::Move { id, to }) => {
+            move_expense(id, to, path)?;
+        }
+        Some(Commands::Update { id, update }) => {
+            update_expense(id, update.clone(), currency, path)?;
+        }
+        Some(Commands::Split { id, ways }) => {
+            // The value parser rejects anything below 2
+            let ways = NonZeroU32::new(*ways).unwrap_or(NonZeroU32::MIN);
+            split_expense_command(id, ways, currency, path)?;
+        }
+        Some(Commands::Show { id }) => {
+            show_expense(id, currency, path)?;
+        }
+        Some(Commands::Last) => {
+            show_last_expense(currency, path)?;
+        }
+        Some(Commands::Open { id }) => {
+            open_receipt(id, path)?;
+        }
+        Some(Commands::List {
+            filter,
+            options,
+            watch,
+            fail_on_empty,
+        }) => {
+            let options = ListOptions {
+                sort: options.sort.or(config.sort),
+                highlight_above: options.highlight_above.or(config.highlight_above),
+                ..options.clone()
+            };
+            set_redaction(options.redaction);
+
+            // Keep machine-readable output clean
+            if !options.json && options.output_format == OutputFormat::Table {
+                show_daily_digest(currency, path)?;
+            }
+
+            if *watch {
+                watch_expenses(filter, &options, currency, path)?;
+            } else {
+                let shown = list_expenses(filter, &options, currency, path)?;
+
+                if *fail_on_empty && shown == 0 {
+                    process::exit(EMPTY_EXIT_CODE);
+                }
+            }
+        }
+        Some(Commands::Search {
+            query,
+            fail_on_empty,
+        }) => {
+            let found = search_expenses(query, currency, path)?;
+
+            if *fail_on_empty && found == 0 {
+                process::exit(EMPTY_EXIT_CODE);
+            }
+        }
+        Some(Commands::Summary {
+            month,
+            category,
+            by_category,
+            chart,
+            by_vendor,
+            group_by,
+            total_only,
+            convert_to,
+            since_last,
+            pay_period,
+            as_pct_of_income,
+            compare,
+            weekday,
+            week_start,
+            redaction,
+        }) => {
+            let convert_to = convert_to.as_deref().map(str::to_uppercase);
+            let pay_period = match (pay_period, config.pay_period) {
+                (true, None) => return Err(Error::NoPayPeriod.into()),
+                (true, period) => period,
+                (false, _) => None,
+            };
+            let options = SummaryOptions {
+                month: *month,
+                category: category.as_deref(),
+                by_category: *by_category,
+                chart: *chart,
+                by_vendor: *by_vendor,
+                group_by: *group_by,
+                total_only: *total_only,
+                convert_to: convert_to.as_deref(),
+                since_last: *since_last,
+                pay_period,
+                as_pct_of_income: *as_pct_of_income,
+                compare: *compare,
+                weekday: weekday.then(|| {
+                    week_start
+                        .or(config.week_start)
+                        .unwrap_or(WeekStart::Monday)
+                        .weekday()
+                }),
+            };
+            set_redaction(*redaction);
+            summarize_expenses(&options, &config.rates, currency, path)?;
+        }
+        Some(Commands::Top { count }) => {
+            top_expenses(*count, currency, path)?;
+        }
+        Some(Commands::Stats { sparkline }) => {
+            show_stats(*sparkline, currency, path)?;
+        }
+        Some(Commands::Trend) => {
+            show_trend(currency, path)?;
+        }
+        Some(Commands::Balance) => {
+            show_balance(currency, path)?;
+        }
+        Some(Commands::TagsList) => {
+            list_labels(path)?;
+        }
+        Some(Commands::Replace {
+            from, to, regex, ..
+        }) => {
+            replace_in_descriptions(from, to, *regex, path)?;
+        }
+        Some(Commands::RenameCategory { from, to }) => {
+            rename_category(from, to, path)?;
+        }
+        // clap makes sure exactly one of them is given
+        Some(Commands::Archive { before, .. }) => match before {
+            Some(before) => archive_expenses(*before, path)?,
+            None => unarchive_expenses(path)?,
+        },
+        Some(Commands::Clear { yes }) => {
+            clear_expenses(*yes, args.dry_run, path)?;
+        }
+        Some(Commands::Compact { yes }) => {
+            compact_ids(*yes, path)?;
+        }
+        Some(Commands::Edit) => {
+            edit_datastore(path)?;
+        }
+        Some(Commands::Undo) => {
+            undo(path)?;
+        }
+        Some(Commands::Doctor { fix }) => {
+            doctor(*fix, path)?;
+        }
+        Some(Commands::Restore { id, .. }) if !id.is_empty() => {
+            restore_expenses(id, path)?;
+        }
+        Some(Commands::Restore { backup, .. }) => {
+            restore_backup(backup.as_deref(), path)?;
+        }
+        Some(Commands::Purge { yes }) => {
+            purge_deleted(*yes, args.dry_run, path)?;
+        }
+        Some(Commands::Interactive) => {
+            run_interactive(&config, currency, path)?;
+        }
+        Some(Commands::Export {
+            format,
+            output,
+            pretty,
+            compact,
+        }) => {
+            let layout = JsonLayout::from_flags(*pretty, *compact);
+            export_expenses(*format, layout, output, currency, path)?;
+        }
+        Some(Commands::Report {
+            year,
+            month,
+            output,
+        }) => {
+            let year = year.unwrap_or_else(|| Local::now().year());
+            write_report(year, *month, output, currency, path)?;
+        }
+        Some(Commands::Reconcile {
+            file,
+            map,
+            skip_header,
+            tolerance_days,
+        }) => {
+            reconcile(file, map, *skip_header, *tolerance_days, currency, path)?;
+        }
+        Some(Commands::Import {
+            file,
+            format,
+            map,
+            skip_header,
+            preserve_ids,
+        }) => {
+            import_expenses(
+                file,
+                *format,
+                map,
+                *skip_header,
+                *preserve_ids,
+                currency,
+                path,
+            )?;
+        }
+        Some(Commands::Diff { against }) => {
+            diff_datastores(against, currency, path)?;
+        }
+        Some(Commands::Budget { command }) => match command {
+            BudgetCommands::Set { amount } => set_budget(*amount, currency, path)?,
+            BudgetCommands::Status => budget_status(currency, path)?,
+        },
+        Some(Commands::Migrate { to }) => {
+            migrate_datastore(to, path)?;
+        }
+        Some(Commands::Quota { command }) => match command {
+            QuotaCommands::Set { category, amount } => {
+                set_quota(category, *amount, currency, path)?
+            }
+            QuotaCommands::Status => quota_status(currency, path)?,
+        },
+        Some(Commands::History { id }) => {
+            show_history(id.as_ref(), path)?;
+        }
+        Some(Commands::WhatIf { amount, category }) => {
+            what_if(*amount, category.as_deref(), currency, path)?;
+        }
+        Some(Commands::Template { command }) => match command {
+            TemplateCommands::Save {
+                name,
+                description,
+                amount,
+                category,
+            } => {
+                let template = Template {
+                    description: description.clone(),
+                    amount: *amount,
+                    category: category.clone(),
+                };
+                save_template(name, &template, currency)?;
+            }
+            TemplateCommands::List => list_templates(&config.templates, currency),
+        },
+        Some(Commands::Recurring { command }) => match command {
+            RecurringCommands::Add {
+                description,
+                amount,
+                category,
+                day,
+            } => add_recurring(
+                description.clone(),
+                *amount,
+                category.clone(),
+                *day,
+                currency,
+                path,
+            )?,
+            RecurringCommands::List => list_recurring(currency, path)?,
+            RecurringCommands::Pause { id } => set_recurring_active(*id, false, path)?,
+            RecurringCommands::Resume { id } => set_recurring_active(*id, true, path)?,
+        },
+        Some(Commands::ApplyRecurring { month }) => {
+            apply_recurring_expenses(*month, path)?;
+        }
+        Some(Commands::Completions { .. })
+        | Some(Commands::Version)
+        | Some(Commands::Profiles)
+        | None => {}
     }
 
-    println!("Expense with ID: '{}' deleted successfully", id);
+    Ok(())
 }
 
-fn list_expenses(path: &str) {
-    let expenses = match read_expenses(path) {
-        Ok(data) => data,
-        Err(error) => {
-            panic!("Failed to read from datastore: {}", error);
+/// Prints what `--explain` found out about how the command would run
+fn explain(args: &Args, config: &Config, source: &str, path: &str, unresolved: &str) {
+    println!("Datastore: {} (from {})", unresolved, source);
+    if path != unresolved {
+        println!("Resolved to: {}", path);
+    }
+    println!("Format: {}", Format::from_path(path).name());
+
+    let exists = std::path::Path::new(path).exists();
+    println!(
+        "Exists: {}",
+        match (exists, args.no_init) {
+            (true, _) => "yes",
+            (false, true) => "no, and --no-init leaves it missing",
+            (false, false) => "no, it would be created",
         }
-    };
+    );
 
-    if expenses.is_empty() {
-        println!("No expenses found");
-        return;
+    match config_path() {
+        Some(config_file) if config_file.exists() => {
+            println!("Config file: {}", config_file.display())
+        }
+        Some(config_file) => println!("Config file: {} (not found)", config_file.display()),
+        None => println!("Config file: none, HOME isn't set"),
     }
 
-    let mut table = Table::new();
+    // Same precedence as when running the command
+    let remembered = read_settings(path)
+        .ok()
+        .and_then(|settings| settings.currency);
+    let (currency, currency_source) = match (&args.currency, remembered, &config.currency) {
+        (Some(currency), _, _) => (currency.to_uppercase(), "--currency"),
+        (None, Some(currency), _) => (currency, "remembered for the datastore"),
+        (None, None, Some(currency)) => (currency.clone(), "the config file"),
+        (None, None, None) => (DEFAULT_CURRENCY.to_string(), "the default"),
+    };
+    println!("Currency: {} (from {})", currency, currency_source);
 
-    table.add_row(row!["ID", "Description", "Amount"]);
+    if let Some(Commands::List { filter, .. }) = &args.command {
+        let criteria = filter.describe();
+        if criteria.is_empty() {
+            println!("Filters: none");
+        } else {
+            println!("Filters: {}", criteria.join(", "));
+        }
+    }
 
-    for expense in expenses {
-        table.add_row(row![
-            expense.id,
-            expense.description,
-            format!("{:.2}", expense.amount)
-        ]);
+    let writes = match writes(&args.command) {
+        Some(_) if args.dry_run && honors_dry_run(&args.command) => "nothing, --dry-run is given",
+        Some(target) => target,
+        None => "nothing",
+    };
+    println!("Writes: {}", writes);
+}
+
+/// What running `command` would write to, if anything
+fn writes(command: &Option<Commands>) -> Option<&'static str> {
+    match command {
+        Some(
+            Commands::Add(_)
+            | Commands::Income(_)
+            | Commands::BulkAdd { .. }
+            | Commands::Delete { .. }
+            | Commands::Move { .. }
+            | Commands::Update { .. }
+            | Commands::Split { .. }
+            | Commands::Replace { .. }
+            | Commands::RenameCategory { .. }
+            | Commands::Archive { .. }
+            | Commands::Clear { .. }
+            | Commands::Compact { .. }
+            | Commands::Edit
+            | Commands::Undo
+            | Commands::Purge { .. }
+            | Commands::Interactive
+            | Commands::Import { .. }
+            | Commands::ApplyRecurring { .. },
+        ) => Some("the datastore"),
+        Some(Commands::Doctor { fix: true }) => Some("the datastore"),
+        Some(Commands::Restore { backup, id }) if backup.is_some() || !id.is_empty() => {
+            Some("the datastore")
+        }
+        Some(Commands::Recurring {
+            command:
+                RecurringCommands::Add { .. }
+                | RecurringCommands::Pause { .. }
+                | RecurringCommands::Resume { .. },
+        }) => Some("the datastore"),
+        Some(Commands::Budget {
+            command: BudgetCommands::Set { .. },
+        })
+        | Some(Commands::Quota {
+            command: QuotaCommands::Set { .. },
+        })
+        | Some(Commands::Summary {
+            since_last: true, ..
+        }) => Some("the datastore's settings"),
+        Some(Commands::Export { .. } | Commands::Report { .. }) => Some("the output file"),
+        Some(Commands::Migrate { .. }) => Some("the datastore given with --to"),
+        Some(Commands::Template {
+            command: TemplateCommands::Save { .. },
+        }) => Some("the config file"),
+        _ => None,
     }
+}
 
-    table.printstd();
+/// Whether `command` writes nothing under `--dry-run`
+fn honors_dry_run(command: &Option<Commands>) -> bool {
+    matches!(
+        command,
+        Some(
+            Commands::Add(_)
+                | Commands::Income(_)
+                | Commands::Delete { .. }
+                | Commands::Clear { .. }
+                | Commands::Purge { .. }
+        )
+    )
 }
 
-fn main() {
-    let args = Args::parse();
+const INTERACTIVE_HELP: &str = "\
+Commands: add, income, delete and list, with the same options as on the command line
+  save    write the changes to the datastore
+  quit    save and leave (also exit or Ctrl-D)
+  help    show this message";
 
-    if let Err(error) = init_datastore(DATASTORE_PATH) {
-        panic!("Failed to initialize datastore: {}", error);
-    }
+fn run_interactive(config: &Config, currency: &str, path: &str) -> Result<(), Error> {
+    let mut session = Session::open(path)?;
+    let mut editor = rustyline::DefaultEditor::new()
+        .map_err(io::Error::other)
+        .map_err(Error::Prompt)?;
 
-    match &args.command {
-        Some(Commands::Add {
-            description,
-            amount,
-        }) => {
-            add_expense(description.clone(), *amount, DATASTORE_PATH);
+    println!("{}", INTERACTIVE_HELP);
+
+    loop {
+        let line = match editor.readline("expense-tracker> ") {
+            Ok(line) => line,
+            // Ctrl-C only drops the line being typed
+            Err(rustyline::error::ReadlineError::Interrupted) => continue,
+            Err(rustyline::error::ReadlineError::Eof) => break,
+            Err(error) => return Err(Error::Prompt(io::Error::other(error))),
+        };
+
+        if line.trim().is_empty() {
+            continue;
         }
-        Some(Commands::Delete { id }) => {
-            delete_expense(*id, DATASTORE_PATH);
+
+        let _ = editor.add_history_entry(line.as_str());
+
+        let Some(words) = shlex::split(&line) else {
+            eprintln!("Error: unbalanced quotes");
+            continue;
+        };
+
+        // A line of only a `#` comment splits into no words
+        let Some(name) = words.first().cloned() else {
+            continue;
+        };
+
+        match name.as_str() {
+            "quit" | "exit" => break,
+            "save" => {
+                session.save()?;
+                continue;
+            }
+            "help" => {
+                println!("{}", INTERACTIVE_HELP);
+                continue;
+            }
+            _ => {}
         }
-        Some(Commands::List) => {
-            list_expenses(DATASTORE_PATH);
+
+        let args =
+            match Args::try_parse_from(iter::once("expense-tracker".to_string()).chain(words)) {
+                Ok(mut args) => {
+                    apply_config(&mut args, config);
+                    args
+                }
+                Err(error) => {
+                    let _ = error.print();
+                    continue;
+                }
+            };
+
+        let result = match &args.command {
+            Some(Commands::Add(add)) => new_expense(add, config, currency)
+                .and_then(|new| session.add(&new, Kind::Expense, currency)),
+            Some(Commands::Income(new)) => session.add(new, Kind::Income, currency),
+            Some(Commands::Delete { id, yes, soft }) => {
+                session.delete(id, *yes, *soft || config.soft_delete, currency)
+            }
+            Some(Commands::List {
+                filter, options, ..
+            }) => {
+                let options = ListOptions {
+                    sort: options.sort.or(config.sort),
+                    highlight_above: options.highlight_above.or(config.highlight_above),
+                    ..options.clone()
+                };
+                set_redaction(options.redaction);
+                let listed = session.list(filter, &options, currency);
+                // Later commands of the session show amounts again
+                set_redaction(Redaction::default());
+                listed
+            }
+            Some(_) => {
+                eprintln!("Error: '{}' isn't available in interactive mode", name);
+                Ok(())
+            }
+            None => Ok(()),
+        };
+
+        // A failed command shouldn't end the session and lose its changes
+        if let Err(error) = result {
+            eprintln!("Error: {}", error);
         }
-        None => {}
     }
+
+    if session.is_dirty() {
+        session.save()?;
+    }
+
+    Ok(())
 }
@@ -0,0 +1,176 @@
+//! SQLite datastores, used for paths ending in `.db`
+//!
+//! ```text
+//! header    one row holding everything but the expenses, as JSON
+//! expenses  one row per expense, as JSON, in the order they were added
+//! ```
+//!
+//! The expenses table also has columns for the description, amount, date and
+//! category taken from the JSON, so the datastore can be queried with
+//! `sqlite3`. Writes only touch the rows that changed.
+
+use std::collections::HashMap;
+
+use rusqlite::{params, Connection, OpenFlags};
+use serde::Serialize;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS header (
+        id INTEGER PRIMARY KEY CHECK (id = 1),
+        data TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS expenses (
+        id TEXT PRIMARY KEY,
+        data TEXT NOT NULL,
+        description TEXT GENERATED ALWAYS AS (json_extract(data, '$.description')) VIRTUAL,
+        amount_cents INTEGER GENERATED ALWAYS AS (json_extract(data, '$.amount_cents')) VIRTUAL,
+        date TEXT GENERATED ALWAYS AS (json_extract(data, '$.date')) VIRTUAL,
+        category TEXT GENERATED ALWAYS AS (json_extract(data, '$.category')) VIRTUAL
+    );
+";
+
+/// Reads the datastore at `path`, which must exist, as the same JSON value the
+/// text formats parse to
+pub fn read_value(path: &str) -> rusqlite::Result<serde_json::Value> {
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+
+    let header: Option<String> = connection
+        .query_row("SELECT data FROM header WHERE id = 1", [], |row| row.get(0))
+        .map(Some)
+        .or_else(|error| match error {
+            rusqlite::Error::QueryReturnedNoRows => Ok(None),
+            error => Err(error),
+        })?;
+
+    let mut value = match header {
+        Some(header) => parse(&header)?,
+        None => serde_json::json!({}),
+    };
+
+    let mut statement = connection.prepare("SELECT data FROM expenses ORDER BY rowid")?;
+    let expenses = statement
+        .query_map([], |row| row.get::<_, String>(0))?
+        .map(|data| parse(&data?))
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    if let Some(header) = value.as_object_mut() {
+        header.insert("expenses".to_string(), serde_json::Value::Array(expenses));
+    }
+
+    Ok(value)
+}
+
+/// Replaces the datastore at `path` with `datastore`, creating it if needed,
+/// in one transaction
+pub fn write(path: &str, datastore: &impl Serialize) -> rusqlite::Result<()> {
+    let mut header = serde_json::to_value(datastore).map_err(to_sql_error)?;
+
+    let expenses = match header
+        .as_object_mut()
+        .and_then(|header| header.remove("expenses"))
+    {
+        Some(serde_json::Value::Array(expenses)) => expenses,
+        _ => Vec::new(),
+    };
+
+    let rows = expenses
+        .iter()
+        .map(|expense| {
+            let id = match &expense["id"] {
+                serde_json::Value::String(id) => id.clone(),
+                id => id.to_string(),
+            };
+            Ok((id, serde_json::to_string(expense).map_err(to_sql_error)?))
+        })
+        .collect::<rusqlite::Result<Vec<(String, String)>>>()?;
+
+    let mut connection = Connection::open(path)?;
+    connection.execute_batch(SCHEMA)?;
+    let transaction = connection.transaction()?;
+
+    let mut existing: Vec<(String, String)> = transaction
+        .prepare("SELECT id, data FROM expenses ORDER BY rowid")?
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<rusqlite::Result<_>>()?;
+
+    // Rows are read back in the order they were inserted, so unless the
+    // expenses kept theirs, start over
+    if !keeps_order(&existing, &rows) {
+        transaction.execute("DELETE FROM expenses", [])?;
+        existing.clear();
+    }
+
+    let new: HashMap<&str, &str> = rows
+        .iter()
+        .map(|(id, data)| (id.as_str(), data.as_str()))
+        .collect();
+    let old: HashMap<&str, &str> = existing
+        .iter()
+        .map(|(id, data)| (id.as_str(), data.as_str()))
+        .collect();
+
+    for (id, _) in &existing {
+        if !new.contains_key(id.as_str()) {
+            transaction.execute("DELETE FROM expenses WHERE id = ?1", params![id])?;
+        }
+    }
+
+    for (id, data) in &rows {
+        match old.get(id.as_str()) {
+            Some(current) if current == data => {}
+            Some(_) => {
+                transaction.execute(
+                    "UPDATE expenses SET data = ?2 WHERE id = ?1",
+                    params![id, data],
+                )?;
+            }
+            None => {
+                transaction.execute(
+                    "INSERT INTO expenses (id, data) VALUES (?1, ?2)",
+                    params![id, data],
+                )?;
+            }
+        }
+    }
+
+    transaction.execute(
+        "INSERT INTO header (id, data) VALUES (1, ?1)
+         ON CONFLICT (id) DO UPDATE SET data = excluded.data",
+        params![header.to_string()],
+    )?;
+
+    transaction.commit()
+}
+
+/// Whether writing `rows` over `existing` by deleting, updating and appending
+/// rows leaves them in the order of `rows`
+fn keeps_order(existing: &[(String, String)], rows: &[(String, String)]) -> bool {
+    let old: HashMap<&str, usize> = existing
+        .iter()
+        .enumerate()
+        .map(|(index, (id, _))| (id.as_str(), index))
+        .collect();
+
+    let mut last_kept = None;
+    let mut appending = false;
+
+    for (id, _) in rows {
+        match old.get(id.as_str()) {
+            // A kept row after an appended one, or before the previous kept one
+            Some(_) if appending => return false,
+            Some(&index) if last_kept.is_some_and(|last| index < last) => return false,
+            Some(&index) => last_kept = Some(index),
+            None => appending = true,
+        }
+    }
+
+    true
+}
+
+fn parse(data: &str) -> rusqlite::Result<serde_json::Value> {
+    serde_json::from_str(data).map_err(to_sql_error)
+}
+
+fn to_sql_error(error: serde_json::Error) -> rusqlite::Error {
+    rusqlite::Error::ToSqlConversionFailure(Box::new(error))
+}
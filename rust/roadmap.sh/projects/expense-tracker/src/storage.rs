@@ -0,0 +1,95 @@
+//! Where a datastore is kept, picked from its path by `Format::from_path`
+//!
+//! ```text
+//! TextStorage    JSON, JSON lines, TOML and YAML files, rewritten whole
+//! SqliteStorage  SQLite databases, changed a row at a time
+//! ```
+//!
+//! Both read to the same JSON value and write a `Datastore`, so everything
+//! above them, from the schema check to migrations, is shared.
+
+use std::io;
+
+use crate::{Datastore, Format};
+
+/// Reads and writes one datastore, leaving locking, backups of the previous
+/// contents and the schema to the callers in the crate root
+pub trait Storage {
+    /// The datastore as the JSON value the text formats parse to, before any
+    /// upgrade. The datastore must exist.
+    fn read_value(&self) -> io::Result<serde_json::Value>;
+
+    /// Replaces the datastore with `datastore`, creating it if needed
+    fn write(&self, datastore: &Datastore) -> io::Result<()>;
+}
+
+/// A datastore kept as a text file in one of the text formats
+pub struct TextStorage<'a> {
+    path: &'a str,
+    format: Format,
+}
+
+impl<'a> TextStorage<'a> {
+    pub fn new(path: &'a str, format: Format) -> TextStorage<'a> {
+        TextStorage { path, format }
+    }
+}
+
+impl Storage for TextStorage<'_> {
+    fn read_value(&self) -> io::Result<serde_json::Value> {
+        let data = std::fs::read_to_string(self.path)?;
+
+        self.format
+            .parse(&data)
+            .map_err(|error| invalid(self.path, self.format, error))
+    }
+
+    fn write(&self, datastore: &Datastore) -> io::Result<()> {
+        let data = self.format.serialize(datastore)?;
+
+        crate::retry_transient(crate::WRITE_ATTEMPTS, crate::WRITE_BACKOFF, || {
+            crate::write_atomically(self.path, data.as_bytes())
+        })
+    }
+}
+
+/// A datastore kept in a SQLite database, see the `sqlite` module
+pub struct SqliteStorage<'a> {
+    path: &'a str,
+}
+
+impl<'a> SqliteStorage<'a> {
+    pub fn new(path: &'a str) -> SqliteStorage<'a> {
+        SqliteStorage { path }
+    }
+}
+
+impl Storage for SqliteStorage<'_> {
+    fn read_value(&self) -> io::Result<serde_json::Value> {
+        // Opening a missing database would create it
+        if !std::path::Path::new(self.path).exists() {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+
+        crate::sqlite::read_value(self.path)
+            .map_err(|error| invalid(self.path, Format::Sqlite, error))
+    }
+
+    fn write(&self, datastore: &Datastore) -> io::Result<()> {
+        crate::retry_transient(crate::WRITE_ATTEMPTS, crate::WRITE_BACKOFF, || {
+            crate::sqlite::write(self.path, datastore).map_err(crate::sqlite_error)
+        })
+    }
+}
+
+/// The storage for the datastore at `path`, by its extension
+pub fn for_path(path: &str) -> Box<dyn Storage + '_> {
+    match Format::from_path(path) {
+        Format::Sqlite => Box::new(SqliteStorage::new(path)),
+        format => Box::new(TextStorage::new(path, format)),
+    }
+}
+
+fn invalid(path: &str, format: Format, error: impl std::fmt::Display) -> io::Error {
+    crate::corrupt_datastore(path, format!("invalid {}: {}", format.name(), error))
+}
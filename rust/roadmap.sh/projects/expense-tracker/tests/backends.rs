@@ -0,0 +1,292 @@
+//! The same checks against every datastore backend, picked by extension
+
+use clap::Parser;
+use expense_tracker::{
+    add_expense, delete_expense, init_datastore, migrate_datastore, read_all_expenses,
+    read_datastore, read_expenses, restore_expenses, storage, undo, update_expense,
+    write_datastore, Datastore, Expense, ExpenseUpdate, Id, Kind, LineItem, NewExpense,
+};
+
+#[derive(Parser)]
+struct Add {
+    #[command(flatten)]
+    new: NewExpense,
+}
+
+#[derive(Parser)]
+struct Update {
+    #[command(flatten)]
+    update: ExpenseUpdate,
+}
+
+fn new_expense(args: &[&str]) -> NewExpense {
+    let args = ["add", "--force", "--no-suggest"].iter().chain(args);
+    Add::parse_from(args).new
+}
+
+fn expense_update(args: &[&str]) -> ExpenseUpdate {
+    Update::parse_from(["update"].iter().chain(args)).update
+}
+
+fn expense(id: Id, description: &str, amount: i64) -> Expense {
+    let value = serde_json::json!({
+        "id": id,
+        "description": description,
+        "amount_cents": amount,
+    });
+    serde_json::from_value(value).unwrap()
+}
+
+fn descriptions(expenses: &[Expense]) -> Vec<&str> {
+    expenses
+        .iter()
+        .map(|expense| expense.description.as_str())
+        .collect()
+}
+
+/// A datastore path with `extension` in a directory removed with the guard
+fn datastore_path(extension: &str) -> (tempfile::TempDir, String) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join(format!("expenses.{}", extension));
+    (dir, path.to_str().unwrap().to_string())
+}
+
+fn missing_datastore_reads_as_empty(extension: &str) {
+    let (_dir, path) = datastore_path(extension);
+
+    assert!(read_expenses(&path).unwrap().is_empty());
+    assert!(!std::path::Path::new(&path).exists());
+}
+
+fn round_trips_every_field(extension: &str) {
+    let (_dir, path) = datastore_path(extension);
+
+    let mut coffee = expense(Id::from(1), "Café \"au lait\", ☕", 350);
+    coffee.category = Some("food".to_string());
+    coffee.tags = vec!["work".to_string(), "daily".to_string()];
+    coffee.note = Some("two lines\nof note".to_string());
+    coffee.currency = Some("EUR".to_string());
+    coffee.date = "2024-03-01".parse().ok();
+    coffee.items = vec![
+        LineItem {
+            description: "Coffee".to_string(),
+            amount: 300,
+        },
+        LineItem {
+            description: "Tip".to_string(),
+            amount: 50,
+        },
+    ];
+
+    let mut refund = expense("k3x9ab".parse().unwrap(), "Returned shoes", -4999);
+    refund.kind = Kind::Refund;
+    refund.vendor = Some("Shoe shop".to_string());
+
+    let datastore = Datastore {
+        next_id: 7,
+        expenses: vec![coffee, refund],
+        recurring: Vec::new(),
+    };
+
+    write_datastore(&path, &datastore).unwrap();
+    let read = read_datastore(&path).unwrap();
+
+    assert_eq!(read.next_id, 7);
+    assert_eq!(
+        serde_json::to_value(&read.expenses).unwrap(),
+        serde_json::to_value(&datastore.expenses).unwrap()
+    );
+}
+
+fn commands_change_the_datastore(extension: &str) {
+    let (_dir, path) = datastore_path(extension);
+    init_datastore(&path).unwrap();
+
+    let coffee = new_expense(&["--description", "Coffee", "--amount", "3.50"]);
+    let lunch = new_expense(&["--description", "Lunch", "--amount", "12"]);
+    add_expense(&coffee, Kind::Expense, "USD", false, &path).unwrap();
+    add_expense(&lunch, Kind::Expense, "USD", false, &path).unwrap();
+
+    update_expense(
+        &Id::from(1),
+        expense_update(&["--amount", "4.25", "--note", "large"]),
+        "USD",
+        &path,
+    )
+    .unwrap();
+
+    let expenses = read_expenses(&path).unwrap();
+    assert_eq!(descriptions(&expenses), ["Coffee", "Lunch"]);
+    assert_eq!(expenses[0].amount, 425);
+    assert_eq!(expenses[0].note.as_deref(), Some("large"));
+
+    delete_expense(&[Id::from(2)], true, true, "USD", false, &path).unwrap();
+    assert_eq!(descriptions(&read_expenses(&path).unwrap()), ["Coffee"]);
+    assert_eq!(read_all_expenses(&path).unwrap().len(), 2);
+
+    restore_expenses(&[Id::from(2)], &path).unwrap();
+    assert_eq!(
+        descriptions(&read_expenses(&path).unwrap()),
+        ["Coffee", "Lunch"]
+    );
+
+    delete_expense(&[Id::from(1)], true, false, "USD", false, &path).unwrap();
+    let expenses = read_all_expenses(&path).unwrap();
+    assert_eq!(descriptions(&expenses), ["Lunch"]);
+
+    // IDs aren't handed out again after a delete
+    add_expense(&coffee, Kind::Expense, "USD", false, &path).unwrap();
+    let ids: Vec<Id> = read_expenses(&path)
+        .unwrap()
+        .into_iter()
+        .map(|expense| expense.id)
+        .collect();
+    assert_eq!(ids, [Id::from(2), Id::from(3)]);
+}
+
+fn keeps_the_order_written(extension: &str) {
+    let (_dir, path) = datastore_path(extension);
+
+    let mut datastore = Datastore {
+        next_id: 4,
+        expenses: vec![
+            expense(Id::from(1), "First", 100),
+            expense(Id::from(2), "Second", 200),
+            expense(Id::from(3), "Third", 300),
+        ],
+        recurring: Vec::new(),
+    };
+    write_datastore(&path, &datastore).unwrap();
+
+    // Moving one forward and changing another, as `edit` might
+    datastore.expenses.swap(0, 2);
+    datastore.expenses[1].amount = 250;
+    write_datastore(&path, &datastore).unwrap();
+
+    let expenses = read_expenses(&path).unwrap();
+    assert_eq!(descriptions(&expenses), ["Third", "Second", "First"]);
+    assert_eq!(expenses[1].amount, 250);
+}
+
+fn undo_puts_back_the_previous_write(extension: &str) {
+    let (_dir, path) = datastore_path(extension);
+    init_datastore(&path).unwrap();
+
+    let coffee = new_expense(&["--description", "Coffee", "--amount", "3.50"]);
+    let lunch = new_expense(&["--description", "Lunch", "--amount", "12"]);
+    add_expense(&coffee, Kind::Expense, "USD", false, &path).unwrap();
+    add_expense(&lunch, Kind::Expense, "USD", false, &path).unwrap();
+
+    undo(&path).unwrap();
+
+    assert_eq!(descriptions(&read_expenses(&path).unwrap()), ["Coffee"]);
+}
+
+fn storage_reads_what_it_wrote(extension: &str) {
+    let (_dir, path) = datastore_path(extension);
+    let storage = storage::for_path(&path);
+
+    let datastore = Datastore {
+        next_id: 2,
+        expenses: vec![expense(Id::from(1), "Coffee", 350)],
+        recurring: Vec::new(),
+    };
+    storage.write(&datastore).unwrap();
+
+    let value = storage.read_value().unwrap();
+    assert_eq!(value["next_id"], 2);
+    assert_eq!(value["expenses"][0]["description"], "Coffee");
+    assert_eq!(value["expenses"][0]["amount_cents"], 350);
+}
+
+fn corrupt_datastore_is_reported(extension: &str) {
+    let (_dir, path) = datastore_path(extension);
+    std::fs::write(&path, "{ this is not a datastore").unwrap();
+
+    let error = read_datastore(&path).unwrap_err();
+
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    assert!(error.to_string().contains("is corrupt"), "{}", error);
+}
+
+fn migrates_from_a_json_datastore(extension: &str) {
+    let (dir, path) = datastore_path(extension);
+    let json = dir.path().join("old.json");
+    let json = json.to_str().unwrap();
+
+    let datastore = Datastore {
+        next_id: 5,
+        expenses: vec![
+            expense(Id::from(1), "Coffee", 350),
+            expense(Id::from(4), "Lunch", 1200),
+        ],
+        recurring: Vec::new(),
+    };
+    write_datastore(json, &datastore).unwrap();
+
+    migrate_datastore(&path, json).unwrap();
+
+    let migrated = read_datastore(&path).unwrap();
+    assert_eq!(migrated.next_id, 5);
+    assert_eq!(descriptions(&migrated.expenses), ["Coffee", "Lunch"]);
+
+    // An existing datastore is never overwritten
+    let error = migrate_datastore(&path, json).unwrap_err();
+    assert!(error.to_string().contains("already exists"), "{}", error);
+}
+
+macro_rules! backend_suite {
+    ($($backend:ident => $extension:literal),* $(,)?) => {
+        $(
+            mod $backend {
+                #[test]
+                fn missing_datastore_reads_as_empty() {
+                    super::missing_datastore_reads_as_empty($extension);
+                }
+
+                #[test]
+                fn round_trips_every_field() {
+                    super::round_trips_every_field($extension);
+                }
+
+                #[test]
+                fn commands_change_the_datastore() {
+                    super::commands_change_the_datastore($extension);
+                }
+
+                #[test]
+                fn keeps_the_order_written() {
+                    super::keeps_the_order_written($extension);
+                }
+
+                #[test]
+                fn undo_puts_back_the_previous_write() {
+                    super::undo_puts_back_the_previous_write($extension);
+                }
+
+                #[test]
+                fn storage_reads_what_it_wrote() {
+                    super::storage_reads_what_it_wrote($extension);
+                }
+
+                #[test]
+                fn corrupt_datastore_is_reported() {
+                    super::corrupt_datastore_is_reported($extension);
+                }
+
+                #[test]
+                fn migrates_from_a_json_datastore() {
+                    super::migrates_from_a_json_datastore($extension);
+                }
+            }
+        )*
+    };
+}
+
+backend_suite! {
+    json => "json",
+    jsonl => "jsonl",
+    toml => "toml",
+    yaml => "yaml",
+    sqlite => "db",
+}
@@ -0,0 +1,128 @@
+//! The library used the way another program would, on temporary datastores
+
+use clap::Parser;
+use expense_tracker::{
+    add_expense, clear_expenses, delete_expense, export_expenses, init_datastore, read_expenses,
+    rename_category, Error, ExportFormat, Id, JsonLayout, Kind, NewExpense,
+};
+
+#[derive(Parser)]
+struct Add {
+    #[command(flatten)]
+    new: NewExpense,
+}
+
+fn new_expense(args: &[&str]) -> NewExpense {
+    // Suggestions only apply without a category, and clap refuses both
+    let no_suggest = if args.contains(&"--category") {
+        None
+    } else {
+        Some(&"--no-suggest")
+    };
+    let args = ["add", "--force"].iter().chain(no_suggest).chain(args);
+    Add::parse_from(args).new
+}
+
+/// An initialized datastore in a directory removed with the guard
+fn datastore() -> (tempfile::TempDir, String) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("expenses.json");
+    let path = path.to_str().unwrap().to_string();
+    init_datastore(&path).unwrap();
+    (dir, path)
+}
+
+fn add(args: &[&str], path: &str) {
+    add_expense(&new_expense(args), Kind::Expense, "USD", false, path).unwrap();
+}
+
+#[test]
+fn init_creates_an_empty_datastore_and_its_directory() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("nested").join("expenses.json");
+    let path = path.to_str().unwrap();
+
+    init_datastore(path).unwrap();
+
+    assert!(std::path::Path::new(path).exists());
+    assert!(read_expenses(path).unwrap().is_empty());
+}
+
+#[test]
+fn added_expenses_are_exported() {
+    let (dir, path) = datastore();
+    add(
+        &[
+            "--description",
+            "Coffee",
+            "--amount",
+            "3.50",
+            "--date",
+            "2024-03-01",
+        ],
+        &path,
+    );
+    add(
+        &[
+            "--description",
+            "Lunch",
+            "--amount",
+            "12",
+            "--category",
+            "food",
+            "--date",
+            "2024-03-02",
+        ],
+        &path,
+    );
+
+    let output = dir.path().join("expenses.csv");
+    let output = output.to_str().unwrap();
+    export_expenses(ExportFormat::Csv, JsonLayout::Auto, output, "USD", &path).unwrap();
+
+    assert_eq!(
+        std::fs::read_to_string(output).unwrap(),
+        "id,description,amount,date,category,kind\r\n\
+         1,Coffee,3.50,2024-03-01,,Expense\r\n\
+         2,Lunch,12.00,2024-03-02,food,Expense\r\n"
+    );
+}
+
+#[test]
+fn failures_come_back_as_errors() {
+    let (_dir, path) = datastore();
+    add(&["--description", "Coffee", "--amount", "3.50"], &path);
+
+    let result = delete_expense(&[Id::from(9)], true, false, "USD", false, &path);
+    assert!(matches!(result, Err(Error::ExpenseNotFound(ids)) if ids == [Id::from(9)]));
+
+    let result = rename_category("travel", "trips", &path);
+    assert!(matches!(result, Err(Error::CategoryNotFound(_))));
+
+    assert_eq!(read_expenses(&path).unwrap().len(), 1);
+}
+
+#[test]
+fn renamed_categories_and_clears_are_written() {
+    let (_dir, path) = datastore();
+    add(
+        &[
+            "--description",
+            "Coffee",
+            "--amount",
+            "3.50",
+            "--category",
+            "Food",
+        ],
+        &path,
+    );
+    add(&["--description", "Train", "--amount", "9"], &path);
+
+    rename_category("food", "groceries", &path).unwrap();
+    let expenses = read_expenses(&path).unwrap();
+    assert_eq!(expenses[0].category.as_deref(), Some("groceries"));
+    assert_eq!(expenses[1].category, None);
+
+    clear_expenses(true, false, &path).unwrap();
+    assert!(read_expenses(&path).unwrap().is_empty());
+}